@@ -0,0 +1,136 @@
+//! Checkers which wait for the network without blocking a thread, to be run
+//! by the [async scheduler](crate::scheduler::async_schd).
+//!
+//! These checkers implement
+//! [`AsyncChecker`](crate::checkers::async_checker::AsyncChecker) natively:
+//! [`HttpChecker`](crate::checkers::http::HttpChecker),
+//! [`CertificateChecker`](crate::checkers::tls::CertificateChecker), the
+//! [database checkers](crate::checkers::database),
+//! [`SshChecker`](crate::checkers::ssh::SshChecker),
+//! [`GrpcHealthChecker`](crate::checkers::grpc::GrpcHealthChecker), the
+//! [UDP checkers](crate::checkers::udp) and
+//! [`DomainExpiryChecker`](crate::checkers::domain::DomainExpiryChecker).
+//! Any other [`Checker`](crate::checkers::Checker) can be run by wrapping it
+//! in a [`BlockingChecker`](crate::checkers::async_checker::BlockingChecker),
+//! which takes up a thread of the blocking pool while it runs and can't be
+//! cancelled, so the number of those checks is limited by the size of the
+//! pool rather than by the network.
+//!
+//! Requires the `async` feature, which is not enabled by default, and has to
+//! be used within a [tokio](https://tokio.rs) runtime.
+
+use crate::checkers::{AddressFamily, Checker, CheckResult};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// The future returned by
+/// [`AsyncChecker::check`](crate::checkers::async_checker::AsyncChecker::check).
+pub type CheckFuture<'a> = Pin<Box<dyn Future<Output = CheckResult> + Send + 'a>>;
+
+/// Like [`Checker`](crate::checkers::Checker), but `check` returns a future.
+///
+/// Dropping the future cancels the check, so a time limit can be put on it
+/// with e.g. `tokio::time::timeout`.
+///
+/// For types implementing both traits, call this one as
+/// `AsyncChecker::check(&mut checker)` if both are in scope.
+pub trait AsyncChecker: Send {
+  fn check(&mut self) -> CheckFuture<'_>;
+}
+
+/// Runs a blocking [`Checker`](crate::checkers::Checker) on the blocking
+/// thread pool of the tokio runtime.
+///
+/// Blocking code can't be cancelled, so if the future is dropped the check
+/// keeps running on its thread until it returns on its own. Further checks
+/// wait for it without taking up a thread, and then run as usual.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{CheckResult, Checker, async_checker::{AsyncChecker, BlockingChecker}};
+/// struct AlwaysUp;
+/// impl Checker for AlwaysUp {
+///   fn check(&mut self) -> CheckResult {
+///     CheckResult::up(None)
+///   }
+/// }
+/// let mut checker = BlockingChecker::new(AlwaysUp);
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(checker.check()).expect();
+/// ```
+pub struct BlockingChecker<C> {
+  inner: Arc<Mutex<C>>,
+}
+
+impl<C: Checker + Send + 'static> BlockingChecker<C> {
+  pub fn new(checker: C) -> Self {
+    BlockingChecker{inner: Arc::new(Mutex::new(checker))}
+  }
+}
+
+impl<C: Checker + Send + 'static> AsyncChecker for BlockingChecker<C> {
+  fn check(&mut self) -> CheckFuture<'_> {
+    let inner = self.inner.clone();
+    Box::pin(async move {
+      let mut checker = inner.lock_owned().await;
+      let res = tokio::task::spawn_blocking(move || checker.check()).await;
+      match res {
+        Ok(r) => r,
+        Err(e) => CheckResult::error(Some(format!("Check panicked: {}", &e))),
+      }
+    })
+  }
+}
+
+/// `future`, or an error once `timeout` has passed.
+pub(crate) async fn within<T>(timeout: time::Duration, future: impl Future<Output = Result<T, String>>) -> Result<T, String> {
+  tokio::time::timeout(timeout, future).await.unwrap_or_else(|_| Err(format!("Timed out after {}ms.", timeout.as_millis())))
+}
+
+/// Like `checkers::resolve_addresses`, without blocking.
+pub(crate) async fn resolve_addresses(host: &str, port: u16, family: AddressFamily) -> Result<Vec<SocketAddr>, String> {
+  let addrs = tokio::net::lookup_host((host, port)).await.map_err(|e| format!("Unable to resolve {}: {}", host, &e))?;
+  let addrs: Vec<_> = addrs.filter(|a| family.includes(a)).collect();
+  if addrs.is_empty() {
+    return Err(format!("{} has no {}addresses.", host, family.name()));
+  }
+  Ok(addrs)
+}
+
+/// Connect to the first of `addrs` which accepts the connection, or to any
+/// address of `host` if `addrs` is `None`.
+pub(crate) async fn connect(host: &str, port: u16, addrs: Option<&[SocketAddr]>) -> Result<TcpStream, String> {
+  let conn = match addrs {
+    Some(addrs) => TcpStream::connect(addrs).await,
+    None => TcpStream::connect((host, port)).await,
+  };
+  let conn = conn.map_err(|e| format!("Unable to connect: {}", &e))?;
+  let _ = conn.set_nodelay(true);
+  Ok(conn)
+}
+
+#[test]
+fn blocking_checker_test() {
+  struct Slow(time::Duration);
+  impl Checker for Slow {
+    fn check(&mut self) -> CheckResult {
+      std::thread::sleep(self.0);
+      CheckResult::up(Some(format!("slept {}ms", self.0.as_millis())))
+    }
+  }
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut checker = BlockingChecker::new(Slow(time::Duration::from_millis(300)));
+  runtime.block_on(async {
+    assert_eq!(checker.check().await, CheckResult::up(Some("slept 300ms".to_owned())));
+    assert!(tokio::time::timeout(time::Duration::from_millis(50), checker.check()).await.is_err());
+    // Still sleeping on its own thread, so the next check waits for it.
+    let start = time::Instant::now();
+    checker.check().await.expect();
+    assert!(start.elapsed() >= time::Duration::from_millis(500));
+  });
+}
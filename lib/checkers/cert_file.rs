@@ -0,0 +1,270 @@
+//! Check that certificates stored in local files are not too close to expiry.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType};
+use crate::checkers::tls::{ExpiryLevel, ExpiryTiers, days_until, system_time_to_time_t};
+use openssl::x509::X509;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// Check that certificates stored on disk, such as internal CAs, client
+/// certificates, or certificates of services not reachable from here, are not
+/// too close to expiry.
+///
+/// Each path can be a PEM file (possibly holding a whole chain or bundle), a DER
+/// file, or a directory, in which case every certificate file directly inside it
+/// is checked, and other files are ignored. For every file, the soonest-expiring
+/// certificate is reported. The soonest expiry over all paths is reported in the
+/// `expiry_days` [metric](crate::checkers::CheckResult::metrics).
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{cert_file::CertificateFileChecker, Checker};
+/// # use std::time::Duration;
+/// let mut checker = CertificateFileChecker::new("/etc/ssl/certs/ca-certificates.crt");
+/// checker.set_expiry_threshold(Duration::from_secs(14*24*60*60));
+/// let result = checker.check();
+/// ```
+pub struct CertificateFileChecker {
+  paths: Vec<PathBuf>,
+  failure_mode: CheckResultType,
+  expiry_threshold: time::Duration,
+  tiers: Option<Vec<(time::Duration, ExpiryLevel)>>,
+  expiry_tiers: ExpiryTiers,
+  fake_now: Option<time::SystemTime>,
+}
+
+impl CertificateFileChecker {
+  /// Construct a checker for `path`. More paths can be added with
+  /// [`add_path`](crate::checkers::cert_file::CertificateFileChecker::add_path).
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    let expiry_threshold = time::Duration::from_secs(2*24*60*60);
+    CertificateFileChecker{
+      paths: vec![path.into()],
+      failure_mode: CheckResultType::WARN,
+      expiry_threshold,
+      tiers: None,
+      expiry_tiers: ExpiryTiers::new(vec![(expiry_threshold, ExpiryLevel::WARN)], false),
+      fake_now: None,
+    }
+  }
+
+  /// Also check the certificate file or directory at `path`.
+  pub fn add_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+    self.paths.push(path.into());
+    self
+  }
+
+  /// Same as
+  /// [`CertificateCheckerBuilder::set_failure_mode`](crate::checkers::tls::CertificateCheckerBuilder::set_failure_mode).
+  ///
+  /// Certificates which have already expired, or are not yet valid, always
+  /// result in `ERROR`.
+  pub fn set_failure_mode(&mut self, value: CheckResultType) -> &mut Self {
+    self.failure_mode = value;
+    self.update_expiry_tiers();
+    self
+  }
+
+  /// Same as
+  /// [`CertificateCheckerBuilder::set_expiry_threshold`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_threshold).
+  ///
+  /// Defaults to 2 days.
+  pub fn set_expiry_threshold(&mut self, value: time::Duration) -> &mut Self {
+    self.expiry_threshold = value;
+    self.update_expiry_tiers();
+    self
+  }
+
+  /// Same as
+  /// [`CertificateCheckerBuilder::set_expiry_tiers`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_tiers).
+  pub fn set_expiry_tiers(&mut self, tiers: Vec<(time::Duration, ExpiryLevel)>) -> &mut Self {
+    self.tiers = Some(tiers);
+    self.update_expiry_tiers();
+    self
+  }
+
+  fn update_expiry_tiers(&mut self) {
+    self.expiry_tiers = match self.tiers {
+      Some(ref tiers) => ExpiryTiers::new(tiers.clone(), true),
+      None => ExpiryTiers::new(vec![(self.expiry_threshold, ExpiryLevel::from_failure_mode(self.failure_mode))], false),
+    };
+  }
+
+  /// For testing. This make `check` act as if the system time is `value`.
+  pub fn fake_time(&mut self, value: time::SystemTime) -> &mut Self {
+    self.fake_now = Some(value);
+    self
+  }
+
+  fn check_bundle(&self, path: &Path, certs: &[X509]) -> CheckResult {
+    let now = self.fake_now.unwrap_or(time::SystemTime::now());
+    let now_time_t = system_time_to_time_t(now);
+    for cert in certs.iter() {
+      if days_until(now_time_t, cert.not_before()) > 0f32 {
+        return CheckResult::error(Some(format!("{}: certificate {} is not yet valid: valid from {}.", path.display(), subject_of(cert), cert.not_before())));
+      }
+    }
+    let soonest = match certs.iter().min_by(|a, b| {
+      days_until(now_time_t, a.not_after()).partial_cmp(&days_until(now_time_t, b.not_after())).unwrap()
+    }) {
+      Some(c) => c,
+      None => return CheckResult::error(Some(format!("{}: no certificates found.", path.display()))),
+    };
+    let not_after = soonest.not_after();
+    let rem_days = days_until(now_time_t, not_after);
+    let tier = self.expiry_tiers.tier(rem_days);
+    let result = if rem_days <= 0f32 {
+      CheckResult::error(Some(format!("{}: certificate {} has expired: valid until {}.", path.display(), subject_of(soonest), not_after)))
+    } else if tier.is_some() {
+      CheckResult::new(self.expiry_tiers.result_type(tier), Some(format!("{}: certificate {} expiring in {:.1} days: valid until {}.", path.display(), subject_of(soonest), rem_days, not_after)))
+    } else {
+      CheckResult::up(Some(format!("{}: certificate {} valid until {}", path.display(), subject_of(soonest), not_after)))
+    };
+    result.with_metric("expiry_days", rem_days as f64, "days")
+  }
+}
+
+/// Parse a PEM (possibly with several certificates) or DER certificate file.
+fn parse_certs(content: &[u8]) -> Result<Vec<X509>, String> {
+  if content.windows(11).any(|w| w == b"-----BEGIN ") {
+    let certs = X509::stack_from_pem(content).map_err(|e| format!("{}", &e))?;
+    if certs.is_empty() {
+      return Err("no certificates found".to_owned());
+    }
+    Ok(certs)
+  } else {
+    X509::from_der(content).map(|c| vec![c]).map_err(|e| format!("{}", &e))
+  }
+}
+
+fn subject_of(cert: &X509) -> String {
+  let entries: Vec<String> = cert.subject_name().entries().map(|e| {
+    let value = String::from_utf8_lossy(e.data().as_slice());
+    format!("{}={}", e.object().nid().short_name().unwrap_or("?"), value)
+  }).collect();
+  entries.join(", ")
+}
+
+impl Checker for CertificateFileChecker {
+  fn check(&mut self) -> CheckResult {
+    let mut results = Vec::new();
+    for path in self.paths.iter() {
+      let meta = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) => {
+          results.push(CheckResult::error(Some(format!("{}: {}", path.display(), &e))));
+          continue;
+        }
+      };
+      if meta.is_dir() {
+        let mut files: Vec<PathBuf> = match fs::read_dir(path) {
+          Ok(rd) => rd.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect(),
+          Err(e) => {
+            results.push(CheckResult::error(Some(format!("{}: {}", path.display(), &e))));
+            continue;
+          }
+        };
+        files.sort();
+        let mut found_any = false;
+        for file in files.iter() {
+          // Directories may hold keys and other files as well, so only look at
+          // those which parse as certificates.
+          if let Ok(certs) = fs::read(file).map_err(|e| format!("{}", &e)).and_then(|c| parse_certs(&c)) {
+            found_any = true;
+            results.push(self.check_bundle(file, &certs));
+          }
+        }
+        if !found_any {
+          results.push(CheckResult::error(Some(format!("{}: no certificate files in directory.", path.display()))));
+        }
+      } else {
+        match fs::read(path).map_err(|e| format!("{}", &e)).and_then(|c| parse_certs(&c)) {
+          Ok(certs) => results.push(self.check_bundle(path, &certs)),
+          Err(e) => results.push(CheckResult::error(Some(format!("{}: unable to load certificates: {}", path.display(), e)))),
+        }
+      }
+    }
+    let result_type = results.iter().map(|r| r.result_type).max().unwrap_or(CheckResultType::UP);
+    let rem_days = results.iter().filter_map(|r| r.get_metric("expiry_days")).fold(None, |a: Option<f64>, b| Some(a.map_or(b, |a| a.min(b))));
+    let mut result = CheckResult::new(result_type, Some(results.into_iter().filter_map(|r| r.info).collect::<Vec<String>>().join("\n")));
+    if let Some(rem_days) = rem_days {
+      result = result.with_metric("expiry_days", rem_days, "days");
+      // Like the handshake failure of the certificate checker, an expired
+      // certificate notifies as usual.
+      if rem_days > 0f64 {
+        self.expiry_tiers.set_notify(&mut result, rem_days as f32);
+      }
+    }
+    result
+  }
+}
+
+#[test]
+fn cert_file_checker_test() {
+  use crate::checkers::tls::testing;
+  let dir = crate::utils::testing::temp_dir("cert-file");
+  let ca = testing::make_ca("Test CA");
+  let leaf = testing::make_cert(&ca, &["leaf.test"], 10);
+  let mut bundle = leaf.0.to_pem().unwrap();
+  bundle.extend(ca.0.to_pem().unwrap());
+  fs::write(dir.join("bundle.pem"), &bundle).unwrap();
+  fs::write(dir.join("ca.der"), ca.0.to_der().unwrap()).unwrap();
+  fs::write(dir.join("leaf.key"), leaf.1.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+  let day = time::Duration::from_secs(24*60*60);
+  let mut chk = CertificateFileChecker::new(dir.join("bundle.pem"));
+  chk.set_expiry_threshold(5*day);
+  let res = chk.check();
+  res.expect();
+  assert!(res.info.unwrap().contains("certificate CN=leaf.test valid until"));
+
+  chk.fake_time(time::SystemTime::now() + 7*day);
+  let res = chk.check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("certificate CN=leaf.test expiring in 3."));
+  chk.set_failure_mode(CheckResultType::ERROR);
+  chk.check().expect_err_contains("expiring in");
+
+  chk.fake_time(time::SystemTime::now() + 11*day);
+  chk.check().expect_err_contains("certificate CN=leaf.test has expired");
+
+  // The key file in the directory is ignored, and the DER CA is picked up.
+  let mut chk = CertificateFileChecker::new(&dir);
+  let res = chk.check();
+  res.expect();
+  let info = res.info.unwrap();
+  assert!(info.contains("ca.der: certificate CN=Test CA valid until"));
+  assert!(!info.contains("leaf.key"));
+
+  chk.add_path(dir.join("leaf.key"));
+  chk.check().expect_err_contains("leaf.key: unable to load certificates");
+
+  let mut chk = CertificateFileChecker::new(dir.join("nonexistent.pem"));
+  chk.check().expect_err_contains("nonexistent.pem");
+
+  // Tiers notify only when one is crossed, like the certificate checker.
+  use crate::checkers::NotifyHint;
+  let mut chk = CertificateFileChecker::new(dir.join("bundle.pem"));
+  chk.set_expiry_tiers(vec![(30*day, ExpiryLevel::INFO), (7*day, ExpiryLevel::WARN)]);
+  let now = time::SystemTime::now();
+  chk.fake_time(now);
+  let res = chk.check();
+  res.expect();
+  assert!(res.info.as_ref().unwrap().contains("expiring in"));
+  assert_eq!(res.notify, NotifyHint::Always);
+  let days = res.get_metric("expiry_days").unwrap();
+  assert!(days > 9.9 && days <= 10.0, "expiry_days = {}", days);
+  assert_eq!(chk.check().notify, NotifyHint::Never);
+  chk.fake_time(now + 4*day);
+  let res = chk.check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.notify, NotifyHint::Always);
+  assert_eq!(chk.check().notify, NotifyHint::Never);
+  chk.fake_time(now + 11*day);
+  let res = chk.check();
+  res.expect_err_contains("has expired");
+  assert_eq!(res.notify, NotifyHint::Default);
+
+  let _ = fs::remove_dir_all(&dir);
+}
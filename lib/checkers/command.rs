@@ -0,0 +1,326 @@
+//! Run external check commands following the Nagios plugin convention.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType, Metric};
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process;
+use std::time;
+
+/// Runs an external command which follows the
+/// [Nagios plugin](https://nagios-plugins.org/doc/guidelines.html) (or
+/// Monitoring Plugins) conventions, such as the `check_*` scripts:
+///
+/// * Exit code 0 is `UP`, 1 is `WARN`, 2 is `ERROR`, and 3 (`UNKNOWN`) or
+///   anything else is also `ERROR`.
+/// * The first line of output becomes the info, and the performance data after
+///   `|` becomes the [metrics](crate::checkers::CheckResult::metrics) of the
+///   result.
+///
+/// The command runs in its own process group. If it does not finish within the
+/// timeout, the whole group is killed, so that nothing it started is left
+/// behind.
+///
+/// ## Example
+/// ```rust
+/// use serverwatch::checkers::{Checker, command::CommandChecker};
+/// let mut checker = CommandChecker::new("sh");
+/// checker.args(&["-c", "echo 'DISK OK | /=2643MB;5948;5958;0;5968'"]);
+/// let result = checker.check();
+/// result.expect();
+/// assert_eq!(result.get_metric("/"), Some(2643f64));
+/// ```
+pub struct CommandChecker {
+  program: String,
+  args: Vec<String>,
+  env: Vec<(String, String)>,
+  timeout: time::Duration,
+}
+
+impl CommandChecker {
+  pub fn new(program: &str) -> Self {
+    CommandChecker{program: program.to_owned(), args: Vec::new(), env: Vec::new(), timeout: time::Duration::from_secs(10)}
+  }
+
+  pub fn arg(&mut self, arg: &str) -> &mut Self {
+    self.args.push(arg.to_owned());
+    self
+  }
+
+  pub fn args(&mut self, args: &[&str]) -> &mut Self {
+    self.args.extend(args.iter().map(|a| (*a).to_owned()));
+    self
+  }
+
+  /// Set an environment variable for the command, in addition to those
+  /// inherited from this process.
+  pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+    self.env.push((key.to_owned(), value.to_owned()));
+    self
+  }
+
+  /// Kill the command (and everything else in its process group) if it has not
+  /// exited after `value`.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+}
+
+/// How long to wait for stdout to be closed after killing the process group.
+const KILL_GRACE: time::Duration = time::Duration::from_millis(200);
+
+/// Append what can be read from `stdout` without blocking to `output`.
+/// Returns whether it is still open.
+fn read_available(stdout: &mut process::ChildStdout, output: &mut Vec<u8>) -> bool {
+  let mut buf = [0u8; 4096];
+  loop {
+    match stdout.read(&mut buf) {
+      Ok(0) => return false,
+      Ok(n) => output.extend_from_slice(&buf[..n]),
+      Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return true,
+      Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+      Err(_) => return false,
+    }
+  }
+}
+
+/// An `ERROR` with `message`, followed by whatever the command printed.
+fn timed_out(message: String, output: &[u8]) -> CheckResult {
+  let (text, _) = parse_plugin_output(&String::from_utf8_lossy(output));
+  CheckResult::error(Some(if text.is_empty() { message } else { format!("{}\n{}", message, text) }))
+}
+
+fn kill_group(pgid: u32) {
+  unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+}
+
+impl Checker for CommandChecker {
+  fn check(&mut self) -> CheckResult {
+    let deadline = time::Instant::now() + self.timeout;
+    let mut cmd = process::Command::new(&self.program);
+    cmd.args(&self.args)
+      .envs(self.env.iter().map(|(k, v)| (k, v)))
+      .stdin(process::Stdio::null())
+      .stdout(process::Stdio::piped())
+      .stderr(process::Stdio::null())
+      .process_group(0);
+    let mut child = match cmd.spawn() {
+      Ok(c) => c,
+      Err(e) => return CheckResult::error(Some(format!("Unable to run {}: {}", &self.program, &e))),
+    };
+    let pgid = child.id();
+    let mut stdout = child.stdout.take().unwrap();
+    // Read in this thread without blocking, so that a process holding stdout
+    // open can't keep us waiting past the timeout.
+    unsafe {
+      let fd = stdout.as_raw_fd();
+      libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK);
+    }
+    let mut output = Vec::new();
+    let mut stdout_open = true;
+    let mut status = None;
+    let mut killed_at = None;
+    loop {
+      if stdout_open {
+        stdout_open = read_available(&mut stdout, &mut output);
+      }
+      if status.is_none() {
+        match child.try_wait() {
+          Ok(s) => status = s,
+          Err(e) => {
+            kill_group(pgid);
+            let _ = child.wait();
+            return CheckResult::error(Some(format!("Waiting for {}: {}", &self.program, &e)));
+          }
+        }
+      }
+      if status.is_some() && !stdout_open {
+        break;
+      }
+      let now = time::Instant::now();
+      match killed_at {
+        None if now >= deadline => {
+          kill_group(pgid);
+          if status.is_none() {
+            let _ = child.wait();
+            read_available(&mut stdout, &mut output);
+            return timed_out(format!("Timed out after {}ms: killed {}.", self.timeout.as_millis(), &self.program), &output);
+          }
+          // It exited, but something it started in the background is still
+          // holding stdout open. Killing the group should close it.
+          killed_at = Some(now);
+        },
+        Some(t) if now >= t + KILL_GRACE => {
+          return timed_out(format!("Timed out after {}ms: something {} started outside its process group is holding its output open.", self.timeout.as_millis(), &self.program), &output);
+        },
+        _ => {},
+      }
+      if stdout_open {
+        let mut pollfd = libc::pollfd{fd: stdout.as_raw_fd(), events: libc::POLLIN, revents: 0};
+        unsafe { libc::poll(&mut pollfd, 1, 10) };
+      } else {
+        std::thread::sleep(time::Duration::from_millis(10));
+      }
+    }
+    let status = status.unwrap();
+    let (text, metrics) = parse_plugin_output(&String::from_utf8_lossy(&output));
+    let result_type = match status.code() {
+      Some(0) => CheckResultType::UP,
+      Some(1) => CheckResultType::WARN,
+      _ => CheckResultType::ERROR,
+    };
+    let info = match status.code() {
+      Some(0) | Some(1) | Some(2) => text,
+      Some(3) => format!("UNKNOWN: {}", text),
+      Some(c) => format!("Exited with code {}: {}", c, text),
+      None => format!("Killed by signal {}: {}", status.signal().unwrap_or(0), text),
+    };
+    let mut result = CheckResult::new(result_type, if info.is_empty() { None } else { Some(info) });
+    result.metrics = metrics;
+    result
+  }
+}
+
+/// Split plugin output into the text (first line, followed by any long output)
+/// and the performance data, which may follow a `|` on the first line and on
+/// any line after the long output.
+fn parse_plugin_output(output: &str) -> (String, Vec<Metric>) {
+  let mut lines = output.lines();
+  let first = lines.next().unwrap_or("");
+  let (text, mut perf) = match first.find('|') {
+    Some(i) => (first[..i].trim().to_owned(), first[i + 1..].to_owned()),
+    None => (first.trim().to_owned(), String::new()),
+  };
+  let mut long_text = Vec::new();
+  let mut in_perf = false;
+  for line in lines {
+    if in_perf {
+      perf.push(' ');
+      perf.push_str(line);
+    } else if let Some(i) = line.find('|') {
+      long_text.push(line[..i].trim_end());
+      perf.push(' ');
+      perf.push_str(&line[i + 1..]);
+      in_perf = true;
+    } else {
+      long_text.push(line);
+    }
+  }
+  let mut text = text;
+  for l in long_text.into_iter().filter(|l| !l.is_empty()) {
+    text.push('\n');
+    text.push_str(l);
+  }
+  (text, parse_perfdata(&perf))
+}
+
+/// Parse `'label'=value[UOM];[warn];[crit];[min];[max]` items, separated by
+/// spaces. Only the label, value and unit are kept. Malformed items, and those
+/// with value `U` (unknown), are skipped.
+fn parse_perfdata(perf: &str) -> Vec<Metric> {
+  let mut metrics = Vec::new();
+  let mut rest = perf.trim_start();
+  while !rest.is_empty() {
+    let (label, after_label) = if let Some(quoted) = rest.strip_prefix('\'') {
+      match quoted.find("'=") {
+        Some(i) => (&quoted[..i], &quoted[i + 2..]),
+        None => break,
+      }
+    } else {
+      match rest.find('=') {
+        Some(i) if !rest[..i].contains(' ') => (&rest[..i], &rest[i + 1..]),
+        _ => {
+          // Not an item. Skip to the next word.
+          rest = rest.find(' ').map(|i| rest[i..].trim_start()).unwrap_or("");
+          continue;
+        }
+      }
+    };
+    let end = after_label.find(' ').unwrap_or(after_label.len());
+    let item = &after_label[..end];
+    rest = after_label[end..].trim_start();
+    let value_and_unit = item.split(';').next().unwrap_or("");
+    let num_end = value_and_unit.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e' || c == 'E')).unwrap_or(value_and_unit.len());
+    if let Ok(value) = value_and_unit[..num_end].parse::<f64>() {
+      metrics.push(Metric{name: label.to_owned(), value, unit: value_and_unit[num_end..].to_owned()});
+    }
+  }
+  metrics
+}
+
+#[test]
+fn parse_plugin_output_test() {
+  let (text, metrics) = parse_plugin_output("DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968\n/ 15272 MB (77%);\n/boot 68 MB (69%);\n/home 69357 MB (27%);\n/var/log 819 MB (84%); | /boot=68MB;88;93;0;98\n/home=69357MB;253404;253409;0;253414 \n'/var/log'=818MB;970;975;0;980\n");
+  assert_eq!(text, "DISK OK - free space: / 3326 MB (56%);\n/ 15272 MB (77%);\n/boot 68 MB (69%);\n/home 69357 MB (27%);\n/var/log 819 MB (84%);");
+  let names: Vec<&str> = metrics.iter().map(|m| &m.name[..]).collect();
+  assert_eq!(names, vec!["/", "/boot", "/home", "/var/log"]);
+  assert_eq!(metrics[0], Metric{name: "/".to_owned(), value: 2643f64, unit: "MB".to_owned()});
+  assert_eq!(metrics[3].value, 818f64);
+
+  let (text, metrics) = parse_plugin_output("PING OK - Packet loss = 0%, RTA = 0.80 ms | 'round trip'=0.8ms;100;500 loss=0%;20;60 time=U;; bogus\n");
+  assert_eq!(text, "PING OK - Packet loss = 0%, RTA = 0.80 ms");
+  assert_eq!(metrics, vec![
+    Metric{name: "round trip".to_owned(), value: 0.8, unit: "ms".to_owned()},
+    Metric{name: "loss".to_owned(), value: 0f64, unit: "%".to_owned()},
+  ]);
+
+  assert_eq!(parse_plugin_output(""), (String::new(), vec![]));
+}
+
+#[test]
+fn command_checker_test() {
+  macro_rules! sh {
+    ($script:expr) => {{
+      let mut c = CommandChecker::new("sh");
+      c.args(&["-c", $script]).set_timeout(time::Duration::from_secs(2));
+      c
+    }};
+  }
+  let res = sh!("echo 'OK - all good | users=3;5;10'").check();
+  res.expect();
+  assert_eq!(res.info.as_ref().unwrap(), "OK - all good");
+  assert_eq!(res.get_metric("users"), Some(3f64));
+  assert_eq!(sh!("echo 'WARNING - hmm'; exit 1").check().result_type, CheckResultType::WARN);
+  sh!("echo 'CRITICAL - oh no'; exit 2").check().expect_err_contains("CRITICAL - oh no");
+  sh!("echo 'what'; exit 3").check().expect_err_contains("UNKNOWN: what");
+  sh!("exit 42").check().expect_err_contains("Exited with code 42");
+  sh!("kill -9 $$").check().expect_err_contains("Killed by signal 9");
+  assert_eq!(sh!("echo \"$MY_VAR\"").env("MY_VAR", "hello").check().info.unwrap(), "hello");
+  CommandChecker::new("/nonexistent/check_foo").check().expect_err_contains("Unable to run");
+
+  // Timeout kills everything the command started.
+  let pid_file = std::env::temp_dir().join(format!("serverwatch-test-command-{}", process::id()));
+  let mut chk = sh!(&format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+  chk.set_timeout(time::Duration::from_millis(500));
+  let start = time::Instant::now();
+  chk.check().expect_err_contains("Timed out after 500ms");
+  assert!(start.elapsed() < time::Duration::from_secs(2));
+  let pid = std::fs::read_to_string(&pid_file).unwrap();
+  let _ = std::fs::remove_file(&pid_file);
+  std::thread::sleep(time::Duration::from_millis(100));
+  let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+  assert!(stat.is_empty() || stat.contains(") Z "), "background process still alive: {}", stat);
+
+  // The command exits but leaves something holding stdout open.
+  let mut chk = sh!("echo 'OK'; sleep 30 &");
+  chk.set_timeout(time::Duration::from_millis(500));
+  let start = time::Instant::now();
+  chk.check().expect();
+  assert!(start.elapsed() < time::Duration::from_secs(2));
+
+  // ... and that something has left the process group, so killing the group
+  // doesn't close stdout.
+  let pid_file = std::env::temp_dir().join(format!("serverwatch-test-command-setsid-{}", process::id()));
+  let mut chk = sh!(&format!("echo 'OK - partial'; setsid sh -c 'echo $$ > {}; exec sleep 30' &", pid_file.display()));
+  chk.set_timeout(time::Duration::from_millis(500));
+  let start = time::Instant::now();
+  let res = chk.check();
+  assert!(start.elapsed() < time::Duration::from_secs(2));
+  res.expect_err_contains("holding its output open.\nOK - partial");
+  let pid = std::fs::read_to_string(&pid_file).unwrap();
+  let _ = std::fs::remove_file(&pid_file);
+  unsafe { libc::kill(pid.trim().parse().unwrap(), libc::SIGKILL) };
+}
@@ -0,0 +1,248 @@
+//! Checkers which combine the results of other checkers.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType, Metric, NotifyHint, summarize_results};
+
+/// How a [`CompositeChecker`](crate::checkers::composite::CompositeChecker)
+/// combines the results of its children.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompositeMode {
+  /// The worst of the results: everything has to be up.
+  All,
+  /// The best of the results: at least one thing has to be up.
+  Any,
+  /// `UP` if at least this many children are `UP`, `WARN` if that many are at
+  /// least `WARN`, `ERROR` otherwise.
+  Quorum(usize),
+}
+
+/// Runs several checkers, optionally in parallel, and combines their results,
+/// for example for a service which is up if any of its mirrors is.
+///
+/// The info of the combined result summarizes the children which are not `UP`,
+/// followed by the others, each prefixed by the description given to
+/// [`add`](crate::checkers::composite::CompositeChecker::add). Metrics of the
+/// children are kept, as `<description>.<name>`. A notification is always
+/// asked for if any child asks for one, and suppressed if every child which is
+/// not `UP` has already notified.
+///
+/// ## Example
+/// Using `HttpChecker` needs the `checkers` feature.
+/// ```rust,no_run
+/// # #[cfg(feature = "checkers")] {
+/// # use serverwatch::checkers::{Checker, CheckResultType, composite::CompositeChecker, http::HttpChecker};
+/// let mut checker = CompositeChecker::any_of();
+/// checker.add("mirror 1", Box::new(HttpChecker::new("https://mirror1.example.com/").unwrap()))
+///        .add("mirror 2", Box::new(HttpChecker::new("https://mirror2.example.com/").unwrap()))
+///        .set_parallel(true)
+///        .set_degraded_mode(CheckResultType::WARN);
+/// let result = checker.check();
+/// # }
+/// ```
+pub struct CompositeChecker {
+  children: Vec<(String, Box<dyn Checker + Send + Sync>)>,
+  mode: CompositeMode,
+  parallel: bool,
+  failure_mode: CheckResultType,
+  degraded_mode: CheckResultType,
+}
+
+impl CompositeChecker {
+  pub fn new(mode: CompositeMode) -> Self {
+    CompositeChecker{children: Vec::new(), mode, parallel: false, failure_mode: CheckResultType::ERROR, degraded_mode: CheckResultType::UP}
+  }
+
+  /// Same as `new(CompositeMode::All)`.
+  pub fn all_of() -> Self {
+    Self::new(CompositeMode::All)
+  }
+
+  /// Same as `new(CompositeMode::Any)`.
+  pub fn any_of() -> Self {
+    Self::new(CompositeMode::Any)
+  }
+
+  /// Same as `new(CompositeMode::Quorum(k))`.
+  pub fn quorum(k: usize) -> Self {
+    Self::new(CompositeMode::Quorum(k))
+  }
+
+  pub fn add(&mut self, desc: &str, checker: Box<dyn Checker + Send + Sync>) -> &mut Self {
+    self.children.push((desc.to_owned(), checker));
+    self
+  }
+
+  /// Run the children on separate threads, so that the check takes as long as
+  /// the slowest child instead of all of them together.
+  ///
+  /// Default is false.
+  pub fn set_parallel(&mut self, value: bool) -> &mut Self {
+    self.parallel = value;
+    self
+  }
+
+  /// The result to report instead of `ERROR` when the combined result is
+  /// `ERROR`, for example `WARN` for something non-critical.
+  ///
+  /// Default is `ERROR`.
+  pub fn set_failure_mode(&mut self, value: CheckResultType) -> &mut Self {
+    self.failure_mode = value;
+    self
+  }
+
+  /// The result to report when the combined result is `UP`, but some children
+  /// are not, for example `WARN` to hear about a mirror being down before all
+  /// of them are.
+  ///
+  /// Default is `UP`.
+  pub fn set_degraded_mode(&mut self, value: CheckResultType) -> &mut Self {
+    self.degraded_mode = value;
+    self
+  }
+
+  fn run_children(&mut self) -> Vec<CheckResult> {
+    if !self.parallel {
+      return self.children.iter_mut().map(|(_, c)| c.check()).collect();
+    }
+    std::thread::scope(|s| {
+      let handles: Vec<_> = self.children.iter_mut().map(|(_, c)| s.spawn(move || c.check())).collect();
+      handles.into_iter().map(|h| match h.join() {
+        Ok(r) => r,
+        Err(_) => CheckResult::error(Some("Checker panicked.".to_owned())),
+      }).collect()
+    })
+  }
+
+  fn combine(&self, types: &[CheckResultType]) -> CheckResultType {
+    let count_at_least = |t: CheckResultType| types.iter().filter(|r| **r <= t).count();
+    match self.mode {
+      CompositeMode::All => types.iter().cloned().max().unwrap_or(CheckResultType::UP),
+      CompositeMode::Any => types.iter().cloned().min().unwrap_or(CheckResultType::UP),
+      CompositeMode::Quorum(k) => {
+        if count_at_least(CheckResultType::UP) >= k {
+          CheckResultType::UP
+        } else if count_at_least(CheckResultType::WARN) >= k {
+          CheckResultType::WARN
+        } else {
+          CheckResultType::ERROR
+        }
+      }
+    }
+  }
+}
+
+/// The notification hint of the combined result of `results`.
+fn combine_notify(results: &[CheckResult]) -> NotifyHint {
+  if results.iter().any(|r| r.notify == NotifyHint::Always) {
+    return NotifyHint::Always;
+  }
+  let mut failed = results.iter().filter(|r| r.result_type != CheckResultType::UP).peekable();
+  if failed.peek().is_some() && failed.all(|r| r.notify == NotifyHint::Never) {
+    NotifyHint::Never
+  } else {
+    NotifyHint::Default
+  }
+}
+
+impl Checker for CompositeChecker {
+  fn check(&mut self) -> CheckResult {
+    let results = self.run_children();
+    let types: Vec<CheckResultType> = results.iter().map(|r| r.result_type).collect();
+    let notify = combine_notify(&results);
+    let result_type = match self.combine(&types) {
+      CheckResultType::ERROR => self.failure_mode,
+      CheckResultType::UP if types.iter().any(|t| *t != CheckResultType::UP) => self.degraded_mode,
+      t => t,
+    };
+    let mut errors = Vec::new();
+    let mut warns = Vec::new();
+    let mut ups = Vec::new();
+    let mut metrics = Vec::new();
+    for ((desc, _), child) in self.children.iter().zip(results) {
+      metrics.extend(child.metrics.iter().map(|m| Metric{name: format!("{}.{}", desc, &m.name), value: m.value, unit: m.unit.clone()}));
+      let info = match child.info {
+        Some(ref info) => format!("{}: {}", desc, info),
+        None => desc.clone(),
+      };
+      let child = CheckResult::new(child.result_type, Some(info));
+      match child.result_type {
+        CheckResultType::ERROR => errors.push(child),
+        CheckResultType::WARN => warns.push(child),
+        CheckResultType::UP => ups.push(child),
+      }
+    }
+    let mut lines = Vec::new();
+    if !errors.is_empty() {
+      lines.push(summarize_results("checks", &errors));
+    }
+    if !warns.is_empty() {
+      lines.push(summarize_results("checks", &warns));
+    }
+    lines.extend(ups.into_iter().filter_map(|r| r.info));
+    let mut result = CheckResult::new(result_type, if lines.is_empty() { None } else { Some(lines.join("\n")) });
+    result.metrics = metrics;
+    result.with_notify(notify)
+  }
+}
+
+#[cfg(test)]
+struct FixedChecker(CheckResult, std::time::Duration);
+
+#[cfg(test)]
+impl Checker for FixedChecker {
+  fn check(&mut self) -> CheckResult {
+    std::thread::sleep(self.1);
+    self.0.clone()
+  }
+}
+
+#[test]
+fn composite_test() {
+  use std::time::{Duration, Instant};
+  let make = |mode: CompositeMode, types: &[CheckResultType]| {
+    let mut c = CompositeChecker::new(mode);
+    for (i, t) in types.iter().enumerate() {
+      let info = if *t == CheckResultType::UP { None } else { Some(format!("{:?} {}", t, i)) };
+      c.add(&format!("c{}", i), Box::new(FixedChecker(CheckResult::new(*t, info).with_metric("ms", i as f64, "ms"), Duration::from_millis(0))));
+    }
+    c
+  };
+  use CheckResultType::{UP, WARN, ERROR};
+
+  let res = make(CompositeMode::All, &[UP, WARN, ERROR, WARN]).check();
+  assert_eq!(res.result_type, ERROR);
+  assert_eq!(res.info.as_ref().unwrap(), "1 checks reported ERROR: c2: ERROR 2\n2 checks reported WARN: c1: WARN 1, c3: WARN 3\nc0");
+  assert_eq!(res.get_metric("c3.ms"), Some(3f64));
+  assert_eq!(make(CompositeMode::All, &[UP, WARN]).check().result_type, WARN);
+  assert_eq!(make(CompositeMode::All, &[]).check(), CheckResult::up(None));
+
+  assert_eq!(make(CompositeMode::Any, &[ERROR, WARN, ERROR]).check().result_type, WARN);
+  assert_eq!(make(CompositeMode::Any, &[ERROR, UP]).check().result_type, UP);
+  assert_eq!(make(CompositeMode::Any, &[ERROR, UP]).set_degraded_mode(WARN).check().result_type, WARN);
+  assert_eq!(make(CompositeMode::Any, &[UP, UP]).set_degraded_mode(WARN).check().result_type, UP);
+  assert_eq!(make(CompositeMode::Any, &[ERROR, ERROR]).set_failure_mode(WARN).check().result_type, WARN);
+
+  assert_eq!(make(CompositeMode::Quorum(2), &[UP, ERROR, UP]).check().result_type, UP);
+  assert_eq!(make(CompositeMode::Quorum(2), &[UP, ERROR, WARN]).check().result_type, WARN);
+  assert_eq!(make(CompositeMode::Quorum(2), &[UP, ERROR, ERROR]).check().result_type, ERROR);
+
+  let make_notify = |children: &[(CheckResultType, NotifyHint)]| {
+    let mut c = CompositeChecker::all_of();
+    for (i, (t, notify)) in children.iter().enumerate() {
+      c.add(&format!("c{}", i), Box::new(FixedChecker(CheckResult::new(*t, None).with_notify(*notify), Duration::from_millis(0))));
+    }
+    c.check().notify
+  };
+  assert_eq!(make_notify(&[(WARN, NotifyHint::Never), (UP, NotifyHint::Default)]), NotifyHint::Never);
+  assert_eq!(make_notify(&[(WARN, NotifyHint::Never), (ERROR, NotifyHint::Default)]), NotifyHint::Default);
+  assert_eq!(make_notify(&[(UP, NotifyHint::Always), (ERROR, NotifyHint::Never)]), NotifyHint::Always);
+  assert_eq!(make_notify(&[(UP, NotifyHint::Default)]), NotifyHint::Default);
+  assert!(!CompositeChecker::all_of().add("tls", Box::new(FixedChecker(CheckResult::warn(None).with_notify(NotifyHint::Never), Duration::from_millis(0)))).check().should_notify());
+
+  let mut c = CompositeChecker::all_of();
+  for i in 0..4 {
+    c.add(&format!("slow{}", i), Box::new(FixedChecker(CheckResult::up(None), Duration::from_millis(200))));
+  }
+  let start = Instant::now();
+  c.set_parallel(true).check().expect();
+  assert!(start.elapsed() < Duration::from_millis(600));
+}
@@ -0,0 +1,69 @@
+//! Checkers which speak enough of a database's wire protocol to log in and run
+//! a trivial query, and optionally look at replication lag and the number of
+//! connections.
+//!
+//! Connections are plain TCP. Use these on a trusted network, or through
+//! something like stunnel.
+
+pub mod redis;
+pub mod postgres;
+pub mod mysql;
+
+use crate::checkers::{CheckResult, CheckResultType, Threshold};
+
+/// What a database checker has measured, on top of being able to query the
+/// server.
+#[derive(Default)]
+pub(crate) struct DbStats {
+  /// Seconds, or `None` when not a replica. `Some(None)` when the server is a
+  /// replica but replication is not running.
+  pub replication_lag: Option<Option<f64>>,
+  pub connections: Option<f64>,
+  /// e.g. the role of the server.
+  pub notes: Vec<String>,
+}
+
+/// Turn the measurements of a database checker into a result, applying the
+/// thresholds to them.
+pub(crate) fn stats_result(server: &str, stats: DbStats, lag_threshold: &Threshold, connections_threshold: &Threshold) -> CheckResult {
+  let mut result_type = CheckResultType::UP;
+  let mut parts = stats.notes;
+  let mut metrics = Vec::new();
+  match stats.replication_lag {
+    Some(Some(lag)) => {
+      result_type = result_type.max(lag_threshold.evaluate(lag));
+      parts.push(format!("replication lag {}s", lag));
+      metrics.push(("replication_lag", lag, "s"));
+    },
+    Some(None) => {
+      result_type = CheckResultType::ERROR;
+      parts.push("replication is not running".to_owned());
+    },
+    None => {},
+  }
+  if let Some(connections) = stats.connections {
+    result_type = result_type.max(connections_threshold.evaluate(connections));
+    parts.push(format!("{} connections", connections));
+    metrics.push(("connections", connections, ""));
+  }
+  let info = if parts.is_empty() { format!("{} is up.", server) } else { format!("{} is up: {}.", server, parts.join(", ")) };
+  let mut result = CheckResult::new(result_type, Some(info));
+  for (name, value, unit) in metrics {
+    result = result.with_metric(name, value, unit);
+  }
+  result
+}
+
+#[test]
+fn stats_result_test() {
+  let lag = Threshold::new(Some(10f64), Some(60f64));
+  let conns = Threshold::new(Some(80f64), None);
+  let res = stats_result("db", DbStats::default(), &lag, &conns);
+  assert_eq!(res, CheckResult::up(Some("db is up.".to_owned())));
+  let res = stats_result("db", DbStats{replication_lag: Some(Some(12f64)), connections: Some(3f64), notes: vec!["replica".to_owned()]}, &lag, &conns);
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.as_ref().unwrap(), "db is up: replica, replication lag 12s, 3 connections.");
+  assert_eq!(res.get_metric("replication_lag"), Some(12f64));
+  assert_eq!(res.get_metric("connections"), Some(3f64));
+  stats_result("db", DbStats{replication_lag: Some(None), ..Default::default()}, &lag, &conns).expect_err_contains("replication is not running");
+}
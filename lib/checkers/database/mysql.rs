@@ -0,0 +1,685 @@
+//! MySQL / MariaDB health checks over the client/server protocol.
+
+use crate::checkers::{Checker, CheckResult, Threshold};
+use crate::checkers::database::{stats_result, DbStats};
+use crate::utils::Reader;
+use crate::utils::transport::{Buffered, Transport, block_on, connect_blocking};
+use openssl::sha::{sha1, sha256};
+use std::time;
+
+const CLIENT_LONG_PASSWORD: u32 = 0x1;
+const CLIENT_CONNECT_WITH_DB: u32 = 0x8;
+const CLIENT_PROTOCOL_41: u32 = 0x200;
+const CLIENT_TRANSACTIONS: u32 = 0x2000;
+const CLIENT_SECURE_CONNECTION: u32 = 0x8000;
+const CLIENT_PLUGIN_AUTH: u32 = 0x80000;
+
+const COM_QUIT: u8 = 0x01;
+const COM_QUERY: u8 = 0x03;
+
+const ER_PARSE_ERROR: u16 = 1064;
+
+/// Reads and writes packets, keeping track of the sequence id.
+struct Conn<T> {
+  inner: Buffered<T>,
+  seq: u8,
+}
+
+impl<T: Transport> Conn<T> {
+  async fn read_packet(&mut self) -> Result<Vec<u8>, String> {
+    let header = self.inner.read_exact(4).await?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    self.seq = header[3].wrapping_add(1);
+    self.inner.read_exact(len).await
+  }
+
+  async fn write_packet(&mut self, payload: &[u8]) -> Result<(), String> {
+    let mut buf = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+    buf.push(self.seq);
+    buf.extend_from_slice(payload);
+    self.seq = self.seq.wrapping_add(1);
+    self.inner.write_all(&buf).await
+  }
+
+  /// Start a new command.
+  async fn command(&mut self, command: u8, data: &[u8]) -> Result<(), String> {
+    self.seq = 0;
+    let mut payload = vec![command];
+    payload.extend_from_slice(data);
+    self.write_packet(&payload).await
+  }
+}
+
+/// An error packet from the server.
+struct ServerError {
+  code: u16,
+  message: String,
+}
+
+impl std::fmt::Display for ServerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "error {}: {}", self.code, &self.message)
+  }
+}
+
+fn parse_error(packet: &[u8]) -> ServerError {
+  let code = if packet.len() >= 3 { u16::from_le_bytes([packet[1], packet[2]]) } else { 0 };
+  let mut message = packet.get(3..).unwrap_or(&[]);
+  if message.first() == Some(&b'#') && message.len() >= 6 {
+    message = &message[6..];
+  }
+  ServerError{code, message: String::from_utf8_lossy(message).into_owned()}
+}
+
+fn reader(packet: &[u8]) -> Reader<'_> {
+  Reader::new(packet, "Truncated packet from server.")
+}
+
+/// The MySQL field types on top of [`Reader`].
+trait MysqlReader<'a> {
+  fn cstr(&mut self) -> Result<&'a [u8], String>;
+  fn lenenc_int(&mut self) -> Result<Option<u64>, String>;
+  fn lenenc_str(&mut self) -> Result<Option<&'a [u8]>, String>;
+}
+
+impl<'a> MysqlReader<'a> for Reader<'a> {
+  fn cstr(&mut self) -> Result<&'a [u8], String> {
+    self.take_until(0)
+  }
+
+  /// A length-encoded integer, or `None` for the `NULL` marker `0xfb`.
+  fn lenenc_int(&mut self) -> Result<Option<u64>, String> {
+    let first = self.u8()?;
+    let n = match first {
+      0xfb => return Ok(None),
+      0xfc => 2,
+      0xfd => 3,
+      0xfe => 8,
+      _ => return Ok(Some(first as u64)),
+    };
+    let mut bytes = [0u8; 8];
+    bytes[..n].copy_from_slice(self.take(n)?);
+    Ok(Some(u64::from_le_bytes(bytes)))
+  }
+
+  fn lenenc_str(&mut self) -> Result<Option<&'a [u8]>, String> {
+    match self.lenenc_int()? {
+      Some(len) => Ok(Some(self.take(len as usize)?)),
+      None => Ok(None),
+    }
+  }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+  a.iter().zip(b.iter().cycle()).map(|(x, y)| x ^ y).collect()
+}
+
+/// The scramble for `mysql_native_password`:
+/// `SHA1(password) XOR SHA1(nonce + SHA1(SHA1(password)))`.
+fn native_password(password: &str, nonce: &[u8]) -> Vec<u8> {
+  if password.is_empty() {
+    return Vec::new();
+  }
+  let stage1 = sha1(password.as_bytes());
+  let mut input = nonce.to_vec();
+  input.extend_from_slice(&sha1(&stage1));
+  xor(&stage1, &sha1(&input))
+}
+
+/// The scramble for `caching_sha2_password`:
+/// `SHA256(password) XOR SHA256(SHA256(SHA256(password)) + nonce)`.
+fn caching_sha2_password(password: &str, nonce: &[u8]) -> Vec<u8> {
+  if password.is_empty() {
+    return Vec::new();
+  }
+  let stage1 = sha256(password.as_bytes());
+  let mut input = sha256(&stage1).to_vec();
+  input.extend_from_slice(nonce);
+  xor(&stage1, &sha256(&input))
+}
+
+fn auth_response(plugin: &[u8], password: &str, nonce: &[u8], allow_cleartext: bool) -> Result<Vec<u8>, String> {
+  match plugin {
+    b"mysql_native_password" => Ok(native_password(password, nonce)),
+    b"caching_sha2_password" => Ok(caching_sha2_password(password, nonce)),
+    b"mysql_clear_password" if !allow_cleartext => Err("server requested cleartext password".to_owned()),
+    b"mysql_clear_password" => {
+      let mut data = password.as_bytes().to_vec();
+      data.push(0);
+      Ok(data)
+    },
+    _ => Err(format!("Unsupported authentication plugin {}.", String::from_utf8_lossy(plugin))),
+  }
+}
+
+/// A result set: column names and rows.
+struct ResultSet {
+  columns: Vec<String>,
+  rows: Vec<Vec<Option<String>>>,
+}
+
+impl ResultSet {
+  fn get(&self, row: usize, column: &str) -> Option<&str> {
+    let i = self.columns.iter().position(|c| c == column)?;
+    self.rows.get(row)?.get(i)?.as_ref().map(|v| &v[..])
+  }
+}
+
+/// Check that a MySQL or MariaDB server accepts a login and answers
+/// `SELECT 1`.
+///
+/// Supports the `mysql_native_password` and `caching_sha2_password`
+/// authentication plugins. For the latter, when the server does not have the
+/// password cached, it asks for the password itself, encrypted with an RSA
+/// public key which it sends over the same unencrypted connection. Anyone on
+/// the path could send their own key, so this is refused like
+/// `mysql_clear_password` unless
+/// [allowed](crate::checkers::database::mysql::MySqlChecker::allow_cleartext_password).
+/// The number of connections (`Threads_connected`) is also reported if the
+/// user may see it. Not being able to is only an error with a
+/// [connections threshold](crate::checkers::database::mysql::MySqlChecker::set_connections_threshold).
+///
+/// With a
+/// [replication lag threshold](crate::checkers::database::mysql::MySqlChecker::set_replication_lag_threshold),
+/// replicas are checked to have both replication threads running, with the lag
+/// being `Seconds_Behind_Source`. This needs the `REPLICATION CLIENT`
+/// privilege.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, database::mysql::MySqlChecker};
+/// let mut checker = MySqlChecker::new("localhost", 3306, "monitor");
+/// checker.set_password("hunter2")
+///        .set_replication_lag_threshold(Threshold::new(Some(60f64), Some(600f64)));
+/// let result = checker.check();
+/// ```
+pub struct MySqlChecker {
+  host: String,
+  port: u16,
+  user: String,
+  password: String,
+  database: Option<String>,
+  allow_cleartext: bool,
+  timeout: time::Duration,
+  lag_threshold: Option<Threshold>,
+  connections_threshold: Threshold,
+}
+
+impl MySqlChecker {
+  pub fn new(host: &str, port: u16, user: &str) -> Self {
+    MySqlChecker{
+      host: host.to_owned(),
+      port,
+      user: user.to_owned(),
+      password: String::new(),
+      database: None,
+      allow_cleartext: false,
+      timeout: time::Duration::from_secs(5),
+      lag_threshold: None,
+      connections_threshold: Threshold::default(),
+    }
+  }
+
+  /// Default is an empty password.
+  pub fn set_password(&mut self, password: &str) -> &mut Self {
+    self.password = password.to_owned();
+    self
+  }
+
+  /// Default is to not select a database.
+  pub fn set_database(&mut self, value: Option<&str>) -> &mut Self {
+    self.database = value.map(|d| d.to_owned());
+    self
+  }
+
+  /// Whether to send the password in a form which whoever is at the other end
+  /// of the connection can read: with `mysql_clear_password`, or for the full
+  /// authentication of `caching_sha2_password`. Connections are not
+  /// encrypted, so this is only safe on a trusted network.
+  ///
+  /// Default is false, which makes such a server an `ERROR`.
+  pub fn allow_cleartext_password(&mut self, value: bool) -> &mut Self {
+    self.allow_cleartext = value;
+    self
+  }
+
+  /// Same as
+  /// [`RedisChecker::set_timeout`](crate::checkers::database::redis::RedisChecker::set_timeout).
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// Thresholds on replication lag, in seconds. Setting this also makes a
+  /// replica whose replication threads are not running an `ERROR`.
+  ///
+  /// Default is to not look at replication.
+  pub fn set_replication_lag_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.lag_threshold = Some(value);
+    self
+  }
+
+  /// Thresholds on the number of connections.
+  ///
+  /// Default is none.
+  pub fn set_connections_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.connections_threshold = value;
+    self
+  }
+
+  async fn handshake<T: Transport>(&self, conn: &mut Conn<T>) -> Result<(), String> {
+    let packet = conn.read_packet().await?;
+    if packet.first() == Some(&0xff) {
+      return Err(format!("Server refused connection: {}", parse_error(&packet)));
+    }
+    let mut r = reader(&packet);
+    if r.u8()? != 10 {
+      return Err("Unsupported protocol version.".to_owned());
+    }
+    r.cstr()?; // server version
+    r.take(4)?; // connection id
+    let mut nonce = r.take(8)?.to_vec();
+    r.take(1)?;
+    let caps_low = r.take(2)?;
+    r.take(3)?; // character set, status flags
+    let caps_high = r.take(2)?;
+    let server_caps = u32::from_le_bytes([caps_low[0], caps_low[1], caps_high[0], caps_high[1]]);
+    let nonce_len = r.u8()? as usize;
+    r.take(10)?;
+    if server_caps & CLIENT_SECURE_CONNECTION != 0 {
+      let part2 = r.take(std::cmp::max(13, nonce_len.saturating_sub(8)))?;
+      nonce.extend_from_slice(&part2[..part2.len() - 1]);
+    }
+    let mut plugin = if server_caps & CLIENT_PLUGIN_AUTH != 0 { r.cstr()?.to_vec() } else { b"mysql_native_password".to_vec() };
+    if server_caps & CLIENT_PROTOCOL_41 == 0 {
+      return Err("Server does not support protocol 4.1.".to_owned());
+    }
+
+    let mut caps = CLIENT_LONG_PASSWORD | CLIENT_PROTOCOL_41 | CLIENT_TRANSACTIONS | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH;
+    if self.database.is_some() {
+      caps |= CLIENT_CONNECT_WITH_DB;
+    }
+    let auth = auth_response(&plugin, &self.password, &nonce, self.allow_cleartext)?;
+    let mut response = caps.to_le_bytes().to_vec();
+    response.extend_from_slice(&(1u32 << 24).to_le_bytes());
+    response.push(45); // utf8mb4_general_ci
+    response.extend_from_slice(&[0u8; 23]);
+    response.extend_from_slice(self.user.as_bytes());
+    response.push(0);
+    response.push(auth.len() as u8);
+    response.extend_from_slice(&auth);
+    if let Some(ref db) = self.database {
+      response.extend_from_slice(db.as_bytes());
+      response.push(0);
+    }
+    response.extend_from_slice(&plugin);
+    response.push(0);
+    conn.write_packet(&response).await?;
+
+    loop {
+      let packet = conn.read_packet().await?;
+      match packet.first() {
+        Some(0x00) => return Ok(()),
+        Some(0xff) => return Err(format!("Login failed: {}", parse_error(&packet))),
+        Some(0xfe) => {
+          // AuthSwitchRequest
+          let mut r = reader(&packet[1..]);
+          plugin = r.cstr()?.to_vec();
+          nonce = r.rest().to_vec();
+          if nonce.last() == Some(&0) {
+            nonce.pop();
+          }
+          conn.write_packet(&auth_response(&plugin, &self.password, &nonce, self.allow_cleartext)?).await?;
+        },
+        Some(0x01) if plugin == b"caching_sha2_password" => {
+          match packet.get(1) {
+            // Fast authentication succeeded. An OK packet follows.
+            Some(0x03) => {},
+            // Full authentication. Ask for the public key.
+            Some(0x04) if !self.allow_cleartext => return Err("server requested cleartext password (RSA-encrypted with a key sent over plain TCP)".to_owned()),
+            Some(0x04) => conn.write_packet(&[0x02]).await?,
+            // The public key.
+            _ => {
+              let key = openssl::rsa::Rsa::public_key_from_pem(&packet[1..]).map_err(|e| format!("Invalid public key from server: {}", &e))?;
+              let mut plain = self.password.as_bytes().to_vec();
+              plain.push(0);
+              let plain = xor(&plain, &nonce);
+              let mut encrypted = vec![0u8; key.size() as usize];
+              let len = key.public_encrypt(&plain, &mut encrypted, openssl::rsa::Padding::PKCS1_OAEP).map_err(|e| format!("Encrypting password: {}", &e))?;
+              encrypted.truncate(len);
+              conn.write_packet(&encrypted).await?;
+            },
+          }
+        },
+        _ => return Err("Unexpected packet during authentication.".to_owned()),
+      }
+    }
+  }
+
+  async fn query<T: Transport>(&self, conn: &mut Conn<T>, sql: &str) -> Result<Result<ResultSet, ServerError>, String> {
+    conn.command(COM_QUERY, sql.as_bytes()).await?;
+    let packet = conn.read_packet().await?;
+    match packet.first() {
+      Some(0x00) => return Ok(Ok(ResultSet{columns: Vec::new(), rows: Vec::new()})),
+      Some(0xff) => return Ok(Err(parse_error(&packet))),
+      _ => {},
+    }
+    let column_count = reader(&packet).lenenc_int()?.unwrap_or(0);
+    let mut columns = Vec::new();
+    for _ in 0..column_count {
+      let packet = conn.read_packet().await?;
+      let mut r = reader(&packet);
+      for _ in 0..4 {
+        r.lenenc_str()?; // catalog, schema, table, org_table
+      }
+      columns.push(String::from_utf8_lossy(r.lenenc_str()?.unwrap_or(b"")).into_owned());
+    }
+    conn.read_packet().await?; // EOF
+    let mut rows = Vec::new();
+    loop {
+      let packet = conn.read_packet().await?;
+      match packet.first() {
+        Some(0xfe) if packet.len() < 9 => break,
+        Some(0xff) => return Ok(Err(parse_error(&packet))),
+        _ => {},
+      }
+      let mut r = reader(&packet);
+      let mut row = Vec::new();
+      for _ in 0..column_count {
+        row.push(r.lenenc_str()?.map(|v| String::from_utf8_lossy(v).into_owned()));
+      }
+      rows.push(row);
+    }
+    Ok(Ok(ResultSet{columns, rows}))
+  }
+
+  async fn check_server<T: Transport>(&self, inner: Buffered<T>) -> Result<DbStats, String> {
+    let mut conn = Conn{inner, seq: 0};
+    self.handshake(&mut conn).await?;
+    let rs = self.query(&mut conn, "SELECT 1").await?.map_err(|e| format!("SELECT 1 failed: {}", e))?;
+    if rs.rows != vec![vec![Some("1".to_owned())]] {
+      return Err(format!("Unexpected result of SELECT 1: {:?}", rs.rows));
+    }
+    let mut stats = DbStats::default();
+    let sql = "SHOW GLOBAL STATUS LIKE 'Threads_connected'";
+    match self.query(&mut conn, sql).await? {
+      Ok(rs) => stats.connections = rs.rows.first().and_then(|r| r.get(1)).and_then(|v| v.as_ref()).and_then(|v| v.parse().ok()),
+      Err(e) if self.connections_threshold != Threshold::default() => return Err(format!("{} failed: {}", sql, e)),
+      Err(_) => {},
+    }
+    if self.lag_threshold.is_some() {
+      // SHOW REPLICA STATUS is MySQL 8.0.22 and later.
+      let rs = match self.query(&mut conn, "SHOW REPLICA STATUS").await? {
+        Err(ref e) if e.code == ER_PARSE_ERROR => self.query(&mut conn, "SHOW SLAVE STATUS").await?,
+        r => r,
+      }.map_err(|e| format!("Checking replication status failed: {}", e))?;
+      if rs.rows.is_empty() {
+        stats.notes.push("not a replica".to_owned());
+      } else {
+        let get = |new: &str, old: &str| rs.get(0, new).or_else(|| rs.get(0, old));
+        stats.notes.push(format!("replica of {}", get("Source_Host", "Master_Host").unwrap_or("?")));
+        let running = get("Replica_IO_Running", "Slave_IO_Running") == Some("Yes") && get("Replica_SQL_Running", "Slave_SQL_Running") == Some("Yes");
+        stats.replication_lag = Some(if running {
+          get("Seconds_Behind_Source", "Seconds_Behind_Master").and_then(|v| v.parse().ok())
+        } else {
+          None
+        });
+      }
+    }
+    let _ = conn.command(COM_QUIT, &[]).await;
+    Ok(stats)
+  }
+
+  fn result(&self, stats: Result<DbStats, String>) -> CheckResult {
+    match stats {
+      Ok(stats) => stats_result(&format!("MySQL {}:{}", &self.host, self.port), stats, &self.lag_threshold.unwrap_or_default(), &self.connections_threshold),
+      Err(e) => CheckResult::error(Some(e)),
+    }
+  }
+}
+
+impl Checker for MySqlChecker {
+  fn check(&mut self) -> CheckResult {
+    self.result(connect_blocking(&self.host, self.port, self.timeout).and_then(|conn| block_on(self.check_server(conn))))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for MySqlChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    use crate::checkers::async_checker::within;
+    use crate::utils::transport::connect_async;
+    Box::pin(async move {
+      let stats = within(self.timeout, async {
+        self.check_server(connect_async(&self.host, self.port).await?).await
+      }).await;
+      self.result(stats)
+    })
+  }
+}
+
+#[cfg(test)]
+mod testing {
+  use super::*;
+  use crate::testing::read_n;
+  use std::io::Write;
+  use std::net::TcpStream;
+
+  pub const NONCE: &[u8; 20] = b"abcdefghijklmnopqrst";
+
+  pub fn write_packet(s: &mut TcpStream, seq: u8, payload: &[u8]) {
+    let mut buf = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+    buf.push(seq);
+    buf.extend_from_slice(payload);
+    s.write_all(&buf).unwrap();
+  }
+
+  pub fn read_packet(s: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = read_n(s, 4);
+    (header[3], read_n(s, u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize))
+  }
+
+  pub fn handshake(plugin: &str) -> Vec<u8> {
+    let mut p = vec![10];
+    p.extend_from_slice(b"8.0.36\0");
+    p.extend_from_slice(&7u32.to_le_bytes());
+    p.extend_from_slice(&NONCE[..8]);
+    p.push(0);
+    let caps = CLIENT_LONG_PASSWORD | CLIENT_PROTOCOL_41 | CLIENT_TRANSACTIONS | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH | CLIENT_CONNECT_WITH_DB;
+    p.extend_from_slice(&caps.to_le_bytes()[..2]);
+    p.extend_from_slice(&[45, 2, 0]);
+    p.extend_from_slice(&caps.to_le_bytes()[2..]);
+    p.push(21);
+    p.extend_from_slice(&[0u8; 10]);
+    p.extend_from_slice(&NONCE[8..]);
+    p.push(0);
+    p.extend_from_slice(plugin.as_bytes());
+    p.push(0);
+    p
+  }
+
+  /// Parse a HandshakeResponse41 into (user, auth response, database).
+  pub fn parse_response(p: &[u8]) -> (String, Vec<u8>, Option<String>) {
+    let caps = u32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+    let mut r = reader(&p[32..]);
+    let user = String::from_utf8(r.cstr().unwrap().to_vec()).unwrap();
+    let len = r.u8().unwrap() as usize;
+    let auth = r.take(len).unwrap().to_vec();
+    let db = if caps & CLIENT_CONNECT_WITH_DB != 0 { Some(String::from_utf8(r.cstr().unwrap().to_vec()).unwrap()) } else { None };
+    (user, auth, db)
+  }
+
+  fn lenenc_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+      Some(s) => {
+        buf.push(s.len() as u8);
+        buf.extend_from_slice(s.as_bytes());
+      },
+      None => buf.push(0xfb),
+    }
+  }
+
+  /// Expect a COM_QUERY for `sql`, and reply with a result set.
+  pub fn reply_rows(s: &mut TcpStream, sql: &str, columns: &[&str], rows: &[&[Option<&str>]]) {
+    let (seq, p) = read_packet(s);
+    assert_eq!(seq, 0);
+    assert_eq!(p[0], COM_QUERY);
+    assert_eq!(String::from_utf8_lossy(&p[1..]), sql);
+    let mut seq = 1;
+    let mut send = |s: &mut TcpStream, payload: &[u8]| {
+      write_packet(s, seq, payload);
+      seq += 1;
+    };
+    send(s, &[columns.len() as u8]);
+    for c in columns {
+      let mut def = Vec::new();
+      for f in &["def", "", "", "", c, c] {
+        lenenc_str(&mut def, Some(f));
+      }
+      def.extend_from_slice(&[0x0c, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+      send(s, &def);
+    }
+    send(s, &[0xfe, 0, 0, 2, 0]);
+    for r in rows {
+      let mut row = Vec::new();
+      for v in r.iter() {
+        lenenc_str(&mut row, *v);
+      }
+      send(s, &row);
+    }
+    send(s, &[0xfe, 0, 0, 2, 0]);
+  }
+
+  pub fn reply_error(s: &mut TcpStream, seq: u8, code: u16, message: &str) {
+    let mut p = vec![0xff];
+    p.extend_from_slice(&code.to_le_bytes());
+    p.extend_from_slice(b"#42000");
+    p.extend_from_slice(message.as_bytes());
+    write_packet(s, seq, &p);
+  }
+}
+
+#[test]
+fn mysql_test() {
+  use self::testing::*;
+  use crate::checkers::CheckResultType;
+  use crate::testing::serve_once;
+
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("mysql_native_password"));
+    let (seq, p) = read_packet(&mut s);
+    assert_eq!(seq, 1);
+    let (user, auth, db) = parse_response(&p);
+    assert_eq!((&user[..], db), ("monitor", Some("app".to_owned())));
+    // SHA1(password) XOR SHA1(nonce + SHA1(SHA1(password)))
+    let stage1 = sha1(b"hunter2");
+    let mut input = NONCE.to_vec();
+    input.extend_from_slice(&sha1(&stage1));
+    let expected: Vec<u8> = stage1.iter().zip(sha1(&input).iter()).map(|(a, b)| a ^ b).collect();
+    assert_eq!(auth, expected);
+    write_packet(&mut s, 2, &[0, 0, 0, 2, 0, 0, 0]);
+    reply_rows(&mut s, "SELECT 1", &["1"], &[&[Some("1")]]);
+    reply_rows(&mut s, "SHOW GLOBAL STATUS LIKE 'Threads_connected'", &["Variable_name", "Value"], &[&[Some("Threads_connected"), Some("17")]]);
+    let (_, p) = read_packet(&mut s);
+    assert_eq!(&p[1..], b"SHOW REPLICA STATUS");
+    reply_error(&mut s, 1, ER_PARSE_ERROR, "You have an error in your SQL syntax");
+    reply_rows(&mut s, "SHOW SLAVE STATUS", &["Master_Host", "Slave_IO_Running", "Slave_SQL_Running", "Seconds_Behind_Master"], &[&[Some("10.0.0.1"), Some("Yes"), Some("Yes"), Some("700")]]);
+    assert_eq!(read_packet(&mut s), (0, vec![COM_QUIT]));
+  });
+  let res = MySqlChecker::new("127.0.0.1", addr.port(), "monitor").set_password("hunter2").set_database(Some("app"))
+    .set_replication_lag_threshold(Threshold::new(Some(60f64), Some(600f64))).check();
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.info.as_ref().unwrap(), &format!("MySQL 127.0.0.1:{} is up: replica of 10.0.0.1, replication lag 700s, 17 connections.", addr.port()));
+
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("caching_sha2_password"));
+    let (_, p) = read_packet(&mut s);
+    let (_, auth, db) = parse_response(&p);
+    assert_eq!(db, None);
+    assert_eq!(auth, caching_sha2_password("hunter2", NONCE));
+    // Not cached: full authentication with the server's public key.
+    write_packet(&mut s, 2, &[0x01, 0x04]);
+    assert_eq!(read_packet(&mut s), (3, vec![0x02]));
+    let key = openssl::rsa::Rsa::generate(2048).unwrap();
+    let mut reply = vec![0x01];
+    reply.extend_from_slice(&key.public_key_to_pem().unwrap());
+    write_packet(&mut s, 4, &reply);
+    let (seq, encrypted) = read_packet(&mut s);
+    assert_eq!(seq, 5);
+    let mut plain = vec![0u8; key.size() as usize];
+    let len = key.private_decrypt(&encrypted, &mut plain, openssl::rsa::Padding::PKCS1_OAEP).unwrap();
+    assert_eq!(xor(&plain[..len], NONCE), b"hunter2\0".to_vec());
+    write_packet(&mut s, 6, &[0, 0, 0, 2, 0, 0, 0]);
+    reply_rows(&mut s, "SELECT 1", &["1"], &[&[Some("1")]]);
+    let (_, p) = read_packet(&mut s);
+    assert_eq!(&p[1..], b"SHOW GLOBAL STATUS LIKE 'Threads_connected'");
+    reply_error(&mut s, 1, 1227, "Access denied");
+  });
+  MySqlChecker::new("127.0.0.1", addr.port(), "monitor").set_password("hunter2").allow_cleartext_password(true)
+    .set_connections_threshold(Threshold::new(Some(100f64), None)).check()
+    .expect_err_contains("SHOW GLOBAL STATUS LIKE 'Threads_connected' failed: error 1227: Access denied");
+
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("caching_sha2_password"));
+    read_packet(&mut s);
+    // Switch to native password with a new nonce.
+    let mut switch = b"\xfemysql_native_password\0".to_vec();
+    switch.extend_from_slice(b"ABCDEFGHIJKLMNOPQRST\0");
+    write_packet(&mut s, 2, &switch);
+    assert_eq!(read_packet(&mut s), (3, native_password("wrong", b"ABCDEFGHIJKLMNOPQRST")));
+    reply_error(&mut s, 4, 1045, "Access denied for user 'monitor'@'localhost' (using password: YES)");
+  });
+  MySqlChecker::new("127.0.0.1", addr.port(), "monitor").set_password("wrong").check()
+    .expect_err_contains("Login failed: error 1045: Access denied for user 'monitor'");
+
+  // Neither full caching_sha2_password authentication nor cleartext
+  // passwords happen unless allowed.
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("caching_sha2_password"));
+    read_packet(&mut s);
+    write_packet(&mut s, 2, &[0x01, 0x04]);
+  });
+  MySqlChecker::new("127.0.0.1", addr.port(), "monitor").set_password("hunter2").check()
+    .expect_err_contains("server requested cleartext password");
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("mysql_native_password"));
+    read_packet(&mut s);
+    write_packet(&mut s, 2, b"\xfemysql_clear_password\0");
+  });
+  MySqlChecker::new("127.0.0.1", addr.port(), "monitor").set_password("hunter2").check()
+    .expect_err_contains("server requested cleartext password");
+
+  // Without a connections threshold, not being allowed to count them is fine.
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("mysql_native_password"));
+    read_packet(&mut s);
+    write_packet(&mut s, 2, &[0, 0, 0, 2, 0, 0, 0]);
+    reply_rows(&mut s, "SELECT 1", &["1"], &[&[Some("1")]]);
+    read_packet(&mut s);
+    reply_error(&mut s, 1, 1227, "Access denied");
+    assert_eq!(read_packet(&mut s), (0, vec![COM_QUIT]));
+  });
+  let res = MySqlChecker::new("127.0.0.1", addr.port(), "monitor").check();
+  res.expect();
+  assert_eq!(res.info.as_ref().unwrap(), &format!("MySQL 127.0.0.1:{} is up.", addr.port()));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_mysql_test() {
+  use self::testing::*;
+  use crate::checkers::async_checker::AsyncChecker;
+  use crate::testing::serve_once;
+  let addr = serve_once(|mut s| {
+    write_packet(&mut s, 0, &handshake("mysql_native_password"));
+    read_packet(&mut s);
+    write_packet(&mut s, 2, &[0, 0, 0, 2, 0, 0, 0]);
+    reply_rows(&mut s, "SELECT 1", &["1"], &[&[Some("1")]]);
+    reply_rows(&mut s, "SHOW GLOBAL STATUS LIKE 'Threads_connected'", &["Variable_name", "Value"], &[&[Some("Threads_connected"), Some("5")]]);
+    assert_eq!(read_packet(&mut s), (0, vec![COM_QUIT]));
+  });
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut checker = MySqlChecker::new("127.0.0.1", addr.port(), "monitor");
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert_eq!(res.info.as_ref().unwrap(), &format!("MySQL 127.0.0.1:{} is up: 5 connections.", addr.port()));
+}
@@ -0,0 +1,594 @@
+//! PostgreSQL health checks over the frontend/backend protocol (version 3).
+
+use crate::checkers::{Checker, CheckResult, Threshold};
+use crate::checkers::database::{stats_result, DbStats};
+use crate::utils::transport::{Buffered, Transport, block_on, connect_blocking};
+use openssl::hash::{hash, MessageDigest};
+use std::time;
+
+const PROTOCOL_VERSION: u32 = 3 << 16;
+
+const AUTH_OK: u32 = 0;
+const AUTH_CLEARTEXT: u32 = 3;
+const AUTH_MD5: u32 = 5;
+const AUTH_SASL: u32 = 10;
+const AUTH_SASL_CONTINUE: u32 = 11;
+const AUTH_SASL_FINAL: u32 = 12;
+
+/// A message from the server: its type byte and body.
+struct Message(u8, Vec<u8>);
+
+async fn read_message<T: Transport>(conn: &mut Buffered<T>) -> Result<Message, String> {
+  let header = conn.read_exact(5).await?;
+  let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+  if !(4..=1 << 24).contains(&len) {
+    return Err(format!("Invalid message length {} from server.", len));
+  }
+  Ok(Message(header[0], conn.read_exact(len - 4).await?))
+}
+
+fn encode_message(kind: u8, body: &[u8]) -> Vec<u8> {
+  let mut buf = vec![kind];
+  buf.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+  buf.extend_from_slice(body);
+  buf
+}
+
+fn encode_startup(user: &str, database: &str) -> Vec<u8> {
+  let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+  for s in &["user", user, "database", database, "application_name", "serverwatch"] {
+    body.extend_from_slice(s.as_bytes());
+    body.push(0);
+  }
+  body.push(0);
+  let mut buf = (body.len() as u32 + 4).to_be_bytes().to_vec();
+  buf.extend_from_slice(&body);
+  buf
+}
+
+/// Format the fields of an `ErrorResponse` like `FATAL 28P01: password
+/// authentication failed for user "x"`.
+fn format_error(body: &[u8]) -> String {
+  let mut severity = "";
+  let mut code = "";
+  let mut message = "";
+  for field in body.split(|b| *b == 0) {
+    if field.is_empty() {
+      continue;
+    }
+    let value = std::str::from_utf8(&field[1..]).unwrap_or("?");
+    match field[0] {
+      b'S' => severity = value,
+      b'C' => code = value,
+      b'M' => message = value,
+      _ => {},
+    }
+  }
+  format!("{} {}: {}", severity, code, message)
+}
+
+fn hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn md5_password(user: &str, password: &str, salt: &[u8]) -> Result<String, String> {
+  let inner = hash(MessageDigest::md5(), format!("{}{}", password, user).as_bytes()).map_err(|e| format!("{}", &e))?;
+  let mut outer_input = hex(&inner).into_bytes();
+  outer_input.extend_from_slice(salt);
+  let outer = hash(MessageDigest::md5(), &outer_input).map_err(|e| format!("{}", &e))?;
+  Ok(format!("md5{}", hex(&outer)))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+  let key = openssl::pkey::PKey::hmac(key).map_err(|e| format!("{}", &e))?;
+  let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), &key).map_err(|e| format!("{}", &e))?;
+  signer.update(data).map_err(|e| format!("{}", &e))?;
+  signer.sign_to_vec().map_err(|e| format!("{}", &e))
+}
+
+/// The client side of a SCRAM-SHA-256 exchange (RFC 5802 / 7677), without
+/// channel binding.
+struct Scram {
+  password: String,
+  client_first_bare: String,
+  expected_server_signature: Option<Vec<u8>>,
+}
+
+impl Scram {
+  fn new(user: &str, password: &str, client_nonce: &str) -> Self {
+    Scram{password: password.to_owned(), client_first_bare: format!("n={},r={}", user, client_nonce), expected_server_signature: None}
+  }
+
+  fn client_first(&self) -> String {
+    format!("n,,{}", &self.client_first_bare)
+  }
+
+  fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+    let attr = |name: &str| server_first.split(',').find(|a| a.starts_with(name)).map(|a| &a[name.len()..]);
+    let nonce = attr("r=").ok_or("No nonce in SCRAM server-first-message.")?;
+    let client_nonce = &self.client_first_bare[self.client_first_bare.find(",r=").unwrap() + 3..];
+    if !nonce.starts_with(client_nonce) {
+      return Err("Server SCRAM nonce does not start with the client nonce.".to_owned());
+    }
+    let salt = openssl::base64::decode_block(attr("s=").ok_or("No salt in SCRAM server-first-message.")?).map_err(|e| format!("Invalid SCRAM salt: {}", &e))?;
+    let iterations: usize = attr("i=").and_then(|i| i.parse().ok()).ok_or("No iteration count in SCRAM server-first-message.")?;
+    let mut salted_password = [0u8; 32];
+    openssl::pkcs5::pbkdf2_hmac(self.password.as_bytes(), &salt, iterations, MessageDigest::sha256(), &mut salted_password).map_err(|e| format!("{}", &e))?;
+    let client_key = hmac_sha256(&salted_password, b"Client Key")?;
+    let stored_key = openssl::sha::sha256(&client_key);
+    let without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!("{},{},{}", &self.client_first_bare, server_first, &without_proof);
+    let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes())?;
+    let proof: Vec<u8> = client_key.iter().zip(client_signature.iter()).map(|(a, b)| a ^ b).collect();
+    let server_key = hmac_sha256(&salted_password, b"Server Key")?;
+    self.expected_server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes())?);
+    Ok(format!("{},p={}", without_proof, openssl::base64::encode_block(&proof)))
+  }
+
+  fn verify_server_final(&self, server_final: &str) -> Result<(), String> {
+    let signature = server_final.strip_prefix("v=").ok_or_else(|| format!("SCRAM authentication failed: {}", server_final))?;
+    let signature = openssl::base64::decode_block(signature).map_err(|e| format!("Invalid SCRAM server signature: {}", &e))?;
+    if Some(signature) != self.expected_server_signature {
+      return Err("SCRAM server signature does not match. Is this the right server?".to_owned());
+    }
+    Ok(())
+  }
+}
+
+/// Check that a PostgreSQL server accepts a login and answers `SELECT 1`.
+///
+/// Supports password (MD5 and SCRAM-SHA-256) and trust authentication.
+/// Cleartext passwords are refused unless
+/// [allowed](crate::checkers::database::postgres::PostgresChecker::allow_cleartext_password).
+/// The number of connections, from `pg_stat_activity`, is also reported if the
+/// user may see it. Not being able to is only an error with a
+/// [connections threshold](crate::checkers::database::postgres::PostgresChecker::set_connections_threshold).
+///
+/// With a
+/// [replication lag threshold](crate::checkers::database::postgres::PostgresChecker::set_replication_lag_threshold),
+/// standbys are checked to be receiving WAL, with the lag being the time since
+/// the last replayed transaction was committed on the primary. Note that this
+/// grows when nothing is written on the primary.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, database::postgres::PostgresChecker};
+/// let mut checker = PostgresChecker::new("localhost", 5432, "monitor", "postgres");
+/// checker.set_password("hunter2")
+///        .set_replication_lag_threshold(Threshold::new(Some(60f64), Some(600f64)));
+/// let result = checker.check();
+/// ```
+pub struct PostgresChecker {
+  host: String,
+  port: u16,
+  user: String,
+  database: String,
+  password: Option<String>,
+  allow_cleartext: bool,
+  timeout: time::Duration,
+  lag_threshold: Option<Threshold>,
+  connections_threshold: Threshold,
+}
+
+impl PostgresChecker {
+  pub fn new(host: &str, port: u16, user: &str, database: &str) -> Self {
+    PostgresChecker{
+      host: host.to_owned(),
+      port,
+      user: user.to_owned(),
+      database: database.to_owned(),
+      password: None,
+      allow_cleartext: false,
+      timeout: time::Duration::from_secs(5),
+      lag_threshold: None,
+      connections_threshold: Threshold::default(),
+    }
+  }
+
+  pub fn set_password(&mut self, password: &str) -> &mut Self {
+    self.password = Some(password.to_owned());
+    self
+  }
+
+  /// Whether to send the password in cleartext when the server asks for it.
+  /// Connections are not encrypted, so anyone on the path could read it.
+  ///
+  /// Default is false, which makes such a server an `ERROR`.
+  pub fn allow_cleartext_password(&mut self, value: bool) -> &mut Self {
+    self.allow_cleartext = value;
+    self
+  }
+
+  /// Same as
+  /// [`RedisChecker::set_timeout`](crate::checkers::database::redis::RedisChecker::set_timeout).
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// Thresholds on replication lag, in seconds. Setting this also makes a
+  /// standby which is not receiving WAL an `ERROR`.
+  ///
+  /// Default is to not look at replication.
+  pub fn set_replication_lag_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.lag_threshold = Some(value);
+    self
+  }
+
+  /// Thresholds on the number of connections.
+  ///
+  /// Default is none.
+  pub fn set_connections_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.connections_threshold = value;
+    self
+  }
+
+  fn password(&self) -> Result<&str, String> {
+    self.password.as_ref().map(|p| &p[..]).ok_or_else(|| "Server asked for a password, but none is set.".to_owned())
+  }
+
+  async fn authenticate<T: Transport>(&self, conn: &mut Buffered<T>) -> Result<(), String> {
+    let mut scram: Option<Scram> = None;
+    // Whether the server has proven that it knows the password, once SCRAM
+    // has started.
+    let mut scram_verified = false;
+    loop {
+      let Message(kind, body) = read_message(conn).await?;
+      match kind {
+        b'E' => return Err(format_error(&body)),
+        b'R' if body.len() >= 4 => {},
+        _ => return Err(format!("Unexpected message {:?} during authentication.", kind as char)),
+      }
+      let code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+      let data = &body[4..];
+      match code {
+        AUTH_OK if scram.is_some() && !scram_verified => return Err("Server accepted SCRAM authentication without sending its signature. Is this the right server?".to_owned()),
+        AUTH_OK => return Ok(()),
+        AUTH_CLEARTEXT if !self.allow_cleartext => return Err("server requested cleartext password".to_owned()),
+        AUTH_CLEARTEXT => {
+          let mut msg = self.password()?.as_bytes().to_vec();
+          msg.push(0);
+          conn.write_all(&encode_message(b'p', &msg)).await?;
+        },
+        AUTH_MD5 => {
+          let mut msg = md5_password(&self.user, self.password()?, data)?.into_bytes();
+          msg.push(0);
+          conn.write_all(&encode_message(b'p', &msg)).await?;
+        },
+        AUTH_SASL => {
+          let mechanisms: Vec<&[u8]> = data.split(|b| *b == 0).filter(|m| !m.is_empty()).collect();
+          if !mechanisms.contains(&&b"SCRAM-SHA-256"[..]) {
+            return Err(format!("Unsupported SASL mechanisms: {:?}", mechanisms.iter().map(|m| String::from_utf8_lossy(m)).collect::<Vec<_>>()));
+          }
+          let mut nonce = [0u8; 18];
+          openssl::rand::rand_bytes(&mut nonce).map_err(|e| format!("{}", &e))?;
+          // The server ignores the user name here and uses the one from the
+          // startup message.
+          let s = Scram::new("", self.password()?, &openssl::base64::encode_block(&nonce));
+          let first = s.client_first();
+          let mut msg = b"SCRAM-SHA-256\0".to_vec();
+          msg.extend_from_slice(&(first.len() as u32).to_be_bytes());
+          msg.extend_from_slice(first.as_bytes());
+          conn.write_all(&encode_message(b'p', &msg)).await?;
+          scram = Some(s);
+        },
+        AUTH_SASL_CONTINUE => {
+          let s = scram.as_mut().ok_or("Unexpected SASLContinue.")?;
+          let reply = s.client_final(&String::from_utf8_lossy(data))?;
+          conn.write_all(&encode_message(b'p', reply.as_bytes())).await?;
+        },
+        AUTH_SASL_FINAL => {
+          scram.as_ref().ok_or("Unexpected SASLFinal.")?.verify_server_final(&String::from_utf8_lossy(data))?;
+          scram_verified = true;
+        },
+        _ => return Err(format!("Unsupported authentication method {}.", code)),
+      }
+    }
+  }
+
+  /// Run `sql` with the simple query protocol, returning the rows as text.
+  async fn query<T: Transport>(&self, conn: &mut Buffered<T>, sql: &str) -> Result<Vec<Vec<Option<String>>>, String> {
+    let mut msg = sql.as_bytes().to_vec();
+    msg.push(0);
+    conn.write_all(&encode_message(b'Q', &msg)).await?;
+    let mut rows = Vec::new();
+    let mut error = None;
+    loop {
+      let Message(kind, body) = read_message(conn).await?;
+      match kind {
+        b'D' => rows.push(parse_data_row(&body)?),
+        b'E' => error = Some(format_error(&body)),
+        b'Z' => break,
+        _ => {},
+      }
+    }
+    match error {
+      Some(e) => Err(format!("{} failed: {}", sql, e)),
+      None => Ok(rows),
+    }
+  }
+
+  async fn check_server<T: Transport>(&self, conn: &mut Buffered<T>) -> Result<DbStats, String> {
+    conn.write_all(&encode_startup(&self.user, &self.database)).await?;
+    self.authenticate(conn).await?;
+    // Wait for the server to be ready, skipping ParameterStatus and
+    // BackendKeyData.
+    loop {
+      let Message(kind, body) = read_message(conn).await?;
+      match kind {
+        b'Z' => break,
+        b'E' => return Err(format_error(&body)),
+        _ => {},
+      }
+    }
+    let rows = self.query(conn, "SELECT 1").await?;
+    if rows != vec![vec![Some("1".to_owned())]] {
+      return Err(format!("Unexpected result of SELECT 1: {:?}", rows));
+    }
+    let mut stats = DbStats::default();
+    match self.query(conn, "SELECT count(*) FROM pg_stat_activity").await {
+      Ok(rows) => stats.connections = rows.first().and_then(|r| r.first()).and_then(|v| v.as_ref()).and_then(|v| v.parse().ok()),
+      Err(e) if self.connections_threshold != Threshold::default() => return Err(e),
+      Err(_) => {},
+    }
+    if self.lag_threshold.is_some() {
+      let rows = self.query(conn, "SELECT pg_is_in_recovery(), EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), (SELECT status FROM pg_stat_wal_receiver LIMIT 1)").await?;
+      let row = rows.first().ok_or("No result from replication query.")?;
+      let col = |i: usize| row.get(i).and_then(|v| v.as_ref()).map(|v| &v[..]);
+      if col(0) == Some("t") {
+        stats.notes.push("standby".to_owned());
+        stats.replication_lag = Some(if col(2) == Some("streaming") {
+          col(1).and_then(|v| v.parse::<f64>().ok()).map(|lag| lag.max(0f64).round())
+        } else {
+          None
+        });
+      } else {
+        stats.notes.push("primary".to_owned());
+      }
+    }
+    let _ = conn.write_all(&encode_message(b'X', &[])).await;
+    Ok(stats)
+  }
+
+  fn result(&self, stats: Result<DbStats, String>) -> CheckResult {
+    match stats {
+      Ok(stats) => stats_result(&format!("PostgreSQL {}:{}", &self.host, self.port), stats, &self.lag_threshold.unwrap_or_default(), &self.connections_threshold),
+      Err(e) => CheckResult::error(Some(e)),
+    }
+  }
+}
+
+fn parse_data_row(body: &[u8]) -> Result<Vec<Option<String>>, String> {
+  let invalid = || "Invalid DataRow from server.".to_owned();
+  let count = u16::from_be_bytes([*body.first().ok_or_else(invalid)?, *body.get(1).ok_or_else(invalid)?]);
+  let mut pos = 2;
+  let mut row = Vec::new();
+  for _ in 0..count {
+    let len_bytes = body.get(pos..pos + 4).ok_or_else(invalid)?;
+    let len = i32::from_be_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]);
+    pos += 4;
+    if len < 0 {
+      row.push(None);
+    } else {
+      let value = body.get(pos..pos + len as usize).ok_or_else(invalid)?;
+      row.push(Some(String::from_utf8_lossy(value).into_owned()));
+      pos += len as usize;
+    }
+  }
+  Ok(row)
+}
+
+impl Checker for PostgresChecker {
+  fn check(&mut self) -> CheckResult {
+    self.result(connect_blocking(&self.host, self.port, self.timeout).and_then(|mut conn| block_on(self.check_server(&mut conn))))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for PostgresChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    use crate::checkers::async_checker::within;
+    use crate::utils::transport::connect_async;
+    Box::pin(async move {
+      let stats = within(self.timeout, async {
+        self.check_server(&mut connect_async(&self.host, self.port).await?).await
+      }).await;
+      self.result(stats)
+    })
+  }
+}
+
+#[cfg(test)]
+fn data_row(values: &[Option<&str>]) -> Vec<u8> {
+  let mut body = (values.len() as u16).to_be_bytes().to_vec();
+  for v in values {
+    match v {
+      Some(v) => {
+        body.extend_from_slice(&(v.len() as i32).to_be_bytes());
+        body.extend_from_slice(v.as_bytes());
+      },
+      None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+    }
+  }
+  encode_message(b'D', &body)
+}
+
+#[cfg(test)]
+mod testing {
+  use super::{data_row, encode_message};
+  use crate::testing::read_n;
+  use std::io::Write;
+  use std::net::TcpStream;
+
+  pub fn read_startup(s: &mut TcpStream) -> Vec<u8> {
+    let len = read_n(s, 4);
+    read_n(s, u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize - 4)
+  }
+
+  pub fn read_msg(s: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = read_n(s, 5);
+    (header[0], read_n(s, u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize - 4))
+  }
+
+  pub fn ready(s: &mut TcpStream) {
+    s.write_all(&encode_message(b'S', b"server_version\x0016.2\x00")).unwrap();
+    s.write_all(&encode_message(b'Z', b"I")).unwrap();
+  }
+
+  pub fn reply_rows(s: &mut TcpStream, expected_sql: &str, rows: &[&[Option<&str>]]) {
+    assert_eq!(read_msg(s), (b'Q', format!("{}\0", expected_sql).into_bytes()));
+    s.write_all(&encode_message(b'T', b"")).unwrap();
+    for r in rows {
+      s.write_all(&data_row(r)).unwrap();
+    }
+    s.write_all(&encode_message(b'C', b"SELECT 1\0")).unwrap();
+    s.write_all(&encode_message(b'Z', b"I")).unwrap();
+  }
+}
+
+#[test]
+fn scram_test() {
+  // From RFC 7677.
+  let mut s = Scram::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+  assert_eq!(s.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+  let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+  assert_eq!(s.client_final(server_first).unwrap(), "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+  s.verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap();
+  assert!(s.verify_server_final("v=AAAA").is_err());
+  assert!(s.verify_server_final("e=invalid-proof").unwrap_err().contains("invalid-proof"));
+  assert!(Scram::new("", "x", "abc").client_final("r=xyz,s=AAAA,i=1").is_err());
+}
+
+#[test]
+fn postgres_test() {
+  use crate::checkers::CheckResultType;
+  use crate::testing::serve_once;
+  use testing::*;
+  use std::io::Write;
+
+  let addr = serve_once(|mut s| {
+    assert_eq!(read_startup(&mut s), encode_startup("monitor", "app")[4..].to_vec());
+    let mut auth = AUTH_MD5.to_be_bytes().to_vec();
+    auth.extend_from_slice(b"salt");
+    s.write_all(&encode_message(b'R', &auth)).unwrap();
+    // md5(hex(md5("hunter2" + "monitor")) + "salt")
+    let inner = hex(&hash(MessageDigest::md5(), b"hunter2monitor").unwrap());
+    let expected = format!("md5{}\0", hex(&hash(MessageDigest::md5(), format!("{}salt", inner).as_bytes()).unwrap()));
+    assert_eq!(read_msg(&mut s), (b'p', expected.into_bytes()));
+    s.write_all(&encode_message(b'R', &AUTH_OK.to_be_bytes())).unwrap();
+    ready(&mut s);
+    reply_rows(&mut s, "SELECT 1", &[&[Some("1")]]);
+    reply_rows(&mut s, "SELECT count(*) FROM pg_stat_activity", &[&[Some("12")]]);
+    reply_rows(&mut s, "SELECT pg_is_in_recovery(), EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), (SELECT status FROM pg_stat_wal_receiver LIMIT 1)", &[&[Some("t"), Some("130.25"), Some("streaming")]]);
+    assert_eq!(read_msg(&mut s).0, b'X');
+  });
+  let res = PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").set_password("hunter2")
+    .set_replication_lag_threshold(Threshold::new(Some(60f64), Some(600f64)))
+    .set_connections_threshold(Threshold::new(Some(100f64), None)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.as_ref().unwrap(), &format!("PostgreSQL 127.0.0.1:{} is up: standby, replication lag 130s, 12 connections.", addr.port()));
+
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    s.write_all(&encode_message(b'R', &AUTH_CLEARTEXT.to_be_bytes())).unwrap();
+    assert_eq!(read_msg(&mut s), (b'p', b"wrong\0".to_vec()));
+    s.write_all(&encode_message(b'E', b"SFATAL\0C28P01\0Mpassword authentication failed for user \"monitor\"\0\0")).unwrap();
+  });
+  PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").set_password("wrong").allow_cleartext_password(true).check()
+    .expect_err_contains("FATAL 28P01: password authentication failed for user \"monitor\"");
+
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    let mut auth = AUTH_SASL.to_be_bytes().to_vec();
+    auth.extend_from_slice(b"SCRAM-SHA-256\0\0");
+    s.write_all(&encode_message(b'R', &auth)).unwrap();
+    let (kind, body) = read_msg(&mut s);
+    assert_eq!(kind, b'p');
+    assert!(body.starts_with(b"SCRAM-SHA-256\0"));
+    let client_first = String::from_utf8(body[18..].to_vec()).unwrap();
+    let client_nonce = client_first.strip_prefix("n,,n=,r=").unwrap().to_owned();
+    let server_first = format!("r={}srv,s=c2FsdA==,i=4096", client_nonce);
+    let mut reply = AUTH_SASL_CONTINUE.to_be_bytes().to_vec();
+    reply.extend_from_slice(server_first.as_bytes());
+    s.write_all(&encode_message(b'R', &reply)).unwrap();
+    // Check the proof by running the same computation as the client.
+    let mut server_side = Scram::new("", "hunter2", &client_nonce);
+    let expected_final = server_side.client_final(&server_first).unwrap();
+    assert_eq!(read_msg(&mut s), (b'p', expected_final.into_bytes()));
+    let mut reply = AUTH_SASL_FINAL.to_be_bytes().to_vec();
+    reply.extend_from_slice(format!("v={}", openssl::base64::encode_block(server_side.expected_server_signature.as_ref().unwrap())).as_bytes());
+    s.write_all(&encode_message(b'R', &reply)).unwrap();
+    s.write_all(&encode_message(b'R', &AUTH_OK.to_be_bytes())).unwrap();
+    ready(&mut s);
+    reply_rows(&mut s, "SELECT 1", &[&[Some("1")]]);
+    assert_eq!(read_msg(&mut s).0, b'Q');
+    s.write_all(&encode_message(b'E', b"SERROR\0C42501\0Mpermission denied\0\0")).unwrap();
+    s.write_all(&encode_message(b'Z', b"I")).unwrap();
+  });
+  PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").set_password("hunter2")
+    .set_connections_threshold(Threshold::new(Some(100f64), None)).check()
+    .expect_err_contains("SELECT count(*) FROM pg_stat_activity failed: ERROR 42501: permission denied");
+
+  // Without a connections threshold, not being allowed to count them is fine.
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    s.write_all(&encode_message(b'R', &AUTH_OK.to_be_bytes())).unwrap();
+    ready(&mut s);
+    reply_rows(&mut s, "SELECT 1", &[&[Some("1")]]);
+    assert_eq!(read_msg(&mut s).0, b'Q');
+    s.write_all(&encode_message(b'E', b"SERROR\0C42501\0Mpermission denied\0\0")).unwrap();
+    s.write_all(&encode_message(b'Z', b"I")).unwrap();
+    assert_eq!(read_msg(&mut s).0, b'X');
+  });
+  let res = PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").check();
+  res.expect();
+  assert_eq!(res.info.as_ref().unwrap(), &format!("PostgreSQL 127.0.0.1:{} is up.", addr.port()));
+
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    s.write_all(&encode_message(b'R', &AUTH_CLEARTEXT.to_be_bytes())).unwrap();
+  });
+  PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").set_password("hunter2").check()
+    .expect_err_contains("server requested cleartext password");
+
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    let mut auth = AUTH_MD5.to_be_bytes().to_vec();
+    auth.extend_from_slice(b"salt");
+    s.write_all(&encode_message(b'R', &auth)).unwrap();
+  });
+  PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").check().expect_err_contains("none is set");
+
+  // A server which doesn't know the password can't skip SASLFinal.
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    let mut auth = AUTH_SASL.to_be_bytes().to_vec();
+    auth.extend_from_slice(b"SCRAM-SHA-256\0\0");
+    s.write_all(&encode_message(b'R', &auth)).unwrap();
+    read_msg(&mut s);
+    s.write_all(&encode_message(b'R', &AUTH_OK.to_be_bytes())).unwrap();
+  });
+  PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app").set_password("hunter2").check()
+    .expect_err_contains("without sending its signature");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_postgres_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  use crate::testing::serve_once;
+  use testing::*;
+  use std::io::Write;
+  let addr = serve_once(|mut s| {
+    read_startup(&mut s);
+    s.write_all(&encode_message(b'R', &AUTH_OK.to_be_bytes())).unwrap();
+    ready(&mut s);
+    reply_rows(&mut s, "SELECT 1", &[&[Some("1")]]);
+    reply_rows(&mut s, "SELECT count(*) FROM pg_stat_activity", &[&[Some("4")]]);
+    assert_eq!(read_msg(&mut s).0, b'X');
+  });
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut checker = PostgresChecker::new("127.0.0.1", addr.port(), "monitor", "app");
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert_eq!(res.info.as_ref().unwrap(), &format!("PostgreSQL 127.0.0.1:{} is up: 4 connections.", addr.port()));
+}
@@ -0,0 +1,334 @@
+//! Redis health checks over RESP.
+
+use crate::checkers::{Checker, CheckResult, Threshold};
+use crate::checkers::database::{stats_result, DbStats};
+use crate::utils::transport::{Buffered, Transport, block_on, connect_blocking};
+use std::time;
+
+/// Longest reply line read, e.g. a status or error message.
+const MAX_LINE_LEN: usize = 1 << 16;
+/// Largest bulk string read. `INFO` replies are a few kilobytes.
+const MAX_BULK_LEN: i64 = 1 << 24;
+/// Most elements read in one array reply.
+const MAX_ARRAY_LEN: i64 = 1 << 16;
+/// Deepest nesting of array replies read.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+  Simple(String),
+  Error(String),
+  Integer(i64),
+  Bulk(Option<Vec<u8>>),
+  Array(Option<Vec<Reply>>),
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+  let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+  for arg in args {
+    buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+    buf.extend_from_slice(arg);
+    buf.extend_from_slice(b"\r\n");
+  }
+  buf
+}
+
+async fn read_line<T: Transport>(conn: &mut Buffered<T>) -> Result<String, String> {
+  let line = conn.read_line(MAX_LINE_LEN).await?;
+  if line.len() >= MAX_LINE_LEN && !line.ends_with(b"\r\n") {
+    return Err(format!("Reply line longer than {} bytes.", MAX_LINE_LEN));
+  }
+  if !line.ends_with(b"\r\n") {
+    return Err("Connection closed by server.".to_owned());
+  }
+  Ok(String::from_utf8_lossy(&line[..line.len() - 2]).into_owned())
+}
+
+async fn read_reply<T: Transport>(conn: &mut Buffered<T>) -> Result<Reply, String> {
+  read_nested_reply(conn, 0).await
+}
+
+/// Read a reply which is nested in `depth` arrays.
+async fn read_nested_reply<T: Transport>(conn: &mut Buffered<T>, depth: usize) -> Result<Reply, String> {
+  let line = read_line(conn).await?;
+  let (kind, rest) = match line.chars().next() {
+    Some(c) => (c, &line[c.len_utf8()..]),
+    None => return Err("Empty reply from server.".to_owned()),
+  };
+  let parse_len = |s: &str| s.parse::<i64>().map_err(|_| format!("Invalid reply: {:?}", &line));
+  Ok(match kind {
+    '+' => Reply::Simple(rest.to_owned()),
+    '-' => Reply::Error(rest.to_owned()),
+    ':' => Reply::Integer(parse_len(rest)?),
+    '$' => {
+      let len = parse_len(rest)?;
+      if len < 0 {
+        Reply::Bulk(None)
+      } else if len > MAX_BULK_LEN {
+        return Err(format!("Bulk reply of {} bytes is too large.", len));
+      } else {
+        let mut data = conn.read_exact(len as usize + 2).await?;
+        data.truncate(len as usize);
+        Reply::Bulk(Some(data))
+      }
+    },
+    '*' => {
+      let len = parse_len(rest)?;
+      if len < 0 {
+        Reply::Array(None)
+      } else if len > MAX_ARRAY_LEN {
+        return Err(format!("Array reply of {} elements is too large.", len));
+      } else if depth >= MAX_DEPTH {
+        return Err("Array replies nested too deeply.".to_owned());
+      } else {
+        let mut items = Vec::new();
+        for _ in 0..len {
+          items.push(Box::pin(read_nested_reply(conn, depth + 1)).await?);
+        }
+        Reply::Array(Some(items))
+      }
+    },
+    _ => return Err(format!("Invalid reply: {:?}", &line)),
+  })
+}
+
+/// Parse the `key:value` lines of an `INFO` reply.
+fn parse_info(info: &str) -> Vec<(&str, &str)> {
+  info.lines().filter(|l| !l.starts_with('#')).filter_map(|l| {
+    let i = l.find(':')?;
+    Some((&l[..i], l[i + 1..].trim()))
+  }).collect()
+}
+
+/// Check that a Redis server answers `PING`, after authenticating if a
+/// password is set.
+///
+/// The number of connected clients is also reported. With a
+/// [replication lag threshold](crate::checkers::database::redis::RedisChecker::set_replication_lag_threshold),
+/// replicas are checked to be connected to their master, with the lag being
+/// the seconds since they last heard from it.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, database::redis::RedisChecker};
+/// let mut checker = RedisChecker::new("localhost", 6379);
+/// checker.set_password(None, "hunter2")
+///        .set_connections_threshold(Threshold::new(Some(5000f64), Some(9000f64)));
+/// let result = checker.check();
+/// ```
+pub struct RedisChecker {
+  host: String,
+  port: u16,
+  auth: Option<(Option<String>, String)>,
+  timeout: time::Duration,
+  lag_threshold: Option<Threshold>,
+  connections_threshold: Threshold,
+}
+
+impl RedisChecker {
+  pub fn new(host: &str, port: u16) -> Self {
+    RedisChecker{host: host.to_owned(), port, auth: None, timeout: time::Duration::from_secs(5), lag_threshold: None, connections_threshold: Threshold::default()}
+  }
+
+  /// Authenticate with `AUTH`. `user` is for Redis 6 ACLs.
+  pub fn set_password(&mut self, user: Option<&str>, password: &str) -> &mut Self {
+    self.auth = Some((user.map(|u| u.to_owned()), password.to_owned()));
+    self
+  }
+
+  /// Time limit for the whole check, from resolving the host to reading the
+  /// last reply.
+  ///
+  /// Default is 5s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// Thresholds on replication lag, in seconds. Setting this also makes a
+  /// replica which is not connected to its master an `ERROR`.
+  ///
+  /// Default is to not look at replication.
+  pub fn set_replication_lag_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.lag_threshold = Some(value);
+    self
+  }
+
+  /// Thresholds on the number of connected clients.
+  ///
+  /// Default is none.
+  pub fn set_connections_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.connections_threshold = value;
+    self
+  }
+
+  async fn command<T: Transport>(&self, conn: &mut Buffered<T>, args: &[&[u8]]) -> Result<Reply, String> {
+    conn.write_all(&encode_command(args)).await?;
+    match read_reply(conn).await? {
+      Reply::Error(e) => Err(format!("{} failed: {}", String::from_utf8_lossy(args[0]), e)),
+      r => Ok(r),
+    }
+  }
+
+  async fn info<T: Transport>(&self, conn: &mut Buffered<T>, section: &str) -> Result<String, String> {
+    match self.command(conn, &[b"INFO", section.as_bytes()]).await? {
+      Reply::Bulk(Some(data)) => Ok(String::from_utf8_lossy(&data).into_owned()),
+      r => Err(format!("Unexpected reply to INFO: {:?}", r)),
+    }
+  }
+
+  async fn check_server<T: Transport>(&self, conn: &mut Buffered<T>) -> Result<DbStats, String> {
+    if let Some((ref user, ref password)) = self.auth {
+      let mut args: Vec<&[u8]> = vec![b"AUTH"];
+      if let Some(user) = user {
+        args.push(user.as_bytes());
+      }
+      args.push(password.as_bytes());
+      self.command(conn, &args).await?;
+    }
+    match self.command(conn, &[b"PING"]).await? {
+      Reply::Simple(ref s) if s == "PONG" => {},
+      r => return Err(format!("Unexpected reply to PING: {:?}", r)),
+    }
+    let mut stats = DbStats::default();
+    let clients = self.info(conn, "clients").await?;
+    stats.connections = parse_info(&clients).into_iter().find(|(k, _)| *k == "connected_clients").and_then(|(_, v)| v.parse().ok());
+    if self.lag_threshold.is_some() {
+      let replication = self.info(conn, "replication").await?;
+      let replication = parse_info(&replication);
+      let get = |key: &str| replication.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+      match get("role") {
+        Some("slave") => {
+          stats.notes.push(format!("replica of {}:{}", get("master_host").unwrap_or("?"), get("master_port").unwrap_or("?")));
+          stats.replication_lag = Some(if get("master_link_status") == Some("up") {
+            get("master_last_io_seconds_ago").and_then(|v| v.parse().ok())
+          } else {
+            None
+          });
+        },
+        Some(role) => stats.notes.push(format!("{} with {} replicas", role, get("connected_slaves").unwrap_or("0"))),
+        None => return Err("No role in INFO replication.".to_owned()),
+      }
+    }
+    let _ = self.command(conn, &[b"QUIT"]).await;
+    Ok(stats)
+  }
+
+  fn result(&self, stats: Result<DbStats, String>) -> CheckResult {
+    match stats {
+      Ok(stats) => stats_result(&format!("Redis {}:{}", &self.host, self.port), stats, &self.lag_threshold.unwrap_or_default(), &self.connections_threshold),
+      Err(e) => CheckResult::error(Some(e)),
+    }
+  }
+}
+
+impl Checker for RedisChecker {
+  fn check(&mut self) -> CheckResult {
+    self.result(connect_blocking(&self.host, self.port, self.timeout).and_then(|mut conn| block_on(self.check_server(&mut conn))))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for RedisChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    use crate::checkers::async_checker::within;
+    use crate::utils::transport::connect_async;
+    Box::pin(async move {
+      let stats = within(self.timeout, async {
+        self.check_server(&mut connect_async(&self.host, self.port).await?).await
+      }).await;
+      self.result(stats)
+    })
+  }
+}
+
+#[cfg(test)]
+fn bulk(s: &str) -> Vec<u8> {
+  format!("${}\r\n{}\r\n", s.len(), s).into_bytes()
+}
+
+#[test]
+fn redis_test() {
+  use crate::checkers::CheckResultType;
+  use crate::testing::{serve_once, read_n};
+  use crate::utils::transport::Blocking;
+  use std::io::Write;
+  use std::net::TcpStream;
+  let expect = |stream: &mut TcpStream, args: &[&[u8]]| {
+    let expected = encode_command(args);
+    assert_eq!(String::from_utf8_lossy(&read_n(stream, expected.len())), String::from_utf8_lossy(&expected));
+  };
+
+  let addr = serve_once(move |mut s| {
+    expect(&mut s, &[b"AUTH", b"watcher", b"secret"]);
+    s.write_all(b"+OK\r\n").unwrap();
+    expect(&mut s, &[b"PING"]);
+    s.write_all(b"+PONG\r\n").unwrap();
+    expect(&mut s, &[b"INFO", b"clients"]);
+    s.write_all(&bulk("# Clients\r\nconnected_clients:42\r\nblocked_clients:0\r\n")).unwrap();
+    expect(&mut s, &[b"INFO", b"replication"]);
+    s.write_all(&bulk("# Replication\r\nrole:slave\r\nmaster_host:10.0.0.1\r\nmaster_port:6379\r\nmaster_link_status:up\r\nmaster_last_io_seconds_ago:7\r\n")).unwrap();
+    expect(&mut s, &[b"QUIT"]);
+    s.write_all(b"+OK\r\n").unwrap();
+  });
+  let res = RedisChecker::new("127.0.0.1", addr.port()).set_password(Some("watcher"), "secret")
+    .set_replication_lag_threshold(Threshold::new(Some(5f64), Some(30f64))).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.as_ref().unwrap(), &format!("Redis 127.0.0.1:{} is up: replica of 10.0.0.1:6379, replication lag 7s, 42 connections.", addr.port()));
+
+  let addr = serve_once(move |mut s| {
+    expect(&mut s, &[b"AUTH", b"wrong"]);
+    s.write_all(b"-WRONGPASS invalid username-password pair\r\n").unwrap();
+  });
+  RedisChecker::new("127.0.0.1", addr.port()).set_password(None, "wrong").check().expect_err_contains("AUTH failed: WRONGPASS");
+
+  let addr = serve_once(move |mut s| {
+    expect(&mut s, &[b"PING"]);
+    s.write_all(b"+PONG\r\n").unwrap();
+    expect(&mut s, &[b"INFO", b"clients"]);
+    s.write_all(&bulk("connected_clients:1\r\n")).unwrap();
+    expect(&mut s, &[b"INFO", b"replication"]);
+    s.write_all(&bulk("role:slave\r\nmaster_host:10.0.0.1\r\nmaster_port:6379\r\nmaster_link_status:down\r\n")).unwrap();
+  });
+  RedisChecker::new("127.0.0.1", addr.port()).set_replication_lag_threshold(Threshold::default()).check().expect_err_contains("replication is not running");
+
+  let addr = serve_once(move |mut s| {
+    expect(&mut s, &[b"PING"]);
+    s.write_all(b"-LOADING Redis is loading the dataset in memory\r\n").unwrap();
+  });
+  RedisChecker::new("127.0.0.1", addr.port()).check().expect_err_contains("PING failed: LOADING");
+
+  let read = |data: &[u8]| block_on(read_reply(&mut Buffered::new(Blocking(std::io::Cursor::new(data.to_vec())))));
+  assert_eq!(read(b"*3\r\n:1\r\n$-1\r\n$3\r\nabc\r\n").unwrap(), Reply::Array(Some(vec![Reply::Integer(1), Reply::Bulk(None), Reply::Bulk(Some(b"abc".to_vec()))])));
+  assert!(read(b"$9223372036854775807\r\n").unwrap_err().contains("too large"));
+  assert!(read(b"*9223372036854775807\r\n").unwrap_err().contains("too large"));
+  assert!(read(&b"*1\r\n".repeat(100)).unwrap_err().contains("nested too deeply"));
+  assert!(read(&vec![b'+'; 1 << 20]).unwrap_err().contains("longer than"));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_redis_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  use crate::testing::{serve_once, read_n};
+  use std::io::Write;
+  let addr = serve_once(move |mut s| {
+    for (args, reply) in &[(&[&b"PING"[..]][..], b"+PONG\r\n".to_vec()), (&[b"INFO", b"clients"], bulk("connected_clients:3\r\n")), (&[b"QUIT"], b"+OK\r\n".to_vec())] {
+      let expected = encode_command(args);
+      assert_eq!(read_n(&mut s, expected.len()), expected);
+      s.write_all(reply).unwrap();
+    }
+  });
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut checker = RedisChecker::new("127.0.0.1", addr.port());
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert_eq!(res.info.as_ref().unwrap(), &format!("Redis 127.0.0.1:{} is up: 3 connections.", addr.port()));
+
+  // Never replies.
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut checker = RedisChecker::new("127.0.0.1", listener.local_addr().unwrap().port());
+  checker.set_timeout(time::Duration::from_millis(200));
+  let start = time::Instant::now();
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect_err_contains("Timed out after 200ms.");
+  assert!(start.elapsed() < time::Duration::from_millis(400));
+}
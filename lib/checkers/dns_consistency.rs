@@ -0,0 +1,315 @@
+//! Check that every authoritative nameserver of a zone serves the same data.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType, format_duration};
+use crate::utils::dns::{self, DnsClient, RecordData};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time;
+
+/// What one nameserver address answered.
+struct Answers {
+  /// e.g. `ns1.example.com`, or `ns1.example.com (192.0.2.1)` if the
+  /// nameserver has several addresses.
+  label: String,
+  serial: u32,
+  /// The answer to each watched record, formatted.
+  records: Vec<String>,
+}
+
+/// Group nameservers by what they answered, as `answer on a, b; answer on c`.
+fn describe_disagreement<'a>(answers: impl Iterator<Item = (String, &'a str)>) -> String {
+  let mut by_answer: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+  for (answer, label) in answers {
+    by_answer.entry(answer).or_default().push(label);
+  }
+  by_answer.iter().map(|(answer, labels)| format!("{} on {}", answer, labels.join(", "))).collect::<Vec<_>>().join("; ")
+}
+
+/// Discovers the nameservers of a zone from its `NS` records, queries each of
+/// them directly, and checks that they all serve the same SOA serial and the
+/// same answers for the
+/// [watched records](crate::checkers::dns_consistency::DnsConsistencyChecker::watch_record).
+///
+/// A nameserver which does not respond, has no address, or is lame (does not
+/// answer authoritatively for the zone) is `ERROR`.
+///
+/// Nameservers disagreeing is normal for a while after a change, until it
+/// has reached every secondary. It is `UP` for the
+/// [grace period](crate::checkers::dns_consistency::DnsConsistencyChecker::set_grace_period),
+/// counted from the first check which saw the disagreement, and `WARN` after
+/// that.
+///
+/// The result info lists each problem on its own line. The metric is
+/// `nameservers`.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, dns_consistency::DnsConsistencyChecker};
+/// # use serverwatch::utils::dns::TYPE_A;
+/// let mut checker = DnsConsistencyChecker::new("example.com");
+/// checker.watch_record("www.example.com", TYPE_A);
+/// let result = checker.check();
+/// ```
+pub struct DnsConsistencyChecker {
+  zone: String,
+  watched: Vec<(String, u16)>,
+  grace_period: time::Duration,
+  dns_server: Option<SocketAddr>,
+  nameserver_addresses: HashMap<String, Vec<SocketAddr>>,
+  timeout: time::Duration,
+  disagreeing_since: Option<time::SystemTime>,
+  fake_now: Option<time::SystemTime>,
+}
+
+impl DnsConsistencyChecker {
+  pub fn new(zone: &str) -> Self {
+    DnsConsistencyChecker{
+      zone: dns::normalize_name(zone),
+      watched: Vec::new(),
+      grace_period: time::Duration::from_secs(30*60),
+      dns_server: None,
+      nameserver_addresses: HashMap::new(),
+      timeout: time::Duration::from_secs(5),
+      disagreeing_since: None,
+      fake_now: None,
+    }
+  }
+
+  /// Also compare the answers of every nameserver for the records of type
+  /// `rtype` (e.g. [`TYPE_A`](crate::utils::dns::TYPE_A)) at `name`.
+  pub fn watch_record(&mut self, name: &str, rtype: u16) -> &mut Self {
+    self.watched.push((dns::normalize_name(name), rtype));
+    self
+  }
+
+  /// How long nameservers may disagree before it is `WARN`.
+  ///
+  /// Default is 30 minutes.
+  pub fn set_grace_period(&mut self, value: time::Duration) -> &mut Self {
+    self.grace_period = value;
+    self
+  }
+
+  /// The resolver used to find the nameservers of the zone and their
+  /// addresses.
+  ///
+  /// Default is the first nameserver in `/etc/resolv.conf`.
+  pub fn set_dns_server(&mut self, value: SocketAddr) -> &mut Self {
+    self.dns_server = Some(value);
+    self
+  }
+
+  /// Query the nameserver `name` at `addrs` instead of the addresses the
+  /// resolver has for it. Useful for testing, or for nameservers without
+  /// public addresses.
+  pub fn set_nameserver_addresses(&mut self, name: &str, addrs: &[SocketAddr]) -> &mut Self {
+    self.nameserver_addresses.insert(dns::normalize_name(name), addrs.to_vec());
+    self
+  }
+
+  /// Time limit for each DNS query.
+  ///
+  /// Default is 5s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// For testing. This make `check` act as if the system time is `value`.
+  pub fn fake_time(&mut self, value: time::SystemTime) -> &mut Self {
+    self.fake_now = Some(value);
+    self
+  }
+
+  fn nameservers(&self, resolver: &DnsClient) -> Result<Vec<String>, String> {
+    let res = resolver.query(&self.zone, dns::TYPE_NS)?;
+    if res.rcode != dns::RCODE_NOERROR {
+      return Err(format!("Looking up the nameservers of {}: rcode {}", self.zone, res.rcode));
+    }
+    let mut names: Vec<String> = res.answers_of_type(dns::TYPE_NS).filter_map(|r| match r {
+      RecordData::NS(name) => Some(dns::normalize_name(name)),
+      _ => None,
+    }).collect();
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+      return Err(format!("{} has no NS records", self.zone));
+    }
+    Ok(names)
+  }
+
+  fn addresses(&self, resolver: &DnsClient, name: &str) -> Result<Vec<SocketAddr>, String> {
+    if let Some(addrs) = self.nameserver_addresses.get(name) {
+      return Ok(addrs.clone());
+    }
+    let mut addrs = Vec::new();
+    for rtype in &[dns::TYPE_A, dns::TYPE_AAAA] {
+      let res = resolver.query(name, *rtype)?;
+      addrs.extend(res.answers_of_type(*rtype).filter_map(|r| match r {
+        RecordData::A(a) => Some(SocketAddr::new(IpAddr::V4(*a), 53)),
+        RecordData::AAAA(a) => Some(SocketAddr::new(IpAddr::V6(*a), 53)),
+        _ => None,
+      }));
+    }
+    if addrs.is_empty() {
+      return Err(format!("{}: has no addresses", name));
+    }
+    Ok(addrs)
+  }
+
+  /// Query the nameserver at `addr` for the SOA serial and the watched
+  /// records.
+  fn query_nameserver(&self, label: String, addr: SocketAddr) -> Result<Answers, String> {
+    let mut client = DnsClient::new(addr);
+    client.set_timeout(self.timeout).set_recursion_desired(false);
+    let res = client.query(&self.zone, dns::TYPE_SOA).map_err(|e| format!("{}: unreachable: {}", label, e))?;
+    let serial = res.answers_of_type(dns::TYPE_SOA).find_map(|r| match r {
+      RecordData::SOA{serial, ..} => Some(*serial),
+      _ => None,
+    });
+    let serial = match serial {
+      Some(s) if res.authoritative && res.rcode == dns::RCODE_NOERROR => s,
+      _ => return Err(format!("{}: lame, not authoritative for {}", label, self.zone)),
+    };
+    let mut records = Vec::new();
+    for (name, rtype) in self.watched.iter() {
+      let res = client.query(name, *rtype).map_err(|e| format!("{}: unreachable: {}", label, e))?;
+      let answer = match res.rcode {
+        dns::RCODE_NXDOMAIN => "NXDOMAIN".to_owned(),
+        dns::RCODE_NOERROR => {
+          let mut data: Vec<String> = res.answers.iter().map(|r| format!("{}", r.data)).collect();
+          data.sort();
+          if data.is_empty() { "no records".to_owned() } else { data.join(", ") }
+        },
+        rcode => return Err(format!("{}: answered {} with rcode {}", label, name, rcode)),
+      };
+      records.push(answer);
+    }
+    Ok(Answers{label, serial, records})
+  }
+}
+
+impl Checker for DnsConsistencyChecker {
+  fn check(&mut self) -> CheckResult {
+    let mut resolver = match self.dns_server {
+      Some(addr) => DnsClient::new(addr),
+      None => match DnsClient::system() {
+        Ok(c) => c,
+        Err(e) => return CheckResult::error(Some(e)),
+      },
+    };
+    resolver.set_timeout(self.timeout);
+    let names = match self.nameservers(&resolver) {
+      Ok(n) => n,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let mut failures = Vec::new();
+    let mut answers = Vec::new();
+    for name in names.iter() {
+      let addrs = match self.addresses(&resolver, name) {
+        Ok(a) => a,
+        Err(e) => {
+          failures.push(e);
+          continue;
+        },
+      };
+      for addr in addrs.iter() {
+        let label = if addrs.len() > 1 { format!("{} ({})", name, addr.ip()) } else { name.clone() };
+        match self.query_nameserver(label, *addr) {
+          Ok(a) => answers.push(a),
+          Err(e) => failures.push(e),
+        }
+      }
+    }
+
+    let mut disagreements = Vec::new();
+    if answers.iter().any(|a| a.serial != answers[0].serial) {
+      disagreements.push(format!("SOA serial differs: {}", describe_disagreement(answers.iter().map(|a| (a.serial.to_string(), &a.label[..])))));
+    }
+    for (i, (name, rtype)) in self.watched.iter().enumerate() {
+      if answers.iter().any(|a| a.records[i] != answers[0].records[i]) {
+        disagreements.push(format!("{} {} differs: {}", name, dns::type_name(*rtype), describe_disagreement(answers.iter().map(|a| (a.records[i].clone(), &a.label[..])))));
+      }
+    }
+
+    let now = self.fake_now.unwrap_or(time::SystemTime::now());
+    let mut result_type = CheckResultType::UP;
+    let mut lines = failures.clone();
+    if disagreements.is_empty() {
+      self.disagreeing_since = None;
+      if let Some(a) = answers.first() {
+        lines.push(format!("{} nameserver addresses agree on SOA serial {}", answers.len(), a.serial));
+      }
+    } else {
+      let since = *self.disagreeing_since.get_or_insert(now);
+      let elapsed = now.duration_since(since).unwrap_or_default();
+      if elapsed >= self.grace_period {
+        result_type = CheckResultType::WARN;
+        lines.push(format!("Nameservers have disagreed for {}:", format_duration(elapsed)));
+      } else {
+        lines.push(format!("Nameservers have disagreed for {}, within the grace period of {}:", format_duration(elapsed), format_duration(self.grace_period)));
+      }
+      lines.extend(disagreements);
+    }
+    if !failures.is_empty() {
+      result_type = CheckResultType::ERROR;
+    }
+    CheckResult::new(result_type, Some(lines.join("\n"))).with_metric("nameservers", names.len() as f64, "")
+  }
+}
+
+#[test]
+fn dns_consistency_test() {
+  use crate::utils::dns::testing::{record, serve_dns, Zone};
+  use std::net::Ipv4Addr;
+  let soa = |serial| RecordData::SOA{mname: "ns1.example.test".to_owned(), rname: "hostmaster.example.test".to_owned(), serial, refresh: 1, retry: 2, expire: 3, minimum: 4};
+  let zone = |serial, www| Zone{records: vec![
+    record("example.test", soa(serial)),
+    record("www.example.test", RecordData::A(Ipv4Addr::new(192, 0, 2, www))),
+  ], authoritative: true, ..Zone::default()};
+  let (resolver, resolver_zone) = serve_dns(Zone{records: vec![
+    record("example.test", RecordData::NS("ns1.example.test".to_owned())),
+    record("example.test", RecordData::NS("NS2.example.test.".to_owned())),
+    record("example.test", RecordData::NS("ns3.example.test".to_owned())),
+  ], ..Zone::default()});
+  let (ns1, _) = serve_dns(zone(5, 1));
+  let (ns2, ns2_zone) = serve_dns(zone(5, 1));
+  let (ns3, ns3_zone) = serve_dns(zone(5, 1));
+  let mut checker = DnsConsistencyChecker::new("example.test");
+  checker.set_dns_server(resolver).set_timeout(time::Duration::from_millis(300))
+    .set_nameserver_addresses("ns1.example.test", &[ns1])
+    .set_nameserver_addresses("ns2.example.test", &[ns2])
+    .set_nameserver_addresses("ns3.example.test", &[ns3])
+    .watch_record("www.example.test", dns::TYPE_A)
+    .watch_record("missing.example.test", dns::TYPE_A);
+  let t0 = time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000);
+  let res = checker.fake_time(t0).check();
+  assert_eq!(res, CheckResult::up(Some("3 nameserver addresses agree on SOA serial 5".to_owned())).with_metric("nameservers", 3f64, ""));
+
+  *ns3_zone.lock().unwrap() = zone(6, 2);
+  let res = checker.fake_time(t0 + time::Duration::from_secs(60)).check();
+  assert_eq!(res.result_type, CheckResultType::UP);
+  assert_eq!(res.info.unwrap(), "Nameservers have disagreed for 0s, within the grace period of 30m 0s:\n\
+    SOA serial differs: 5 on ns1.example.test, ns2.example.test; 6 on ns3.example.test\n\
+    www.example.test A differs: A 192.0.2.1 on ns1.example.test, ns2.example.test; A 192.0.2.2 on ns3.example.test");
+  let res = checker.fake_time(t0 + time::Duration::from_secs(60 + 30*60)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().starts_with("Nameservers have disagreed for 30m 0s:\n"));
+
+  // Agreeing again resets the grace period.
+  *ns3_zone.lock().unwrap() = zone(5, 1);
+  checker.fake_time(t0 + time::Duration::from_secs(2*60*60)).check().expect();
+  *ns3_zone.lock().unwrap() = zone(6, 1);
+  checker.fake_time(t0 + time::Duration::from_secs(3*60*60)).check().expect();
+
+  ns2_zone.lock().unwrap().authoritative = false;
+  ns3_zone.lock().unwrap().silent = true;
+  let res = checker.check();
+  res.expect_err_contains("ns2.example.test: lame, not authoritative for example.test\nns3.example.test: unreachable: Timed out");
+  assert!(res.info.unwrap().ends_with("\n1 nameserver addresses agree on SOA serial 5"));
+
+  resolver_zone.lock().unwrap().records.push(record("example.test", RecordData::NS("ns4.example.test".to_owned())));
+  checker.check().expect_err_contains("ns4.example.test: has no addresses");
+
+  DnsConsistencyChecker::new("other.test").set_dns_server(resolver).check().expect_err_contains("Looking up the nameservers of other.test: rcode 3");
+}
@@ -0,0 +1,516 @@
+//! Check that a domain's registration is not about to lapse.
+
+use crate::checkers::{Checker, CheckResult};
+use crate::checkers::tls::{ExpiryLevel, ExpiryTiers, days_until, system_time_to_time_t};
+use crate::utils::transport::{Buffered, Transport, block_on, connect_blocking};
+use openssl::asn1::Asn1Time;
+use reqwest::Url;
+use serde_json::Value;
+use std::future::Future;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time;
+
+const RDAP_BOOTSTRAP_URL: &str = "https://data.iana.org/rdap/dns.json";
+const IANA_WHOIS_SERVER: &str = "whois.iana.org";
+/// How long to keep the RDAP bootstrap registry.
+const BOOTSTRAP_CACHE_DURATION: time::Duration = time::Duration::from_secs(24*60*60);
+/// How long to wait before retrying a failed lookup, unless the cache
+/// duration is shorter.
+const FAILURE_CACHE_DURATION: time::Duration = time::Duration::from_secs(15*60);
+/// Largest RDAP or WHOIS response we read.
+const MAX_RESPONSE_LEN: u64 = 1 << 20;
+/// Most redirects followed by an RDAP request.
+const MAX_REDIRECTS: usize = 5;
+
+/// Pairs of TLDs and the base URL of their RDAP server.
+type RdapServices = Vec<(Vec<String>, String)>;
+
+lazy_static! {
+  /// The IANA registry of RDAP servers, and when it was fetched.
+  static ref RDAP_BOOTSTRAP: Mutex<Option<(time::Instant, RdapServices)>> = Mutex::new(None);
+}
+
+/// An expiry date, and where it came from.
+struct Registration {
+  expiry: Asn1Time,
+  /// e.g. `RDAP https://rdap.example/` or `WHOIS whois.example:43`.
+  source: String,
+}
+
+const ACCEPT: &str = "application/rdap+json, application/json";
+
+/// How lookups reach the network, each request within `timeout`.
+trait Lookups: Sync {
+  /// GET `url`, without following redirects.
+  fn get(&self, url: Url) -> impl Future<Output = Result<reqwest::Response, String>> + Send;
+  /// The response of the WHOIS server `host:port` to `query`.
+  fn whois<'a>(&'a self, host: &'a str, port: u16, query: &'a str) -> impl Future<Output = Result<String, String>> + Send + 'a;
+}
+
+/// Blocking lookups, with the shared HTTP clients.
+struct BlockingLookups {
+  timeout: time::Duration,
+}
+
+impl Lookups for BlockingLookups {
+  fn get(&self, url: Url) -> impl Future<Output = Result<reqwest::Response, String>> + Send {
+    let client = crate::checkers::http::acquire_client(self.timeout);
+    std::future::ready(client.get(url).header(reqwest::header::ACCEPT, ACCEPT).send().map_err(|e| format!("{}", &e)))
+  }
+
+  fn whois<'a>(&'a self, host: &'a str, port: u16, query: &'a str) -> impl Future<Output = Result<String, String>> + Send + 'a {
+    std::future::ready(connect_blocking(host, port, self.timeout).and_then(|conn| block_on(whois_query(conn, query))))
+  }
+}
+
+/// Lookups without blocking, for the async checker.
+#[cfg(feature = "async")]
+struct AsyncLookups {
+  timeout: time::Duration,
+}
+
+#[cfg(feature = "async")]
+impl Lookups for AsyncLookups {
+  async fn get(&self, url: Url) -> Result<reqwest::Response, String> {
+    use crate::checkers::async_checker::within;
+    let headers = [("Accept".to_owned(), ACCEPT.to_owned())];
+    within(self.timeout, crate::checkers::http::async_check::fetch(url, None, &headers)).await
+  }
+
+  fn whois<'a>(&'a self, host: &'a str, port: u16, query: &'a str) -> impl Future<Output = Result<String, String>> + Send + 'a {
+    use crate::checkers::async_checker::within;
+    use crate::utils::transport::connect_async;
+    within(self.timeout, async move { whois_query(connect_async(host, port).await?, query).await })
+  }
+}
+
+/// GET the JSON document at `url`, following redirects, which RDAP servers
+/// use to send queries on to other servers.
+async fn get_json<L: Lookups>(lookups: &L, url: &str) -> Result<Value, String> {
+  let mut url = Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, &e))?;
+  for _ in 0..=MAX_REDIRECTS {
+    let mut res = lookups.get(url.clone()).await.map_err(|e| format!("GET {}: {}", url, &e))?;
+    if res.status().is_redirection() {
+      let location = res.headers().get(reqwest::header::LOCATION).and_then(|l| l.to_str().ok()).ok_or_else(|| format!("GET {}: redirect without a location.", url))?;
+      url = url.join(location).map_err(|e| format!("GET {}: invalid redirect: {}", url, &e))?;
+      continue;
+    }
+    if !res.status().is_success() {
+      return Err(format!("GET {}: status {}.", url, res.status()));
+    }
+    let mut body = Vec::new();
+    (&mut res).take(MAX_RESPONSE_LEN).read_to_end(&mut body).map_err(|e| format!("GET {}: {}", url, &e))?;
+    return serde_json::from_slice(&body).map_err(|e| format!("GET {}: invalid JSON: {}", url, &e));
+  }
+  Err(format!("GET {}: too many redirects.", url))
+}
+
+/// The RDAP base URLs by TLD, fetched from IANA at most once a day.
+async fn rdap_bootstrap<L: Lookups>(lookups: &L) -> Result<RdapServices, String> {
+  if let Some((fetched_at, ref services)) = *RDAP_BOOTSTRAP.lock().unwrap_or_else(|e| e.into_inner()) {
+    if fetched_at.elapsed() < BOOTSTRAP_CACHE_DURATION {
+      return Ok(services.clone());
+    }
+  }
+  let registry = get_json(lookups, RDAP_BOOTSTRAP_URL).await?;
+  let mut services = Vec::new();
+  for service in elements(registry.get("services")) {
+    let parts = elements(Some(service));
+    let tlds: Vec<String> = elements(parts.first()).iter().filter_map(|t| t.as_str()).map(|t| t.to_lowercase()).collect();
+    // Prefer https.
+    let mut urls: Vec<&str> = elements(parts.get(1)).iter().filter_map(|u| u.as_str()).collect();
+    urls.sort_by_key(|u| !u.starts_with("https:"));
+    if let Some(url) = urls.first() {
+      services.push((tlds, url.to_string()));
+    }
+  }
+  if services.is_empty() {
+    return Err(format!("No services in {}.", RDAP_BOOTSTRAP_URL));
+  }
+  *RDAP_BOOTSTRAP.lock().unwrap_or_else(|e| e.into_inner()) = Some((time::Instant::now(), services.clone()));
+  Ok(services)
+}
+
+/// The RDAP base URL for the longest matching suffix of `domain`.
+fn rdap_base_url(services: &[(Vec<String>, String)], domain: &str) -> Option<String> {
+  let labels: Vec<&str> = domain.split('.').collect();
+  (1..labels.len()).map(|i| labels[i..].join(".")).find_map(|suffix| {
+    services.iter().find(|(tlds, _)| tlds.contains(&suffix)).map(|(_, url)| url.clone())
+  })
+}
+
+/// The expiration date of an RDAP domain object.
+/// The elements of `value` if it is an array, else none.
+fn elements(value: Option<&Value>) -> &[Value] {
+  value.and_then(|v| v.as_array()).map(|a| &a[..]).unwrap_or(&[])
+}
+
+fn rdap_expiry(object: &Value) -> Result<Asn1Time, String> {
+  let date = elements(object.get("events")).iter()
+    .find(|e| e.get("eventAction").and_then(|a| a.as_str()) == Some("expiration"))
+    .and_then(|e| e.get("eventDate")).and_then(|d| d.as_str())
+    .ok_or_else(|| "No expiration event in RDAP response.".to_owned())?;
+  parse_date(date).ok_or_else(|| format!("Unable to parse expiration date {:?}.", date))
+}
+
+/// Parse the dates found in RDAP and WHOIS responses, such as
+/// `2030-08-13T04:00:00Z`, `2030-08-13 04:00:00+08:00`, `2030.08.13`,
+/// `13-Aug-2030` or `13.08.2030`. Time zones are ignored.
+fn parse_date(text: &str) -> Option<Asn1Time> {
+  const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+  // Runs of digits, and runs of letters.
+  let mut tokens: Vec<String> = Vec::new();
+  let mut last_kind = None;
+  for c in text.trim().chars() {
+    let kind = if c.is_ascii_digit() { Some(true) } else if c.is_ascii_alphabetic() { Some(false) } else { None };
+    if kind.is_some() && kind == last_kind {
+      tokens.last_mut().unwrap().push(c);
+    } else if kind.is_some() {
+      tokens.push(c.to_string());
+    }
+    last_kind = kind;
+  }
+  let number = |t: &str| if t.bytes().all(|b| b.is_ascii_digit()) { t.parse::<u32>().ok() } else { None };
+  let month = |t: &str| number(t).or_else(|| MONTHS.iter().position(|m| t.to_lowercase().starts_with(m)).map(|m| m as u32 + 1));
+  let (year, mon, day) = match tokens.get(0..3) {
+    Some([y, m, d]) if y.len() == 4 => (number(y)?, month(m)?, number(d)?),
+    Some([d, m, y]) if y.len() == 4 => (number(y)?, month(m)?, number(d)?),
+    _ => return None,
+  };
+  let rest: Vec<u32> = tokens[3..].iter().filter(|t| !t.eq_ignore_ascii_case("t")).take(3).map_while(|t| number(t)).collect();
+  let (hour, min, sec) = match rest[..] {
+    [h, m, s] => (h, m, s),
+    [h, m] => (h, m, 0),
+    _ => (0, 0, 0),
+  };
+  if !(1..=12).contains(&mon) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+    return None;
+  }
+  Asn1Time::from_str(&format!("{:04}{:02}{:02}{:02}{:02}{:02}Z", year, mon, day, hour, min, sec)).ok()
+}
+
+async fn whois_query<T: Transport>(mut conn: Buffered<T>, query: &str) -> Result<String, String> {
+  conn.write_all(format!("{}\r\n", query).as_bytes()).await?;
+  let response = conn.read_to_end(MAX_RESPONSE_LEN as usize).await?;
+  Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+/// The values of the lines `key: value` of a WHOIS response, where `key`
+/// matches `pred`.
+fn whois_values<'a, P: Fn(&str) -> bool + 'a>(text: &'a str, pred: P) -> impl Iterator<Item = &'a str> + 'a {
+  text.lines().filter_map(move |line| {
+    let i = line.find(':')?;
+    let key = line[..i].trim().to_lowercase();
+    if pred(&key) { Some(line[i + 1..].trim()) } else { None }
+  })
+}
+
+fn whois_expiry(text: &str) -> Option<Asn1Time> {
+  whois_values(text, |key| key.contains("expir") || key == "paid-till" || key == "renewal date").find_map(parse_date)
+}
+
+/// Check that a domain's registration is not about to lapse, which would take
+/// down everything under it at once.
+///
+/// The expiration date is looked up with RDAP, using the server IANA lists for
+/// the TLD, and with WHOIS if that fails. `domain` has to be the registered
+/// domain (`example.co.uk`, not `www.example.co.uk`), in its ASCII form for
+/// internationalized names.
+///
+/// Registries rate-limit these lookups, so the expiration date is only looked
+/// up again after a
+/// [cache duration](crate::checkers::domain::DomainExpiryChecker::set_cache_duration).
+/// The remaining time is reported in the `expiry_days` metric.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, domain::DomainExpiryChecker, tls::ExpiryLevel};
+/// # use std::time::Duration;
+/// let mut checker = DomainExpiryChecker::new("example.com");
+/// checker.set_expiry_tiers(vec![(Duration::from_secs(60*24*60*60), ExpiryLevel::INFO), (Duration::from_secs(14*24*60*60), ExpiryLevel::ERROR)]);
+/// let result = checker.check();
+/// ```
+pub struct DomainExpiryChecker {
+  domain: String,
+  rdap_server: Option<String>,
+  whois_server: Option<(String, u16)>,
+  expiry_tiers: ExpiryTiers,
+  cache_duration: time::Duration,
+  cached: Option<(time::Instant, Result<Registration, String>)>,
+  timeout: time::Duration,
+  fake_now: Option<time::SystemTime>,
+}
+
+impl DomainExpiryChecker {
+  pub fn new(domain: &str) -> Self {
+    DomainExpiryChecker{
+      domain: domain.trim_end_matches('.').to_lowercase(),
+      rdap_server: None,
+      whois_server: None,
+      expiry_tiers: ExpiryTiers::new(vec![(time::Duration::from_secs(30*24*60*60), ExpiryLevel::WARN), (time::Duration::from_secs(7*24*60*60), ExpiryLevel::ERROR)], true),
+      cache_duration: time::Duration::from_secs(6*60*60),
+      cached: None,
+      timeout: time::Duration::from_secs(10),
+      fake_now: None,
+    }
+  }
+
+  /// Same as
+  /// [`CertificateCheckerBuilder::set_expiry_tiers`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_tiers).
+  ///
+  /// Default is `WARN` at 30 days and `ERROR` at 7 days. An expired domain is
+  /// always `ERROR`.
+  pub fn set_expiry_tiers(&mut self, tiers: Vec<(time::Duration, ExpiryLevel)>) -> &mut Self {
+    self.expiry_tiers = ExpiryTiers::new(tiers, true);
+    self
+  }
+
+  /// Use the RDAP server at the base URL `value`, such as
+  /// `https://rdap.verisign.com/com/v1/`, instead of the one IANA lists.
+  pub fn set_rdap_server(&mut self, value: &str) -> &mut Self {
+    let mut url = value.to_owned();
+    if !url.ends_with('/') {
+      url.push('/');
+    }
+    self.rdap_server = Some(url);
+    self
+  }
+
+  /// Ask the WHOIS server `host:port` instead of the one `whois.iana.org`
+  /// refers to.
+  pub fn set_whois_server(&mut self, host: &str, port: u16) -> &mut Self {
+    self.whois_server = Some((host.to_owned(), port));
+    self
+  }
+
+  /// How long to keep using an expiration date before looking it up again.
+  /// Failed lookups are retried after 15 minutes, or this duration if shorter.
+  ///
+  /// Default is 6 hours.
+  pub fn set_cache_duration(&mut self, value: time::Duration) -> &mut Self {
+    self.cache_duration = value;
+    self
+  }
+
+  /// Time limit for each request.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// For testing. This make `check` act as if the system time is `value`.
+  pub fn fake_time(&mut self, value: time::SystemTime) -> &mut Self {
+    self.fake_now = Some(value);
+    self
+  }
+
+  async fn lookup<L: Lookups>(&self, lookups: &L) -> Result<Registration, String> {
+    let rdap_err = match self.lookup_rdap(lookups).await {
+      Ok(r) => return Ok(r),
+      Err(e) => e,
+    };
+    self.lookup_whois(lookups).await.map_err(|whois_err| format!("Unable to find when {} expires. RDAP: {} WHOIS: {}", &self.domain, rdap_err, whois_err))
+  }
+
+  async fn lookup_rdap<L: Lookups>(&self, lookups: &L) -> Result<Registration, String> {
+    let base = match self.rdap_server {
+      Some(ref url) => url.clone(),
+      None => rdap_base_url(&rdap_bootstrap(lookups).await?, &self.domain).ok_or_else(|| format!("No RDAP server for {}.", &self.domain))?,
+    };
+    let object = get_json(lookups, &format!("{}domain/{}", base, &self.domain)).await?;
+    Ok(Registration{expiry: rdap_expiry(&object)?, source: format!("RDAP {}", base)})
+  }
+
+  async fn lookup_whois<L: Lookups>(&self, lookups: &L) -> Result<Registration, String> {
+    let (host, port) = match self.whois_server {
+      Some(ref server) => server.clone(),
+      None => {
+        let iana = lookups.whois(IANA_WHOIS_SERVER, 43, &self.domain).await?;
+        let refer = whois_values(&iana, |key| key == "refer" || key == "whois").find(|v| !v.is_empty())
+          .ok_or_else(|| format!("{} knows no WHOIS server for {}.", IANA_WHOIS_SERVER, &self.domain))?;
+        (refer.to_owned(), 43)
+      },
+    };
+    let text = lookups.whois(&host, port, &self.domain).await?;
+    let expiry = whois_expiry(&text).ok_or_else(|| format!("No expiration date in the response of {}.", &host))?;
+    Ok(Registration{expiry, source: format!("WHOIS {}:{}", host, port)})
+  }
+
+  /// Whether the cached lookup is missing or too old.
+  fn stale(&self) -> bool {
+    match self.cached {
+      Some((looked_up_at, Ok(_))) => looked_up_at.elapsed() >= self.cache_duration,
+      Some((looked_up_at, Err(_))) => looked_up_at.elapsed() >= std::cmp::min(self.cache_duration, FAILURE_CACHE_DURATION),
+      None => true,
+    }
+  }
+
+  /// The result for the cached lookup.
+  fn result(&mut self) -> CheckResult {
+    let registration = match self.cached {
+      Some((_, Ok(ref r))) => r,
+      Some((_, Err(ref e))) => return CheckResult::error(Some(e.clone())),
+      None => unreachable!(),
+    };
+    let now = system_time_to_time_t(self.fake_now.unwrap_or(time::SystemTime::now()));
+    let rem_days = days_until(now, &registration.expiry);
+    let tier = self.expiry_tiers.tier(rem_days);
+    let mut result = if rem_days <= 0f32 {
+      CheckResult::error(Some(format!("{} expired: registered until {} ({}).", &self.domain, &*registration.expiry, &registration.source)))
+    } else if tier.is_some() {
+      CheckResult::new(self.expiry_tiers.result_type(tier), Some(format!("{} expiring in {:.1} days: registered until {} ({}).", &self.domain, rem_days, &*registration.expiry, &registration.source)))
+    } else {
+      CheckResult::up(Some(format!("{} registered until {} ({}).", &self.domain, &*registration.expiry, &registration.source)))
+    };
+    self.expiry_tiers.set_notify(&mut result, rem_days);
+    result.with_metric("expiry_days", rem_days as f64, "days")
+  }
+}
+
+impl Checker for DomainExpiryChecker {
+  fn check(&mut self) -> CheckResult {
+    if self.stale() {
+      let registration = block_on(self.lookup(&BlockingLookups{timeout: self.timeout}));
+      self.cached = Some((time::Instant::now(), registration));
+    }
+    self.result()
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for DomainExpiryChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    Box::pin(async move {
+      if self.stale() {
+        let registration = self.lookup(&AsyncLookups{timeout: self.timeout}).await;
+        self.cached = Some((time::Instant::now(), registration));
+      }
+      self.result()
+    })
+  }
+}
+
+#[test]
+fn parse_date_test() {
+  let parsed = |s: &str| parse_date(s).map(|t| format!("{}", &*t));
+  assert_eq!(parsed("2030-08-13T04:00:00Z").unwrap(), "Aug 13 04:00:00 2030 GMT");
+  assert_eq!(parsed("2030-08-13T04:00:00.123+08:00").unwrap(), "Aug 13 04:00:00 2030 GMT");
+  assert_eq!(parsed("2030-08-13 04:05").unwrap(), "Aug 13 04:05:00 2030 GMT");
+  assert_eq!(parsed("2030.08.13").unwrap(), "Aug 13 00:00:00 2030 GMT");
+  assert_eq!(parsed(" 13-Aug-2030 ").unwrap(), "Aug 13 00:00:00 2030 GMT");
+  assert_eq!(parsed("13.08.2030 (dd.mm.yyyy)").unwrap(), "Aug 13 00:00:00 2030 GMT");
+  assert_eq!(parsed("2030-02-30"), None);
+  assert_eq!(parsed("2030-13-01"), None);
+  assert_eq!(parsed("never"), None);
+  let text = "Domain Name: EXAMPLE.COM\r\nCreation Date: 1995-08-14T04:00:00Z\r\nRegistry Expiry Date: 2030-08-13T04:00:00Z\r\n";
+  assert_eq!(whois_expiry(text).map(|t| format!("{}", &*t)).unwrap(), "Aug 13 04:00:00 2030 GMT");
+  let services = vec![(vec!["com".to_owned()], "https://rdap.example/com/".to_owned()), (vec!["co.uk".to_owned(), "uk".to_owned()], "https://rdap.example/uk/".to_owned())];
+  assert_eq!(rdap_base_url(&services, "example.co.uk").unwrap(), "https://rdap.example/uk/");
+  assert_eq!(rdap_base_url(&services, "example.com").unwrap(), "https://rdap.example/com/");
+  assert_eq!(rdap_base_url(&services, "example.org"), None);
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+  use crate::testing::serve_once;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::SocketAddr;
+
+  /// Answer one HTTP request with `status`, `content_type` and `body`, and
+  /// return the request line through `tx`.
+  pub fn serve_http(status: &'static str, content_type: &'static str, body: &'static str, tx: std::sync::mpsc::Sender<String>) -> SocketAddr {
+    serve_once(move |mut stream| {
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut request_line = String::new();
+      reader.read_line(&mut request_line).unwrap();
+      let mut line = String::new();
+      while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+      }
+      tx.send(request_line.trim_end().to_owned()).unwrap();
+      write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body).unwrap();
+    })
+  }
+
+  /// Answer one WHOIS query with `response`.
+  pub fn serve_whois(response: &'static str) -> SocketAddr {
+    serve_once(move |mut stream| {
+      let mut query = String::new();
+      BufReader::new(stream.try_clone().unwrap()).read_line(&mut query).unwrap();
+      assert_eq!(query, "example.com\r\n");
+      stream.write_all(response.as_bytes()).unwrap();
+    })
+  }
+}
+
+#[test]
+fn domain_expiry_test() {
+  use crate::checkers::{CheckResultType, NotifyHint};
+  let day = |d: u64| time::UNIX_EPOCH + time::Duration::from_secs(d * 24*60*60);
+  // 2030-08-13 is day 22139.
+  let (tx, rx) = std::sync::mpsc::channel();
+  let rdap = testing::serve_http("200 OK", "application/rdap+json", r#"{"objectClassName": "domain", "ldhName": "EXAMPLE.COM", "events": [
+    {"eventAction": "registration", "eventDate": "1995-08-14T04:00:00Z"},
+    {"eventAction": "expiration", "eventDate": "2030-08-13T00:00:00Z"}]}"#, tx);
+  let mut checker = DomainExpiryChecker::new("Example.com.");
+  checker.set_rdap_server(&format!("http://{}", rdap)).fake_time(day(22139 - 60));
+  let res = checker.check();
+  res.expect();
+  assert_eq!(rx.recv().unwrap(), "GET /domain/example.com HTTP/1.1");
+  assert_eq!(res.get_metric("expiry_days"), Some(60f64));
+  assert_eq!(res.info.unwrap(), format!("example.com registered until Aug 13 00:00:00 2030 GMT (RDAP http://{}/).", rdap));
+  // Cached from now on, as the stand-in is gone.
+  let res = checker.fake_time(day(22139 - 20)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.notify, NotifyHint::Always);
+  assert!(res.info.unwrap().starts_with("example.com expiring in 20.0 days"));
+  assert_eq!(checker.check().notify, NotifyHint::Never);
+  let res = checker.fake_time(day(22139 - 3)).check();
+  res.expect_err_contains("expiring in 3.0 days");
+  assert_eq!(res.notify, NotifyHint::Always);
+  checker.fake_time(day(22139 + 1)).check().expect_err_contains("example.com expired");
+
+  // WHOIS, after RDAP fails.
+  let (tx, _rx) = std::sync::mpsc::channel();
+  let rdap = testing::serve_http("404 Not Found", "application/rdap+json", r#"{"errorCode": 404}"#, tx);
+  let whois = testing::serve_whois("Domain Name: EXAMPLE.COM\r\nRegistrar Registration Expiration Date: 13-Aug-2030\r\n");
+  let res = DomainExpiryChecker::new("example.com").set_rdap_server(&format!("http://{}/", rdap)).set_whois_server("127.0.0.1", whois.port()).fake_time(day(22139 - 10)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().ends_with(&format!("(WHOIS 127.0.0.1:{}).", whois.port())));
+
+  let (tx, _rx) = std::sync::mpsc::channel();
+  let rdap = testing::serve_http("200 OK", "application/rdap+json", r#"{"events": []}"#, tx);
+  let whois = testing::serve_whois("No match for \"EXAMPLE.COM\".\r\n");
+  let mut checker = DomainExpiryChecker::new("example.com");
+  checker.set_rdap_server(&format!("http://{}/", rdap)).set_whois_server("127.0.0.1", whois.port());
+  checker.check().expect_err_contains("RDAP: No expiration event in RDAP response. WHOIS: No expiration date in the response of 127.0.0.1.");
+  // Failures are cached too.
+  checker.check().expect_err_contains("RDAP: No expiration event");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_domain_expiry_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  let day = |d: u64| time::UNIX_EPOCH + time::Duration::from_secs(d * 24*60*60);
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let (tx, rx) = std::sync::mpsc::channel();
+  let rdap = testing::serve_http("200 OK", "application/rdap+json", r#"{"events": [{"eventAction": "expiration", "eventDate": "2030-08-13T00:00:00Z"}]}"#, tx);
+  let mut checker = DomainExpiryChecker::new("example.com");
+  checker.set_rdap_server(&format!("http://{}", rdap)).fake_time(day(22139 - 60));
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert_eq!(res.info.unwrap(), format!("example.com registered until Aug 13 00:00:00 2030 GMT (RDAP http://{}/).", rdap));
+  assert_eq!(rx.recv().unwrap(), "GET /domain/example.com HTTP/1.1");
+
+  let (tx, _rx) = std::sync::mpsc::channel();
+  let rdap = testing::serve_http("404 Not Found", "application/rdap+json", r#"{"errorCode": 404}"#, tx);
+  let whois = testing::serve_whois("Domain Name: EXAMPLE.COM\r\nRegistrar Registration Expiration Date: 13-Aug-2030\r\n");
+  let mut checker = DomainExpiryChecker::new("example.com");
+  checker.set_rdap_server(&format!("http://{}/", rdap)).set_whois_server("127.0.0.1", whois.port()).fake_time(day(22139 - 60));
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert!(res.info.unwrap().ends_with(&format!("(WHOIS 127.0.0.1:{}).", whois.port())));
+}
@@ -0,0 +1,515 @@
+//! Audit the DNS records mail deliverability depends on: SPF, DKIM, DMARC and
+//! MTA-STS.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType};
+use crate::utils::dns::{self, DnsClient, RecordData};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::time;
+
+/// The most DNS lookups an SPF check may need (RFC 7208 section 4.6.4).
+const SPF_LOOKUP_LIMIT: usize = 10;
+/// Largest MTA-STS policy file we read (RFC 8461 section 3.3 allows 64KiB).
+const MAX_POLICY_LEN: u64 = 64 << 10;
+
+fn finding(result_type: CheckResultType, what: &str, info: String) -> CheckResult {
+  CheckResult::new(result_type, Some(format!("{}: {}", what, info)))
+}
+
+/// Split a `tag=value; tag=value` record, as used by DKIM and DMARC, into
+/// lower-cased tags and their values.
+fn tag_list(record: &str) -> Vec<(String, String)> {
+  record.split(';').filter_map(|part| {
+    let i = part.find('=')?;
+    Some((part[..i].trim().to_ascii_lowercase(), part[i + 1..].trim().to_owned()))
+  }).collect()
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+  tags.iter().find(|(t, _)| t == name).map(|(_, v)| &v[..])
+}
+
+/// Whether the MTA-STS MX pattern `pattern` matches `host`. A leading `*.`
+/// matches exactly one label.
+fn mx_pattern_matches(pattern: &str, host: &str) -> bool {
+  let pattern = dns::normalize_name(pattern);
+  let host = dns::normalize_name(host);
+  if let Some(suffix) = pattern.strip_prefix("*.") {
+    match host.find('.') {
+      Some(i) => i > 0 && host[i + 1..] == *suffix,
+      None => false,
+    }
+  } else {
+    pattern == host
+  }
+}
+
+/// The number of bits of a DKIM public key, which is base64 in the `p=` tag.
+fn dkim_key_bits(key_type: &str, p: &str) -> Result<u32, String> {
+  let p: String = p.chars().filter(|c| !c.is_whitespace()).collect();
+  let der = openssl::base64::decode_block(&p).map_err(|_| "public key is not valid base64".to_owned())?;
+  match key_type {
+    "rsa" => {
+      // Usually a SubjectPublicKeyInfo, sometimes a bare RSAPublicKey.
+      if let Ok(key) = openssl::pkey::PKey::public_key_from_der(&der) {
+        Ok(key.bits())
+      } else {
+        let rsa = openssl::rsa::Rsa::public_key_from_der_pkcs1(&der).map_err(|_| "unable to parse the RSA public key".to_owned())?;
+        Ok(rsa.size() * 8)
+      }
+    },
+    "ed25519" if der.len() == 32 => Ok(256),
+    "ed25519" => Err(format!("ed25519 public key has {} bytes instead of 32", der.len())),
+    k => Err(format!("unknown key type k={}", k)),
+  }
+}
+
+/// Audit the DNS records that mail deliverability depends on, which tend to
+/// drift as providers and servers change.
+///
+/// * SPF: there has to be exactly one SPF record, it has to need no more than
+///   10 DNS lookups (counting through `include:` and `redirect=`), and should
+///   end with `~all` or `-all`.
+/// * DKIM: the public key of each
+///   [selector](crate::checkers::email_auth::EmailAuthChecker::add_dkim_selector)
+///   has to be published. RSA keys shorter than 1024 bits are `ERROR`, and
+///   shorter than 2048 bits `WARN`.
+/// * DMARC: there should be a policy, and `p=none` or `pct` below 100 is `WARN`.
+/// * MTA-STS: the policy file is fetched over HTTPS, and every MX record has to
+///   match one of its `mx` patterns, which is `ERROR` in `enforce` mode and
+///   `WARN` otherwise. Patterns matching no MX record are `WARN`.
+///
+/// Each finding is listed on its own line of the result info, and the result
+/// is the worst of them. The metrics `spf_lookups` and `dkim_key_bits` (of the
+/// weakest key) are reported when known.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, email_auth::EmailAuthChecker};
+/// let mut checker = EmailAuthChecker::new("example.com");
+/// checker.add_dkim_selector("mail");
+/// let result = checker.check();
+/// ```
+pub struct EmailAuthChecker {
+  domain: String,
+  dkim_selectors: Vec<String>,
+  check_mta_sts: bool,
+  mta_sts_policy_url: Option<String>,
+  dns_server: Option<SocketAddr>,
+  timeout: time::Duration,
+}
+
+impl EmailAuthChecker {
+  pub fn new(domain: &str) -> Self {
+    EmailAuthChecker{
+      domain: dns::normalize_name(domain),
+      dkim_selectors: Vec::new(),
+      check_mta_sts: true,
+      mta_sts_policy_url: None,
+      dns_server: None,
+      timeout: time::Duration::from_secs(10),
+    }
+  }
+
+  /// Check the DKIM public key published at
+  /// `<selector>._domainkey.<domain>`. Selectors can't be discovered, so no
+  /// DKIM keys are checked unless some are added.
+  pub fn add_dkim_selector(&mut self, selector: &str) -> &mut Self {
+    self.dkim_selectors.push(selector.to_owned());
+    self
+  }
+
+  /// Whether to check MTA-STS. If `true`, not having an MTA-STS policy is
+  /// `WARN`.
+  ///
+  /// Default is `true`.
+  pub fn set_check_mta_sts(&mut self, value: bool) -> &mut Self {
+    self.check_mta_sts = value;
+    self
+  }
+
+  /// For testing. Fetch the MTA-STS policy from `value` instead of
+  /// `https://mta-sts.<domain>/.well-known/mta-sts.txt`.
+  pub fn set_mta_sts_policy_url(&mut self, value: &str) -> &mut Self {
+    self.mta_sts_policy_url = Some(value.to_owned());
+    self
+  }
+
+  /// Where to send DNS queries.
+  ///
+  /// Default is the first nameserver in `/etc/resolv.conf`.
+  pub fn set_dns_server(&mut self, value: SocketAddr) -> &mut Self {
+    self.dns_server = Some(value);
+    self
+  }
+
+  /// Time limit for each DNS query and for fetching the MTA-STS policy.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// The strings of the TXT records at `name` which start with `prefix`,
+  /// ignoring case.
+  fn txt_records(dns: &DnsClient, name: &str, prefix: &str) -> Result<Vec<String>, String> {
+    let res = dns.query(name, dns::TYPE_TXT)?;
+    if res.rcode != dns::RCODE_NOERROR && res.rcode != dns::RCODE_NXDOMAIN {
+      return Err(format!("Looking up TXT records for {}: rcode {}", name, res.rcode));
+    }
+    Ok(res.answers_of_type(dns::TYPE_TXT).filter_map(|r| r.txt_string())
+      .filter(|t| t.len() >= prefix.len() && t[..prefix.len()].eq_ignore_ascii_case(prefix)).collect())
+  }
+
+  /// The single SPF record of `name`.
+  fn spf_record(dns: &DnsClient, name: &str) -> Result<String, String> {
+    let mut records: Vec<String> = Self::txt_records(dns, name, "v=spf1")?.into_iter()
+      .filter(|r| r.len() == 6 || r.as_bytes()[6] == b' ').collect();
+    match records.len() {
+      0 => Err(format!("no SPF record at {}", name)),
+      1 => Ok(records.remove(0)),
+      n => Err(format!("{} SPF records at {}, where only one is allowed", n, name)),
+    }
+  }
+
+  /// Count the DNS lookups evaluating the SPF record of `name` takes, and find
+  /// the qualifier of its `all` mechanism, following `redirect=`.
+  fn spf_walk(dns: &DnsClient, name: &str, depth: usize, notes: &mut Vec<String>) -> Result<(usize, Option<char>), String> {
+    if depth > SPF_LOOKUP_LIMIT {
+      return Err(format!("includes nested too deeply at {}, probably a loop", name));
+    }
+    let record = Self::spf_record(dns, name)?;
+    let mut lookups = 0;
+    let mut all = None;
+    let mut redirect = None;
+    for term in record.split_ascii_whitespace().skip(1) {
+      let (qualifier, term) = match term.chars().next() {
+        Some(q) if "+-~?".contains(q) => (q, &term[1..]),
+        _ => ('+', term),
+      };
+      let end = term.find([':', '/', '=']).unwrap_or(term.len());
+      let (mechanism, arg) = (term[..end].to_ascii_lowercase(), term[end..].get(1..).unwrap_or(""));
+      match &mechanism[..] {
+        "include" => {
+          lookups += 1;
+          // Targets built from macros depend on the message.
+          if !arg.contains('%') {
+            let (nested, _) = Self::spf_walk(dns, arg, depth + 1, notes).map_err(|e| format!("include:{}: {}", arg, e))?;
+            lookups += nested;
+          }
+        },
+        "a" | "mx" | "exists" => lookups += 1,
+        "ptr" => {
+          lookups += 1;
+          notes.push(format!("{} uses the deprecated ptr mechanism", name));
+        },
+        "all" => all = Some(qualifier),
+        "ip4" | "ip6" => {},
+        "redirect" if term[end..].starts_with('=') => redirect = Some(arg.to_owned()),
+        _ if term[end..].starts_with('=') => {},
+        _ => return Err(format!("unknown mechanism {:?} at {}", term, name)),
+      }
+    }
+    // redirect= is ignored if there is an all mechanism.
+    if let (None, Some(target)) = (all, redirect) {
+      lookups += 1;
+      if !target.contains('%') {
+        let (nested, redirect_all) = Self::spf_walk(dns, &target, depth + 1, notes).map_err(|e| format!("redirect={}: {}", target, e))?;
+        lookups += nested;
+        all = redirect_all;
+      }
+    }
+    Ok((lookups, all))
+  }
+
+  fn check_spf(&self, dns: &DnsClient) -> CheckResult {
+    let mut notes = Vec::new();
+    let (lookups, all) = match Self::spf_walk(dns, &self.domain, 0, &mut notes) {
+      Ok(r) => r,
+      Err(e) => return finding(CheckResultType::ERROR, "SPF", format!("{}.", e)),
+    };
+    let result = if lookups > SPF_LOOKUP_LIMIT {
+      finding(CheckResultType::ERROR, "SPF", format!("needs {} DNS lookups, more than the limit of {}.", lookups, SPF_LOOKUP_LIMIT))
+    } else if all == Some('+') {
+      finding(CheckResultType::ERROR, "SPF", "ends with +all, which allows anyone to send mail.".to_owned())
+    } else if all != Some('-') && all != Some('~') {
+      finding(CheckResultType::WARN, "SPF", format!("ends with {}, which does not reject other senders.", all.map(|q| format!("{}all", q)).unwrap_or_else(|| "no all mechanism".to_owned())))
+    } else if !notes.is_empty() {
+      finding(CheckResultType::WARN, "SPF", format!("{}.", notes.join(", ")))
+    } else {
+      finding(CheckResultType::UP, "SPF", format!("{} DNS lookups.", lookups))
+    };
+    result.with_metric("spf_lookups", lookups as f64, "")
+  }
+
+  fn check_dkim(&self, dns: &DnsClient, selector: &str) -> CheckResult {
+    let what = format!("DKIM {}", selector);
+    let name = format!("{}._domainkey.{}", selector, &self.domain);
+    let records = match Self::txt_records(dns, &name, "") {
+      Ok(r) => r,
+      Err(e) => return finding(CheckResultType::ERROR, &what, e),
+    };
+    let records: Vec<Vec<(String, String)>> = records.iter().map(|r| tag_list(r)).filter(|t| tag(t, "p").is_some()).collect();
+    let tags = match records.len() {
+      0 => return finding(CheckResultType::ERROR, &what, format!("no DKIM key at {}.", name)),
+      1 => &records[0],
+      n => return finding(CheckResultType::ERROR, &what, format!("{} DKIM keys at {}.", n, name)),
+    };
+    let p = tag(tags, "p").unwrap();
+    if p.is_empty() {
+      return finding(CheckResultType::ERROR, &what, "key has been revoked (empty p=).".to_owned());
+    }
+    let key_type = tag(tags, "k").unwrap_or("rsa").to_ascii_lowercase();
+    let bits = match dkim_key_bits(&key_type, p) {
+      Ok(b) => b,
+      Err(e) => return finding(CheckResultType::ERROR, &what, format!("{}.", e)),
+    };
+    let result = if key_type == "rsa" && bits < 1024 {
+      finding(CheckResultType::ERROR, &what, format!("{}-bit RSA key, which receivers reject.", bits))
+    } else if key_type == "rsa" && bits < 2048 {
+      finding(CheckResultType::WARN, &what, format!("{}-bit RSA key, 2048 bits are recommended.", bits))
+    } else {
+      finding(CheckResultType::UP, &what, format!("{}-bit {} key.", bits, key_type))
+    };
+    result.with_metric("dkim_key_bits", bits as f64, "bits")
+  }
+
+  fn check_dmarc(&self, dns: &DnsClient) -> CheckResult {
+    let name = format!("_dmarc.{}", &self.domain);
+    let records = match Self::txt_records(dns, &name, "v=DMARC1") {
+      Ok(r) => r,
+      Err(e) => return finding(CheckResultType::ERROR, "DMARC", e),
+    };
+    let tags = match records.len() {
+      0 => return finding(CheckResultType::WARN, "DMARC", format!("no DMARC policy at {}.", name)),
+      1 => tag_list(&records[0]),
+      n => return finding(CheckResultType::ERROR, "DMARC", format!("{} DMARC records at {}, which receivers ignore.", n, name)),
+    };
+    let policy = match tag(&tags, "p") {
+      Some(p) => p.to_ascii_lowercase(),
+      None => return finding(CheckResultType::ERROR, "DMARC", "no p= policy.".to_owned()),
+    };
+    let pct = tag(&tags, "pct").and_then(|p| p.parse::<u8>().ok()).unwrap_or(100);
+    let reports = if tag(&tags, "rua").is_some() { "" } else { ", no aggregate reports (rua=)" };
+    match &policy[..] {
+      "none" => finding(CheckResultType::WARN, "DMARC", format!("p=none only monitors{}.", reports)),
+      "quarantine" | "reject" if pct < 100 => finding(CheckResultType::WARN, "DMARC", format!("p={} only applies to {}% of mail{}.", policy, pct, reports)),
+      "quarantine" | "reject" => finding(CheckResultType::UP, "DMARC", format!("p={}{}.", policy, reports)),
+      _ => finding(CheckResultType::ERROR, "DMARC", format!("invalid policy p={}.", policy)),
+    }
+  }
+
+  fn fetch_mta_sts_policy(&self) -> Result<String, String> {
+    let url = self.mta_sts_policy_url.clone().unwrap_or_else(|| format!("https://mta-sts.{}/.well-known/mta-sts.txt", &self.domain));
+    // Redirects are not allowed for policy fetches.
+    let client = reqwest::Client::builder().redirect(reqwest::RedirectPolicy::none()).timeout(self.timeout).build().map_err(|e| format!("{}", &e))?;
+    let mut res = client.get(&url).send().map_err(|e| format!("fetching {}: {}", url, &e))?;
+    if res.status().as_u16() != 200 {
+      return Err(format!("fetching {}: status {}", url, res.status()));
+    }
+    let mut body = Vec::new();
+    (&mut res).take(MAX_POLICY_LEN).read_to_end(&mut body).map_err(|e| format!("fetching {}: {}", url, &e))?;
+    String::from_utf8(body).map_err(|_| format!("{} is not UTF-8", url))
+  }
+
+  fn check_mta_sts(&self, dns: &DnsClient, mx_hosts: &Result<Vec<String>, String>) -> Vec<CheckResult> {
+    let name = format!("_mta-sts.{}", &self.domain);
+    let records = match Self::txt_records(dns, &name, "v=STSv1") {
+      Ok(r) => r,
+      Err(e) => return vec![finding(CheckResultType::ERROR, "MTA-STS", e)],
+    };
+    match records.len() {
+      0 => return vec![finding(CheckResultType::WARN, "MTA-STS", format!("no MTA-STS record at {}.", name))],
+      1 => {},
+      n => return vec![finding(CheckResultType::ERROR, "MTA-STS", format!("{} MTA-STS records at {}, which senders ignore.", n, name))],
+    }
+    let policy = match self.fetch_mta_sts_policy() {
+      Ok(p) => p,
+      Err(e) => return vec![finding(CheckResultType::ERROR, "MTA-STS", format!("{}.", e))],
+    };
+    let mut mode = None;
+    let mut max_age = None;
+    let mut patterns = Vec::new();
+    for line in policy.lines() {
+      if let Some(i) = line.find(':') {
+        let value = line[i + 1..].trim();
+        match line[..i].trim() {
+          "mode" => mode = Some(value.to_owned()),
+          "max_age" => max_age = value.parse::<u64>().ok(),
+          "mx" => patterns.push(value.to_owned()),
+          _ => {},
+        }
+      }
+    }
+    let mode = match mode {
+      Some(ref m) if m == "enforce" || m == "testing" || m == "none" => m.clone(),
+      _ => return vec![finding(CheckResultType::ERROR, "MTA-STS", "policy has no valid mode.".to_owned())],
+    };
+    let mut results = Vec::new();
+    if mode == "none" {
+      results.push(finding(CheckResultType::WARN, "MTA-STS", "policy mode is none.".to_owned()));
+    } else {
+      results.push(finding(CheckResultType::UP, "MTA-STS", format!("policy mode is {}, max_age {}.", mode, max_age.map(|a| a.to_string()).unwrap_or_else(|| "missing".to_owned()))));
+    }
+    match max_age {
+      None => results.push(finding(CheckResultType::ERROR, "MTA-STS", "policy has no valid max_age.".to_owned())),
+      Some(a) if a < 24*60*60 => results.push(finding(CheckResultType::WARN, "MTA-STS", format!("max_age {} is less than a day.", a))),
+      _ => {},
+    }
+    let mx_hosts = match mx_hosts {
+      Ok(hosts) => hosts,
+      Err(e) => {
+        results.push(finding(CheckResultType::ERROR, "MX", e.clone()));
+        return results;
+      },
+    };
+    let uncovered_type = if mode == "enforce" { CheckResultType::ERROR } else { CheckResultType::WARN };
+    for host in mx_hosts {
+      if !patterns.iter().any(|p| mx_pattern_matches(p, host)) {
+        results.push(finding(uncovered_type, "MTA-STS", format!("MX {} matches none of the mx patterns of the policy.", host)));
+      }
+    }
+    for pattern in patterns.iter() {
+      if !mx_hosts.iter().any(|h| mx_pattern_matches(pattern, h)) {
+        results.push(finding(CheckResultType::WARN, "MTA-STS", format!("mx pattern {} matches no MX record.", pattern)));
+      }
+    }
+    results
+  }
+
+  fn mx_hosts(&self, dns: &DnsClient) -> Result<Vec<String>, String> {
+    let res = dns.query(&self.domain, dns::TYPE_MX)?;
+    if res.rcode != dns::RCODE_NOERROR {
+      return Err(format!("Looking up MX records for {}: rcode {}", &self.domain, res.rcode));
+    }
+    let hosts: Vec<String> = res.answers_of_type(dns::TYPE_MX).filter_map(|r| match r {
+      RecordData::MX{exchange, ..} => Some(dns::normalize_name(exchange)),
+      _ => None,
+    }).collect();
+    if hosts.is_empty() {
+      return Err(format!("{} has no MX records.", &self.domain));
+    }
+    Ok(hosts)
+  }
+}
+
+impl Checker for EmailAuthChecker {
+  fn check(&mut self) -> CheckResult {
+    let mut dns = match self.dns_server {
+      Some(addr) => DnsClient::new(addr),
+      None => match DnsClient::system() {
+        Ok(c) => c,
+        Err(e) => return CheckResult::error(Some(e)),
+      },
+    };
+    dns.set_timeout(self.timeout);
+    let mut findings = vec![self.check_spf(&dns)];
+    for selector in self.dkim_selectors.iter() {
+      findings.push(self.check_dkim(&dns, selector));
+    }
+    findings.push(self.check_dmarc(&dns));
+    if self.check_mta_sts {
+      let mx_hosts = self.mx_hosts(&dns);
+      findings.extend(self.check_mta_sts(&dns, &mx_hosts));
+    }
+    let result_type = findings.iter().map(|f| f.result_type).max().unwrap_or(CheckResultType::UP);
+    let info = findings.iter().map(|f| format!("{}", f)).collect::<Vec<_>>().join("\n");
+    let mut result = CheckResult::new(result_type, Some(info));
+    for name in &["spf_lookups", "dkim_key_bits"] {
+      if let Some(value) = findings.iter().filter_map(|f| f.get_metric(name)).fold(None, |a: Option<f64>, b| Some(a.map_or(b, |a| a.min(b)))) {
+        result = result.with_metric(name, value, if *name == "dkim_key_bits" { "bits" } else { "" });
+      }
+    }
+    result
+  }
+}
+
+#[test]
+fn mx_pattern_test() {
+  assert!(mx_pattern_matches("mail.example.com", "Mail.Example.com."));
+  assert!(mx_pattern_matches("*.example.com", "mx1.example.com"));
+  assert!(!mx_pattern_matches("*.example.com", "a.mx1.example.com"));
+  assert!(!mx_pattern_matches("*.example.com", "example.com"));
+  assert!(!mx_pattern_matches("mail.example.com", "mx.example.com"));
+  assert_eq!(tag_list("v=DMARC1; p=reject ;rua=mailto:a@example.com"), vec![
+    ("v".to_owned(), "DMARC1".to_owned()), ("p".to_owned(), "reject".to_owned()), ("rua".to_owned(), "mailto:a@example.com".to_owned())]);
+}
+
+#[test]
+fn email_auth_test() {
+  use crate::checkers::domain::testing::serve_http;
+  use crate::utils::dns::testing::{record, serve_dns, Zone};
+  let txt = |s: &str| RecordData::TXT(s.as_bytes().chunks(255).map(|c| c.to_vec()).collect());
+  let dkim_key = |bits: u32| {
+    let key = openssl::rsa::Rsa::generate(bits).unwrap();
+    let der = openssl::pkey::PKey::from_rsa(key).unwrap().public_key_to_der().unwrap();
+    txt(&format!("v=DKIM1; k=rsa; p={}", openssl::base64::encode_block(&der)))
+  };
+  let mut records = vec![
+    record("example.test", txt("v=spf1 mx include:_spf.provider.test ~all")),
+    record("example.test", txt("google-site-verification=abc")),
+    record("_spf.provider.test", txt("v=spf1 ip4:192.0.2.0/24 a include:_spf2.provider.test -all")),
+    record("_spf2.provider.test", txt("v=spf1 ip6:2001:db8::/32 -all")),
+    record("example.test", RecordData::MX{preference: 10, exchange: "mx1.example.test".to_owned()}),
+    record("example.test", RecordData::MX{preference: 20, exchange: "backup.example.test".to_owned()}),
+    record("strong._domainkey.example.test", dkim_key(2048)),
+    record("weak._domainkey.example.test", dkim_key(1024)),
+    record("revoked._domainkey.example.test", txt("v=DKIM1; p=")),
+    record("_dmarc.example.test", txt("v=DMARC1; p=reject; rua=mailto:dmarc@example.test")),
+    record("_mta-sts.example.test", txt("v=STSv1; id=20190429T010101")),
+  ];
+  for i in 0..6 {
+    records.push(record(&format!("many{}.example.test", i), txt(&format!("v=spf1 a include:many{}.example.test -all", i + 1))));
+  }
+  records.push(record("many6.example.test", txt("v=spf1 -all")));
+  records.push(record("loop.example.test", txt("v=spf1 include:loop.example.test -all")));
+  let (dns_addr, zone) = serve_dns(Zone{records, ..Zone::default()});
+  let (tx, _rx) = std::sync::mpsc::channel();
+  let policy = serve_http("200 OK", "text/plain", "version: STSv1\r\nmode: enforce\r\nmx: *.example.test\r\nmx: mail.old.test\r\nmax_age: 604800\r\n", tx);
+  let mut checker = EmailAuthChecker::new("example.test");
+  checker.set_dns_server(dns_addr).set_mta_sts_policy_url(&format!("http://{}/.well-known/mta-sts.txt", policy));
+  checker.add_dkim_selector("strong").add_dkim_selector("weak").add_dkim_selector("revoked").add_dkim_selector("missing");
+  let res = checker.check();
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.get_metric("spf_lookups"), Some(4f64));
+  assert_eq!(res.get_metric("dkim_key_bits"), Some(1024f64));
+  assert_eq!(res.info.unwrap().split('\n').collect::<Vec<_>>(), vec![
+    "UP: SPF: 4 DNS lookups.",
+    "UP: DKIM strong: 2048-bit rsa key.",
+    "WARN: DKIM weak: 1024-bit RSA key, 2048 bits are recommended.",
+    "ERROR: DKIM revoked: key has been revoked (empty p=).",
+    "ERROR: DKIM missing: no DKIM key at missing._domainkey.example.test.",
+    "UP: DMARC: p=reject.",
+    "UP: MTA-STS: policy mode is enforce, max_age 604800.",
+    "WARN: MTA-STS: mx pattern mail.old.test matches no MX record.",
+  ]);
+
+  // Findings about SPF, DMARC and MX coverage.
+  let (tx, _rx) = std::sync::mpsc::channel();
+  let policy = serve_http("200 OK", "text/plain", "version: STSv1\r\nmode: testing\r\nmx: mx1.example.test\r\nmax_age: 3600\r\n", tx);
+  let mut records = zone.lock().unwrap().records.clone();
+  records.retain(|r| (r.name != "example.test" && r.name != "_dmarc.example.test") || r.data.rtype() == dns::TYPE_MX);
+  records.push(record("example.test", txt("v=spf1 include:many0.example.test ?all")));
+  records.push(record("_dmarc.example.test", txt("v=DMARC1; p=none")));
+  zone.lock().unwrap().records = records;
+  let mut checker = EmailAuthChecker::new("example.test");
+  checker.set_dns_server(dns_addr).set_mta_sts_policy_url(&format!("http://{}/", policy));
+  let res = checker.check();
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.info.unwrap().split('\n').collect::<Vec<_>>(), vec![
+    "ERROR: SPF: needs 13 DNS lookups, more than the limit of 10.",
+    "WARN: DMARC: p=none only monitors, no aggregate reports (rua=).",
+    "UP: MTA-STS: policy mode is testing, max_age 3600.",
+    "WARN: MTA-STS: max_age 3600 is less than a day.",
+    "WARN: MTA-STS: MX backup.example.test matches none of the mx patterns of the policy.",
+  ]);
+
+  let mut checker = EmailAuthChecker::new("loop.example.test");
+  checker.set_dns_server(dns_addr).set_check_mta_sts(false);
+  let res = checker.check();
+  assert!(res.info.unwrap().starts_with("ERROR: SPF: include:loop.example.test: include:loop.example.test: "));
+  let mut checker = EmailAuthChecker::new("nothing.example.test");
+  checker.set_dns_server(dns_addr);
+  assert_eq!(checker.check().info.unwrap(), "ERROR: SPF: no SPF record at nothing.example.test.\nWARN: DMARC: no DMARC policy at _dmarc.nothing.example.test.\nWARN: MTA-STS: no MTA-STS record at _mta-sts.nothing.example.test.");
+}
@@ -0,0 +1,243 @@
+//! Check that files written periodically, like backups or cron job outputs,
+//! are recent.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType, format_duration};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// What to check about the content of the newest file, in addition to its age
+/// and size.
+#[derive(Clone, Debug)]
+pub enum FileContentCheck {
+  Any,
+  /// The file contains something other than whitespace.
+  NonEmpty,
+  /// The SHA-256 of the file is this (hex).
+  Sha256(String),
+  /// The SHA-256 of the file matches the one in the file with this suffix
+  /// appended to its name, in the format written by `sha256sum`. For example,
+  /// with `".sha256"`, `backup.tar.gz` is checked against
+  /// `backup.tar.gz.sha256`.
+  Sha256File(String),
+}
+
+/// A path with its mtime and size.
+type FileStat = (PathBuf, time::SystemTime, u64);
+
+/// Check that a file, or the newest file matching a glob, was modified recently
+/// enough and has a reasonable size.
+///
+/// Any problem results in `ERROR`, with the actual age of the file. Matches
+/// which can't be read are skipped, and turn an `UP` result into `WARN`. The
+/// result has the metrics `age` (seconds) and `size` (bytes).
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, file_freshness::{FileFreshnessChecker, FileContentCheck}};
+/// # use std::time::Duration;
+/// let mut checker = FileFreshnessChecker::new("/var/backups/db-*.sql.gz", Duration::from_secs(26*60*60));
+/// checker.set_size_bounds(Some(1024*1024), None)
+///        .set_content_check(FileContentCheck::Sha256File(".sha256".to_owned()));
+/// let result = checker.check();
+/// ```
+pub struct FileFreshnessChecker {
+  pattern: String,
+  max_age: time::Duration,
+  min_size: Option<u64>,
+  max_size: Option<u64>,
+  content_check: FileContentCheck,
+  fake_now: Option<time::SystemTime>,
+}
+
+impl FileFreshnessChecker {
+  /// `pattern` is a path, or a glob like `/backups/*/dump-*.sql`.
+  pub fn new(pattern: &str, max_age: time::Duration) -> Self {
+    FileFreshnessChecker{
+      pattern: pattern.to_owned(),
+      max_age,
+      min_size: None,
+      max_size: None,
+      content_check: FileContentCheck::Any,
+      fake_now: None,
+    }
+  }
+
+  /// Bounds on the size of the file in bytes, both inclusive.
+  ///
+  /// Default is no bounds.
+  pub fn set_size_bounds(&mut self, min: Option<u64>, max: Option<u64>) -> &mut Self {
+    self.min_size = min;
+    self.max_size = max;
+    self
+  }
+
+  /// Default is [`FileContentCheck::Any`](crate::checkers::file_freshness::FileContentCheck::Any).
+  pub fn set_content_check(&mut self, value: FileContentCheck) -> &mut Self {
+    self.content_check = value;
+    self
+  }
+
+  /// For testing. This make `check` act as if the system time is `value`.
+  pub fn fake_time(&mut self, value: time::SystemTime) -> &mut Self {
+    self.fake_now = Some(value);
+    self
+  }
+
+  /// The newest file matching the pattern, with its mtime and size, and the
+  /// errors for the matches which couldn't be read.
+  fn newest(&self) -> Result<(Option<FileStat>, Vec<String>), String> {
+    let paths = glob::glob(&self.pattern).map_err(|e| format!("Invalid pattern {}: {}", &self.pattern, &e))?;
+    let mut newest: Option<FileStat> = None;
+    let mut unreadable = Vec::new();
+    for path in paths {
+      let path = match path {
+        Ok(path) => path,
+        Err(e) => {
+          unreadable.push(format!("{}", &e));
+          continue;
+        },
+      };
+      let meta = match fs::metadata(&path).and_then(|meta| meta.modified().map(|mtime| (meta, mtime))) {
+        Ok(meta) => meta,
+        Err(e) => {
+          unreadable.push(format!("{}: {}", path.display(), &e));
+          continue;
+        },
+      };
+      if !meta.0.is_file() {
+        continue;
+      }
+      if newest.as_ref().map(|n| meta.1 > n.1).unwrap_or(true) {
+        newest = Some((path, meta.1, meta.0.len()));
+      }
+    }
+    Ok((newest, unreadable))
+  }
+
+  fn check_content(&self, path: &Path) -> Result<(), String> {
+    let expected = match self.content_check {
+      FileContentCheck::Any => return Ok(()),
+      FileContentCheck::NonEmpty => {
+        let mut file = fs::File::open(path).map_err(|e| format!("Opening {}: {}", path.display(), &e))?;
+        let mut buf = [0u8; 4096];
+        loop {
+          let n = file.read(&mut buf).map_err(|e| format!("Reading {}: {}", path.display(), &e))?;
+          if n == 0 {
+            return Err(format!("{} is empty.", path.display()));
+          }
+          if buf[..n].iter().any(|b| !b.is_ascii_whitespace()) {
+            return Ok(());
+          }
+        }
+      },
+      FileContentCheck::Sha256(ref hex) => hex.to_ascii_lowercase(),
+      FileContentCheck::Sha256File(ref suffix) => {
+        let mut sum_path = path.as_os_str().to_owned();
+        sum_path.push(suffix);
+        let sum_path = PathBuf::from(sum_path);
+        let content = fs::read_to_string(&sum_path).map_err(|e| format!("Reading {}: {}", sum_path.display(), &e))?;
+        content.split_whitespace().next().ok_or_else(|| format!("{} is empty.", sum_path.display()))?.to_ascii_lowercase()
+      },
+    };
+    let actual = sha256_file(path)?;
+    if actual != expected {
+      return Err(format!("SHA-256 of {} is {}, expected {}.", path.display(), actual, expected));
+    }
+    Ok(())
+  }
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+  let mut file = fs::File::open(path).map_err(|e| format!("Opening {}: {}", path.display(), &e))?;
+  let mut hasher = openssl::sha::Sha256::new();
+  let mut buf = vec![0u8; 64*1024];
+  loop {
+    let n = file.read(&mut buf).map_err(|e| format!("Reading {}: {}", path.display(), &e))?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+  }
+  Ok(hasher.finish().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl Checker for FileFreshnessChecker {
+  fn check(&mut self) -> CheckResult {
+    let (newest, unreadable) = match self.newest() {
+      Ok(n) => n,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let unreadable = if unreadable.is_empty() { String::new() } else { format!(" Unreadable: {}.", unreadable.join(", ")) };
+    let (path, mtime, size) = match newest {
+      Some(n) => n,
+      None => return CheckResult::error(Some(format!("No file matches {}.{}", &self.pattern, &unreadable))),
+    };
+    let now = self.fake_now.unwrap_or_else(time::SystemTime::now);
+    // A file modified in the future counts as brand new.
+    let age = now.duration_since(mtime).unwrap_or(time::Duration::from_secs(0));
+    let desc = format!("{} was modified {} ago", path.display(), format_duration(age));
+    let mut result = if age > self.max_age {
+      CheckResult::error(Some(format!("{}, more than {}.", desc, format_duration(self.max_age))))
+    } else if self.min_size.map(|min| size < min).unwrap_or(false) {
+      CheckResult::error(Some(format!("{}, but is only {} bytes.", desc, size)))
+    } else if self.max_size.map(|max| size > max).unwrap_or(false) {
+      CheckResult::error(Some(format!("{}, but is {} bytes.", desc, size)))
+    } else {
+      match self.check_content(&path) {
+        Ok(()) => CheckResult::up(Some(format!("{} ({} bytes).", desc, size))),
+        Err(e) => CheckResult::error(Some(format!("{}, but {}", desc, e))),
+      }
+    };
+    if !unreadable.is_empty() {
+      if result.result_type == CheckResultType::UP {
+        result.result_type = CheckResultType::WARN;
+      }
+      result.info = Some(format!("{}{}", result.info.unwrap_or_default(), &unreadable));
+    }
+    result.with_metric("age", age.as_secs_f64(), "s").with_metric("size", size as f64, "B")
+  }
+}
+
+#[test]
+fn file_freshness_test() {
+  let dir = crate::utils::testing::temp_dir("freshness");
+  let old = dir.join("backup-1.txt");
+  fs::write(&old, "old backup\n").unwrap();
+  std::thread::sleep(time::Duration::from_millis(20));
+  let new = dir.join("backup-2.txt");
+  fs::write(&new, "new backup\n").unwrap();
+  fs::write(dir.join("backup-2.txt.sha256"), format!("{}  backup-2.txt\n", sha256_file(&new).unwrap())).unwrap();
+  let blank = dir.join("blank.txt");
+  fs::write(&blank, " \n").unwrap();
+  let pattern = format!("{}/backup-*.txt", dir.display());
+  let hour = time::Duration::from_secs(60*60);
+  let now = fs::metadata(&new).unwrap().modified().unwrap();
+
+  let res = FileFreshnessChecker::new(&pattern, hour).fake_time(now + 30*hour / 60).check();
+  res.expect();
+  assert!(res.info.as_ref().unwrap().contains("backup-2.txt was modified 30m 0s ago (11 bytes)"));
+  assert_eq!(res.get_metric("age"), Some(1800f64));
+  assert_eq!(res.get_metric("size"), Some(11f64));
+  FileFreshnessChecker::new(&pattern, hour).fake_time(now + 26*hour + hour / 2).check()
+    .expect_err_contains("backup-2.txt was modified 1d 2h 30m ago, more than 1h 0m.");
+  FileFreshnessChecker::new(&pattern, hour).set_size_bounds(Some(100), None).check().expect_err_contains("only 11 bytes");
+  FileFreshnessChecker::new(&pattern, hour).set_size_bounds(None, Some(10)).check().expect_err_contains("is 11 bytes");
+  FileFreshnessChecker::new(&pattern, hour).set_content_check(FileContentCheck::Sha256File(".sha256".to_owned())).check().expect();
+  FileFreshnessChecker::new(&old.to_string_lossy(), hour).set_content_check(FileContentCheck::Sha256File(".sha256".to_owned())).check()
+    .expect_err_contains("backup-1.txt.sha256");
+  FileFreshnessChecker::new(&pattern, hour).set_content_check(FileContentCheck::Sha256("00".to_owned())).check()
+    .expect_err_contains("expected 00.");
+  FileFreshnessChecker::new(&pattern, hour).set_content_check(FileContentCheck::NonEmpty).check().expect();
+  FileFreshnessChecker::new(&blank.to_string_lossy(), hour).set_content_check(FileContentCheck::NonEmpty).check()
+    .expect_err_contains("blank.txt is empty.");
+  FileFreshnessChecker::new(&format!("{}/nothing-*", dir.display()), hour).check().expect_err_contains("No file matches");
+  // A match which can't be read doesn't hide the others.
+  std::os::unix::fs::symlink(dir.join("gone"), dir.join("backup-3.txt")).unwrap();
+  let res = FileFreshnessChecker::new(&pattern, hour).fake_time(now).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.as_ref().unwrap().contains("backup-2.txt was modified 0s ago (11 bytes). Unreadable: "), "{:?}", res.info);
+  assert!(res.info.as_ref().unwrap().contains("backup-3.txt"));
+  fs::remove_dir_all(&dir).unwrap();
+}
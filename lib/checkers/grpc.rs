@@ -0,0 +1,565 @@
+//! Check services implementing the standard gRPC health checking protocol,
+//! `grpc.health.v1.Health/Check`, over a minimal HTTP/2 client.
+
+mod hpack;
+
+use crate::checkers::{Checker, CheckResult};
+use crate::utils::connect;
+use crate::utils::transport::{Blocking, Buffered, Transport, block_on};
+use std::io::{Read, Write};
+use std::time;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+
+/// The only stream we open.
+const STREAM_ID: u32 = 1;
+
+/// Largest frame we accept, the default `SETTINGS_MAX_FRAME_SIZE`.
+const MAX_FRAME_SIZE: usize = 16384;
+
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+#[cfg(feature = "async")]
+trait AsyncStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+#[cfg(feature = "async")]
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+struct Frame {
+  kind: u8,
+  flags: u8,
+  stream_id: u32,
+  payload: Vec<u8>,
+}
+
+fn encode_frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+  let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+  frame.push(kind);
+  frame.push(flags);
+  frame.extend_from_slice(&stream_id.to_be_bytes());
+  frame.extend_from_slice(payload);
+  frame
+}
+
+async fn read_frame<T: Transport>(conn: &mut Buffered<T>) -> Result<Frame, String> {
+  let header = conn.read_exact(9).await?;
+  let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+  if len > MAX_FRAME_SIZE {
+    return Err(format!("Frame of {} bytes is too large.", len));
+  }
+  let payload = conn.read_exact(len).await?;
+  let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+  Ok(Frame{kind: header[3], flags: header[4], stream_id, payload})
+}
+
+/// The payload of a `DATA` or `HEADERS` frame without padding and priority.
+fn frame_content(frame: &Frame) -> Result<&[u8], String> {
+  let mut content = &frame.payload[..];
+  let mut padding = 0;
+  if frame.flags & FLAG_PADDED != 0 {
+    padding = *content.first().ok_or("Truncated frame.")? as usize;
+    content = &content[1..];
+  }
+  if frame.kind == FRAME_HEADERS && frame.flags & FLAG_PRIORITY != 0 {
+    content = content.get(5..).ok_or("Truncated frame.")?;
+  }
+  if padding > content.len() {
+    return Err("Invalid padding in frame.".to_owned());
+  }
+  Ok(&content[..content.len() - padding])
+}
+
+/// gRPC status codes, by number.
+const GRPC_STATUS_NAMES: &[&str] = &[
+  "OK", "CANCELLED", "UNKNOWN", "INVALID_ARGUMENT", "DEADLINE_EXCEEDED", "NOT_FOUND", "ALREADY_EXISTS",
+  "PERMISSION_DENIED", "RESOURCE_EXHAUSTED", "FAILED_PRECONDITION", "ABORTED", "OUT_OF_RANGE",
+  "UNIMPLEMENTED", "INTERNAL", "UNAVAILABLE", "DATA_LOSS", "UNAUTHENTICATED",
+];
+
+/// Undo the percent-encoding of `grpc-message`.
+fn percent_decode(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut out = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' && i + 2 < bytes.len() {
+      if let Some(b) = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+        out.push(b);
+        i += 3;
+        continue;
+      }
+    }
+    out.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The serialized `HealthCheckRequest{service}`, as a length-prefixed gRPC
+/// message.
+fn encode_request(service: &str) -> Vec<u8> {
+  let mut message = Vec::new();
+  if !service.is_empty() {
+    message.push(0x0a);
+    let mut len = service.len();
+    while len >= 0x80 {
+      message.push((len & 0x7f) as u8 | 0x80);
+      len >>= 7;
+    }
+    message.push(len as u8);
+    message.extend_from_slice(service.as_bytes());
+  }
+  let mut out = vec![0];
+  out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+  out.extend_from_slice(&message);
+  out
+}
+
+/// The `status` of a length-prefixed `HealthCheckResponse`.
+fn decode_response(body: &[u8]) -> Result<u64, String> {
+  if body.len() < 5 {
+    return Err("No response message from server.".to_owned());
+  }
+  if body[0] != 0 {
+    return Err("Compressed response message from server.".to_owned());
+  }
+  let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+  let mut message = body.get(5..5 + len).ok_or("Truncated response message from server.")?;
+  let varint = |data: &mut &[u8]| -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+      let (b, rest) = data.split_first().ok_or("Truncated response message from server.")?;
+      *data = rest;
+      value |= ((b & 0x7f) as u64) << shift;
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err("Invalid varint in response message.".to_owned())
+  };
+  // A missing status is the default, UNKNOWN.
+  let mut status = 0;
+  while !message.is_empty() {
+    let key = varint(&mut message)?;
+    match (key >> 3, key & 0x7) {
+      (1, 0) => status = varint(&mut message)?,
+      (_, 0) => { varint(&mut message)?; },
+      (_, 2) => {
+        let len = varint(&mut message)? as usize;
+        message = message.get(len..).ok_or("Truncated response message from server.")?;
+      },
+      (_, 1) => message = message.get(8..).ok_or("Truncated response message from server.")?,
+      (_, 5) => message = message.get(4..).ok_or("Truncated response message from server.")?,
+      (_, wire_type) => return Err(format!("Unexpected wire type {} in response message.", wire_type)),
+    }
+  }
+  Ok(status)
+}
+
+/// What came back for the call.
+struct Response {
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+  latency: time::Duration,
+}
+
+impl Response {
+  fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+  }
+}
+
+/// Call `grpc.health.v1.Health/Check` and report the serving status of the
+/// server, or of one service on it.
+///
+/// `SERVING` is `UP`, `UNKNOWN` is `WARN`, and `NOT_SERVING`, an unknown
+/// service, and any gRPC or HTTP/2 error are `ERROR`, with the gRPC status
+/// code and message. The result has the metric `latency` (milliseconds from
+/// sending the request until the end of the response).
+///
+/// Plaintext connections use HTTP/2 with prior knowledge, and TLS connections
+/// negotiate it with ALPN.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, grpc::GrpcHealthChecker};
+/// let mut checker = GrpcHealthChecker::new("api.example.com", 443, "payments.v1.Payments");
+/// checker.set_tls(true);
+/// let result = checker.check();
+/// ```
+pub struct GrpcHealthChecker {
+  host: String,
+  port: u16,
+  service: String,
+  tls: bool,
+  trusted_cas: Option<Vec<openssl::x509::X509>>,
+  timeout: time::Duration,
+}
+
+impl GrpcHealthChecker {
+  /// An empty `service` asks about the server as a whole.
+  pub fn new(host: &str, port: u16, service: &str) -> Self {
+    GrpcHealthChecker{host: host.to_owned(), port, service: service.to_owned(), tls: false, trusted_cas: None, timeout: time::Duration::from_secs(10)}
+  }
+
+  /// Default is false, plaintext.
+  pub fn set_tls(&mut self, value: bool) -> &mut Self {
+    self.tls = value;
+    self
+  }
+
+  /// By default, the server certificate is verified against openssl's default
+  /// trusted CAs. This changes it so that only those in `value` are trusted.
+  #[allow(non_snake_case)]
+  pub fn set_trusted_CAs(&mut self, value: Vec<openssl::x509::X509>) -> &mut Self {
+    self.trusted_cas = Some(value);
+    self
+  }
+
+  /// Time limit for the whole call, from resolving the host to reading the
+  /// trailers. It is also sent to the server as the deadline of the call.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  fn connector(&self) -> Result<openssl::ssl::SslConnector, String> {
+    let mut builder = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()).map_err(|e| format!("Setting up connector: {}", &e))?;
+    builder.set_alpn_protos(b"\x02h2").map_err(|e| format!("Setting up connector: {}", &e))?;
+    if let Some(ref cas) = self.trusted_cas {
+      let mut st = openssl::x509::store::X509StoreBuilder::new().map_err(|e| format!("Creating X509Store: {}", &e))?;
+      for cert in cas {
+        st.add_cert(cert.clone()).map_err(|e| format!("Adding cert to X509Store: {}", &e))?;
+      }
+      builder.set_verify_cert_store(st.build()).map_err(|e| format!("Setting up connector: {}", &e))?;
+    }
+    Ok(builder.build())
+  }
+
+  fn check_alpn(&self, ssl: &openssl::ssl::SslRef) -> Result<(), String> {
+    if ssl.selected_alpn_protocol() != Some(b"h2") {
+      return Err(format!("{} did not negotiate HTTP/2.", &self.host));
+    }
+    Ok(())
+  }
+
+  fn open(&self) -> Result<Buffered<Blocking<Box<dyn Stream>>>, String> {
+    let tcp = connect(&self.host, self.port, self.timeout)?;
+    if !self.tls {
+      return Ok(Buffered::new(Blocking(Box::new(tcp))));
+    }
+    let stream = self.connector()?.connect(&self.host, tcp).map_err(|e| format!("TLS handshake with {}: {}", &self.host, &e))?;
+    self.check_alpn(stream.ssl())?;
+    Ok(Buffered::new(Blocking(Box::new(stream))))
+  }
+
+  #[cfg(feature = "async")]
+  async fn open_async(&self) -> Result<Buffered<crate::utils::transport::Async<Box<dyn AsyncStream>>>, String> {
+    use crate::utils::transport::Async;
+    let tcp = crate::checkers::async_checker::connect(&self.host, self.port, None).await?;
+    if !self.tls {
+      return Ok(Buffered::new(Async(Box::new(tcp))));
+    }
+    let ssl = self.connector()?.configure().and_then(|c| c.into_ssl(&self.host)).map_err(|e| format!("Allocating SSL: {}", &e))?;
+    let mut stream = tokio_openssl::SslStream::new(ssl, tcp).map_err(|e| format!("Allocating SSL: {}", &e))?;
+    std::pin::Pin::new(&mut stream).connect().await.map_err(|e| format!("TLS handshake with {}: {}", &self.host, &e))?;
+    self.check_alpn(stream.ssl())?;
+    Ok(Buffered::new(Async(Box::new(stream))))
+  }
+
+  async fn call<T: Transport>(&self, conn: &mut Buffered<T>) -> Result<Response, String> {
+    let authority = format!("{}:{}", &self.host, self.port);
+    let grpc_timeout = format!("{}m", self.timeout.as_millis());
+    let headers = hpack::encode(&[
+      (":method", "POST"),
+      (":scheme", if self.tls { "https" } else { "http" }),
+      (":path", HEALTH_CHECK_PATH),
+      (":authority", &authority),
+      ("content-type", "application/grpc"),
+      ("te", "trailers"),
+      ("grpc-timeout", &grpc_timeout),
+      ("user-agent", "serverwatch"),
+    ]);
+    let mut settings = SETTINGS_ENABLE_PUSH.to_be_bytes().to_vec();
+    settings.extend_from_slice(&0u32.to_be_bytes());
+    let mut out = PREFACE.to_vec();
+    out.extend(encode_frame(FRAME_SETTINGS, 0, 0, &settings));
+    out.extend(encode_frame(FRAME_HEADERS, FLAG_END_HEADERS, STREAM_ID, &headers));
+    out.extend(encode_frame(FRAME_DATA, FLAG_END_STREAM, STREAM_ID, &encode_request(&self.service)));
+    let start = time::Instant::now();
+    conn.write_all(&out).await?;
+
+    let mut decoder = hpack::Decoder::new();
+    let mut response = Response{headers: Vec::new(), body: Vec::new(), latency: time::Duration::default()};
+    loop {
+      let frame = read_frame(conn).await?;
+      let mut end_stream = false;
+      match frame.kind {
+        FRAME_SETTINGS if frame.flags & FLAG_ACK == 0 => {
+          conn.write_all(&encode_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[])).await?;
+        },
+        FRAME_PING if frame.flags & FLAG_ACK == 0 => {
+          conn.write_all(&encode_frame(FRAME_PING, FLAG_ACK, 0, &frame.payload)).await?;
+        },
+        FRAME_GOAWAY => {
+          let code = frame.payload.get(4..8).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).unwrap_or(0);
+          let debug = String::from_utf8_lossy(frame.payload.get(8..).unwrap_or(&[])).into_owned();
+          return Err(format!("Server closed the connection with HTTP/2 error {}: {}", code, debug));
+        },
+        FRAME_RST_STREAM if frame.stream_id == STREAM_ID => {
+          let code = frame.payload.get(0..4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).unwrap_or(0);
+          return Err(format!("Server reset the stream with HTTP/2 error {}.", code));
+        },
+        FRAME_HEADERS => {
+          let mut block = frame_content(&frame)?.to_vec();
+          let mut flags = frame.flags;
+          while flags & FLAG_END_HEADERS == 0 {
+            let continuation = read_frame(conn).await?;
+            if continuation.kind != FRAME_CONTINUATION {
+              return Err("Expected CONTINUATION frame.".to_owned());
+            }
+            block.extend_from_slice(&continuation.payload);
+            flags = continuation.flags;
+          }
+          // Decode every block, to keep the dynamic table in sync.
+          let headers = decoder.decode(&block)?;
+          if frame.stream_id == STREAM_ID {
+            response.headers.extend(headers);
+            end_stream = frame.flags & FLAG_END_STREAM != 0;
+          }
+        },
+        FRAME_DATA if frame.stream_id == STREAM_ID => {
+          response.body.extend_from_slice(frame_content(&frame)?);
+          end_stream = frame.flags & FLAG_END_STREAM != 0;
+        },
+        _ => {},
+      }
+      if end_stream {
+        break;
+      }
+    }
+    response.latency = start.elapsed();
+    let _ = conn.write_all(&encode_frame(FRAME_GOAWAY, 0, 0, &[0; 8])).await;
+    Ok(response)
+  }
+
+  fn result(&self, response: Result<Response, String>) -> CheckResult {
+    let response = match response {
+      Ok(r) => r,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let latency_ms = response.latency.as_secs_f64() * 1000f64;
+    let what = if self.service.is_empty() { format!("{}:{}", &self.host, self.port) } else { format!("{} on {}:{}", &self.service, &self.host, self.port) };
+    let result = match (response.header(":status"), response.header("grpc-status")) {
+      (Some("200"), Some("0")) => match decode_response(&response.body) {
+        Ok(1) => CheckResult::up(Some(format!("{} is SERVING.", what))),
+        Ok(0) => CheckResult::warn(Some(format!("{} is UNKNOWN.", what))),
+        Ok(2) => CheckResult::error(Some(format!("{} is NOT_SERVING.", what))),
+        Ok(3) => CheckResult::error(Some(format!("{} is SERVICE_UNKNOWN.", what))),
+        Ok(status) => CheckResult::error(Some(format!("{} has unknown serving status {}.", what, status))),
+        Err(e) => CheckResult::error(Some(e)),
+      },
+      (Some("200"), Some(code)) => {
+        let name = code.parse::<usize>().ok().and_then(|c| GRPC_STATUS_NAMES.get(c)).unwrap_or(&"");
+        let message = percent_decode(response.header("grpc-message").unwrap_or(""));
+        CheckResult::error(Some(format!("Health check of {} failed with gRPC status {} {}: {}", what, code, name, message)))
+      },
+      (Some("200"), None) => CheckResult::error(Some("No grpc-status in response.".to_owned())),
+      (status, _) => CheckResult::error(Some(format!("Expected HTTP status 200, got {}.", status.unwrap_or("none")))),
+    };
+    result.with_metric("latency", latency_ms, "ms")
+  }
+}
+
+impl Checker for GrpcHealthChecker {
+  fn check(&mut self) -> CheckResult {
+    self.result(self.open().and_then(|mut conn| block_on(self.call(&mut conn))))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for GrpcHealthChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    use crate::checkers::async_checker::within;
+    Box::pin(async move {
+      let response = within(self.timeout, async {
+        self.call(&mut self.open_async().await?).await
+      }).await;
+      self.result(response)
+    })
+  }
+}
+
+/// Play a gRPC server on one connection, answering the health check with
+/// `respond(service)`, which returns the response headers (or `None` for a
+/// trailers-only response), the body and the trailers.
+#[cfg(test)]
+fn serve_grpc<F>(respond: F) -> std::net::SocketAddr
+where F: FnOnce(&str) -> (Option<Vec<(&'static str, String)>>, Vec<u8>, Vec<(&'static str, String)>) + Send + 'static {
+  crate::testing::serve_once(move |s| grpc_exchange(s, respond))
+}
+
+/// Like `serve_grpc`, but over TLS with `cert`, negotiating HTTP/2 with ALPN.
+#[cfg(test)]
+fn serve_grpc_tls<F>(cert: &crate::checkers::tls::testing::CertAndKey, respond: F) -> std::net::SocketAddr
+where F: FnOnce(&str) -> (Option<Vec<(&'static str, String)>>, Vec<u8>, Vec<(&'static str, String)>) + Send + 'static {
+  use openssl::ssl::{AlpnError, SslAcceptor, SslMethod, select_next_proto};
+  let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+  acceptor.set_certificate(&cert.0).unwrap();
+  acceptor.set_private_key(&cert.1).unwrap();
+  acceptor.set_alpn_select_callback(|_, client| select_next_proto(b"\x02h2", client).ok_or(AlpnError::NOACK));
+  let acceptor = acceptor.build();
+  crate::testing::serve_once(move |s| {
+    // The handshake fails if the client doesn't trust `cert`.
+    if let Ok(s) = acceptor.accept(s) {
+      grpc_exchange(s, respond);
+    }
+  })
+}
+
+#[cfg(test)]
+fn grpc_exchange<S, F>(s: S, respond: F)
+where S: Stream, F: FnOnce(&str) -> (Option<Vec<(&'static str, String)>>, Vec<u8>, Vec<(&'static str, String)>) {
+  let mut conn = Buffered::new(Blocking(s));
+  assert_eq!(block_on(conn.read_exact(PREFACE.len())).unwrap(), PREFACE);
+  let s = &mut conn;
+  let send = |s: &mut Buffered<Blocking<S>>, frame: Vec<u8>| block_on(s.write_all(&frame)).unwrap();
+  send(s, encode_frame(FRAME_SETTINGS, 0, 0, &[]));
+  send(s, encode_frame(FRAME_PING, 0, 0, b"12345678"));
+  let mut decoder = hpack::Decoder::new();
+  let mut headers = Vec::new();
+  let mut body = Vec::new();
+  loop {
+    let frame = block_on(read_frame(s)).unwrap();
+    match frame.kind {
+      FRAME_HEADERS => headers = decoder.decode(&frame.payload).unwrap(),
+      FRAME_DATA => body.extend_from_slice(&frame.payload),
+      _ => {},
+    }
+    if frame.flags & FLAG_END_STREAM != 0 && frame.stream_id == STREAM_ID {
+      break;
+    }
+  }
+  assert!(headers.contains(&(":path".to_owned(), HEALTH_CHECK_PATH.to_owned())));
+  assert!(headers.contains(&("te".to_owned(), "trailers".to_owned())));
+  // Skip the length prefix, tag and length of the service name.
+  let service = String::from_utf8(body.get(7..).unwrap_or(&[]).to_vec()).unwrap();
+  let (response_headers, response_body, trailers) = respond(&service);
+  let encode = |h: &[(&str, String)]| hpack::encode(&h.iter().map(|(n, v)| (*n, &v[..])).collect::<Vec<_>>());
+  if let Some(response_headers) = response_headers {
+    // Split the headers over a CONTINUATION frame.
+    let block = encode(&response_headers);
+    let (first, second) = block.split_at(block.len() / 2);
+    send(s, encode_frame(FRAME_HEADERS, 0, STREAM_ID, first));
+    send(s, encode_frame(FRAME_CONTINUATION, FLAG_END_HEADERS, STREAM_ID, second));
+    let mut padded = vec![3];
+    padded.extend_from_slice(&response_body);
+    padded.extend_from_slice(&[0; 3]);
+    send(s, encode_frame(FRAME_DATA, FLAG_PADDED, STREAM_ID, &padded));
+  }
+  send(s, encode_frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, STREAM_ID, &encode(&trailers)));
+  // Read until the client's GOAWAY, as closing with its ACKs unread would
+  // reset the connection.
+  while let Ok(frame) = block_on(read_frame(s)) {
+    if frame.kind == FRAME_GOAWAY {
+      break;
+    }
+  }
+}
+
+#[test]
+fn grpc_health_test() {
+  use crate::checkers::CheckResultType;
+  let ok_headers = || Some(vec![(":status", "200".to_owned()), ("content-type", "application/grpc".to_owned())]);
+  let serving = |status: u8| move |service: &str| {
+    assert_eq!(service, "payments");
+    (ok_headers(), vec![0, 0, 0, 0, 2, 0x08, status], vec![("grpc-status", "0".to_owned())])
+  };
+
+  let addr = serve_grpc(serving(1));
+  let res = GrpcHealthChecker::new("127.0.0.1", addr.port(), "payments").check();
+  assert_eq!(res.info.as_ref().unwrap(), &format!("payments on 127.0.0.1:{} is SERVING.", addr.port()));
+  assert_eq!(res.result_type, CheckResultType::UP);
+  assert!(res.get_metric("latency").is_some());
+
+  let addr = serve_grpc(serving(2));
+  GrpcHealthChecker::new("127.0.0.1", addr.port(), "payments").check().expect_err_contains("is NOT_SERVING.");
+
+  let addr = serve_grpc(move |_| (ok_headers(), vec![0, 0, 0, 0, 0], vec![("grpc-status", "0".to_owned())]));
+  assert_eq!(GrpcHealthChecker::new("127.0.0.1", addr.port(), "").check().result_type, CheckResultType::WARN);
+
+  let addr = serve_grpc(|_| (None, Vec::new(), vec![
+    (":status", "200".to_owned()),
+    ("grpc-status", "5".to_owned()),
+    ("grpc-message", "unknown service %22payments%22".to_owned()),
+  ]));
+  GrpcHealthChecker::new("127.0.0.1", addr.port(), "payments").check()
+    .expect_err_contains("failed with gRPC status 5 NOT_FOUND: unknown service \"payments\"");
+
+  let addr = serve_grpc(|_| (None, Vec::new(), vec![(":status", "503".to_owned())]));
+  GrpcHealthChecker::new("127.0.0.1", addr.port(), "").check().expect_err_contains("Expected HTTP status 200, got 503.");
+
+  assert_eq!(percent_decode("a%20b%2"), "a b%2");
+  assert_eq!(decode_response(&[0, 0, 0, 0, 6, 0x12, 1, b'x', 0x08, 0x96, 0x01]), Ok(150));
+}
+
+#[test]
+fn grpc_health_tls_test() {
+  use crate::checkers::tls::testing;
+  let ca = testing::make_ca("Test CA");
+  let cert = testing::make_cert(&ca, &["localhost"], 30);
+  let serving = |_: &str| (Some(vec![(":status", "200".to_owned()), ("content-type", "application/grpc".to_owned())]), vec![0, 0, 0, 0, 2, 0x08, 1], vec![("grpc-status", "0".to_owned())]);
+
+  let addr = serve_grpc_tls(&cert, serving);
+  GrpcHealthChecker::new("localhost", addr.port(), "").set_tls(true).set_trusted_CAs(vec![ca.0.clone()]).check().expect();
+
+  let addr = serve_grpc_tls(&cert, serving);
+  GrpcHealthChecker::new("localhost", addr.port(), "").set_tls(true).check().expect_err_contains("certificate verify failed");
+
+  // A TLS server without HTTP/2.
+  let addr = testing::serve_tls(&cert, None);
+  GrpcHealthChecker::new("localhost", addr.port(), "").set_tls(true).set_trusted_CAs(vec![ca.0.clone()]).check().expect_err_contains("did not negotiate HTTP/2.");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_grpc_health_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  use crate::checkers::tls::testing;
+  let serving = |_: &str| (Some(vec![(":status", "200".to_owned()), ("content-type", "application/grpc".to_owned())]), vec![0, 0, 0, 0, 2, 0x08, 1], vec![("grpc-status", "0".to_owned())]);
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+  let addr = serve_grpc(serving);
+  let res = runtime.block_on(AsyncChecker::check(&mut GrpcHealthChecker::new("127.0.0.1", addr.port(), "")));
+  assert_eq!(res.info.as_ref().unwrap(), &format!("127.0.0.1:{} is SERVING.", addr.port()));
+
+  let ca = testing::make_ca("Test CA");
+  let cert = testing::make_cert(&ca, &["localhost"], 30);
+  let addr = serve_grpc_tls(&cert, serving);
+  let mut checker = GrpcHealthChecker::new("localhost", addr.port(), "");
+  checker.set_tls(true).set_trusted_CAs(vec![ca.0.clone()]);
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect();
+  let addr = testing::serve_tls(&cert, None);
+  checker.port = addr.port();
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect_err_contains("did not negotiate HTTP/2.");
+}
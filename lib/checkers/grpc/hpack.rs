@@ -0,0 +1,252 @@
+//! Just enough HPACK (RFC 7541) for one request: a decoder with the dynamic
+//! table and Huffman strings, and an encoder which only emits literals.
+
+use crate::utils::Reader;
+use std::collections::VecDeque;
+
+const STATIC_TABLE: &[(&str, &str)] = &[
+  (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"), (":path", "/index.html"),
+  (":scheme", "http"), (":scheme", "https"), (":status", "200"), (":status", "204"), (":status", "206"),
+  (":status", "304"), (":status", "400"), (":status", "404"), (":status", "500"), ("accept-charset", ""),
+  ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""), ("accept", ""),
+  ("access-control-allow-origin", ""), ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""),
+  ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+  ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""), ("date", ""),
+  ("etag", ""), ("expect", ""), ("expires", ""), ("from", ""), ("host", ""), ("if-match", ""),
+  ("if-modified-since", ""), ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""),
+  ("last-modified", ""), ("link", ""), ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""),
+  ("proxy-authorization", ""), ("range", ""), ("referer", ""), ("refresh", ""), ("retry-after", ""),
+  ("server", ""), ("set-cookie", ""), ("strict-transport-security", ""), ("transfer-encoding", ""),
+  ("user-agent", ""), ("vary", ""), ("via", ""), ("www-authenticate", ""),
+];
+
+/// Lengths of the Huffman codes of each symbol, 256 being EOS. The code is
+/// canonical, so this is all that is needed to decode it.
+const HUFFMAN_CODE_LENGTHS: [u8; 257] = [
+  13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+  6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+  13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+  15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+  20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+  22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+  26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+  20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+  30,
+];
+
+const EOS: u16 = 256;
+
+/// The canonical Huffman code: the first code of each length, and the symbols
+/// ordered by code.
+struct HuffmanTable {
+  first_code: [u32; 31],
+  count: [u32; 31],
+  first_index: [usize; 31],
+  symbols: Vec<u16>,
+}
+
+lazy_static! {
+  static ref HUFFMAN: HuffmanTable = {
+    let mut symbols: Vec<u16> = (0..=EOS).collect();
+    symbols.sort_by_key(|s| (HUFFMAN_CODE_LENGTHS[*s as usize], *s));
+    let mut count = [0u32; 31];
+    for len in HUFFMAN_CODE_LENGTHS.iter() {
+      count[*len as usize] += 1;
+    }
+    let mut first_code = [0u32; 31];
+    let mut first_index = [0usize; 31];
+    let (mut code, mut index) = (0u32, 0usize);
+    for len in 1..31 {
+      first_code[len] = code;
+      first_index[len] = index;
+      code = (code + count[len]) << 1;
+      index += count[len] as usize;
+    }
+    HuffmanTable{first_code, count, first_index, symbols}
+  };
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+  let table = &*HUFFMAN;
+  let mut out = Vec::new();
+  let (mut code, mut len) = (0u32, 0usize);
+  for byte in data {
+    for bit in (0..8).rev() {
+      code = code << 1 | ((byte >> bit) & 1) as u32;
+      len += 1;
+      if len > 30 {
+        return Err("Invalid Huffman code in header.".to_owned());
+      }
+      if code.wrapping_sub(table.first_code[len]) < table.count[len] {
+        let symbol = table.symbols[table.first_index[len] + (code - table.first_code[len]) as usize];
+        if symbol == EOS {
+          return Err("EOS in Huffman-encoded header.".to_owned());
+        }
+        out.push(symbol as u8);
+        code = 0;
+        len = 0;
+      }
+    }
+  }
+  // What is left has to be a prefix of EOS, which is all ones.
+  if len >= 8 || code != (1 << len) - 1 {
+    return Err("Invalid padding in Huffman-encoded header.".to_owned());
+  }
+  Ok(out)
+}
+
+/// Reads the representations in a header block, on top of [`Reader`].
+trait HpackReader {
+  fn integer(&mut self, first: u8, n: u32) -> Result<usize, String>;
+  fn string(&mut self) -> Result<Vec<u8>, String>;
+}
+
+impl HpackReader for Reader<'_> {
+  /// An integer with an `n`-bit prefix, the first byte being `first`.
+  fn integer(&mut self, first: u8, n: u32) -> Result<usize, String> {
+    let max = (1usize << n) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+      return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+      let b = self.u8()?;
+      if shift > 28 {
+        return Err("Integer too large in header block.".to_owned());
+      }
+      value += ((b & 0x7f) as usize) << shift;
+      shift += 7;
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+  }
+
+  fn string(&mut self) -> Result<Vec<u8>, String> {
+    let first = self.u8()?;
+    let len = self.integer(first, 7)?;
+    let data = self.take(len)?;
+    if first & 0x80 != 0 {
+      huffman_decode(data)
+    } else {
+      Ok(data.to_vec())
+    }
+  }
+}
+
+/// Decodes header blocks, keeping the dynamic table between them.
+pub struct Decoder {
+  table: VecDeque<(String, String)>,
+  size: usize,
+  max_size: usize,
+}
+
+impl Decoder {
+  pub fn new() -> Self {
+    Decoder{table: VecDeque::new(), size: 0, max_size: 4096}
+  }
+
+  fn get(&self, index: usize) -> Result<(String, String), String> {
+    if index == 0 {
+      return Err("Header index 0.".to_owned());
+    }
+    if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+      return Ok((name.to_string(), value.to_string()));
+    }
+    self.table.get(index - STATIC_TABLE.len() - 1).cloned().ok_or_else(|| format!("Header index {} out of range.", index))
+  }
+
+  fn evict(&mut self) {
+    while self.size > self.max_size {
+      let (name, value) = self.table.pop_back().unwrap();
+      self.size -= name.len() + value.len() + 32;
+    }
+  }
+
+  fn insert(&mut self, name: String, value: String) {
+    self.size += name.len() + value.len() + 32;
+    self.table.push_front((name, value));
+    self.evict();
+  }
+
+  pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut r = Reader::new(block, "Truncated header block.");
+    let mut headers = Vec::new();
+    let text = |b: Vec<u8>| String::from_utf8_lossy(&b).into_owned();
+    while !r.rest().is_empty() {
+      let first = r.u8()?;
+      if first & 0x80 != 0 {
+        let index = r.integer(first, 7)?;
+        headers.push(self.get(index)?);
+      } else if first & 0xe0 == 0x20 {
+        let size = r.integer(first, 5)?;
+        if size > 4096 {
+          return Err(format!("Header table size {} larger than allowed.", size));
+        }
+        self.max_size = size;
+        self.evict();
+      } else {
+        // With incremental indexing, or without (never) indexing.
+        let indexing = first & 0x40 != 0;
+        let index = r.integer(first, if indexing { 6 } else { 4 })?;
+        let name = if index == 0 { text(r.string()?) } else { self.get(index)?.0 };
+        let value = text(r.string()?);
+        if indexing {
+          self.insert(name.clone(), value.clone());
+        }
+        headers.push((name, value));
+      }
+    }
+    Ok(headers)
+  }
+}
+
+fn encode_integer(out: &mut Vec<u8>, first: u8, n: u32, value: usize) {
+  let max = (1usize << n) - 1;
+  if value < max {
+    out.push(first | value as u8);
+    return;
+  }
+  out.push(first | max as u8);
+  let mut value = value - max;
+  while value >= 0x80 {
+    out.push((value & 0x7f) as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+/// Encode headers as literals without indexing, with neither the static
+/// table nor Huffman coding.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+  let mut out = Vec::new();
+  for (name, value) in headers {
+    out.push(0);
+    for s in &[name, value] {
+      encode_integer(&mut out, 0, 7, s.len());
+      out.extend_from_slice(s.as_bytes());
+    }
+  }
+  out
+}
+
+#[test]
+fn hpack_test() {
+  let pairs = |headers: Vec<(String, String)>| headers.into_iter().map(|(n, v)| format!("{}: {}", n, v)).collect::<Vec<_>>();
+  // RFC 7541 appendix C.4, requests with Huffman coding.
+  let mut decoder = Decoder::new();
+  let block = b"\x82\x86\x84\x41\x8c\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff";
+  assert_eq!(pairs(decoder.decode(block).unwrap()), vec![":method: GET", ":scheme: http", ":path: /", ":authority: www.example.com"]);
+  let block = b"\x82\x86\x84\xbe\x58\x86\xa8\xeb\x10\x64\x9c\xbf";
+  assert_eq!(pairs(decoder.decode(block).unwrap()), vec![":method: GET", ":scheme: http", ":path: /", ":authority: www.example.com", "cache-control: no-cache"]);
+  let block = b"\x82\x87\x85\xbf\x40\x88\x25\xa8\x49\xe9\x5b\xa9\x7d\x7f\x89\x25\xa8\x49\xe9\x5b\xb8\xe8\xb4\xbf";
+  assert_eq!(pairs(decoder.decode(block).unwrap()), vec![":method: GET", ":scheme: https", ":path: /index.html", ":authority: www.example.com", "custom-key: custom-value"]);
+  assert_eq!(decoder.size, 164);
+
+  let long = "x".repeat(200);
+  let block = encode(&[("grpc-message", &long), (":status", "200")]);
+  assert_eq!(pairs(Decoder::new().decode(&block).unwrap()), vec![format!("grpc-message: {}", long), ":status: 200".to_owned()]);
+  assert!(Decoder::new().decode(b"\x80").is_err());
+  assert!(huffman_decode(b"\xff\xff\xff\xff").is_err());
+}
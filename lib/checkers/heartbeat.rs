@@ -0,0 +1,173 @@
+//! Passive checks for jobs which report in, instead of being polled (a "dead
+//! man's switch").
+
+use crate::checkers::{Checker, CheckResult, format_duration};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// A signal sent by a job to its [`Heartbeat`](crate::checkers::heartbeat::Heartbeat).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeartbeatSignal {
+  /// The job has started. Does not affect the result, but is reported when the
+  /// job then fails to finish in time.
+  START,
+  SUCCESS,
+  /// The job has failed. The check stays `ERROR` until the next `SUCCESS`.
+  FAIL,
+}
+
+struct HeartbeatState {
+  created: time::SystemTime,
+  last_start: Option<time::SystemTime>,
+  last_success: Option<(time::SystemTime, Option<String>)>,
+  last_fail: Option<(time::SystemTime, Option<String>)>,
+}
+
+/// The sending side of a [`HeartbeatChecker`](crate::checkers::heartbeat::HeartbeatChecker),
+/// which can be cloned and passed to whatever receives signals from the job,
+/// such as a web endpoint.
+#[derive(Clone)]
+pub struct Heartbeat(Arc<Mutex<HeartbeatState>>);
+
+impl Heartbeat {
+  /// Record a signal from the job, with an optional message such as the output
+  /// of a failed job.
+  pub fn signal(&self, signal: HeartbeatSignal, message: Option<String>) {
+    self.signal_at(signal, message, time::SystemTime::now());
+  }
+
+  /// Record a signal received at `time`, such as one kept in a database and
+  /// loaded again after a restart.
+  pub fn signal_at(&self, signal: HeartbeatSignal, message: Option<String>, time: time::SystemTime) {
+    let mut state = self.0.lock().unwrap();
+    match signal {
+      HeartbeatSignal::START => state.last_start = Some(time),
+      HeartbeatSignal::SUCCESS => state.last_success = Some((time, message)),
+      HeartbeatSignal::FAIL => state.last_fail = Some((time, message)),
+    }
+  }
+}
+
+/// Check that a job has reported success at least once every `period`, plus
+/// some grace time, through a [`Heartbeat`](crate::checkers::heartbeat::Heartbeat).
+///
+/// Signals are only looked at when the check runs, so with a scheduler, a
+/// failure shows up at the next scheduled check. A newly created checker counts
+/// as having just received a success, so that jobs get one period to report in
+/// after a restart, unless the last signals are loaded again with
+/// [`Heartbeat::signal_at`](crate::checkers::heartbeat::Heartbeat::signal_at).
+/// The result has the metric `since_success` (seconds), if any success has
+/// been received.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, heartbeat::{HeartbeatChecker, HeartbeatSignal}};
+/// # use std::time::Duration;
+/// let mut checker = HeartbeatChecker::new(Duration::from_secs(24*60*60), Duration::from_secs(60*60));
+/// let heartbeat = checker.heartbeat();
+/// // e.g. from a web request made by the job
+/// heartbeat.signal(HeartbeatSignal::SUCCESS, Some("backed up 3 databases".to_owned()));
+/// checker.check().expect();
+/// ```
+pub struct HeartbeatChecker {
+  state: Arc<Mutex<HeartbeatState>>,
+  period: time::Duration,
+  grace: time::Duration,
+  fake_now: Option<time::SystemTime>,
+}
+
+impl HeartbeatChecker {
+  pub fn new(period: time::Duration, grace: time::Duration) -> Self {
+    HeartbeatChecker{
+      state: Arc::new(Mutex::new(HeartbeatState{created: time::SystemTime::now(), last_start: None, last_success: None, last_fail: None})),
+      period,
+      grace,
+      fake_now: None,
+    }
+  }
+
+  /// Get a handle for sending signals to this checker.
+  pub fn heartbeat(&self) -> Heartbeat {
+    Heartbeat(self.state.clone())
+  }
+
+  /// For testing. This make `check` act as if the system time is `value`.
+  pub fn fake_time(&mut self, value: time::SystemTime) -> &mut Self {
+    self.fake_now = Some(value);
+    self
+  }
+}
+
+fn ago(now: time::SystemTime, then: time::SystemTime) -> String {
+  format_duration(now.duration_since(then).unwrap_or(time::Duration::from_secs(0)))
+}
+
+fn with_message(desc: String, message: &Option<String>) -> String {
+  match message {
+    Some(m) => format!("{}: {}", desc, m),
+    None => format!("{}.", desc),
+  }
+}
+
+impl Checker for HeartbeatChecker {
+  fn check(&mut self) -> CheckResult {
+    let now = self.fake_now.unwrap_or_else(time::SystemTime::now);
+    let state = self.state.lock().unwrap();
+    let last_success_time = state.last_success.as_ref().map(|s| s.0);
+    let result = if let Some((fail_time, message)) = state.last_fail.as_ref().filter(|f| last_success_time.map(|s| f.0 >= s).unwrap_or(true)) {
+      CheckResult::error(Some(with_message(format!("Failed {} ago", ago(now, *fail_time)), message)))
+    } else {
+      let since = last_success_time.unwrap_or(state.created);
+      if now.duration_since(since).unwrap_or(time::Duration::from_secs(0)) > self.period + self.grace {
+        let mut desc = match last_success_time {
+          Some(t) => format!("Last success was {} ago", ago(now, t)),
+          None => format!("No success in {}", ago(now, state.created)),
+        };
+        desc.push_str(&format!(", expected every {} (plus {} grace)", format_duration(self.period), format_duration(self.grace)));
+        match state.last_start {
+          Some(start) if start > since => desc.push_str(&format!(". Started {} ago and still running?", ago(now, start))),
+          _ => desc.push('.'),
+        }
+        CheckResult::error(Some(desc))
+      } else {
+        match state.last_success {
+          Some((t, ref message)) => CheckResult::up(Some(with_message(format!("Last success {} ago", ago(now, t)), message))),
+          None => CheckResult::up(Some("Waiting for the first success.".to_owned())),
+        }
+      }
+    };
+    match last_success_time {
+      Some(t) => result.with_metric("since_success", now.duration_since(t).unwrap_or(time::Duration::from_secs(0)).as_secs_f64(), "s"),
+      None => result,
+    }
+  }
+}
+
+#[test]
+fn heartbeat_test() {
+  let hour = time::Duration::from_secs(60*60);
+  let mut checker = HeartbeatChecker::new(24*hour, hour);
+  let hb = checker.heartbeat();
+  let t0 = hb.0.lock().unwrap().created;
+  assert_eq!(checker.fake_time(t0 + 24*hour).check().info.unwrap(), "Waiting for the first success.");
+  checker.fake_time(t0 + 26*hour).check().expect_err_contains("No success in 1d 2h 0m, expected every 1d 0h 0m (plus 1h 0m grace).");
+
+  hb.signal_at(HeartbeatSignal::START, None, t0 + 26*hour);
+  hb.signal_at(HeartbeatSignal::SUCCESS, Some("3 files".to_owned()), t0 + 27*hour);
+  let res = checker.fake_time(t0 + 28*hour).check();
+  res.expect();
+  assert_eq!(res.info.as_ref().unwrap(), "Last success 1h 0m ago: 3 files");
+  assert_eq!(res.get_metric("since_success"), Some(3600f64));
+
+  hb.signal_at(HeartbeatSignal::START, None, t0 + 51*hour);
+  checker.fake_time(t0 + 53*hour).check().expect_err_contains("Last success was 1d 2h 0m ago, expected every 1d 0h 0m (plus 1h 0m grace). Started 2h 0m ago and still running?");
+  hb.signal_at(HeartbeatSignal::FAIL, Some("disk full".to_owned()), t0 + 53*hour);
+  checker.fake_time(t0 + 53*hour).check().expect_err_contains("Failed 0s ago: disk full");
+  hb.signal_at(HeartbeatSignal::SUCCESS, None, t0 + 54*hour);
+  assert_eq!(checker.fake_time(t0 + 54*hour).check().info.unwrap(), "Last success 0s ago.");
+
+  // Signals kept from before a restart count against a new checker.
+  let mut checker = HeartbeatChecker::new(24*hour, hour);
+  checker.heartbeat().signal_at(HeartbeatSignal::SUCCESS, None, t0 - 26*hour);
+  checker.fake_time(t0).check().expect_err_contains("Last success was 1d 2h 0m ago");
+}
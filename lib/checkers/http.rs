@@ -1,42 +1,54 @@
 //! Simple http checks.
 
-use crate::checkers::{Checker, CheckResult, CheckResultType};
-use crate::utils::with_timeout;
+use crate::checkers::{AddressFamily, Checker, CheckResult, CheckResultType, summarize_results, merge_address_results, resolve_addresses};
+use crate::utils::time_left;
 use reqwest;
-use std::ops::Deref;
-use std::fmt::Write;
+use std::net::SocketAddr;
 use std::time;
-use std::sync::{RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "async")] pub(crate) mod async_check;
+pub(crate) mod client;
+pub mod crawl;
+pub mod security;
+use security::SecurityHeaderPolicy;
+use client::{Connection, ResponseHead};
+
+/// Most clients kept by [`acquire_client`], each of which has a thread of its
+/// own.
+const MAX_SHARED_CLIENTS: usize = 8;
 
 lazy_static!{
-	static ref SHARED_CLIENT: RwLock<reqwest::Client> = RwLock::new(new_http_client());
+	/// Clients by timeout, least recently used first. reqwest 0.9 only takes a
+	/// timeout per client, not per request. A request which times out is
+	/// cancelled by the client.
+	static ref SHARED_CLIENTS: Mutex<Vec<(time::Duration, reqwest::Client)>> = Mutex::new(Vec::new());
 }
 
-fn new_http_client() -> reqwest::Client {
-	reqwest::ClientBuilder::new().redirect(reqwest::RedirectPolicy::none()).timeout(None).build().unwrap()
-}
-fn acquire_client<'b>() -> ClientGuard<'b> {
-	match SHARED_CLIENT.read() {
-		Ok(lg) => ClientGuard::LOCKED(lg),
-		Err(_) => ClientGuard::DIRECT(new_http_client())
-	}
+fn new_http_client(timeout: time::Duration) -> reqwest::Client {
+	reqwest::ClientBuilder::new().redirect(reqwest::RedirectPolicy::none()).timeout(timeout).build().unwrap()
 }
-
-enum ClientGuard<'a> {
-	LOCKED(RwLockReadGuard<'a, reqwest::Client>),
-	DIRECT(reqwest::Client)
+/// A client with `timeout`, shared with other checks using the same timeout.
+/// Only the most recently used timeouts keep their clients, so checks with
+/// many different timeouts don't pile up clients and their threads.
+pub(crate) fn acquire_client(timeout: time::Duration) -> reqwest::Client {
+	let mut clients = SHARED_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+	let entry = match clients.iter().position(|(t, _)| *t == timeout) {
+		Some(i) => clients.remove(i),
+		None => {
+			if clients.len() >= MAX_SHARED_CLIENTS {
+				clients.remove(0);
+			}
+			(timeout, new_http_client(timeout))
+		},
+	};
+	let client = entry.1.clone();
+	clients.push(entry);
+	client
 }
 
-impl<'a> Deref for ClientGuard<'a> {
-	type Target = reqwest::Client;
-
-	fn deref(&self) -> &Self::Target {
-		match self {
-			ClientGuard::LOCKED(lockguard) => lockguard.deref(),
-			ClientGuard::DIRECT(client) => client
-		}
-	}
-}
+/// Largest response body read when connecting to addresses ourselves.
+const MAX_BODY_LEN: u64 = 16 << 20;
 
 pub type ExpectFn<'a> = Box<dyn (Fn(&mut reqwest::Response) -> CheckResult) + Send + Sync + 'a>;
 
@@ -52,16 +64,16 @@ pub type ExpectFn<'a> = Box<dyn (Fn(&mut reqwest::Response) -> CheckResult) + Se
 /// ```
 pub struct HttpChecker<'a> {
 	url: reqwest::Url,
-	expects: Vec<ExpectFn<'a>>,
+	expects: Vec<Arc<ExpectFn<'a>>>,
 	warn_timeout: time::Duration,
 	err_timeout: time::Duration,
+	address_family: AddressFamily,
 }
 
 impl<'a> HttpChecker<'a> {
 	pub fn new(url: &str) -> Result<Self, reqwest::UrlError> {
-		acquire_client();
 		let parsed_url = reqwest::Url::parse(url)?;
-		Ok(HttpChecker{url: parsed_url, expects: Vec::new(), warn_timeout: time::Duration::from_secs(30), err_timeout: time::Duration::from_secs(30)})
+		Ok(HttpChecker{url: parsed_url, expects: Vec::new(), warn_timeout: time::Duration::from_secs(30), err_timeout: time::Duration::from_secs(30), address_family: AddressFamily::Any})
 	}
 
 	/// Make sure the server response specifies certain condition&hellip;
@@ -83,7 +95,7 @@ impl<'a> HttpChecker<'a> {
 	/// checker.check().expect();
 	/// ```
 	pub fn expect(&mut self, func: ExpectFn<'a>) -> &mut Self {
-		self.expects.push(func);
+		self.expects.push(Arc::new(func));
 		self
 	}
 
@@ -116,6 +128,38 @@ impl<'a> HttpChecker<'a> {
 		}))
 	}
 
+	/// Audit the security headers of the response against `policy`: HSTS, CSP,
+	/// `X-Content-Type-Options`, `Referrer-Policy` and the attributes of cookies.
+	/// If `policy.require_https_redirect`, also request the `http://` URL of the
+	/// same host and path, which has to redirect to `https://`, within the error
+	/// timeout set before calling this.
+	///
+	/// Anything not meeting the policy makes the result `WARN`, listing every
+	/// regression.
+	///
+	/// ## Example
+	/// ```rust
+	/// use serverwatch::checkers::{Checker, http::{HttpChecker, security::SecurityHeaderPolicy}};
+	/// let mut checker = HttpChecker::new("https://example.com/").unwrap();
+	/// checker.expect_200().expect_security_headers(SecurityHeaderPolicy::default());
+	/// let result = checker.check();
+	/// ```
+	pub fn expect_security_headers(&mut self, policy: SecurityHeaderPolicy) -> &mut Self {
+		let url = self.url.clone();
+		let timeout = self.err_timeout;
+		self.expect(Box::new(move |res| {
+			let mut regressions = security::audit_headers(&policy, &url, res.headers());
+			if policy.require_https_redirect {
+				regressions.extend(security::check_https_redirect(&url, timeout));
+			}
+			if regressions.is_empty() {
+				CheckResult::up(None)
+			} else {
+				CheckResult::warn(Some(format!("{} security regressions: {}.", regressions.len(), regressions.join("; "))))
+			}
+		}))
+	}
+
 	/// Set a time limit for the request.
 	///
 	/// * If the response arrives within `warn`, check result is `UP`.
@@ -134,6 +178,97 @@ impl<'a> HttpChecker<'a> {
 		self.err_timeout = error;
 		self
 	}
+
+	/// Which addresses of the server to send the request to: only IPv4, only
+	/// IPv6, or every resolved address, each with its own request. See
+	/// [`AddressFamily`](crate::checkers::AddressFamily).
+	///
+	/// Other than with the default, `AddressFamily::Any`, the request is made
+	/// over a plain HTTP/1.1 connection without compression, so that it goes to
+	/// the chosen address.
+	pub fn set_address_family(&mut self, value: AddressFamily) -> &mut Self {
+		self.address_family = value;
+		self
+	}
+
+	/// Run the expect checks on a response which took `time_used` to arrive.
+	fn evaluate(&self, time_used: time::Duration, response: reqwest::Response) -> CheckResult {
+		evaluate(&self.expects, self.warn_timeout, time_used, response)
+	}
+
+	/// Make the request to the first of `addrs` which accepts the connection,
+	/// before `deadline`.
+	fn check_addrs(&self, addrs: Vec<SocketAddr>, deadline: time::Instant) -> CheckResult {
+		let start = time::Instant::now();
+		let timeout = match time_left(deadline) {
+			Ok(t) => t,
+			Err(_) => return self.timeout_result(),
+		};
+		match fetch(&self.url, &addrs, timeout) {
+			Ok(response) => self.evaluate(start.elapsed(), response),
+			// However it failed, it ran out of time first.
+			Err(_) if time::Instant::now() >= deadline => self.timeout_result(),
+			Err(e) => CheckResult::error(Some(format!("Failed to send request: {}", &e))),
+		}
+	}
+
+	fn timeout_result(&self) -> CheckResult {
+		CheckResult::error(Some(format!("Timeout of {}ms reached while making the request.", self.err_timeout.as_millis())))
+	}
+}
+
+/// Run `expects` on a response which took `time_used` to arrive.
+fn evaluate(expects: &[Arc<ExpectFn>], warn_timeout: time::Duration, time_used: time::Duration, mut response: reqwest::Response) -> CheckResult {
+	let mut warn_results = Vec::new();
+	if time_used > warn_timeout {
+		warn_results.push(CheckResult::warn(Some(format!("Server took {}ms to response.", time_used.as_millis()))));
+	}
+	let mut infos = Vec::new();
+	for check_fn in expects.iter() {
+		let check_res = (*check_fn)(&mut response);
+		match check_res.result_type {
+			CheckResultType::ERROR => { return check_res },
+			CheckResultType::WARN => { warn_results.push(check_res) },
+			CheckResultType::UP => {
+				if let Some(info) = check_res.info {
+					infos.push(info);
+				}
+			}
+		}
+	}
+	if warn_results.len() > 0 {
+		CheckResult::warn(Some(summarize_results("expect checks", &warn_results)))
+	} else {
+		if infos.len() > 0 {
+			CheckResult::up(Some(infos.join("\n")))
+		} else {
+			CheckResult::up(None)
+		}
+	}
+}
+
+/// Get `url` from the first of `addrs` which accepts the connection, and read
+/// the whole response.
+fn fetch(url: &reqwest::Url, addrs: &[SocketAddr], timeout: time::Duration) -> Result<reqwest::Response, String> {
+	let headers = [("Connection".to_owned(), "close".to_owned())];
+	let conn = Connection::open(url, Some(addrs), &headers, timeout)?;
+	let head = conn.head.clone();
+	let body = conn.read_body(MAX_BODY_LEN)?;
+	into_response(&head, body)
+}
+
+/// Make a response for the expect functions from one we read ourselves.
+fn into_response(head: &ResponseHead, body: Vec<u8>) -> Result<reqwest::Response, String> {
+	let mut builder = http::Response::builder();
+	builder.status(head.status);
+	for (name, value) in head.headers.iter() {
+		// The body is decoded here.
+		if name != "transfer-encoding" {
+			builder.header(&name[..], &value[..]);
+		}
+	}
+	let response = builder.body(body).map_err(|e| format!("Invalid response: {}", &e))?;
+	Ok(reqwest::Response::from(response))
 }
 
 #[test]
@@ -191,6 +326,18 @@ fn should_timeout() {
 	}
 }
 
+#[test]
+fn shared_clients_test() {
+	// Other tests may use the cache at the same time, so only its bound and the
+	// most recent entry are checked.
+	for i in 0..(MAX_SHARED_CLIENTS as u64 + 4) {
+		acquire_client(time::Duration::from_millis(123_000 + i));
+		let clients = SHARED_CLIENTS.lock().unwrap();
+		assert!(clients.len() <= MAX_SHARED_CLIENTS);
+	}
+	acquire_client(time::Duration::from_millis(123_000));
+	assert_eq!(SHARED_CLIENTS.lock().unwrap().iter().filter(|(t, _)| *t == time::Duration::from_millis(123_000)).count(), 1);
+}
 
 #[test]
 fn fail_when_error() {
@@ -198,61 +345,143 @@ fn fail_when_error() {
 	checker.check().expect_err();
 }
 
+#[test]
+fn address_family_test() {
+	use crate::testing::serve_once;
+	use std::io::{BufRead, BufReader, Write};
+	let serve = || serve_once(|mut stream| {
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+		let mut line = String::new();
+		while line != "\r\n" {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+		}
+		stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n").unwrap();
+	});
+	let check = |family: AddressFamily, find: &'static str| {
+		let addr = serve();
+		let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+		checker.set_address_family(family).expect_200().expect_response_contains(find);
+		(addr, checker.check())
+	};
+	let (_, res) = check(AddressFamily::V4, "hello world");
+	res.expect();
+	let (addr, res) = check(AddressFamily::Each, "hello world");
+	res.expect();
+	assert_eq!(res.info.unwrap(), format!("{}: UP", addr));
+	let (_, res) = check(AddressFamily::V4, "goodbye");
+	res.expect_err_contains("goodbye not found in response body.");
+	let (addr, res) = check(AddressFamily::Each, "goodbye");
+	res.expect_err_contains(&format!("1 of 1 addresses failed.\n{}: ERROR: goodbye not found", addr));
+	let mut checker = HttpChecker::new("http://127.0.0.1/").unwrap();
+	checker.set_address_family(AddressFamily::V6).check().expect_err_contains("127.0.0.1 has no IPv6 addresses.");
+}
+
+#[test]
+fn security_headers_test() {
+	use crate::testing::serve_once;
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	std::thread::spawn(move || {
+		// The page, then the probe for a redirect to HTTPS.
+		for _ in 0..2 {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			let mut line = String::new();
+			while line != "\r\n" {
+				line.clear();
+				reader.read_line(&mut line).unwrap();
+			}
+			stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Security-Policy: default-src 'self'\r\nX-Content-Type-Options: nosniff\r\nReferrer-Policy: no-referrer\r\nSet-Cookie: id=1; HttpOnly\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").unwrap();
+		}
+	});
+	let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+	checker.expect_200().expect_security_headers(SecurityHeaderPolicy::default());
+	let res = checker.check();
+	assert_eq!(res.result_type, CheckResultType::WARN);
+	assert_eq!(res.info.unwrap(), format!("1 expect checks reported WARN: 3 security regressions: served over plain HTTP, where HSTS does not apply; cookie id lacks SameSite; http://{}/ does not redirect to HTTPS (status 200).", addr));
+
+	let redirect = |location: &'static str| serve_once(move |mut stream| {
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+		let mut line = String::new();
+		while line != "\r\n" {
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+		}
+		write!(stream, "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location).unwrap();
+	});
+	let five_secs = time::Duration::from_secs(5);
+	let addr = redirect("https://127.0.0.1/");
+	assert_eq!(security::check_https_redirect(&reqwest::Url::parse(&format!("http://{}/", addr)).unwrap(), five_secs), None);
+	let addr = redirect("/login");
+	assert_eq!(security::check_https_redirect(&reqwest::Url::parse(&format!("http://{}/", addr)).unwrap(), five_secs).unwrap(), format!("http://{0}/ redirects to http://{0}/login instead of HTTPS on the same host", addr));
+}
+
+#[test]
+fn slow_server_times_out() {
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	// Never gets to the end of the headers, however long it's given.
+	std::thread::spawn(move || {
+		for stream in listener.incoming() {
+			let mut stream = stream.unwrap();
+			std::thread::spawn(move || {
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+				let mut line = String::new();
+				while line != "\r\n" {
+					line.clear();
+					if reader.read_line(&mut line).unwrap_or(0) == 0 {
+						return;
+					}
+				}
+				let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nX-Slow: ");
+				while stream.write_all(b"x").is_ok() {
+					std::thread::sleep(time::Duration::from_millis(50));
+				}
+			});
+		}
+	});
+	for family in [AddressFamily::Any, AddressFamily::V4].iter() {
+		let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+		checker.set_timeouts(time::Duration::from_millis(100), time::Duration::from_millis(300)).set_address_family(*family);
+		let start = time::Instant::now();
+		checker.check().expect_err_contains("Timeout of 300ms reached");
+		assert!(start.elapsed() < time::Duration::from_millis(600));
+	}
+}
+
 impl<'a> Checker for HttpChecker<'a> {
 	fn check(&mut self) -> CheckResult {
-		let url = self.url.clone();
+		if self.address_family != AddressFamily::Any {
+			let host = match self.url.host_str() {
+				Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_owned(),
+				None => return CheckResult::error(Some(format!("No host in {}.", &self.url))),
+			};
+			let port = self.url.port_or_known_default().unwrap_or(80);
+			// Resolving and every address share the one timeout.
+			let deadline = time::Instant::now() + self.err_timeout;
+			let addrs = match resolve_addresses(&host, port, self.address_family, self.err_timeout) {
+				Ok(addrs) => addrs,
+				Err(e) => return CheckResult::error(Some(e)),
+			};
+			if self.address_family != AddressFamily::Each {
+				return self.check_addrs(addrs, deadline);
+			}
+			let results = addrs.into_iter().map(|addr| (addr, self.check_addrs(vec![addr], deadline))).collect();
+			return merge_address_results(results, &[], true);
+		}
+		let client = acquire_client(self.err_timeout);
 		let start = time::Instant::now();
-		if let Some(res) = with_timeout(move || acquire_client().get(url).send(), self.err_timeout) {
-			let time_used = start.elapsed();
-			match res {
-				Ok(mut response) => {
-					let mut warn_results = Vec::new();
-					if time_used > self.warn_timeout {
-						warn_results.push(CheckResult::warn(Some(format!("Server took {}ms to response.", time_used.as_millis()))));
-					}
-					let mut infos = Vec::new();
-					for check_fn in self.expects.iter() {
-						let check_res = (*check_fn)(&mut response);
-						match check_res.result_type {
-							CheckResultType::ERROR => { return check_res },
-							CheckResultType::WARN => { warn_results.push(check_res) },
-							CheckResultType::UP => {
-								if let Some(info) = check_res.info {
-									infos.push(info);
-								}
-							}
-						}
-					}
-					if warn_results.len() > 0 {
-						let mut f = String::new();
-						write!(f, "{} expect checks reported WARN: ", warn_results.len()).unwrap();
-						let mut is_first = true;
-						for usr in warn_results.iter() {
-							if !is_first {
-								write!(f, ", ").unwrap();
-							}
-							is_first = false;
-							if let Some(ref info) = usr.info {
-								write!(f, "{}", info).unwrap();
-							} else {
-								write!(f, "(no info)").unwrap();
-							}
-						}
-						CheckResult::warn(Some(f))
-					} else {
-						if infos.len() > 0 {
-							CheckResult::up(Some(infos.join("\n")))
-						} else {
-							CheckResult::up(None)
-						}
-					}
-				},
-				Err(err) => {
-					CheckResult::error(Some(format!("Failed to send request: {}", &err)))
-				}
+		match client.get(self.url.clone()).send() {
+			Ok(response) => self.evaluate(start.elapsed(), response),
+			Err(ref err) if err.is_timeout() => self.timeout_result(),
+			Err(err) => {
+				CheckResult::error(Some(format!("Failed to send request: {}", &err)))
 			}
-		} else {
-			CheckResult::error(Some(format!("Timeout of {}ms reached while making the request.", self.err_timeout.as_millis())))
 		}
 	}
 }
@@ -0,0 +1,255 @@
+//! [`AsyncChecker`](crate::checkers::async_checker::AsyncChecker) for
+//! [`HttpChecker`](crate::checkers::http::HttpChecker).
+
+use super::{HttpChecker, MAX_BODY_LEN, evaluate, into_response};
+use super::client::{BodyFraming, ResponseHead, body_too_large, encode_request, parse_chunk_size, url_target};
+use crate::checkers::{AddressFamily, CheckResult, merge_address_results};
+use crate::checkers::async_checker::{self, AsyncChecker, CheckFuture};
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+lazy_static!{
+  static ref TLS_CONNECTOR: Result<openssl::ssl::SslConnector, String> = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())
+    .map(|b| b.build()).map_err(|e| format!("Setting up connector: {}", &e));
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+type Reader = BufReader<Box<dyn Stream>>;
+
+async fn read_line(reader: &mut Reader) -> Result<String, String> {
+  let mut line = String::new();
+  match reader.read_line(&mut line).await {
+    Ok(0) => Err("Connection closed by server.".to_owned()),
+    Ok(_) => Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+    Err(e) => Err(format!("Reading from server: {}", &e)),
+  }
+}
+
+/// Read a response body of at most `limit` bytes, in whichever framing
+/// `head` says it has.
+async fn read_body(reader: &mut Reader, head: &ResponseHead, limit: u64) -> Result<Vec<u8>, String> {
+  let read_err = |e: std::io::Error| format!("Reading response body: {}", &e);
+  let mut body = Vec::new();
+  match head.body_framing(limit)? {
+    BodyFraming::Chunked => loop {
+      let size = parse_chunk_size(&read_line(reader).await?)?;
+      if size == 0 {
+        break;
+      }
+      if body.len() as u64 + size > limit {
+        return Err(body_too_large(limit));
+      }
+      let start = body.len();
+      body.resize(start + size as usize, 0);
+      reader.read_exact(&mut body[start..]).await.map_err(read_err)?;
+      read_line(reader).await?;
+    },
+    BodyFraming::Length(length) => {
+      body.resize(length as usize, 0);
+      reader.read_exact(&mut body).await.map_err(|_| "Connection closed before the end of the response body.".to_owned())?;
+    },
+    BodyFraming::UntilClose => {
+      reader.take(limit + 1).read_to_end(&mut body).await.map_err(read_err)?;
+      if body.len() as u64 > limit {
+        return Err(body_too_large(limit));
+      }
+    },
+  }
+  Ok(body)
+}
+
+/// Get `url` with the extra `headers`, from the first of `addrs` which
+/// accepts the connection if given, over HTTP/1.1, and read the whole response.
+pub(crate) async fn fetch(url: Url, addrs: Option<Vec<SocketAddr>>, headers: &[(String, String)]) -> Result<reqwest::Response, String> {
+  let (host, port, tls) = url_target(&url)?;
+  let tcp = async_checker::connect(host, port, addrs.as_deref()).await?;
+  let stream: Box<dyn Stream> = if tls {
+    let connector = TLS_CONNECTOR.as_ref().map_err(|e| e.clone())?;
+    let ssl = connector.configure().and_then(|c| c.into_ssl(host)).map_err(|e| format!("Allocating SSL: {}", &e))?;
+    let mut stream = tokio_openssl::SslStream::new(ssl, tcp).map_err(|e| format!("Allocating SSL: {}", &e))?;
+    Pin::new(&mut stream).connect().await.map_err(|e| format!("TLS handshake with {}: {}", host, &e))?;
+    Box::new(stream)
+  } else {
+    Box::new(tcp)
+  };
+  let mut headers = headers.to_vec();
+  headers.push(("Connection".to_owned(), "close".to_owned()));
+  let request = encode_request(&url, &headers);
+  let mut reader = BufReader::new(stream);
+  reader.get_mut().write_all(request.as_bytes()).await.map_err(|e| format!("Writing to server: {}", &e))?;
+
+  let mut head = ResponseHead::default();
+  while !head.parse_line(&read_line(&mut reader).await?)? {}
+  let body = read_body(&mut reader, &head, MAX_BODY_LEN).await?;
+  into_response(&head, body)
+}
+
+/// [`fetch`] before `deadline`, which is `timeout` after the check started,
+/// and how long it took.
+async fn timed_fetch(url: Url, addrs: Option<Vec<SocketAddr>>, timeout: time::Duration, deadline: time::Instant) -> (time::Duration, Result<reqwest::Response, String>) {
+  let start = time::Instant::now();
+  let res = match tokio::time::timeout_at(deadline.into(), fetch(url, addrs, &[])).await {
+    Ok(Ok(response)) => Ok(response),
+    Ok(Err(e)) => Err(format!("Failed to send request: {}", &e)),
+    Err(_) => Err(format!("Timeout of {}ms reached while making the request.", timeout.as_millis())),
+  };
+  (start.elapsed(), res)
+}
+
+impl HttpChecker<'static> {
+  /// Run the expect functions on the blocking thread pool, since some, like
+  /// the probe for a redirect to HTTPS, block.
+  async fn evaluate_fetched(&self, fetched: (time::Duration, Result<reqwest::Response, String>)) -> CheckResult {
+    let (time_used, response) = match fetched {
+      (time_used, Ok(response)) => (time_used, response),
+      (_, Err(e)) => return CheckResult::error(Some(e)),
+    };
+    let expects = self.expects.clone();
+    let warn_timeout = self.warn_timeout;
+    match tokio::task::spawn_blocking(move || evaluate(&expects, warn_timeout, time_used, response)).await {
+      Ok(r) => r,
+      Err(e) => CheckResult::error(Some(format!("Expect checks panicked: {}", &e))),
+    }
+  }
+}
+
+/// Always makes the request over HTTP/1.1, without compression. The expect
+/// functions run on the blocking thread pool, so they have to be `'static`.
+impl AsyncChecker for HttpChecker<'static> {
+  fn check(&mut self) -> CheckFuture<'_> {
+    Box::pin(async move {
+      let deadline = time::Instant::now() + self.err_timeout;
+      if self.address_family == AddressFamily::Any {
+        let fetched = timed_fetch(self.url.clone(), None, self.err_timeout, deadline).await;
+        return self.evaluate_fetched(fetched).await;
+      }
+      let host = match self.url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_owned(),
+        None => return CheckResult::error(Some(format!("No host in {}.", &self.url))),
+      };
+      let port = self.url.port_or_known_default().unwrap_or(80);
+      let addrs = match tokio::time::timeout_at(deadline.into(), async_checker::resolve_addresses(&host, port, self.address_family)).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => return CheckResult::error(Some(e)),
+        Err(_) => return CheckResult::error(Some(format!("Timed out resolving {}", host))),
+      };
+      if self.address_family != AddressFamily::Each {
+        let fetched = timed_fetch(self.url.clone(), Some(addrs), self.err_timeout, deadline).await;
+        return self.evaluate_fetched(fetched).await;
+      }
+      // Request every address at once, within the one timeout.
+      let mut requests = tokio::task::JoinSet::new();
+      for (i, addr) in addrs.iter().enumerate() {
+        let request = timed_fetch(self.url.clone(), Some(vec![*addr]), self.err_timeout, deadline);
+        requests.spawn(async move { (i, request.await) });
+      }
+      let mut fetched = Vec::new();
+      while let Some(res) = requests.join_next().await {
+        match res {
+          Ok(f) => fetched.push(f),
+          Err(e) => return CheckResult::error(Some(format!("Request panicked: {}", &e))),
+        }
+      }
+      fetched.sort_by_key(|(i, _)| *i);
+      let mut results = Vec::new();
+      for (i, f) in fetched {
+        results.push((addrs[i], self.evaluate_fetched(f).await));
+      }
+      merge_address_results(results, &[], true)
+    })
+  }
+}
+
+#[test]
+fn async_http_checker_test() {
+  use crate::testing::serve_once;
+  use std::io::{BufRead, BufReader, Write};
+  let serve = |response: &'static [u8]| serve_once(move |mut stream| {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    while line != "\r\n" {
+      line.clear();
+      reader.read_line(&mut line).unwrap();
+    }
+    stream.write_all(response).unwrap();
+  });
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  runtime.block_on(async {
+    let addr = serve(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+    let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+    checker.expect_200().expect_response_contains("hello world");
+    AsyncChecker::check(&mut checker).await.expect();
+
+    let addr = serve(b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope");
+    let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+    checker.expect_200().set_address_family(AddressFamily::Each);
+    let res = AsyncChecker::check(&mut checker).await;
+    res.expect_err_contains(&format!("1 of 1 addresses failed.\n{}: ERROR: Expected status to be 200, got 404 Not Found.", addr));
+
+    // Never responds, so the request is dropped at the timeout.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut checker = HttpChecker::new(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    checker.set_timeouts(time::Duration::from_millis(100), time::Duration::from_millis(200));
+    let start = time::Instant::now();
+    AsyncChecker::check(&mut checker).await.expect_err_contains("Timeout of 200ms reached");
+    assert!(start.elapsed() < time::Duration::from_millis(400));
+  });
+}
+
+#[test]
+fn async_security_headers_test() {
+  use super::security::SecurityHeaderPolicy;
+  use crate::scheduler::async_schd::{AsyncCheck, AsyncSchd};
+  use std::io::{BufRead, BufReader, Write};
+  use std::sync::Arc;
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  std::thread::spawn(move || {
+    // The page, then the probe for a redirect to HTTPS, which is slow.
+    for i in 0..2 {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut line = String::new();
+      while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+      }
+      if i == 1 {
+        std::thread::sleep(time::Duration::from_millis(500));
+      }
+      stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Security-Policy: default-src 'self'\r\nX-Content-Type-Options: nosniff\r\nReferrer-Policy: no-referrer\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").unwrap();
+    }
+  });
+  struct Sleepy;
+  impl AsyncChecker for Sleepy {
+    fn check(&mut self) -> CheckFuture<'_> {
+      Box::pin(async {
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        CheckResult::up(None)
+      })
+    }
+  }
+  let mut checker = HttpChecker::new(&format!("http://{}/", addr)).unwrap();
+  checker.expect_200().expect_security_headers(SecurityHeaderPolicy::default());
+  let check = |checker: Box<dyn AsyncChecker>, desc| AsyncCheck{checker, min_check_interval: time::Duration::from_secs(3600), timeout: time::Duration::from_secs(5), desc};
+  let schd = Arc::new(AsyncSchd::new(vec![check(Box::new(checker), "http"), check(Box::new(Sleepy), "sleepy")]));
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut logs = Vec::new();
+  runtime.block_on(async {
+    let run = tokio::spawn(schd.clone().run());
+    while logs.len() < 2 {
+      schd.wait_logs().await;
+      schd.read_logs(&mut logs);
+    }
+    schd.stop();
+    run.await.unwrap();
+  });
+  // The probe didn't hold up the other check, on the only runtime thread.
+  assert_eq!(logs[0].check_desc, "sleepy");
+  assert_eq!(logs[1].result.info.as_ref().unwrap(), &format!("1 expect checks reported WARN: 2 security regressions: served over plain HTTP, where HSTS does not apply; http://{}/ does not redirect to HTTPS (status 200).", addr));
+}
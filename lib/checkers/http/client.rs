@@ -0,0 +1,246 @@
+//! The HTTP/1.1 client used when connecting to addresses ourselves, and by
+//! the realtime checkers. The async client shares its request and response
+//! parsing.
+
+use crate::checkers::{AddressFamily, resolve_addresses};
+use crate::utils::DeadlineStream;
+use reqwest::Url;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::time;
+
+pub(crate) trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+pub(crate) fn invalid_data(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The host and port to connect to for `url`, and whether to use TLS.
+pub(crate) fn url_target(url: &Url) -> Result<(&str, u16, bool), String> {
+  let host = url.host_str().ok_or_else(|| format!("No host in {}.", url))?;
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  let port = url.port_or_known_default().ok_or_else(|| format!("No port in {}.", url))?;
+  let tls = match url.scheme() {
+    "http" | "ws" => false,
+    "https" | "wss" => true,
+    s => return Err(format!("Unsupported scheme {}.", s)),
+  };
+  Ok((host, port, tls))
+}
+
+/// A `GET` request for `url`, with `headers`.
+pub(crate) fn encode_request(url: &Url, headers: &[(String, String)]) -> String {
+  let mut path = url.path().to_owned();
+  if let Some(query) = url.query() {
+    path.push('?');
+    path.push_str(query);
+  }
+  let host = url.host_str().unwrap_or("");
+  let host_header = match url.port() {
+    Some(port) => format!("{}:{}", host, port),
+    None => host.to_owned(),
+  };
+  let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: serverwatch\r\n", path, host_header);
+  for (name, value) in headers {
+    request.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  request.push_str("\r\n");
+  request
+}
+
+/// How the end of a response body is found.
+pub(crate) enum BodyFraming {
+  Chunked,
+  Length(u64),
+  /// Read until the server closes the connection.
+  UntilClose,
+}
+
+/// The status line and headers of a response, parsed a line at a time, so
+/// that blocking and async connections share the parsing.
+#[derive(Clone, Default)]
+pub(crate) struct ResponseHead {
+  pub(crate) status: u16,
+  /// Names are lowercase.
+  pub(crate) headers: Vec<(String, String)>,
+  has_status: bool,
+}
+
+impl ResponseHead {
+  /// Parse the next line, without its line ending. Returns whether it was
+  /// the empty line which ends the head.
+  pub(crate) fn parse_line(&mut self, line: &str) -> Result<bool, String> {
+    if !self.has_status {
+      self.status = line.split(' ').nth(1).and_then(|s| s.parse().ok()).ok_or_else(|| format!("Invalid status line: {:?}", line))?;
+      self.has_status = true;
+      return Ok(false);
+    }
+    if line.is_empty() {
+      return Ok(true);
+    }
+    if let Some(i) = line.find(':') {
+      self.headers.push((line[..i].trim().to_lowercase(), line[i + 1..].trim().to_owned()));
+    }
+    Ok(false)
+  }
+
+  pub(crate) fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+  }
+
+  pub(crate) fn is_chunked(&self) -> bool {
+    self.header("transfer-encoding").map(|t| t.eq_ignore_ascii_case("chunked")).unwrap_or(false)
+  }
+
+  /// How to read a body which must not be longer than `limit`.
+  pub(crate) fn body_framing(&self, limit: u64) -> Result<BodyFraming, String> {
+    if self.is_chunked() {
+      return Ok(BodyFraming::Chunked);
+    }
+    match self.header("content-length").and_then(|l| l.parse::<u64>().ok()) {
+      Some(length) if length > limit => Err(format!("Response body of {} bytes is too large.", length)),
+      Some(length) => Ok(BodyFraming::Length(length)),
+      None => Ok(BodyFraming::UntilClose),
+    }
+  }
+}
+
+/// The size from the line starting a chunk of a chunked body.
+pub(crate) fn parse_chunk_size(line: &str) -> Result<u64, String> {
+  let size = line.split(';').next().unwrap_or("").trim();
+  u64::from_str_radix(size, 16).map_err(|_| format!("Invalid chunk size {:?}.", size))
+}
+
+pub(crate) fn body_too_large(limit: u64) -> String {
+  format!("Response body is larger than {} bytes.", limit)
+}
+
+/// An HTTP/1.1 connection, after the response headers.
+pub(crate) struct Connection {
+  pub(crate) reader: BufReader<Box<dyn Stream>>,
+  /// The same socket, to move the deadline of.
+  pub(crate) tcp: DeadlineStream,
+  pub(crate) head: ResponseHead,
+}
+
+impl Connection {
+  /// Connect to the server of `url`, or to the first of `addrs` which accepts
+  /// the connection if given, with TLS for `https` and `wss`, and send a `GET`
+  /// request with `headers`. Reads and writes fail once `timeout` has passed,
+  /// until the deadline is moved.
+  pub(crate) fn open(url: &Url, addrs: Option<&[SocketAddr]>, headers: &[(String, String)], timeout: time::Duration) -> Result<Self, String> {
+    let (host, port, tls) = url_target(url)?;
+    let deadline = time::Instant::now() + timeout;
+    let resolved;
+    let addrs = match addrs {
+      Some(addrs) => addrs,
+      None => {
+        resolved = resolve_addresses(host, port, AddressFamily::Any, timeout)?;
+        &resolved[..]
+      },
+    };
+    let tcp = DeadlineStream::connect(addrs, deadline).map_err(|e| format!("Connecting to {}: {}", host, &e))?;
+    let tcp_clone = tcp.try_clone().map_err(|e| format!("{}", &e))?;
+    let stream: Box<dyn Stream> = if tls {
+      let connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()).map_err(|e| format!("Setting up connector: {}", &e))?.build();
+      Box::new(connector.connect(host, tcp).map_err(|e| format!("TLS handshake with {}: {}", host, &e))?)
+    } else {
+      Box::new(tcp)
+    };
+    let mut reader = BufReader::new(stream);
+    reader.get_mut().write_all(encode_request(url, headers).as_bytes()).map_err(|e| format!("Writing to server: {}", &e))?;
+
+    let mut head = ResponseHead::default();
+    loop {
+      let mut line = String::new();
+      match reader.read_line(&mut line) {
+        Ok(0) => return Err("Connection closed by server.".to_owned()),
+        Ok(_) => {},
+        Err(e) => return Err(format!("Reading from server: {}", &e)),
+      }
+      if head.parse_line(line.trim_end_matches(&['\r', '\n'][..]))? {
+        break;
+      }
+    }
+    Ok(Connection{reader, tcp: tcp_clone, head})
+  }
+
+  pub(crate) fn header(&self, name: &str) -> Option<&str> {
+    self.head.header(name)
+  }
+
+  /// Read the whole response body, which must not be longer than `limit`.
+  pub(crate) fn read_body(mut self, limit: u64) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    let read_err = |e: io::Error| format!("Reading response body: {}", &e);
+    match self.head.body_framing(limit)? {
+      BodyFraming::Chunked => {
+        Chunked::new(&mut self.reader).take(limit + 1).read_to_end(&mut body).map_err(read_err)?;
+      },
+      BodyFraming::Length(length) => {
+        (&mut self.reader).take(length).read_to_end(&mut body).map_err(read_err)?;
+        if (body.len() as u64) < length {
+          return Err("Connection closed before the end of the response body.".to_owned());
+        }
+      },
+      BodyFraming::UntilClose => {
+        (&mut self.reader).take(limit + 1).read_to_end(&mut body).map_err(read_err)?;
+      },
+    }
+    if body.len() as u64 > limit {
+      return Err(body_too_large(limit));
+    }
+    Ok(body)
+  }
+
+  /// Make reads and writes fail with `TimedOut` after `deadline`.
+  pub(crate) fn set_deadline(tcp: &DeadlineStream, deadline: time::Instant) -> io::Result<()> {
+    if time::Instant::now() >= deadline {
+      return Err(io::ErrorKind::TimedOut.into());
+    }
+    tcp.set_deadline(deadline);
+    Ok(())
+  }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body.
+pub(crate) struct Chunked<R> {
+  inner: R,
+  remaining: usize,
+  done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+  pub(crate) fn new(inner: R) -> Self {
+    Chunked{inner, remaining: 0, done: false}
+  }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.done || buf.is_empty() {
+      return Ok(0);
+    }
+    if self.remaining == 0 {
+      let mut line = String::new();
+      self.inner.read_line(&mut line)?;
+      self.remaining = parse_chunk_size(&line).map_err(invalid_data)? as usize;
+      if self.remaining == 0 {
+        self.done = true;
+        return Ok(0);
+      }
+    }
+    let len = self.remaining.min(buf.len());
+    let n = self.inner.read(&mut buf[..len])?;
+    if n == 0 {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    self.remaining -= n;
+    if self.remaining == 0 {
+      let mut crlf = String::new();
+      self.inner.read_line(&mut crlf)?;
+    }
+    Ok(n)
+  }
+}
@@ -0,0 +1,447 @@
+//! Crawl a site for broken links.
+
+use crate::checkers::{Checker, CheckResult};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::time;
+
+const USER_AGENT: &str = "serverwatch";
+/// Largest page we look for links in.
+const MAX_PAGE_LEN: u64 = 5 << 20;
+/// At most this many pages are listed as linking to each broken URL.
+const MAX_REFERRERS_SHOWN: usize = 3;
+
+/// What came back for a URL.
+struct Fetched {
+  status: u16,
+  location: Option<String>,
+  /// The body, if it is HTML and we asked for it.
+  html: Option<String>,
+}
+
+fn fetch(url: &Url, read_html: bool, timeout: time::Duration) -> Result<Fetched, String> {
+  let timed_out = |e: reqwest::Error| if e.is_timeout() { format!("timed out after {}ms", timeout.as_millis()) } else { format!("{}", &e) };
+  let mut res = super::acquire_client(timeout).get(url.clone()).header(reqwest::header::USER_AGENT, USER_AGENT).send().map_err(timed_out)?;
+  let location = res.headers().get(reqwest::header::LOCATION).and_then(|l| l.to_str().ok()).map(|l| l.to_owned());
+  let is_html = res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|t| t.to_str().ok()).map(|t| t.starts_with("text/html")).unwrap_or(false);
+  let html = if read_html && is_html && res.status().is_success() {
+    let mut body = Vec::new();
+    (&mut res).take(MAX_PAGE_LEN).read_to_end(&mut body).map_err(|e| format!("{}", &e))?;
+    Some(String::from_utf8_lossy(&body).into_owned())
+  } else {
+    None
+  };
+  Ok(Fetched{status: res.status().as_u16(), location, html})
+}
+
+/// The robots.txt at `url`, following redirects, if there is one.
+fn fetch_robots(url: Url, timeout: time::Duration) -> Option<String> {
+  let client = reqwest::Client::builder().timeout(timeout).build().ok()?;
+  let mut res = client.get(url).header(reqwest::header::USER_AGENT, USER_AGENT).send().ok()?;
+  if !res.status().is_success() {
+    return None;
+  }
+  let mut body = Vec::new();
+  (&mut res).take(MAX_PAGE_LEN).read_to_end(&mut body).ok()?;
+  Some(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// The `href`s of the `<a>` and `<area>` elements of `html`.
+fn extract_links(html: &str) -> Vec<String> {
+  let lower = html.to_ascii_lowercase();
+  let mut links = Vec::new();
+  let mut pos = 0;
+  while let Some(i) = lower[pos..].find('<') {
+    let start = pos + i + 1;
+    if lower[start..].starts_with("!--") {
+      pos = lower[start..].find("-->").map(|e| start + e + 3).unwrap_or(lower.len());
+      continue;
+    }
+    let end = match lower[start..].find('>') {
+      Some(e) => start + e,
+      None => break,
+    };
+    pos = end + 1;
+    let tag = &lower[start..end];
+    let name_end = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+    if &tag[..name_end] != "a" && &tag[..name_end] != "area" {
+      continue;
+    }
+    // Attribute values keep their case, so index into html.
+    let mut attr_pos = name_end;
+    while let Some(h) = tag[attr_pos..].find("href") {
+      let h = attr_pos + h;
+      attr_pos = h + 4;
+      let preceded_by_space = tag[..h].ends_with(|c: char| c.is_ascii_whitespace());
+      let rest = tag[h + 4..].trim_start();
+      if !preceded_by_space || !rest.starts_with('=') {
+        continue;
+      }
+      let value_start = end - rest.len() + 1;
+      let value = html[value_start..end].trim_start();
+      let href = match value.chars().next() {
+        Some(q) if q == '"' || q == '\'' => value[1..].split(q).next().unwrap_or(""),
+        _ => value.split(|c: char| c.is_ascii_whitespace()).next().unwrap_or(""),
+      };
+      links.push(href.replace("&amp;", "&"));
+      break;
+    }
+  }
+  links
+}
+
+/// A robots.txt group: its user agents, its rules, and its crawl delay.
+type RobotsGroup = (Vec<String>, Vec<(bool, String)>, Option<time::Duration>);
+
+/// The rules of the robots.txt group which applies to us, as pairs of whether
+/// the rule allows, and the path pattern. Also the crawl delay, if any.
+fn parse_robots(text: &str) -> (Vec<(bool, String)>, Option<time::Duration>) {
+  // Groups of user agents, and their rules.
+  let mut groups: Vec<RobotsGroup> = Vec::new();
+  let mut in_agents = false;
+  for line in text.lines() {
+    let line = line.split('#').next().unwrap_or("").trim();
+    let i = match line.find(':') {
+      Some(i) => i,
+      None => continue,
+    };
+    let (key, value) = (line[..i].trim().to_ascii_lowercase(), line[i + 1..].trim());
+    match &key[..] {
+      "user-agent" => {
+        if !in_agents {
+          groups.push((Vec::new(), Vec::new(), None));
+        }
+        in_agents = true;
+        groups.last_mut().unwrap().0.push(value.to_ascii_lowercase());
+        continue;
+      },
+      // An empty Disallow allows everything.
+      "allow" | "disallow" if !groups.is_empty() && !value.is_empty() => {
+        groups.last_mut().unwrap().1.push((key == "allow", value.to_owned()));
+      },
+      "crawl-delay" if !groups.is_empty() => {
+        groups.last_mut().unwrap().2 = value.parse::<f64>().ok().filter(|d| d.is_finite() && *d >= 0f64).map(time::Duration::from_secs_f64);
+      },
+      _ => {},
+    }
+    in_agents = false;
+  }
+  let ours = groups.iter().find(|g| g.0.iter().any(|a| a != "*" && USER_AGENT.contains(&a[..])))
+    .or_else(|| groups.iter().find(|g| g.0.iter().any(|a| a == "*")));
+  match ours {
+    Some((_, rules, delay)) => (rules.clone(), *delay),
+    None => (Vec::new(), None),
+  }
+}
+
+/// Whether the robots.txt path pattern `pattern`, which may contain `*` and
+/// end with `$`, matches the start of `path`.
+fn robots_pattern_matches(pattern: &str, path: &str) -> bool {
+  let (pattern, anchored) = match pattern.strip_suffix('$') {
+    Some(p) => (p, true),
+    None => (pattern, false),
+  };
+  let parts: Vec<&str> = pattern.split('*').collect();
+  if !path.starts_with(parts[0]) {
+    return false;
+  }
+  let mut pos = parts[0].len();
+  for (i, part) in parts.iter().enumerate().skip(1) {
+    if i == parts.len() - 1 && anchored {
+      return path.len() >= pos + part.len() && path.ends_with(part);
+    }
+    match path[pos..].find(part) {
+      Some(found) => pos += found + part.len(),
+      None => return false,
+    }
+  }
+  !anchored || pos == path.len()
+}
+
+/// Whether the longest matching rule allows `path`. Ties go to allowing.
+fn robots_allows(rules: &[(bool, String)], path: &str) -> bool {
+  rules.iter().filter(|(_, p)| robots_pattern_matches(p, path))
+    .max_by_key(|(allow, p)| (p.len(), *allow))
+    .map(|(allow, _)| *allow).unwrap_or(true)
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+  a.scheme() == b.scheme() && a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Crawl a site from a URL, following links to pages of the same origin, and
+/// report links to pages which fail to load, return `4xx`/`5xx`, or time out.
+///
+/// Pages are fetched one at a time, with a
+/// [delay](crate::checkers::http::crawl::BrokenLinkChecker::set_delay) in
+/// between, and `robots.txt` is respected, using the user agent
+/// `serverwatch`. Redirects are followed. Links to other sites are only
+/// checked if
+/// [`set_check_outbound`](crate::checkers::http::crawl::BrokenLinkChecker::set_check_outbound)
+/// is set, and are never crawled.
+///
+/// If the start URL itself is broken, the result is `ERROR`. Otherwise broken
+/// links make it `WARN`, listing each broken URL and the pages linking to it.
+/// The metrics are `pages` (URLs requested) and `broken`.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, http::crawl::BrokenLinkChecker};
+/// let mut checker = BrokenLinkChecker::new("https://docs.example.com/").unwrap();
+/// checker.set_max_depth(2).set_max_pages(50);
+/// let result = checker.check();
+/// ```
+pub struct BrokenLinkChecker {
+  start: Url,
+  max_depth: usize,
+  max_pages: usize,
+  delay: time::Duration,
+  check_outbound: bool,
+  timeout: time::Duration,
+}
+
+impl BrokenLinkChecker {
+  pub fn new(url: &str) -> Result<Self, reqwest::UrlError> {
+    let mut start = Url::parse(url)?;
+    start.set_fragment(None);
+    Ok(BrokenLinkChecker{
+      start,
+      max_depth: 3,
+      max_pages: 100,
+      delay: time::Duration::from_millis(500),
+      check_outbound: false,
+      timeout: time::Duration::from_secs(10),
+    })
+  }
+
+  /// How many links away from the start URL to crawl. Pages at this depth are
+  /// checked, but their links are not followed.
+  ///
+  /// Default is 3.
+  pub fn set_max_depth(&mut self, value: usize) -> &mut Self {
+    self.max_depth = value;
+    self
+  }
+
+  /// The most URLs to request in one check, including outbound links.
+  ///
+  /// Default is 100.
+  pub fn set_max_pages(&mut self, value: usize) -> &mut Self {
+    self.max_pages = value.max(1);
+    self
+  }
+
+  /// Time to wait between requests to the site. A longer `Crawl-delay` in
+  /// `robots.txt` takes precedence.
+  ///
+  /// Default is 500ms.
+  pub fn set_delay(&mut self, value: time::Duration) -> &mut Self {
+    self.delay = value;
+    self
+  }
+
+  /// Also check links to other sites.
+  ///
+  /// Default is `false`.
+  pub fn set_check_outbound(&mut self, value: bool) -> &mut Self {
+    self.check_outbound = value;
+    self
+  }
+
+  /// Time limit for each request.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  fn robots(&self) -> (Vec<(bool, String)>, Option<time::Duration>) {
+    let robots_url = match self.start.join("/robots.txt") {
+      Ok(u) => u,
+      Err(_) => return (Vec::new(), None),
+    };
+    // A missing or unreachable robots.txt allows everything.
+    match fetch_robots(robots_url, self.timeout) {
+      Some(text) => parse_robots(&text),
+      None => (Vec::new(), None),
+    }
+  }
+}
+
+impl Checker for BrokenLinkChecker {
+  fn check(&mut self) -> CheckResult {
+    let (rules, crawl_delay) = self.robots();
+    let delay = std::cmp::max(self.delay, crawl_delay.unwrap_or_default());
+    let mut queue = VecDeque::new();
+    queue.push_back((self.start.clone(), 0usize));
+    let mut seen: HashSet<Url> = HashSet::new();
+    seen.insert(self.start.clone());
+    // Pages linking to each URL.
+    let mut referrers: HashMap<Url, Vec<Url>> = HashMap::new();
+    // Broken URLs, in the order found, and why.
+    let mut broken: Vec<(Url, String)> = Vec::new();
+    let mut requested = 0;
+    let mut last_request: Option<time::Instant> = None;
+    while let Some((url, depth)) = queue.pop_front() {
+      if requested >= self.max_pages {
+        break;
+      }
+      let internal = same_origin(&url, &self.start);
+      if internal {
+        let path = match url.query() {
+          Some(q) => format!("{}?{}", url.path(), q),
+          None => url.path().to_owned(),
+        };
+        if !robots_allows(&rules, &path) {
+          continue;
+        }
+        if let Some(last) = last_request {
+          let next = last + delay;
+          let now = time::Instant::now();
+          if next > now {
+            std::thread::sleep(next - now);
+          }
+        }
+        last_request = Some(time::Instant::now());
+      }
+      requested += 1;
+      let fetched = match fetch(&url, internal && depth < self.max_depth, self.timeout) {
+        Ok(f) => f,
+        Err(e) => {
+          broken.push((url, e));
+          continue;
+        },
+      };
+      if fetched.status >= 400 {
+        let reason = reqwest::StatusCode::from_u16(fetched.status).ok().and_then(|s| s.canonical_reason()).unwrap_or("");
+        broken.push((url, format!("{} {}", fetched.status, reason).trim_end().to_owned()));
+        continue;
+      }
+      let mut links = Vec::new();
+      if (300..400).contains(&fetched.status) {
+        // The target of a redirect is at the same depth.
+        if let Some(target) = fetched.location.as_ref().and_then(|l| url.join(l).ok()) {
+          links.push((target, depth));
+        }
+      }
+      if let Some(ref html) = fetched.html {
+        for href in extract_links(html) {
+          if let Ok(link) = url.join(href.trim()) {
+            links.push((link, depth + 1));
+          }
+        }
+      }
+      for (mut link, link_depth) in links {
+        if link.scheme() != "http" && link.scheme() != "https" {
+          continue;
+        }
+        link.set_fragment(None);
+        if !self.check_outbound && !same_origin(&link, &self.start) {
+          continue;
+        }
+        let from = referrers.entry(link.clone()).or_default();
+        if !from.contains(&url) {
+          from.push(url.clone());
+        }
+        if seen.insert(link.clone()) {
+          queue.push_back((link, link_depth));
+        }
+      }
+    }
+    let metrics = |r: CheckResult| r.with_metric("pages", requested as f64, "").with_metric("broken", broken.len() as f64, "");
+    if broken.first().map(|(u, _)| *u == self.start).unwrap_or(false) {
+      return metrics(CheckResult::error(Some(format!("{}: {}", self.start, broken[0].1))));
+    }
+    if broken.is_empty() {
+      return metrics(CheckResult::up(Some(format!("Checked {} URLs, no broken links.", requested))));
+    }
+    let mut lines = vec![format!("Checked {} URLs, {} broken:", requested, broken.len())];
+    for (url, reason) in broken.iter() {
+      let from = referrers.get(url).map(|r| &r[..]).unwrap_or(&[]);
+      let mut shown: Vec<String> = from.iter().take(MAX_REFERRERS_SHOWN).map(|u| u.to_string()).collect();
+      if from.len() > MAX_REFERRERS_SHOWN {
+        shown.push(format!("and {} more", from.len() - MAX_REFERRERS_SHOWN));
+      }
+      lines.push(format!("{}: {}, linked from {}", url, reason, shown.join(", ")));
+    }
+    metrics(CheckResult::warn(Some(lines.join("\n"))))
+  }
+}
+
+#[test]
+fn extract_links_test() {
+  let html = r#"<html><body><A HREF="/Docs?a=1&amp;b=2">Docs</a> <a class="x" href='rel.html'>x</a>
+    <!-- <a href="/commented"> --> <a data-href="/no" href=bare.html>y</a><area shape=rect href="/map">
+    <abbr href="/not-a-link">z</abbr><a name="anchor">no href</a></body></html>"#;
+  assert_eq!(extract_links(html), vec!["/Docs?a=1&b=2", "rel.html", "bare.html", "/map"]);
+}
+
+#[test]
+fn robots_test() {
+  let text = "# comment\nUser-agent: googlebot\nDisallow: /\n\nUser-agent: *\nUser-agent: other\nDisallow: /private\nAllow: /private/public\nDisallow: /*.pdf$\nDisallow:\nCrawl-delay: 2\n";
+  let (rules, delay) = parse_robots(text);
+  assert_eq!(delay, Some(time::Duration::from_secs(2)));
+  assert!(robots_allows(&rules, "/"));
+  assert!(!robots_allows(&rules, "/private/x"));
+  assert!(robots_allows(&rules, "/private/public/x"));
+  assert!(!robots_allows(&rules, "/docs/manual.pdf"));
+  assert!(robots_allows(&rules, "/docs/manual.pdf?download"));
+  let (rules, _) = parse_robots("User-agent: ServerWatch\nDisallow: /\nUser-agent: *\nDisallow:\n");
+  assert!(!robots_allows(&rules, "/anything"));
+  assert!(robots_pattern_matches("/a*b*c", "/axxbyyc/z"));
+  assert!(!robots_pattern_matches("/a*b$", "/axxbc"));
+}
+
+#[test]
+fn broken_link_checker_test() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::sync::{Arc, Mutex};
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  let requested = Arc::new(Mutex::new(Vec::new()));
+  let log = requested.clone();
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut stream = stream.unwrap();
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut request_line = String::new();
+      reader.read_line(&mut request_line).unwrap();
+      let mut line = String::new();
+      while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+      }
+      let path = request_line.split(' ').nth(1).unwrap().to_owned();
+      log.lock().unwrap().push(path.clone());
+      let (status, extra, body) = match &path[..] {
+        "/robots.txt" => ("200 OK", "Content-Type: text/plain\r\n", "User-agent: *\nDisallow: /private\n"),
+        "/" => ("200 OK", "Content-Type: text/html\r\n", "<a href=\"/a\">a</a> <a href=\"b#top\">b</a> <a href=\"/private/x\">p</a> <a href=\"mailto:x@example.com\">m</a> <a href=\"http://127.0.0.1:1/ext\">e</a>"),
+        "/a" => ("200 OK", "Content-Type: text/html; charset=utf-8\r\n", "<a href=\"/missing\">m</a> <a href=\"/\">home</a> <a href=\"/deep\">deep</a>"),
+        "/b" => ("301 Moved Permanently", "Location: /c\r\n", ""),
+        "/c" => ("200 OK", "Content-Type: text/html\r\n", "<a href=\"/missing\">m</a>"),
+        "/deep" => ("200 OK", "Content-Type: text/html\r\n", "<a href=\"/deeper\">d</a>"),
+        _ => ("404 Not Found", "", ""),
+      };
+      let _ = write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, extra, body.len(), body);
+    }
+  });
+  let mut checker = BrokenLinkChecker::new(&format!("http://{}/", addr)).unwrap();
+  checker.set_delay(time::Duration::from_millis(0)).set_max_depth(2);
+  let res = checker.check();
+  assert_eq!(res.result_type, crate::checkers::CheckResultType::WARN);
+  assert_eq!(res.get_metric("broken"), Some(1f64));
+  assert_eq!(res.info.unwrap(), format!("Checked 6 URLs, 1 broken:\nhttp://{0}/missing: 404 Not Found, linked from http://{0}/a, http://{0}/c", addr));
+  let mut paths = requested.lock().unwrap().clone();
+  paths.sort();
+  assert_eq!(paths, vec!["/", "/a", "/b", "/c", "/deep", "/missing", "/robots.txt"]);
+
+  checker.set_check_outbound(true).set_max_depth(1);
+  let res = checker.check();
+  assert!(res.info.unwrap().starts_with("Checked 5 URLs, 1 broken:\nhttp://127.0.0.1:1/ext: "));
+
+  let mut checker = BrokenLinkChecker::new(&format!("http://{}/nothing", addr)).unwrap();
+  checker.check().expect_err_contains("/nothing: 404 Not Found");
+}
@@ -0,0 +1,186 @@
+//! The security header audit of
+//! [`HttpChecker::expect_security_headers`](crate::checkers::http::HttpChecker::expect_security_headers).
+
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+use std::time;
+
+/// Which security headers a response has to have. See
+/// [`HttpChecker::expect_security_headers`](crate::checkers::http::HttpChecker::expect_security_headers).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityHeaderPolicy {
+	/// The lowest acceptable `max-age` of `Strict-Transport-Security`, or `None`
+	/// to not require HSTS. Default is 180 days.
+	pub hsts_min_max_age: Option<time::Duration>,
+	/// Require `includeSubDomains` in `Strict-Transport-Security`. Default is
+	/// `true`.
+	pub hsts_include_subdomains: bool,
+	/// Require a `Content-Security-Policy`. Default is `true`.
+	pub require_csp: bool,
+	/// Require `X-Content-Type-Options: nosniff`. Default is `true`.
+	pub require_nosniff: bool,
+	/// Require a `Referrer-Policy` other than `unsafe-url`. Default is `true`.
+	pub require_referrer_policy: bool,
+	/// Require every cookie set to have the `Secure`, `HttpOnly` and `SameSite`
+	/// attributes, except for the cookies named in `cookies_readable_by_scripts`,
+	/// which don't need `HttpOnly`. Default is `true`.
+	pub check_cookies: bool,
+	pub cookies_readable_by_scripts: Vec<String>,
+	/// Require the `http://` URL of the same host and path to redirect to
+	/// `https://`. Default is `true`.
+	pub require_https_redirect: bool,
+}
+
+impl Default for SecurityHeaderPolicy {
+	fn default() -> Self {
+		SecurityHeaderPolicy{
+			hsts_min_max_age: Some(time::Duration::from_secs(180*24*60*60)),
+			hsts_include_subdomains: true,
+			require_csp: true,
+			require_nosniff: true,
+			require_referrer_policy: true,
+			check_cookies: true,
+			cookies_readable_by_scripts: Vec::new(),
+			require_https_redirect: true,
+		}
+	}
+}
+
+fn header<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+	headers.get(name).map(|v| v.to_str().unwrap_or(""))
+}
+
+/// Everything about the headers of a response from `url` which does not meet
+/// `policy`.
+pub(crate) fn audit_headers(policy: &SecurityHeaderPolicy, url: &Url, headers: &HeaderMap) -> Vec<String> {
+	let mut regressions = Vec::new();
+	let https = url.scheme() == "https";
+	if let Some(min_max_age) = policy.hsts_min_max_age {
+		match header(headers, "strict-transport-security") {
+			_ if !https => regressions.push("served over plain HTTP, where HSTS does not apply".to_owned()),
+			None => regressions.push("no Strict-Transport-Security".to_owned()),
+			Some(hsts) => {
+				let directives: Vec<String> = hsts.split(';').map(|d| d.trim().to_ascii_lowercase()).collect();
+				let max_age = directives.iter().find_map(|d| d.strip_prefix("max-age=")).and_then(|a| a.trim_matches('"').parse::<u64>().ok());
+				match max_age {
+					None => regressions.push(format!("Strict-Transport-Security {:?} has no valid max-age", hsts)),
+					Some(a) if a < min_max_age.as_secs() => regressions.push(format!("HSTS max-age {} is less than {}", a, min_max_age.as_secs())),
+					_ => {},
+				}
+				if policy.hsts_include_subdomains && !directives.iter().any(|d| d == "includesubdomains") {
+					regressions.push("HSTS lacks includeSubDomains".to_owned());
+				}
+			},
+		}
+	}
+	if policy.require_csp && header(headers, "content-security-policy").is_none() {
+		if header(headers, "content-security-policy-report-only").is_some() {
+			regressions.push("Content-Security-Policy is only report-only".to_owned());
+		} else {
+			regressions.push("no Content-Security-Policy".to_owned());
+		}
+	}
+	if policy.require_nosniff && !header(headers, "x-content-type-options").map(|v| v.trim().eq_ignore_ascii_case("nosniff")).unwrap_or(false) {
+		regressions.push("no X-Content-Type-Options: nosniff".to_owned());
+	}
+	if policy.require_referrer_policy {
+		match header(headers, "referrer-policy") {
+			None => regressions.push("no Referrer-Policy".to_owned()),
+			Some(p) if p.split(',').any(|p| p.trim().eq_ignore_ascii_case("unsafe-url")) => regressions.push("Referrer-Policy is unsafe-url".to_owned()),
+			_ => {},
+		}
+	}
+	if policy.check_cookies {
+		for cookie in headers.get_all("set-cookie").iter() {
+			let cookie = cookie.to_str().unwrap_or("");
+			let mut parts = cookie.split(';');
+			let name = parts.next().and_then(|nv| nv.split('=').next()).unwrap_or("").trim();
+			let attrs: Vec<String> = parts.map(|a| a.trim().to_ascii_lowercase()).collect();
+			let has = |attr: &str| attrs.iter().any(|a| a == attr || a.starts_with(&format!("{}=", attr)));
+			let mut missing = Vec::new();
+			if https && !has("secure") {
+				missing.push("Secure");
+			}
+			if !has("httponly") && !policy.cookies_readable_by_scripts.iter().any(|n| n == name) {
+				missing.push("HttpOnly");
+			}
+			if !has("samesite") {
+				missing.push("SameSite");
+			}
+			if !missing.is_empty() {
+				regressions.push(format!("cookie {} lacks {}", name, missing.join(", ")));
+			}
+		}
+	}
+	regressions
+}
+
+/// Check that the `http://` URL of the host and path of `url` redirects to
+/// `https://` on the same host.
+pub(crate) fn check_https_redirect(url: &Url, timeout: time::Duration) -> Option<String> {
+	let mut http_url = url.clone();
+	if url.scheme() == "https" && (http_url.set_scheme("http").is_err() || http_url.set_port(None).is_err()) {
+		return Some(format!("unable to make a http:// URL from {}", url));
+	}
+	let client = match reqwest::Client::builder().redirect(reqwest::RedirectPolicy::none()).timeout(timeout).build() {
+		Ok(c) => c,
+		Err(e) => return Some(format!("{}", &e)),
+	};
+	let res = match client.get(http_url.clone()).send() {
+		Ok(r) => r,
+		Err(e) => return Some(format!("requesting {}: {}", http_url, &e)),
+	};
+	if !res.status().is_redirection() {
+		return Some(format!("{} does not redirect to HTTPS (status {})", http_url, res.status().as_u16()));
+	}
+	let location = header(res.headers(), "location").and_then(|l| http_url.join(l).ok());
+	match location {
+		Some(ref l) if l.scheme() == "https" && l.host_str() == http_url.host_str() => None,
+		Some(l) => Some(format!("{} redirects to {} instead of HTTPS on the same host", http_url, l)),
+		None => Some(format!("{} redirects without a valid Location", http_url)),
+	}
+}
+
+#[test]
+fn audit_headers_test() {
+	use reqwest::header::HeaderValue;
+	let headers = |pairs: &[(&'static str, &'static str)]| {
+		let mut map = HeaderMap::new();
+		for (n, v) in pairs {
+			map.append(*n, HeaderValue::from_static(v));
+		}
+		map
+	};
+	let https = Url::parse("https://example.com/").unwrap();
+	let good = headers(&[
+		("strict-transport-security", "max-age=31536000; includeSubDomains; preload"),
+		("content-security-policy", "default-src 'self'"),
+		("x-content-type-options", "nosniff"),
+		("referrer-policy", "strict-origin-when-cross-origin"),
+		("set-cookie", "session=abc; Path=/; Secure; HttpOnly; SameSite=Lax"),
+		("set-cookie", "csrf=xyz; Secure; SameSite=Strict"),
+	]);
+	let mut policy = SecurityHeaderPolicy::default();
+	assert_eq!(audit_headers(&policy, &https, &good), vec!["cookie csrf lacks HttpOnly"]);
+	policy.cookies_readable_by_scripts.push("csrf".to_owned());
+	assert!(audit_headers(&policy, &https, &good).is_empty());
+
+	let bad = headers(&[
+		("strict-transport-security", "max-age=300"),
+		("content-security-policy-report-only", "default-src 'self'"),
+		("referrer-policy", "no-referrer, unsafe-url"),
+		("set-cookie", "session=abc; HttpOnly"),
+	]);
+	assert_eq!(audit_headers(&policy, &https, &bad), vec![
+		"HSTS max-age 300 is less than 15552000",
+		"HSTS lacks includeSubDomains",
+		"Content-Security-Policy is only report-only",
+		"no X-Content-Type-Options: nosniff",
+		"Referrer-Policy is unsafe-url",
+		"cookie session lacks Secure, SameSite",
+	]);
+	let http = Url::parse("http://example.com/").unwrap();
+	assert_eq!(audit_headers(&policy, &http, &HeaderMap::new())[..2], ["served over plain HTTP, where HSTS does not apply", "no Content-Security-Policy"]);
+	let lax = SecurityHeaderPolicy{hsts_min_max_age: None, require_csp: false, require_nosniff: false, require_referrer_policy: false, ..SecurityHeaderPolicy::default()};
+	assert!(audit_headers(&lax, &http, &HeaderMap::new()).is_empty());
+}
@@ -0,0 +1,292 @@
+//! Ping hosts with ICMP echo requests.
+
+use crate::checkers::{AddressFamily, Checker, CheckResult, Threshold, merge_address_results, resolve_addresses};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::time;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+/// Open an ICMP socket for the family of `addr`, returning whether it is raw.
+///
+/// Datagram ICMP sockets need the group of the process to be in
+/// `net.ipv4.ping_group_range`, which also applies to IPv6. Raw sockets need
+/// `CAP_NET_RAW`. The socket is wrapped in a `UdpSocket` for its
+/// `send_to`/`recv_from` and timeouts, which work the same.
+fn open_socket(addr: &IpAddr) -> Result<(UdpSocket, bool), String> {
+  let (domain, protocol) = match addr {
+    IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+    IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+  };
+  let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, protocol) };
+  if fd >= 0 {
+    return Ok((unsafe { UdpSocket::from_raw_fd(fd) }, false));
+  }
+  let dgram_err = io::Error::last_os_error();
+  let fd = unsafe { libc::socket(domain, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+  if fd >= 0 {
+    return Ok((unsafe { UdpSocket::from_raw_fd(fd) }, true));
+  }
+  let raw_err = io::Error::last_os_error();
+  Err(format!("Unable to open an ICMP socket: {} (is the group of this process in net.ipv4.ping_group_range?), and unable to open a raw socket: {}", &dgram_err, &raw_err))
+}
+
+fn checksum(data: &[u8]) -> u16 {
+  let mut sum: u32 = data.chunks(2).map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32).sum();
+  while sum >> 16 != 0 {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// An echo request. The checksum of ICMPv6 covers a pseudo-header with the
+/// addresses, and is filled in by the kernel.
+fn echo_request(v6: bool, id: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+  let mut packet = vec![if v6 { ECHO_REQUEST_V6 } else { ECHO_REQUEST_V4 }, 0, 0, 0];
+  packet.extend_from_slice(&id.to_be_bytes());
+  packet.extend_from_slice(&seq.to_be_bytes());
+  packet.extend_from_slice(payload);
+  if !v6 {
+    let sum = checksum(&packet);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+  }
+  packet
+}
+
+/// The id, sequence number and payload of an echo reply. Raw IPv4 sockets
+/// also receive the IP header.
+fn parse_echo_reply(v6: bool, raw: bool, packet: &[u8]) -> Option<(u16, u16, &[u8])> {
+  let packet = if raw && !v6 { packet.get(((packet.first()? & 0x0f) as usize) * 4..)? } else { packet };
+  if packet.len() < 8 || packet[0] != (if v6 { ECHO_REPLY_V6 } else { ECHO_REPLY_V4 }) || packet[1] != 0 {
+    return None;
+  }
+  Some((u16::from_be_bytes([packet[4], packet[5]]), u16::from_be_bytes([packet[6], packet[7]]), &packet[8..]))
+}
+
+/// Ping a host, for routers, NAS boxes and other hosts without a service to
+/// check.
+///
+/// Sends a number of echo requests, one at a time, and reports packet loss and
+/// round-trip times like `ping` does, with the metrics `loss` (%), and
+/// `rtt_min`, `rtt_avg` and `rtt_max` (milliseconds). No reply at all is an
+/// `ERROR`. IPv6 is used if the host is, or resolves first to, an IPv6
+/// address, unless an
+/// [address family](crate::checkers::icmp::IcmpChecker::set_address_family)
+/// is set.
+///
+/// Uses unprivileged ICMP sockets where the system allows them, and raw
+/// sockets otherwise.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, icmp::IcmpChecker};
+/// let mut checker = IcmpChecker::new("192.168.1.1");
+/// checker.set_count(10)
+///        .set_rtt_threshold(Threshold::new(Some(50f64), Some(500f64)));
+/// let result = checker.check();
+/// ```
+pub struct IcmpChecker {
+  host: String,
+  count: u16,
+  interval: time::Duration,
+  timeout: time::Duration,
+  loss_threshold: Threshold,
+  rtt_threshold: Threshold,
+  address_family: AddressFamily,
+}
+
+impl IcmpChecker {
+  pub fn new(host: &str) -> Self {
+    IcmpChecker{
+      host: host.to_owned(),
+      count: 5,
+      interval: time::Duration::from_millis(200),
+      timeout: time::Duration::from_secs(1),
+      loss_threshold: Threshold::new(Some(20f64), Some(60f64)),
+      rtt_threshold: Threshold::default(),
+      address_family: AddressFamily::Any,
+    }
+  }
+
+  /// Number of echo requests.
+  ///
+  /// Default is 5.
+  pub fn set_count(&mut self, value: u16) -> &mut Self {
+    self.count = value.max(1);
+    self
+  }
+
+  /// Time between sending echo requests.
+  ///
+  /// Default is 200ms.
+  pub fn set_interval(&mut self, value: time::Duration) -> &mut Self {
+    self.interval = value;
+    self
+  }
+
+  /// How long to wait for each reply.
+  ///
+  /// Default is 1s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// Thresholds on packet loss, in percent.
+  ///
+  /// Default is `WARN` at 20% and `ERROR` at 60%.
+  pub fn set_loss_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.loss_threshold = value;
+    self
+  }
+
+  /// Thresholds on the average round-trip time, in milliseconds.
+  ///
+  /// Default is none.
+  pub fn set_rtt_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.rtt_threshold = value;
+    self
+  }
+
+  /// Which addresses of the host to ping. See
+  /// [`AddressFamily`](crate::checkers::AddressFamily).
+  ///
+  /// Default is `AddressFamily::Any`, which pings the first address.
+  pub fn set_address_family(&mut self, value: AddressFamily) -> &mut Self {
+    self.address_family = value;
+    self
+  }
+
+  /// The round-trip time of each echo request which got a reply.
+  fn ping(&self, addr: IpAddr) -> Result<Vec<f64>, String> {
+    let (socket, raw) = open_socket(&addr)?;
+    let v6 = addr.is_ipv6();
+    let mut token = [0u8; 8];
+    openssl::rand::rand_bytes(&mut token).map_err(|e| format!("{}", &e))?;
+    // Datagram sockets replace the id with their own, and only receive their
+    // replies. Raw sockets receive everything.
+    let id = u16::from_be_bytes([token[0], token[1]]);
+    let mut payload = token.to_vec();
+    payload.extend_from_slice(b"serverwatch ping");
+    let mut buf = [0u8; 1500];
+    let mut rtts = Vec::new();
+    for seq in 0..self.count {
+      let sent_at = time::Instant::now();
+      socket.send_to(&echo_request(v6, id, seq, &payload), SocketAddr::new(addr, 0)).map_err(|e| format!("Sending to {}: {}", addr, &e))?;
+      let deadline = sent_at + self.timeout;
+      loop {
+        let now = time::Instant::now();
+        if now >= deadline {
+          break;
+        }
+        socket.set_read_timeout(Some(deadline - now)).map_err(|e| format!("{}", &e))?;
+        match socket.recv_from(&mut buf) {
+          Ok((n, from)) => {
+            let matches = match parse_echo_reply(v6, raw, &buf[..n]) {
+              Some((reply_id, reply_seq, reply_payload)) => from.ip() == addr && reply_seq == seq && reply_payload == &payload[..] && (!raw || reply_id == id),
+              None => false,
+            };
+            if matches {
+              rtts.push(sent_at.elapsed().as_secs_f64() * 1000f64);
+              break;
+            }
+          },
+          Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+          Err(e) => return Err(format!("Receiving from {}: {}", addr, &e)),
+        }
+      }
+      if seq + 1 < self.count {
+        let next = sent_at + self.interval;
+        let now = time::Instant::now();
+        if next > now {
+          std::thread::sleep(next - now);
+        }
+      }
+    }
+    Ok(rtts)
+  }
+
+  /// Ping `addr`, and apply the thresholds.
+  fn check_addr(&self, addr: IpAddr) -> CheckResult {
+    let rtts = match self.ping(addr) {
+      Ok(rtts) => rtts,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let sent = self.count as usize;
+    let loss = (sent - rtts.len()) as f64 * 100f64 / sent as f64;
+    if rtts.is_empty() {
+      return CheckResult::error(Some(format!("No reply from {} ({}) to {} echo requests.", &self.host, addr, sent))).with_metric("loss", loss, "%");
+    }
+    let min = rtts.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = rtts.iter().cloned().fold(0f64, f64::max);
+    let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+    let result_type = std::cmp::max(self.loss_threshold.evaluate(loss), self.rtt_threshold.evaluate(avg));
+    let info = format!("{} ({}): {} sent, {} received, {:.0}% loss, rtt min/avg/max {:.3}/{:.3}/{:.3}ms.", &self.host, addr, sent, rtts.len(), loss, min, avg, max);
+    CheckResult::new(result_type, Some(info))
+      .with_metric("loss", loss, "%")
+      .with_metric("rtt_min", min, "ms")
+      .with_metric("rtt_avg", avg, "ms")
+      .with_metric("rtt_max", max, "ms")
+  }
+}
+
+impl Checker for IcmpChecker {
+  fn check(&mut self) -> CheckResult {
+    let addrs = match resolve_addresses(&self.host, 0, self.address_family, self.timeout) {
+      Ok(addrs) => addrs,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    if self.address_family != AddressFamily::Each {
+      return self.check_addr(addrs[0].ip());
+    }
+    let results: Vec<(IpAddr, CheckResult)> = addrs.into_iter().map(|a| (a.ip(), self.check_addr(a.ip()))).collect();
+    merge_address_results(results, &[], true)
+  }
+}
+
+#[test]
+fn echo_packet_test() {
+  let request = echo_request(false, 0x1234, 7, b"abc");
+  assert_eq!(checksum(&request), 0);
+  let mut reply = request.clone();
+  reply[0] = ECHO_REPLY_V4;
+  assert_eq!(parse_echo_reply(false, false, &reply), Some((0x1234, 7, &b"abc"[..])));
+  let mut with_ip_header = vec![0x45];
+  with_ip_header.extend_from_slice(&[0; 19]);
+  with_ip_header.extend_from_slice(&reply);
+  assert_eq!(parse_echo_reply(false, true, &with_ip_header), Some((0x1234, 7, &b"abc"[..])));
+  assert_eq!(parse_echo_reply(false, false, &request), None);
+  let request = echo_request(true, 1, 2, b"");
+  assert_eq!(request, vec![ECHO_REQUEST_V6, 0, 0, 0, 0, 1, 0, 2]);
+}
+
+#[test]
+fn icmp_checker_test() {
+  use crate::checkers::CheckResultType;
+  if let Err(e) = open_socket(&"127.0.0.1".parse().unwrap()) {
+    let denied = [libc::EPERM, libc::EACCES].iter().any(|c| e.contains(&io::Error::from_raw_os_error(*c).to_string()));
+    if denied {
+      eprintln!("Skipping icmp_checker_test: not allowed to open ICMP sockets here: {}", e);
+      return;
+    }
+    panic!("{}", e);
+  }
+  for host in &["127.0.0.1", "::1"] {
+    let res = IcmpChecker::new(host).set_count(3).set_interval(time::Duration::from_millis(10)).check();
+    assert_eq!(res.result_type, CheckResultType::UP, "{:?}", res);
+    assert_eq!(res.get_metric("loss"), Some(0f64));
+    assert!(res.get_metric("rtt_max").unwrap() >= res.get_metric("rtt_min").unwrap());
+  }
+  let res = IcmpChecker::new("127.0.0.1").set_count(2).set_rtt_threshold(Threshold::new(Some(0f64), None)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("2 sent, 2 received, 0% loss"));
+
+  let res = IcmpChecker::new("127.0.0.1").set_count(1).set_address_family(AddressFamily::Each).check();
+  res.expect();
+  assert!(res.info.unwrap().starts_with("127.0.0.1: UP: 127.0.0.1 (127.0.0.1): 1 sent, 1 received"));
+  IcmpChecker::new("127.0.0.1").set_address_family(AddressFamily::V6).check().expect_err_contains("127.0.0.1 has no IPv6 addresses.");
+}
@@ -0,0 +1,364 @@
+//! Check resources of the machine serverwatch is running on: disk space,
+//! inodes, memory, swap and load average.
+//!
+//! These only work on Linux, and are enabled with the `local` feature.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType, Threshold};
+use std::ffi::CString;
+use std::fs;
+use std::path::PathBuf;
+
+/// Collects the measured values of a check, and the ones which went over
+/// their threshold.
+struct Measurements {
+  result_type: CheckResultType,
+  problems: Vec<String>,
+  summary: Vec<String>,
+  result: CheckResult,
+}
+
+impl Measurements {
+  fn new() -> Self {
+    Measurements{result_type: CheckResultType::UP, problems: Vec::new(), summary: Vec::new(), result: CheckResult::up(None)}
+  }
+
+  fn metric(&mut self, name: &str, value: f64, unit: &str) {
+    self.result.metrics.push(crate::checkers::Metric{name: name.to_owned(), value, unit: unit.to_owned()});
+  }
+
+  fn checked(&mut self, desc: String, threshold: &Threshold, value: f64) {
+    let t = threshold.evaluate(value);
+    if t != CheckResultType::UP {
+      self.result_type = self.result_type.max(t);
+      self.problems.push(desc);
+    } else {
+      self.summary.push(desc);
+    }
+  }
+
+  fn finish(self) -> CheckResult {
+    let mut result = self.result;
+    result.result_type = self.result_type;
+    let mut lines = self.problems;
+    lines.extend(self.summary);
+    result.info = Some(lines.join(", "));
+    result
+  }
+}
+
+fn format_bytes(bytes: f64) -> String {
+  const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+  let mut value = bytes;
+  let mut unit = 0;
+  while value >= 1024f64 && unit < UNITS.len() - 1 {
+    value /= 1024f64;
+    unit += 1;
+  }
+  format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Check disk space and inode usage of the filesystem mounted at a path, with
+/// `statvfs`.
+///
+/// Usage is calculated the same way as `df`, so space reserved for root counts
+/// as used. The result has the metrics `usage` and `inode_usage` (percent), and
+/// `used`, `available` and `total` (bytes). `inode_usage` is left out for
+/// filesystems which do not have a fixed number of inodes, like btrfs.
+///
+/// Use one checker per mount point, each with its own thresholds.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, local::DiskChecker};
+/// let mut checker = DiskChecker::new("/");
+/// checker.set_usage_threshold(Threshold::new(Some(80f64), Some(95f64)));
+/// let result = checker.check();
+/// assert!(result.get_metric("usage").is_some());
+/// ```
+pub struct DiskChecker {
+  path: PathBuf,
+  usage_threshold: Threshold,
+  inode_threshold: Threshold,
+}
+
+impl DiskChecker {
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    DiskChecker{
+      path: path.into(),
+      usage_threshold: Threshold::new(Some(90f64), Some(95f64)),
+      inode_threshold: Threshold::new(Some(90f64), Some(95f64)),
+    }
+  }
+
+  /// Thresholds on the percentage of space used.
+  ///
+  /// Default is to warn at 90% and error at 95%.
+  pub fn set_usage_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.usage_threshold = value;
+    self
+  }
+
+  /// Thresholds on the percentage of inodes used.
+  ///
+  /// Default is to warn at 90% and error at 95%.
+  pub fn set_inode_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.inode_threshold = value;
+    self
+  }
+}
+
+impl Checker for DiskChecker {
+  fn check(&mut self) -> CheckResult {
+    let path_c = match CString::new(self.path.to_string_lossy().into_owned()) {
+      Ok(p) => p,
+      Err(_) => return CheckResult::error(Some(format!("Invalid path {}", self.path.display()))),
+    };
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path_c.as_ptr(), &mut st) } != 0 {
+      return CheckResult::error(Some(format!("statvfs {}: {}", self.path.display(), std::io::Error::last_os_error())));
+    }
+    let frsize = st.f_frsize as f64;
+    let total = st.f_blocks as f64 * frsize;
+    let used = (st.f_blocks as f64 - st.f_bfree as f64) * frsize;
+    let available = st.f_bavail as f64 * frsize;
+    let mut m = Measurements::new();
+    let usage = if used + available > 0f64 { used / (used + available) * 100f64 } else { 0f64 };
+    m.metric("usage", usage, "%");
+    m.metric("used", used, "B");
+    m.metric("available", available, "B");
+    m.metric("total", total, "B");
+    m.checked(format!("{}: {:.1}% used ({} available)", self.path.display(), usage, format_bytes(available)), &self.usage_threshold, usage);
+    if st.f_files > 0 {
+      let inode_usage = (st.f_files as f64 - st.f_ffree as f64) / st.f_files as f64 * 100f64;
+      m.metric("inode_usage", inode_usage, "%");
+      m.checked(format!("{:.1}% inodes used", inode_usage), &self.inode_threshold, inode_usage);
+    }
+    m.finish()
+  }
+}
+
+/// Parse `/proc/meminfo` into (key, bytes) pairs.
+fn parse_meminfo(content: &str) -> Vec<(&str, f64)> {
+  content.lines().filter_map(|line| {
+    let mut parts = line.split_whitespace();
+    let key = parts.next()?.trim_end_matches(':');
+    let value: f64 = parts.next()?.parse().ok()?;
+    let multiplier = match parts.next() {
+      Some("kB") => 1024f64,
+      None => 1f64,
+      Some(_) => return None,
+    };
+    Some((key, value * multiplier))
+  }).collect()
+}
+
+/// Check memory and swap usage, from `/proc/meminfo`.
+///
+/// Memory usage counts memory which can be freed when needed, like page cache,
+/// as available (`MemAvailable`). The result has the metrics `memory_usage` and
+/// `swap_usage` (percent) and `memory_available` and `swap_used` (bytes). Swap
+/// metrics are left out when there is no swap.
+pub struct MemoryChecker {
+  proc_path: PathBuf,
+  memory_threshold: Threshold,
+  swap_threshold: Threshold,
+}
+
+impl MemoryChecker {
+  pub fn new() -> Self {
+    MemoryChecker{
+      proc_path: PathBuf::from("/proc"),
+      memory_threshold: Threshold::new(Some(90f64), Some(95f64)),
+      swap_threshold: Threshold::default(),
+    }
+  }
+
+  /// Thresholds on the percentage of memory used.
+  ///
+  /// Default is to warn at 90% and error at 95%.
+  pub fn set_memory_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.memory_threshold = value;
+    self
+  }
+
+  /// Thresholds on the percentage of swap used.
+  ///
+  /// Default is none.
+  pub fn set_swap_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.swap_threshold = value;
+    self
+  }
+
+  /// Read from `value` instead of `/proc`, for example when the host's `/proc`
+  /// is mounted elsewhere in a container.
+  pub fn set_proc_path<P: Into<PathBuf>>(&mut self, value: P) -> &mut Self {
+    self.proc_path = value.into();
+    self
+  }
+}
+
+impl Default for MemoryChecker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Checker for MemoryChecker {
+  fn check(&mut self) -> CheckResult {
+    let path = self.proc_path.join("meminfo");
+    let content = match fs::read_to_string(&path) {
+      Ok(c) => c,
+      Err(e) => return CheckResult::error(Some(format!("Reading {}: {}", path.display(), &e))),
+    };
+    let meminfo = parse_meminfo(&content);
+    let get = |key: &str| meminfo.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let total = match get("MemTotal") {
+      Some(t) if t > 0f64 => t,
+      _ => return CheckResult::error(Some(format!("No MemTotal in {}", path.display()))),
+    };
+    let available = get("MemAvailable").unwrap_or_else(|| {
+      // Kernels before 3.14.
+      get("MemFree").unwrap_or(0f64) + get("Buffers").unwrap_or(0f64) + get("Cached").unwrap_or(0f64)
+    });
+    let mut m = Measurements::new();
+    let memory_usage = (total - available) / total * 100f64;
+    m.metric("memory_usage", memory_usage, "%");
+    m.metric("memory_available", available, "B");
+    m.checked(format!("memory {:.1}% used ({} available)", memory_usage, format_bytes(available)), &self.memory_threshold, memory_usage);
+    match (get("SwapTotal"), get("SwapFree")) {
+      (Some(swap_total), Some(swap_free)) if swap_total > 0f64 => {
+        let swap_used = swap_total - swap_free;
+        let swap_usage = swap_used / swap_total * 100f64;
+        m.metric("swap_usage", swap_usage, "%");
+        m.metric("swap_used", swap_used, "B");
+        m.checked(format!("swap {:.1}% used", swap_usage), &self.swap_threshold, swap_usage);
+      },
+      _ => {}
+    }
+    m.finish()
+  }
+}
+
+/// Check the load average, from `/proc/loadavg`.
+///
+/// The result has the metrics `load1`, `load5` and `load15`. With
+/// [`set_per_cpu`](crate::checkers::local::LoadChecker::set_per_cpu), these
+/// (and the thresholds) are divided by the number of online CPUs.
+pub struct LoadChecker {
+  proc_path: PathBuf,
+  thresholds: [Threshold; 3],
+  per_cpu: bool,
+}
+
+impl LoadChecker {
+  /// By default there are no thresholds, so this only records the load.
+  pub fn new() -> Self {
+    LoadChecker{proc_path: PathBuf::from("/proc"), thresholds: [Threshold::default(); 3], per_cpu: false}
+  }
+
+  /// Set the thresholds for the 1, 5 and 15 minute load averages.
+  pub fn set_thresholds(&mut self, load1: Threshold, load5: Threshold, load15: Threshold) -> &mut Self {
+    self.thresholds = [load1, load5, load15];
+    self
+  }
+
+  pub fn set_per_cpu(&mut self, value: bool) -> &mut Self {
+    self.per_cpu = value;
+    self
+  }
+
+  /// Same as
+  /// [`MemoryChecker::set_proc_path`](crate::checkers::local::MemoryChecker::set_proc_path).
+  pub fn set_proc_path<P: Into<PathBuf>>(&mut self, value: P) -> &mut Self {
+    self.proc_path = value.into();
+    self
+  }
+}
+
+impl Default for LoadChecker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Checker for LoadChecker {
+  fn check(&mut self) -> CheckResult {
+    let path = self.proc_path.join("loadavg");
+    let content = match fs::read_to_string(&path) {
+      Ok(c) => c,
+      Err(e) => return CheckResult::error(Some(format!("Reading {}: {}", path.display(), &e))),
+    };
+    let loads: Vec<f64> = content.split_whitespace().take(3).filter_map(|s| s.parse().ok()).collect();
+    if loads.len() != 3 {
+      return CheckResult::error(Some(format!("Unable to parse {}: {:?}", path.display(), content.trim())));
+    }
+    let divisor = if self.per_cpu {
+      let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+      if cpus > 0 { cpus as f64 } else { 1f64 }
+    } else {
+      1f64
+    };
+    let mut m = Measurements::new();
+    for (i, (name, load)) in ["load1", "load5", "load15"].iter().zip(loads).enumerate() {
+      let load = load / divisor;
+      m.metric(name, load, "");
+      m.checked(format!("{} {:.2}", name, load), &self.thresholds[i], load);
+    }
+    if self.per_cpu {
+      m.summary.push(format!("per CPU ({} CPUs)", divisor));
+    }
+    m.finish()
+  }
+}
+
+#[cfg(test)]
+fn fake_proc(name: &str, meminfo: &str, loadavg: &str) -> PathBuf {
+  let dir = crate::utils::testing::temp_dir(name);
+  fs::write(dir.join("meminfo"), meminfo).unwrap();
+  fs::write(dir.join("loadavg"), loadavg).unwrap();
+  dir
+}
+
+#[test]
+fn memory_and_load_test() {
+  let proc_path = fake_proc("local", "MemTotal:        1000 kB\nMemFree:          100 kB\nMemAvailable:     250 kB\nSwapTotal:        400 kB\nSwapFree:         100 kB\n", "1.50 0.75 3.00 2/70 10471\n");
+  let res = MemoryChecker::new().set_proc_path(&proc_path).check();
+  res.expect();
+  assert_eq!(res.get_metric("memory_usage"), Some(75f64));
+  assert_eq!(res.get_metric("memory_available"), Some(250f64 * 1024f64));
+  assert_eq!(res.get_metric("swap_usage"), Some(75f64));
+  let res = MemoryChecker::new().set_proc_path(&proc_path)
+    .set_memory_threshold(Threshold::new(Some(70f64), None))
+    .set_swap_threshold(Threshold::new(Some(50f64), Some(75f64))).check();
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.info.as_ref().unwrap(), "memory 75.0% used (250.0 KiB available), swap 75.0% used");
+  let res = MemoryChecker::new().set_proc_path(&proc_path)
+    .set_swap_threshold(Threshold::new(Some(50f64), Some(80f64))).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.as_ref().unwrap().starts_with("swap 75.0% used, memory"));
+
+  let res = LoadChecker::new().set_proc_path(&proc_path).check();
+  res.expect();
+  assert_eq!(res.get_metric("load15"), Some(3f64));
+  let res = LoadChecker::new().set_proc_path(&proc_path).set_thresholds(
+    Threshold::new(Some(2f64), Some(4f64)), Threshold::new(Some(2f64), Some(4f64)), Threshold::new(Some(2f64), Some(4f64))).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.as_ref().unwrap().starts_with("load15 3.00, load1 1.50"));
+
+  fs::write(proc_path.join("meminfo"), "MemFree: 100 kB\n").unwrap();
+  MemoryChecker::new().set_proc_path(&proc_path).check().expect_err_contains("No MemTotal");
+  fs::remove_dir_all(&proc_path).unwrap();
+  LoadChecker::new().set_proc_path(&proc_path).check().expect_err_contains("Reading");
+}
+
+#[test]
+fn disk_test() {
+  let res = DiskChecker::new("/").set_usage_threshold(Threshold::default()).set_inode_threshold(Threshold::default()).check();
+  res.expect();
+  let usage = res.get_metric("usage").unwrap();
+  assert!((0f64..=100f64).contains(&usage));
+  assert!(res.get_metric("total").unwrap() > 0f64);
+  let res = DiskChecker::new("/").set_usage_threshold(Threshold::new(Some(-1f64), None)).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  DiskChecker::new("/nonexistent/mount").check().expect_err_contains("statvfs /nonexistent/mount");
+}
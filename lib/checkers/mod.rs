@@ -15,14 +15,56 @@ pub trait Checker {
 }
 
 /// The result of a check, along with some additional information, if available.
+///
+/// Construct one with [`new`](crate::checkers::CheckResult::new) or
+/// [`up`](crate::checkers::CheckResult::up) etc. More fields may be added, so
+/// struct literals only work within this crate.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct CheckResult {
   pub result_type: CheckResultType,
   /// optional information, which may be displayed by the [`check()`](crate::checkers::Checker::check) caller.
   pub info: Option<String>,
+  /// Values measured by the check, such as the number of days until a
+  /// certificate expires.
+  pub metrics: Vec<Metric>,
+  /// Whether the caller should send a notification for this result.
+  pub notify: NotifyHint,
 }
 
+/// A named numeric value measured by a check.
+#[derive(Debug, Clone)]
+pub struct Metric {
+  pub name: String,
+  pub value: f64,
+  /// e.g. `"days"`, `"ms"` or `"%"`. Empty if unitless.
+  pub unit: String,
+}
+
+impl PartialEq for Metric {
+  fn eq(&self, other: &Self) -> bool {
+    self.name == other.name && self.value.to_bits() == other.value.to_bits() && self.unit == other.unit
+  }
+}
+
+impl Eq for Metric {}
+
+/// Tells the [`check()`](crate::checkers::Checker::check) caller whether to
+/// notify anyone about a result.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NotifyHint {
+  /// Notify if the result is not `UP`.
+  Default,
+  /// Notify even if the result is `UP`, for example when an informational
+  /// threshold has just been crossed.
+  Always,
+  /// Don't notify, because the checker has already asked for a notification
+  /// about the same condition.
+  Never,
+}
+
+/// Ordered from best to worst, so that `max()` gives the worst of several results.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum CheckResultType {
   /// <span style="color: green">Everything's good.</span>
   UP,
@@ -33,17 +75,49 @@ pub enum CheckResultType {
 }
 
 impl CheckResult {
+  /// Construct a result of type `result_type`, with no metrics.
+  pub fn new(result_type: CheckResultType, info: Option<String>) -> Self {
+    CheckResult{result_type, info, metrics: Vec::new(), notify: NotifyHint::Default}
+  }
   /// Construct an `UP` result.
   pub fn up(info: Option<String>) -> Self {
-    CheckResult{result_type: CheckResultType::UP, info}
+    Self::new(CheckResultType::UP, info)
   }
   /// Construct an `ERROR` result.
   pub fn error(info: Option<String>) -> Self {
-    CheckResult{result_type: CheckResultType::ERROR, info}
+    Self::new(CheckResultType::ERROR, info)
   }
   /// Construct an `WARN` result.
   pub fn warn(info: Option<String>) -> Self {
-    CheckResult{result_type: CheckResultType::WARN, info}
+    Self::new(CheckResultType::WARN, info)
+  }
+
+  /// Add a measured value to this result.
+  pub fn with_metric(mut self, name: &str, value: f64, unit: &str) -> Self {
+    self.metrics.push(Metric{name: name.to_owned(), value, unit: unit.to_owned()});
+    self
+  }
+
+  /// Get the value of the metric called `name`, if any.
+  pub fn get_metric(&self, name: &str) -> Option<f64> {
+    self.metrics.iter().find(|m| m.name == name).map(|m| m.value)
+  }
+
+  /// Set whether the caller should send a notification for this result,
+  /// instead of deciding by its type.
+  pub fn with_notify(mut self, notify: NotifyHint) -> Self {
+    self.notify = notify;
+    self
+  }
+
+  /// Whether a notification should be sent for this result, according to
+  /// [`notify`](crate::checkers::CheckResult::notify).
+  pub fn should_notify(&self) -> bool {
+    match self.notify {
+      NotifyHint::Default => self.result_type != CheckResultType::UP,
+      NotifyHint::Always => true,
+      NotifyHint::Never => false,
+    }
   }
 
   pub fn expect(&self) {
@@ -78,5 +152,165 @@ impl Display for CheckResult {
   }
 }
 
+/// A pair of warn / error thresholds for one measured value. A value at or
+/// above a threshold triggers it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Threshold {
+  pub warn: Option<f64>,
+  pub error: Option<f64>,
+}
+
+impl Threshold {
+  pub fn new(warn: Option<f64>, error: Option<f64>) -> Self {
+    Threshold{warn, error}
+  }
+
+  #[cfg(any(feature = "checkers", feature = "local"))]
+  pub(crate) fn evaluate(&self, value: f64) -> CheckResultType {
+    match (self.warn, self.error) {
+      (_, Some(e)) if value >= e => CheckResultType::ERROR,
+      (Some(w), _) if value >= w => CheckResultType::WARN,
+      _ => CheckResultType::UP,
+    }
+  }
+}
+
+/// Which addresses of a host a checker connects to. Useful on dual-stack
+/// hosts, where a broken AAAA record or IPv6 route would otherwise hide
+/// behind a working IPv4 path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+  /// Let the OS pick an address.
+  #[default]
+  Any,
+  /// Only connect over IPv4.
+  V4,
+  /// Only connect over IPv6.
+  V6,
+  /// Check every resolved address separately. The result lists the outcome
+  /// for each address. For checks of reachability, like HTTP and ping, if
+  /// some addresses are `UP`, failures on the others are reported as `WARN`,
+  /// since the service is still reachable. Certificate checks report the worst
+  /// outcome, since clients connecting to that address are affected.
+  Each,
+}
+
+#[cfg(feature = "checkers")]
+impl AddressFamily {
+  fn includes(self, addr: &std::net::SocketAddr) -> bool {
+    match self {
+      AddressFamily::V4 => addr.is_ipv4(),
+      AddressFamily::V6 => addr.is_ipv6(),
+      AddressFamily::Any | AddressFamily::Each => true,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      AddressFamily::V4 => "IPv4 ",
+      AddressFamily::V6 => "IPv6 ",
+      AddressFamily::Any | AddressFamily::Each => "",
+    }
+  }
+}
+
+/// Resolve `host` within `timeout`, keeping only the addresses of `family`.
+#[cfg(feature = "checkers")]
+pub(crate) fn resolve_addresses(host: &str, port: u16, family: AddressFamily, timeout: std::time::Duration) -> Result<Vec<std::net::SocketAddr>, String> {
+  use std::net::ToSocketAddrs;
+  let owned_host = host.to_owned();
+  let addrs = match crate::utils::with_timeout(move || (&owned_host[..], port).to_socket_addrs().map(|a| a.collect::<Vec<_>>()), timeout) {
+    Some(Ok(addrs)) => addrs,
+    Some(Err(e)) => return Err(format!("Unable to resolve {}: {}", host, &e)),
+    None => return Err(format!("Timed out resolving {}", host)),
+  };
+  let addrs: Vec<_> = addrs.into_iter().filter(|a| family.includes(a)).collect();
+  if addrs.is_empty() {
+    return Err(format!("{} has no {}addresses.", host, family.name()));
+  }
+  Ok(addrs)
+}
+
+/// Combine the results of checking each address of a host into one result,
+/// which lists every address's outcome. The result type is the worst of them
+/// all, but if `cap_partial_failures` is set, no worse than `WARN` if any
+/// address is `UP`. Also keeps the lowest value of each of `min_metrics`.
+#[cfg(feature = "checkers")]
+pub(crate) fn merge_address_results<A: Display>(results: Vec<(A, CheckResult)>, min_metrics: &[&str], cap_partial_failures: bool) -> CheckResult {
+  let worst = results.iter().map(|(_, r)| r.result_type).max().unwrap_or(CheckResultType::ERROR);
+  let any_up = results.iter().any(|(_, r)| r.result_type == CheckResultType::UP);
+  let result_type = if cap_partial_failures && any_up { std::cmp::min(worst, CheckResultType::WARN) } else { worst };
+  let num_bad = results.iter().filter(|(_, r)| r.result_type != CheckResultType::UP).count();
+  let mut lines = Vec::new();
+  if num_bad > 0 {
+    lines.push(format!("{} of {} addresses failed.", num_bad, results.len()));
+  }
+  for (addr, r) in results.iter() {
+    lines.push(format!("{}: {}", addr, r));
+  }
+  let mut merged = CheckResult::new(result_type, Some(lines.join("\n")));
+  for name in min_metrics {
+    if let Some(m) = results.iter().filter_map(|(_, r)| r.metrics.iter().find(|m| m.name == *name)).min_by(|a, b| a.value.partial_cmp(&b.value).unwrap_or(std::cmp::Ordering::Equal)) {
+      merged.metrics.push(m.clone());
+    }
+  }
+  merged
+}
+
+/// Summarize several results, all of which are not `UP`, in one line like
+/// `2 expect checks reported WARN: info 1, info 2`, using the worst of their
+/// types.
+pub(crate) fn summarize_results(what: &str, results: &[CheckResult]) -> String {
+  use std::fmt::Write;
+  let worst = results.iter().map(|r| r.result_type).max().unwrap_or(CheckResultType::UP);
+  let mut f = String::new();
+  write!(f, "{} {} reported {:?}: ", results.len(), what, worst).unwrap();
+  let mut is_first = true;
+  for usr in results.iter() {
+    if !is_first {
+      write!(f, ", ").unwrap();
+    }
+    is_first = false;
+    if let Some(ref info) = usr.info {
+      write!(f, "{}", info).unwrap();
+    } else {
+      write!(f, "(no info)").unwrap();
+    }
+  }
+  f
+}
+
+/// Format a duration like `1d 2h 3m`, leaving out leading zero units.
+pub(crate) fn format_duration(value: std::time::Duration) -> String {
+  let secs = value.as_secs();
+  let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+  if days > 0 {
+    format!("{}d {}h {}m", days, hours, mins)
+  } else if hours > 0 {
+    format!("{}h {}m", hours, mins)
+  } else if mins > 0 {
+    format!("{}m {}s", mins, secs % 60)
+  } else {
+    format!("{}s", secs)
+  }
+}
+
+pub mod heartbeat;
+pub mod composite;
+#[cfg(feature = "async")] pub mod async_checker;
 #[cfg(feature = "checkers")] pub mod http;
+#[cfg(feature = "checkers")] pub mod realtime;
+#[cfg(feature = "checkers")] pub mod grpc;
 #[cfg(feature = "checkers")] pub mod tls;
+#[cfg(feature = "checkers")] pub mod domain;
+#[cfg(feature = "checkers")] pub mod email_auth;
+#[cfg(feature = "checkers")] pub mod dns_consistency;
+#[cfg(feature = "checkers")] pub mod cert_file;
+#[cfg(feature = "checkers")] pub mod command;
+#[cfg(feature = "checkers")] pub mod file_freshness;
+#[cfg(feature = "checkers")] pub mod database;
+#[cfg(feature = "checkers")] pub mod udp;
+#[cfg(feature = "checkers")] pub mod ssh;
+#[cfg(feature = "checkers")] pub mod icmp;
+#[cfg(feature = "local")] pub mod local;
+#[cfg(feature = "local")] pub mod process;
@@ -0,0 +1,389 @@
+//! Check that local processes are running and that sockets are listening, by
+//! reading `/proc`.
+//!
+//! These only work on Linux, and are enabled with the `local` feature.
+
+use crate::checkers::{Checker, CheckResult};
+use regex::Regex;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+enum ProcessMatch {
+  Name(String),
+  Cmdline(Regex),
+  Pidfile(PathBuf),
+}
+
+/// A process found in `/proc`.
+struct ProcessInfo {
+  pid: u32,
+  comm: String,
+  state: char,
+  cmdline: Vec<String>,
+}
+
+/// Parse `/proc/<pid>/stat` into (comm, state). comm is in parentheses and may
+/// itself contain spaces and parentheses.
+fn parse_stat(stat: &str) -> Option<(String, char)> {
+  let start = stat.find('(')?;
+  let end = stat.rfind(')')?;
+  if end < start {
+    return None;
+  }
+  let state = stat[end + 1..].trim_start().chars().next()?;
+  Some((stat[start + 1..end].to_owned(), state))
+}
+
+fn read_process(proc_path: &Path, pid: u32) -> Option<ProcessInfo> {
+  let dir = proc_path.join(pid.to_string());
+  let (comm, state) = parse_stat(&fs::read_to_string(dir.join("stat")).ok()?)?;
+  let cmdline = fs::read(dir.join("cmdline")).unwrap_or_default();
+  let cmdline = cmdline.split(|b| *b == 0).filter(|a| !a.is_empty()).map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+  Some(ProcessInfo{pid, comm, state, cmdline})
+}
+
+fn list_processes(proc_path: &Path) -> Result<Vec<ProcessInfo>, String> {
+  let entries = fs::read_dir(proc_path).map_err(|e| format!("Listing {}: {}", proc_path.display(), &e))?;
+  let mut processes: Vec<ProcessInfo> = entries.filter_map(|entry| {
+    let pid: u32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+    // The process may have exited since listing.
+    read_process(proc_path, pid)
+  }).collect();
+  processes.sort_by_key(|p| p.pid);
+  Ok(processes)
+}
+
+/// Check that the number of running processes matching some criteria is within
+/// a range, by default at least one.
+///
+/// Processes can be matched by name (as in `/proc/<pid>/stat` or the file name
+/// of the executable in the command line), by a regex on the whole command line
+/// (arguments joined by spaces), or by a pidfile. Zombie processes and
+/// serverwatch itself never match.
+///
+/// A maximum count is useful to catch cron jobs or daemons which were started
+/// twice. The result has the metric `count`.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, process::ProcessChecker};
+/// let mut checker = ProcessChecker::by_cmdline(r"^/usr/sbin/nginx\b").unwrap();
+/// checker.set_min_count(1).set_max_count(Some(16));
+/// let result = checker.check();
+/// ```
+pub struct ProcessChecker {
+  matcher: ProcessMatch,
+  min_count: usize,
+  max_count: Option<usize>,
+  proc_path: PathBuf,
+}
+
+impl ProcessChecker {
+  fn new(matcher: ProcessMatch) -> Self {
+    ProcessChecker{matcher, min_count: 1, max_count: None, proc_path: PathBuf::from("/proc")}
+  }
+
+  /// Match processes named `name`. Since the kernel truncates process names to
+  /// 15 bytes, only the first 15 bytes of `name` are compared against those.
+  pub fn by_name(name: &str) -> Self {
+    Self::new(ProcessMatch::Name(name.to_owned()))
+  }
+
+  /// Match processes whose command line matches `regex`.
+  pub fn by_cmdline(regex: &str) -> Result<Self, String> {
+    let regex = Regex::new(regex).map_err(|e| format!("Invalid regex: {}", &e))?;
+    Ok(Self::new(ProcessMatch::Cmdline(regex)))
+  }
+
+  /// Match the process whose pid is in the file at `path`. A missing pidfile
+  /// counts as no process.
+  pub fn by_pidfile<P: Into<PathBuf>>(path: P) -> Self {
+    Self::new(ProcessMatch::Pidfile(path.into()))
+  }
+
+  /// Default is 1.
+  pub fn set_min_count(&mut self, value: usize) -> &mut Self {
+    self.min_count = value;
+    self
+  }
+
+  /// Default is `None`, for no maximum.
+  pub fn set_max_count(&mut self, value: Option<usize>) -> &mut Self {
+    self.max_count = value;
+    self
+  }
+
+  /// Same as
+  /// [`MemoryChecker::set_proc_path`](crate::checkers::local::MemoryChecker::set_proc_path).
+  pub fn set_proc_path<P: Into<PathBuf>>(&mut self, value: P) -> &mut Self {
+    self.proc_path = value.into();
+    self
+  }
+
+  fn describe(&self) -> String {
+    match self.matcher {
+      ProcessMatch::Name(ref name) => format!("processes named {}", name),
+      ProcessMatch::Cmdline(ref regex) => format!("processes matching /{}/", regex.as_str()),
+      ProcessMatch::Pidfile(ref path) => format!("process in {}", path.display()),
+    }
+  }
+
+  fn find(&self) -> Result<Vec<ProcessInfo>, String> {
+    let myself = std::process::id();
+    let matches = |p: &ProcessInfo| -> bool {
+      if p.pid == myself || p.state == 'Z' {
+        return false;
+      }
+      match self.matcher {
+        ProcessMatch::Name(ref name) => {
+          let truncated = name.as_bytes().get(..15).unwrap_or(name.as_bytes());
+          p.comm.as_bytes() == truncated ||
+            p.cmdline.first().map(|arg0| Path::new(arg0).file_name().map(|f| f == &name[..]).unwrap_or(false)).unwrap_or(false)
+        },
+        ProcessMatch::Cmdline(ref regex) => regex.is_match(&p.cmdline.join(" ")),
+        ProcessMatch::Pidfile(_) => true,
+      }
+    };
+    if let ProcessMatch::Pidfile(ref path) = self.matcher {
+      let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Reading {}: {}", path.display(), &e)),
+      };
+      let pid: u32 = content.trim().parse().map_err(|_| format!("{} does not contain a pid.", path.display()))?;
+      return Ok(read_process(&self.proc_path, pid).into_iter().filter(matches).collect());
+    }
+    Ok(list_processes(&self.proc_path)?.into_iter().filter(matches).collect())
+  }
+}
+
+impl Checker for ProcessChecker {
+  fn check(&mut self) -> CheckResult {
+    let found = match self.find() {
+      Ok(f) => f,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let count = found.len();
+    let pids: Vec<String> = found.iter().take(10).map(|p| p.pid.to_string()).collect();
+    let mut info = format!("{} {} running", count, self.describe());
+    if !pids.is_empty() {
+      info.push_str(&format!(" (pid {}{})", pids.join(", "), if count > pids.len() { ", ..." } else { "" }));
+    }
+    let result = if count < self.min_count {
+      CheckResult::error(Some(format!("{}, expected at least {}.", info, self.min_count)))
+    } else if self.max_count.map(|max| count > max).unwrap_or(false) {
+      CheckResult::error(Some(format!("{}, expected at most {}.", info, self.max_count.unwrap())))
+    } else {
+      CheckResult::up(Some(format!("{}.", info)))
+    };
+    result.with_metric("count", count as f64, "")
+  }
+}
+
+const TCP_LISTEN: u8 = 0x0A;
+
+/// Parse a `/proc/net/tcp` or `/proc/net/tcp6` address like `0100007F:0016`.
+/// Addresses are written as 32 bit words in host byte order.
+fn parse_proc_net_addr(s: &str) -> Option<SocketAddr> {
+  let mut parts = s.split(':');
+  let addr = parts.next()?;
+  let port = u16::from_str_radix(parts.next()?, 16).ok()?;
+  let mut bytes = Vec::with_capacity(16);
+  for i in (0..addr.len()).step_by(8) {
+    let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+    bytes.extend_from_slice(&word.to_ne_bytes());
+  }
+  let ip = match bytes.len() {
+    4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+    16 => {
+      let mut octets = [0u8; 16];
+      octets.copy_from_slice(&bytes);
+      IpAddr::V6(Ipv6Addr::from(octets))
+    },
+    _ => return None,
+  };
+  Some(SocketAddr::new(ip, port))
+}
+
+/// Parse the listening sockets out of a `/proc/net/tcp{,6}` table.
+fn parse_proc_net_tcp(content: &str) -> Vec<SocketAddr> {
+  content.lines().skip(1).filter_map(|line| {
+    let mut fields = line.split_whitespace();
+    let local = parse_proc_net_addr(fields.nth(1)?)?;
+    let state = u8::from_str_radix(fields.nth(1)?, 16).ok()?;
+    if state == TCP_LISTEN { Some(local) } else { None }
+  }).collect()
+}
+
+enum ListenTarget {
+  Port(u16),
+  Addr(SocketAddr),
+}
+
+impl ListenTarget {
+  /// Whether a socket listening on `listening` accepts connections for this
+  /// target. Sockets bound to the unspecified address cover every address, and
+  /// `[::]` also covers IPv4 unless the socket is IPv6-only, which can not be
+  /// told from `/proc`.
+  fn covered_by(&self, listening: &SocketAddr) -> bool {
+    match self {
+      ListenTarget::Port(port) => listening.port() == *port,
+      ListenTarget::Addr(addr) => {
+        listening.port() == addr.port() && (listening.ip() == addr.ip() || match listening.ip() {
+          IpAddr::V4(ip) => ip.is_unspecified() && addr.is_ipv4(),
+          IpAddr::V6(ip) => ip.is_unspecified(),
+        })
+      }
+    }
+  }
+}
+
+impl std::fmt::Display for ListenTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      ListenTarget::Port(port) => write!(f, "port {}", port),
+      ListenTarget::Addr(addr) => write!(f, "{}", addr),
+    }
+  }
+}
+
+/// Check that TCP sockets are in `LISTEN` state on this machine, by reading
+/// `/proc/net/tcp` and `/proc/net/tcp6`.
+///
+/// This catches daemons which have died (or never bound their port) even when
+/// nothing probes the port from outside. Without any ports configured, the
+/// check fails rather than reporting `UP` for checking nothing.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, process::ListenChecker};
+/// let mut checker = ListenChecker::new();
+/// checker.add_port(22).add_address("127.0.0.1:5432".parse().unwrap());
+/// let result = checker.check();
+/// ```
+pub struct ListenChecker {
+  targets: Vec<ListenTarget>,
+  proc_path: PathBuf,
+}
+
+impl ListenChecker {
+  pub fn new() -> Self {
+    ListenChecker{targets: Vec::new(), proc_path: PathBuf::from("/proc")}
+  }
+
+  /// Expect something to listen on `port`, on any address.
+  pub fn add_port(&mut self, port: u16) -> &mut Self {
+    self.targets.push(ListenTarget::Port(port));
+    self
+  }
+
+  /// Expect something to accept connections on `addr`, either by listening on
+  /// that address or on all addresses.
+  pub fn add_address(&mut self, addr: SocketAddr) -> &mut Self {
+    self.targets.push(ListenTarget::Addr(addr));
+    self
+  }
+
+  /// Same as
+  /// [`MemoryChecker::set_proc_path`](crate::checkers::local::MemoryChecker::set_proc_path).
+  pub fn set_proc_path<P: Into<PathBuf>>(&mut self, value: P) -> &mut Self {
+    self.proc_path = value.into();
+    self
+  }
+}
+
+impl Default for ListenChecker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Checker for ListenChecker {
+  fn check(&mut self) -> CheckResult {
+    if self.targets.is_empty() {
+      return CheckResult::error(Some("no ports to check".to_owned()));
+    }
+    let mut listening = Vec::new();
+    for file in &["net/tcp", "net/tcp6"] {
+      let path = self.proc_path.join(file);
+      match fs::read_to_string(&path) {
+        Ok(content) => listening.extend(parse_proc_net_tcp(&content)),
+        // No IPv6 support in the kernel.
+        Err(ref e) if *file == "net/tcp6" && e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return CheckResult::error(Some(format!("Reading {}: {}", path.display(), &e))),
+      }
+    }
+    let missing: Vec<String> = self.targets.iter().filter(|t| !listening.iter().any(|l| t.covered_by(l))).map(|t| t.to_string()).collect();
+    if missing.is_empty() {
+      let all: Vec<String> = self.targets.iter().map(|t| t.to_string()).collect();
+      CheckResult::up(Some(format!("Listening on {}.", all.join(", "))))
+    } else {
+      CheckResult::error(Some(format!("Nothing listening on {}.", missing.join(", "))))
+    }
+  }
+}
+
+#[test]
+fn proc_parse_test() {
+  assert_eq!(parse_stat("1234 (tmux: server) S 1 1234"), Some(("tmux: server".to_owned(), 'S')));
+  assert_eq!(parse_stat("99 (a) b)) Z 1"), Some(("a) b)".to_owned(), 'Z')));
+  assert_eq!(parse_stat("garbage"), None);
+
+  let localhost = u32::from_ne_bytes([127, 0, 0, 1]);
+  let v6_first = u32::from_ne_bytes([0x20, 0x01, 0x0d, 0xb8]);
+  let v6_last = u32::from_ne_bytes([0, 0, 0, 1]);
+  let table = format!(
+    "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+     0: {:08X}:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1 1\n\
+     1: {:08X}:0050 {:08X}:D2F0 01 00000000:00000000 00:00000000 00000000     0        0 2 1\n\
+     2: {:08X}0000000000000000{:08X}:01BB 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 3 1\n",
+    localhost, 0, localhost, v6_first, v6_last);
+  assert_eq!(parse_proc_net_tcp(&table), vec![
+    "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+    "[2001:db8::1]:443".parse::<SocketAddr>().unwrap(),
+  ]);
+}
+
+#[test]
+fn process_checker_test() {
+  let pid_file = std::env::temp_dir().join(format!("serverwatch-test-process-{}.pid", std::process::id()));
+  let mut children: Vec<std::process::Child> = (0..2).map(|_| std::process::Command::new("sleep").arg("31.5").spawn().unwrap()).collect();
+  fs::write(&pid_file, format!("{}\n", children[0].id())).unwrap();
+
+  let res = ProcessChecker::by_cmdline(r"^sleep 31\.5$").unwrap().check();
+  res.expect();
+  assert_eq!(res.get_metric("count"), Some(2f64));
+  ProcessChecker::by_cmdline(r"^sleep 31\.5$").unwrap().set_max_count(Some(1)).check().expect_err_contains("expected at most 1");
+  ProcessChecker::by_cmdline(r"^sleep 31\.5$").unwrap().set_min_count(3).check().expect_err_contains("expected at least 3");
+  assert!(ProcessChecker::by_name("sleep").check().get_metric("count").unwrap() >= 2f64);
+  ProcessChecker::by_name("serverwatch-no-such-process").check().expect_err_contains("0 processes named");
+  assert!(ProcessChecker::by_cmdline("(").is_err());
+  ProcessChecker::by_pidfile(&pid_file).check().expect();
+
+  for c in children.iter_mut() {
+    c.kill().unwrap();
+    c.wait().unwrap();
+  }
+  ProcessChecker::by_pidfile(&pid_file).check().expect_err_contains("0 process in");
+  fs::remove_file(&pid_file).unwrap();
+  ProcessChecker::by_pidfile(&pid_file).check().expect_err_contains("expected at least 1");
+  ProcessChecker::by_pidfile(&pid_file).set_min_count(0).check().expect();
+}
+
+#[test]
+fn listen_checker_test() {
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  ListenChecker::new().add_port(addr.port()).add_address(addr).check().expect();
+  let other = "127.0.0.2:1".parse().unwrap();
+  ListenChecker::new().add_port(addr.port()).add_address(other).check().expect_err_contains("Nothing listening on 127.0.0.2:1.");
+  drop(listener);
+  ListenChecker::new().add_port(addr.port()).check().expect_err_contains(&format!("port {}", addr.port()));
+  ListenChecker::new().check().expect_err_contains("no ports to check");
+
+  assert!(ListenTarget::Addr("10.0.0.1:80".parse().unwrap()).covered_by(&"0.0.0.0:80".parse().unwrap()));
+  assert!(ListenTarget::Addr("10.0.0.1:80".parse().unwrap()).covered_by(&"[::]:80".parse().unwrap()));
+  assert!(!ListenTarget::Addr("[::1]:80".parse().unwrap()).covered_by(&"0.0.0.0:80".parse().unwrap()));
+  assert!(!ListenTarget::Addr("10.0.0.1:80".parse().unwrap()).covered_by(&"10.0.0.2:80".parse().unwrap()));
+}
@@ -0,0 +1,512 @@
+//! Checks for realtime endpoints, WebSocket and Server-Sent Events, which
+//! have to actually deliver a message to be considered up.
+
+use crate::checkers::{Checker, CheckResult};
+use crate::checkers::http::client::{Chunked, Connection, invalid_data};
+use crate::utils::DeadlineStream;
+use reqwest::Url;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Largest frame we accept.
+const MAX_FRAME_LEN: u64 = 16 << 20;
+
+fn matches(expect: &Option<String>, message: &[u8]) -> bool {
+  match expect {
+    Some(find) => find.is_empty() || message.windows(find.len()).any(|w| w == find.as_bytes()),
+    None => true,
+  }
+}
+
+/// Turn the outcome of waiting for a message into a result, with the
+/// `handshake` and `first_message` metrics.
+fn wait_result(start: time::Instant, handshake: time::Duration, waited: io::Result<Vec<u8>>, warn_timeout: time::Duration) -> CheckResult {
+  let handshake_ms = handshake.as_secs_f64() * 1000f64;
+  let message = match waited {
+    Ok(m) => m,
+    Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => {
+      return CheckResult::error(Some(format!("No matching message within {}ms.", start.elapsed().as_millis()))).with_metric("handshake", handshake_ms, "ms");
+    },
+    Err(e) => return CheckResult::error(Some(format!("{}", &e))).with_metric("handshake", handshake_ms, "ms"),
+  };
+  let elapsed = start.elapsed();
+  let elapsed_ms = elapsed.as_secs_f64() * 1000f64;
+  let shown: String = String::from_utf8_lossy(&message).chars().take(100).collect();
+  let result = if elapsed > warn_timeout {
+    CheckResult::warn(Some(format!("Server took {}ms to send a matching message: {:?}", elapsed.as_millis(), shown)))
+  } else {
+    CheckResult::up(Some(format!("Handshake in {}ms, matching message after {}ms: {:?}", handshake.as_millis(), elapsed.as_millis(), shown)))
+  };
+  result.with_metric("handshake", handshake_ms, "ms").with_metric("first_message", elapsed_ms, "ms")
+}
+
+/// Encode a frame. Frames from the client have to be masked.
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+  let mut frame = vec![0x80 | opcode];
+  let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+  if payload.len() < 126 {
+    frame.push(mask_bit | payload.len() as u8);
+  } else if payload.len() <= 0xffff {
+    frame.push(mask_bit | 126);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+  } else {
+    frame.push(mask_bit | 127);
+    frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+  }
+  match mask {
+    Some(mask) => {
+      frame.extend_from_slice(&mask);
+      frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    },
+    None => frame.extend_from_slice(payload),
+  }
+  frame
+}
+
+/// Read a frame, returning whether it is the final fragment, its opcode and
+/// its unmasked payload.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<(bool, u8, Vec<u8>)> {
+  let mut header = [0u8; 2];
+  reader.read_exact(&mut header)?;
+  let mut len = (header[1] & 0x7f) as u64;
+  if len == 126 {
+    let mut b = [0u8; 2];
+    reader.read_exact(&mut b)?;
+    len = u16::from_be_bytes(b) as u64;
+  } else if len == 127 {
+    let mut b = [0u8; 8];
+    reader.read_exact(&mut b)?;
+    len = u64::from_be_bytes(b);
+  }
+  if len > MAX_FRAME_LEN {
+    return Err(invalid_data(format!("Frame of {} bytes is too large.", len)));
+  }
+  let mut mask = None;
+  if header[1] & 0x80 != 0 {
+    let mut m = [0u8; 4];
+    reader.read_exact(&mut m)?;
+    mask = Some(m);
+  }
+  let mut payload = vec![0u8; len as usize];
+  reader.read_exact(&mut payload)?;
+  if let Some(mask) = mask {
+    payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+  }
+  Ok((header[0] & 0x80 != 0, header[0] & 0x0f, payload))
+}
+
+fn random_mask() -> [u8; 4] {
+  let mut mask = [0u8; 4];
+  openssl::rand::rand_bytes(&mut mask).unwrap();
+  mask
+}
+
+/// Check a WebSocket endpoint by doing the upgrade handshake, optionally
+/// sending a text message, and waiting for a message, for example the answer
+/// or a periodic update.
+///
+/// Pings from the server are answered while waiting, and messages which do
+/// not match are skipped. The result is `ERROR` if no matching message arrives
+/// within the error timeout, and `WARN` if it takes longer than the warn
+/// timeout. It has the metrics `handshake` and `first_message` (milliseconds
+/// since the start of the check).
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, realtime::WebSocketChecker};
+/// # use std::time::Duration;
+/// let mut checker = WebSocketChecker::new("wss://example.com/live").unwrap();
+/// checker.send_message(r#"{"type":"ping"}"#)
+///        .expect_message_contains("pong")
+///        .set_timeouts(Duration::from_secs(2), Duration::from_secs(10));
+/// let result = checker.check();
+/// ```
+pub struct WebSocketChecker {
+  url: Url,
+  headers: Vec<(String, String)>,
+  protocol: Option<String>,
+  message: Option<String>,
+  expect_contains: Option<String>,
+  warn_timeout: time::Duration,
+  err_timeout: time::Duration,
+}
+
+impl WebSocketChecker {
+  /// `url` is `ws://`, `wss://`, or the same with `http` or `https`.
+  pub fn new(url: &str) -> Result<Self, reqwest::UrlError> {
+    Ok(WebSocketChecker{
+      url: Url::parse(url)?,
+      headers: Vec::new(),
+      protocol: None,
+      message: None,
+      expect_contains: None,
+      warn_timeout: time::Duration::from_secs(30),
+      err_timeout: time::Duration::from_secs(30),
+    })
+  }
+
+  /// Add a header to the upgrade request, for example `Authorization` or
+  /// `Origin`.
+  pub fn add_header(&mut self, name: &str, value: &str) -> &mut Self {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+
+  /// Ask for a subprotocol, which the server has to accept.
+  pub fn set_protocol(&mut self, value: Option<&str>) -> &mut Self {
+    self.protocol = value.map(|p| p.to_owned());
+    self
+  }
+
+  /// A text message to send after the handshake.
+  pub fn send_message(&mut self, message: &str) -> &mut Self {
+    self.message = Some(message.to_owned());
+    self
+  }
+
+  /// Wait for a message containing `find`, instead of any message.
+  pub fn expect_message_contains(&mut self, find: &str) -> &mut Self {
+    self.expect_contains = Some(find.to_owned());
+    self
+  }
+
+  /// Same as
+  /// [`HttpChecker::set_timeouts`](crate::checkers::http::HttpChecker::set_timeouts),
+  /// but for the time until a matching message arrives.
+  ///
+  /// ## Panics
+  ///
+  /// Panics if `warn` is longer than `error`.
+  pub fn set_timeouts(&mut self, warn: time::Duration, error: time::Duration) -> &mut Self {
+    if warn > error {
+      panic!("warn > error");
+    }
+    self.warn_timeout = warn;
+    self.err_timeout = error;
+    self
+  }
+
+  fn handshake(&self) -> Result<Connection, String> {
+    let mut key = [0u8; 16];
+    openssl::rand::rand_bytes(&mut key).map_err(|e| format!("{}", &e))?;
+    let key = openssl::base64::encode_block(&key);
+    let mut headers = vec![
+      ("Upgrade".to_owned(), "websocket".to_owned()),
+      ("Connection".to_owned(), "Upgrade".to_owned()),
+      ("Sec-WebSocket-Key".to_owned(), key.clone()),
+      ("Sec-WebSocket-Version".to_owned(), "13".to_owned()),
+    ];
+    if let Some(ref protocol) = self.protocol {
+      headers.push(("Sec-WebSocket-Protocol".to_owned(), protocol.clone()));
+    }
+    headers.extend(self.headers.iter().cloned());
+    let conn = Connection::open(&self.url, None, &headers, self.err_timeout)?;
+    if conn.head.status != 101 {
+      return Err(format!("Expected status 101, got {}.", conn.head.status));
+    }
+    let expected_accept = openssl::base64::encode_block(&openssl::sha::sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+    if conn.header("sec-websocket-accept") != Some(&expected_accept[..]) {
+      return Err("Invalid Sec-WebSocket-Accept in handshake response.".to_owned());
+    }
+    if let Some(ref protocol) = self.protocol {
+      if conn.header("sec-websocket-protocol") != Some(&protocol[..]) {
+        return Err(format!("Server did not accept subprotocol {}.", protocol));
+      }
+    }
+    Ok(conn)
+  }
+
+  fn wait_for_message(&self, conn: &mut Connection, deadline: time::Instant) -> io::Result<Vec<u8>> {
+    if let Some(ref message) = self.message {
+      conn.reader.get_mut().write_all(&encode_frame(OP_TEXT, message.as_bytes(), Some(random_mask())))?;
+    }
+    let mut message = Vec::new();
+    loop {
+      Connection::set_deadline(&conn.tcp, deadline)?;
+      let (fin, opcode, payload) = read_frame(&mut conn.reader)?;
+      match opcode {
+        OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+          message.extend_from_slice(&payload);
+          if fin {
+            if matches(&self.expect_contains, &message) {
+              return Ok(message);
+            }
+            message.clear();
+          }
+        },
+        OP_PING => conn.reader.get_mut().write_all(&encode_frame(OP_PONG, &payload, Some(random_mask())))?,
+        OP_PONG => {},
+        OP_CLOSE => {
+          let code = if payload.len() >= 2 { u16::from_be_bytes([payload[0], payload[1]]) } else { 1005 };
+          let reason = String::from_utf8_lossy(payload.get(2..).unwrap_or(&[]));
+          return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("Server closed the connection: {} {}", code, reason)));
+        },
+        _ => return Err(invalid_data(format!("Unknown opcode {}.", opcode))),
+      }
+    }
+  }
+}
+
+impl Checker for WebSocketChecker {
+  fn check(&mut self) -> CheckResult {
+    let start = time::Instant::now();
+    let mut conn = match self.handshake() {
+      Ok(c) => c,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let handshake = start.elapsed();
+    let waited = self.wait_for_message(&mut conn, start + self.err_timeout);
+    if waited.is_ok() {
+      let _ = conn.reader.get_mut().write_all(&encode_frame(OP_CLOSE, &1000u16.to_be_bytes(), Some(random_mask())));
+    }
+    wait_result(start, handshake, waited, self.warn_timeout)
+  }
+}
+
+/// Check a Server-Sent Events endpoint by opening the stream and waiting for
+/// an event, for example a periodic update.
+///
+/// Comments, like keep-alives, and events which do not match are skipped. The
+/// results are like those of
+/// [`WebSocketChecker`](crate::checkers::realtime::WebSocketChecker), with
+/// `handshake` being the time until the response headers.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, realtime::SseChecker};
+/// let mut checker = SseChecker::new("https://example.com/events").unwrap();
+/// checker.set_event_type(Some("status"))
+///        .expect_message_contains("\"ok\":true");
+/// let result = checker.check();
+/// ```
+pub struct SseChecker {
+  url: Url,
+  headers: Vec<(String, String)>,
+  event_type: Option<String>,
+  expect_contains: Option<String>,
+  warn_timeout: time::Duration,
+  err_timeout: time::Duration,
+}
+
+impl SseChecker {
+  pub fn new(url: &str) -> Result<Self, reqwest::UrlError> {
+    Ok(SseChecker{
+      url: Url::parse(url)?,
+      headers: Vec::new(),
+      event_type: None,
+      expect_contains: None,
+      warn_timeout: time::Duration::from_secs(30),
+      err_timeout: time::Duration::from_secs(30),
+    })
+  }
+
+  /// Same as [`WebSocketChecker::add_header`](crate::checkers::realtime::WebSocketChecker::add_header).
+  pub fn add_header(&mut self, name: &str, value: &str) -> &mut Self {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+
+  /// Only look at events of this type. Events without an `event` field have
+  /// the type `message`.
+  ///
+  /// Default is any type.
+  pub fn set_event_type(&mut self, value: Option<&str>) -> &mut Self {
+    self.event_type = value.map(|t| t.to_owned());
+    self
+  }
+
+  /// Wait for an event whose data contains `find`, instead of any event.
+  pub fn expect_message_contains(&mut self, find: &str) -> &mut Self {
+    self.expect_contains = Some(find.to_owned());
+    self
+  }
+
+  /// Same as [`WebSocketChecker::set_timeouts`](crate::checkers::realtime::WebSocketChecker::set_timeouts).
+  ///
+  /// ## Panics
+  ///
+  /// Panics if `warn` is longer than `error`.
+  pub fn set_timeouts(&mut self, warn: time::Duration, error: time::Duration) -> &mut Self {
+    if warn > error {
+      panic!("warn > error");
+    }
+    self.warn_timeout = warn;
+    self.err_timeout = error;
+    self
+  }
+
+  fn open(&self) -> Result<Connection, String> {
+    let mut headers = vec![
+      ("Accept".to_owned(), "text/event-stream".to_owned()),
+      ("Cache-Control".to_owned(), "no-cache".to_owned()),
+    ];
+    headers.extend(self.headers.iter().cloned());
+    let conn = Connection::open(&self.url, None, &headers, self.err_timeout)?;
+    if conn.head.status != 200 {
+      return Err(format!("Expected status 200, got {}.", conn.head.status));
+    }
+    let content_type = conn.header("content-type").unwrap_or("");
+    if !content_type.starts_with("text/event-stream") {
+      return Err(format!("Expected Content-Type text/event-stream, got {:?}.", content_type));
+    }
+    Ok(conn)
+  }
+
+  fn wait_for_event<R: BufRead>(&self, body: &mut R, tcp: &DeadlineStream, deadline: time::Instant) -> io::Result<Vec<u8>> {
+    let mut event_type = String::new();
+    let mut data: Vec<String> = Vec::new();
+    loop {
+      Connection::set_deadline(tcp, deadline)?;
+      let mut line = String::new();
+      if body.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the stream."));
+      }
+      let line = line.trim_end_matches(&['\r', '\n'][..]);
+      if line.is_empty() {
+        if !data.is_empty() {
+          let this_type = if event_type.is_empty() { "message" } else { &event_type[..] };
+          let message = data.join("\n");
+          if self.event_type.as_ref().map(|t| t == this_type).unwrap_or(true) && matches(&self.expect_contains, message.as_bytes()) {
+            return Ok(message.into_bytes());
+          }
+        }
+        event_type.clear();
+        data.clear();
+        continue;
+      }
+      if line.starts_with(':') {
+        continue;
+      }
+      let (field, value) = match line.find(':') {
+        Some(i) => (&line[..i], line[i + 1..].strip_prefix(' ').unwrap_or(&line[i + 1..])),
+        None => (line, ""),
+      };
+      match field {
+        "event" => event_type = value.to_owned(),
+        "data" => data.push(value.to_owned()),
+        _ => {},
+      }
+    }
+  }
+}
+
+impl Checker for SseChecker {
+  fn check(&mut self) -> CheckResult {
+    let start = time::Instant::now();
+    let mut conn = match self.open() {
+      Ok(c) => c,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let handshake = start.elapsed();
+    let deadline = start + self.err_timeout;
+    let waited = if conn.head.is_chunked() {
+      let mut body = BufReader::new(Chunked::new(&mut conn.reader));
+      self.wait_for_event(&mut body, &conn.tcp, deadline)
+    } else {
+      self.wait_for_event(&mut conn.reader, &conn.tcp, deadline)
+    };
+    wait_result(start, handshake, waited, self.warn_timeout)
+  }
+}
+
+#[cfg(test)]
+fn read_request(stream: &mut std::net::TcpStream) -> String {
+  let mut request = Vec::new();
+  let mut byte = [0u8; 1];
+  while !request.ends_with(b"\r\n\r\n") {
+    stream.read_exact(&mut byte).unwrap();
+    request.push(byte[0]);
+  }
+  String::from_utf8(request).unwrap()
+}
+
+#[test]
+fn websocket_test() {
+  use crate::checkers::CheckResultType;
+  use std::net::TcpStream;
+  use crate::testing::serve_once;
+  let handshake = |s: &mut TcpStream| {
+    let request = read_request(s);
+    assert!(request.starts_with("GET /live?x=1 HTTP/1.1\r\n"));
+    assert!(request.contains("Origin: https://example.com\r\n"));
+    let key = request.lines().find(|l| l.starts_with("Sec-WebSocket-Key: ")).unwrap()[19..].to_owned();
+    let accept = openssl::base64::encode_block(&openssl::sha::sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+    s.write_all(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept).as_bytes()).unwrap();
+  };
+
+  let addr = serve_once(move |mut s| {
+    handshake(&mut s);
+    let (fin, opcode, payload) = read_frame(&mut s).unwrap();
+    assert_eq!((fin, opcode, &payload[..]), (true, OP_TEXT, &b"hello"[..]));
+    s.write_all(&encode_frame(OP_TEXT, b"welcome", None)).unwrap();
+    s.write_all(&encode_frame(OP_PING, b"p", None)).unwrap();
+    assert_eq!(read_frame(&mut s).unwrap(), (true, OP_PONG, b"p".to_vec()));
+    let mut fragment = encode_frame(OP_TEXT, b"hello ", None);
+    fragment[0] &= 0x7f;
+    s.write_all(&fragment).unwrap();
+    s.write_all(&encode_frame(OP_CONTINUATION, &vec![b'x'; 300], None)).unwrap();
+    assert_eq!(read_frame(&mut s).unwrap().1, OP_CLOSE);
+  });
+  let url = format!("ws://127.0.0.1:{}/live?x=1", addr.port());
+  let res = WebSocketChecker::new(&url).unwrap().add_header("Origin", "https://example.com")
+    .send_message("hello").expect_message_contains("hello xxx").check();
+  assert_eq!(res.result_type, CheckResultType::UP);
+  assert!(res.get_metric("handshake").is_some());
+  assert!(res.get_metric("first_message").is_some());
+
+  let addr = serve_once(move |mut s| {
+    handshake(&mut s);
+    s.write_all(&encode_frame(OP_TEXT, b"tick", None)).unwrap();
+    std::thread::sleep(time::Duration::from_millis(500));
+  });
+  let url = format!("ws://127.0.0.1:{}/live?x=1", addr.port());
+  let short = time::Duration::from_millis(200);
+  WebSocketChecker::new(&url).unwrap().add_header("Origin", "https://example.com")
+    .expect_message_contains("tock").set_timeouts(short, short).check().expect_err_contains("No matching message within");
+
+  let addr = serve_once(move |mut s| {
+    read_request(&mut s);
+    s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+  });
+  WebSocketChecker::new(&format!("ws://127.0.0.1:{}/", addr.port())).unwrap().check().expect_err_contains("Expected status 101, got 200.");
+}
+
+#[test]
+fn sse_test() {
+  use crate::checkers::CheckResultType;
+  use crate::testing::serve_once;
+  let addr = serve_once(move |mut s| {
+    let request = read_request(&mut s);
+    assert!(request.contains("Accept: text/event-stream\r\n"));
+    s.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+    for chunk in &[": keep-alive\n\n", "event: status\ndata: {\"ok\":", "false}\n\n", "data: {\"ok\":true}\n\n", "event: status\r\ndata: {\"ok\":true}\r\n\r\n"] {
+      s.write_all(format!("{:x}\r\n{}\r\n", chunk.len(), chunk).as_bytes()).unwrap();
+    }
+    std::thread::sleep(time::Duration::from_millis(200));
+  });
+  let res = SseChecker::new(&format!("http://127.0.0.1:{}/events", addr.port())).unwrap()
+    .set_event_type(Some("status")).expect_message_contains("\"ok\":true").check();
+  assert_eq!(res.result_type, CheckResultType::UP);
+  assert!(res.info.unwrap().ends_with("\"{\\\"ok\\\":true}\""));
+
+  let addr = serve_once(move |mut s| {
+    read_request(&mut s);
+    s.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: a\n\n").unwrap();
+  });
+  SseChecker::new(&format!("http://127.0.0.1:{}/", addr.port())).unwrap().expect_message_contains("b").check().expect_err_contains("Server closed the stream.");
+
+  let addr = serve_once(move |mut s| {
+    read_request(&mut s);
+    s.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n").unwrap();
+  });
+  SseChecker::new(&format!("http://127.0.0.1:{}/", addr.port())).unwrap().check().expect_err_contains("Expected Content-Type text/event-stream");
+}
@@ -0,0 +1,565 @@
+//! Check SSH servers without logging in: the banner, and the host key, which
+//! the server has to prove it holds during the key exchange.
+
+use crate::checkers::{Checker, CheckResult, CheckResultType};
+use crate::utils::Reader;
+use crate::utils::transport::{Buffered, Transport, block_on, connect_blocking};
+use openssl::bn::BigNum;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use std::time;
+
+const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_DEBUG: u8 = 4;
+const MSG_KEXINIT: u8 = 20;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+
+const DISCONNECT_BY_APPLICATION: u32 = 11;
+
+const CLIENT_VERSION: &str = "SSH-2.0-serverwatch";
+
+const KEX_ALGORITHMS: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org", "ecdh-sha2-nistp256"];
+const HOST_KEY_ALGORITHMS: &[&str] = &["ssh-ed25519", "ecdsa-sha2-nistp256", "ecdsa-sha2-nistp384", "rsa-sha2-512", "rsa-sha2-256"];
+// Never used, as we disconnect before NEWKEYS, but the server has to find
+// something in common.
+const CIPHERS: &str = "aes128-ctr,aes256-ctr,aes128-gcm@openssh.com,aes256-gcm@openssh.com,chacha20-poly1305@openssh.com";
+const MACS: &str = "hmac-sha2-256,hmac-sha2-512,hmac-sha1";
+
+/// Builds the fields of a packet.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+  fn u8(&mut self, value: u8) -> &mut Self {
+    self.0.push(value);
+    self
+  }
+
+  fn u32(&mut self, value: u32) -> &mut Self {
+    self.0.extend_from_slice(&value.to_be_bytes());
+    self
+  }
+
+  fn string(&mut self, value: &[u8]) -> &mut Self {
+    self.u32(value.len() as u32);
+    self.0.extend_from_slice(value);
+    self
+  }
+
+  /// An unsigned big-endian integer as an `mpint`.
+  fn mpint(&mut self, value: &[u8]) -> &mut Self {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    if value.first().map(|b| b & 0x80 != 0).unwrap_or(false) {
+      self.u32(value.len() as u32 + 1).u8(0);
+      self.0.extend_from_slice(value);
+      self
+    } else {
+      self.string(value)
+    }
+  }
+}
+
+fn reader(packet: &[u8]) -> Reader<'_> {
+  Reader::new(packet, "Truncated packet from server.")
+}
+
+/// The SSH field types on top of [`Reader`].
+trait SshReader<'a> {
+  fn string(&mut self) -> Result<&'a [u8], String>;
+  fn name_list(&mut self) -> Result<Vec<String>, String>;
+}
+
+impl<'a> SshReader<'a> for Reader<'a> {
+  fn string(&mut self) -> Result<&'a [u8], String> {
+    let len = self.u32()? as usize;
+    self.take(len)
+  }
+
+  fn name_list(&mut self) -> Result<Vec<String>, String> {
+    let list = String::from_utf8_lossy(self.string()?).into_owned();
+    Ok(list.split(',').filter(|n| !n.is_empty()).map(|n| n.to_owned()).collect())
+  }
+}
+
+fn openssl_err(e: openssl::error::ErrorStack) -> String {
+  format!("{}", &e)
+}
+
+async fn write_packet<T: Transport>(conn: &mut Buffered<T>, payload: &[u8]) -> Result<(), String> {
+  // Padding to a multiple of 8 bytes, and at least 4 bytes.
+  let mut padding = 8 - (payload.len() + 5) % 8;
+  if padding < 4 {
+    padding += 8;
+  }
+  let mut w = Writer::default();
+  w.u32((payload.len() + padding + 1) as u32).u8(padding as u8);
+  w.0.extend_from_slice(payload);
+  w.0.resize(w.0.len() + padding, 0);
+  conn.write_all(&w.0).await
+}
+
+async fn read_packet<T: Transport>(conn: &mut Buffered<T>) -> Result<Vec<u8>, String> {
+  loop {
+    let header = conn.read_exact(5).await?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let padding = header[4] as usize;
+    if len > 35000 || padding + 1 > len {
+      return Err(format!("Invalid packet length {} from server.", len));
+    }
+    let mut payload = conn.read_exact(len - 1).await?;
+    payload.truncate(len - 1 - padding);
+    match payload.first() {
+      Some(&MSG_IGNORE) | Some(&MSG_DEBUG) => continue,
+      Some(&MSG_DISCONNECT) => {
+        let mut r = reader(&payload[1..]);
+        r.u32()?;
+        return Err(format!("Server disconnected: {}", String::from_utf8_lossy(r.string()?)));
+      },
+      Some(_) => return Ok(payload),
+      None => return Err("Empty packet from server.".to_owned()),
+    }
+  }
+}
+
+/// Read the server's version line, skipping any lines sent before it.
+async fn read_version<T: Transport>(conn: &mut Buffered<T>) -> Result<String, String> {
+  for _ in 0..50 {
+    let line = conn.read_line(255).await?;
+    if line.len() < 255 && !line.ends_with(b"\n") {
+      return Err("Connection closed by server before the SSH banner.".to_owned());
+    }
+    let line = String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]).to_owned();
+    if line.starts_with("SSH-") {
+      if !line.starts_with("SSH-2.0-") && !line.starts_with("SSH-1.99-") {
+        return Err(format!("Unsupported protocol version: {}", line));
+      }
+      return Ok(line);
+    }
+  }
+  Err("No SSH banner from server.".to_owned())
+}
+
+fn kexinit(host_key_algorithms: &[&str]) -> Result<Vec<u8>, String> {
+  let mut cookie = [0u8; 16];
+  openssl::rand::rand_bytes(&mut cookie).map_err(openssl_err)?;
+  let mut w = Writer::default();
+  w.u8(MSG_KEXINIT);
+  w.0.extend_from_slice(&cookie);
+  w.string(KEX_ALGORITHMS.join(",").as_bytes())
+    .string(host_key_algorithms.join(",").as_bytes())
+    .string(CIPHERS.as_bytes()).string(CIPHERS.as_bytes())
+    .string(MACS.as_bytes()).string(MACS.as_bytes())
+    .string(b"none").string(b"none")
+    .string(b"").string(b"")
+    .u8(0).u32(0);
+  Ok(w.0)
+}
+
+/// The first of `ours` which the server also supports.
+fn negotiate<'a>(ours: &[&'a str], theirs: &[String]) -> Option<&'a str> {
+  ours.iter().find(|a| theirs.iter().any(|t| t == *a)).cloned()
+}
+
+/// An ephemeral key for the key exchange.
+enum KexKey {
+  X25519(PKey<Private>),
+  Nistp256(EcKey<Private>),
+}
+
+impl KexKey {
+  fn generate(kex: &str) -> Result<Self, String> {
+    if kex.starts_with("curve25519") {
+      Ok(KexKey::X25519(PKey::generate_x25519().map_err(openssl_err)?))
+    } else {
+      let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(openssl_err)?;
+      Ok(KexKey::Nistp256(EcKey::generate(&group).map_err(openssl_err)?))
+    }
+  }
+
+  fn public(&self) -> Result<Vec<u8>, String> {
+    match self {
+      KexKey::X25519(key) => key.raw_public_key().map_err(openssl_err),
+      KexKey::Nistp256(key) => {
+        let mut ctx = openssl::bn::BigNumContext::new().map_err(openssl_err)?;
+        key.public_key().to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut ctx).map_err(openssl_err)
+      },
+    }
+  }
+
+  /// The shared secret with the peer's public key.
+  fn derive(&self, peer: &[u8]) -> Result<Vec<u8>, String> {
+    let (ours, theirs) = match self {
+      KexKey::X25519(key) => (key.clone(), PKey::public_key_from_raw_bytes(peer, Id::X25519).map_err(openssl_err)?),
+      KexKey::Nistp256(key) => {
+        let mut ctx = openssl::bn::BigNumContext::new().map_err(openssl_err)?;
+        let point = EcPoint::from_bytes(key.group(), peer, &mut ctx).map_err(|e| format!("Invalid key exchange reply: {}", &e))?;
+        let theirs = EcKey::from_public_key(key.group(), &point).map_err(openssl_err)?;
+        (PKey::from_ec_key(key.clone()).map_err(openssl_err)?, PKey::from_ec_key(theirs).map_err(openssl_err)?)
+      },
+    };
+    let mut deriver = Deriver::new(&ours).map_err(openssl_err)?;
+    deriver.set_peer(&theirs).map_err(openssl_err)?;
+    deriver.derive_to_vec().map_err(|e| format!("Key exchange failed: {}", &e))
+  }
+}
+
+/// The exchange hash, which the server signs with its host key.
+#[allow(clippy::too_many_arguments)]
+fn exchange_hash(client_version: &str, server_version: &str, client_kexinit: &[u8], server_kexinit: &[u8], host_key: &[u8], client_public: &[u8], server_public: &[u8], secret: &[u8]) -> Vec<u8> {
+  let mut w = Writer::default();
+  w.string(client_version.as_bytes()).string(server_version.as_bytes())
+    .string(client_kexinit).string(server_kexinit)
+    .string(host_key).string(client_public).string(server_public)
+    .mpint(secret);
+  openssl::sha::sha256(&w.0).to_vec()
+}
+
+/// Check that `signature` (an SSH signature blob) over `data` was made by
+/// `host_key` (an SSH public key blob).
+fn verify_signature(host_key: &[u8], signature: &[u8], data: &[u8]) -> Result<bool, String> {
+  let mut key = reader(host_key);
+  let key_type = key.string()?;
+  let mut sig = reader(signature);
+  let sig_type = sig.string()?;
+  let sig = sig.string()?;
+  match (key_type, sig_type) {
+    (b"ssh-ed25519", b"ssh-ed25519") => {
+      let public = PKey::public_key_from_raw_bytes(key.string()?, Id::ED25519).map_err(openssl_err)?;
+      let mut verifier = Verifier::new_without_digest(&public).map_err(openssl_err)?;
+      verifier.verify_oneshot(sig, data).map_err(openssl_err)
+    },
+    (b"ecdsa-sha2-nistp256", b"ecdsa-sha2-nistp256") | (b"ecdsa-sha2-nistp384", b"ecdsa-sha2-nistp384") => {
+      let (nid, digest) = if key_type == b"ecdsa-sha2-nistp256" {
+        (Nid::X9_62_PRIME256V1, MessageDigest::sha256())
+      } else {
+        (Nid::SECP384R1, MessageDigest::sha384())
+      };
+      key.string()?;
+      let group = EcGroup::from_curve_name(nid).map_err(openssl_err)?;
+      let mut ctx = openssl::bn::BigNumContext::new().map_err(openssl_err)?;
+      let point = EcPoint::from_bytes(&group, key.string()?, &mut ctx).map_err(openssl_err)?;
+      let public: EcKey<Public> = EcKey::from_public_key(&group, &point).map_err(openssl_err)?;
+      let mut sig = reader(sig);
+      let r = BigNum::from_slice(sig.string()?).map_err(openssl_err)?;
+      let s = BigNum::from_slice(sig.string()?).map_err(openssl_err)?;
+      let sig = EcdsaSig::from_private_components(r, s).map_err(openssl_err)?;
+      let digest = hash(digest, data).map_err(openssl_err)?;
+      sig.verify(&digest, &public).map_err(openssl_err)
+    },
+    (b"ssh-rsa", b"rsa-sha2-256") | (b"ssh-rsa", b"rsa-sha2-512") => {
+      let e = BigNum::from_slice(key.string()?).map_err(openssl_err)?;
+      let n = BigNum::from_slice(key.string()?).map_err(openssl_err)?;
+      let public = PKey::from_rsa(Rsa::from_public_components(n, e).map_err(openssl_err)?).map_err(openssl_err)?;
+      let digest = if sig_type == b"rsa-sha2-256" { MessageDigest::sha256() } else { MessageDigest::sha512() };
+      let mut verifier = Verifier::new(digest, &public).map_err(openssl_err)?;
+      verifier.update(data).map_err(openssl_err)?;
+      verifier.verify(sig).map_err(openssl_err)
+    },
+    _ => Err(format!("Unsupported signature {} for host key {}.", String::from_utf8_lossy(sig_type), String::from_utf8_lossy(key_type))),
+  }
+}
+
+/// The fingerprint of an SSH public key blob, in the format of
+/// `ssh-keygen -l`: `SHA256:` followed by unpadded base64.
+fn fingerprint(host_key: &[u8]) -> String {
+  let encoded = openssl::base64::encode_block(&openssl::sha::sha256(host_key));
+  format!("SHA256:{}", encoded.trim_end_matches('='))
+}
+
+/// What the server showed us.
+struct ServerInfo {
+  version: String,
+  key_type: String,
+  fingerprint: String,
+}
+
+/// Check an SSH server's version banner and host key, without credentials.
+///
+/// The checker runs the key exchange far enough for the server to send its
+/// host key and prove that it holds the private key, which catches a rebuilt
+/// server or a man in the middle. A host key which differs from the pinned
+/// fingerprint is an `ERROR`, and a banner which differs from the expected one,
+/// for example after an upgrade, is a `WARN`. Without a pinned fingerprint or
+/// banner, the ones seen on the first successful check are used.
+///
+/// Supports the `curve25519-sha256` and `ecdh-sha2-nistp256` key exchanges,
+/// and `ssh-ed25519`, `ecdsa-sha2-nistp256`, `ecdsa-sha2-nistp384` and RSA host
+/// keys.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, ssh::SshChecker};
+/// let mut checker = SshChecker::new("example.com", 22);
+/// checker.set_host_key("ssh-ed25519", "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")
+///        .set_expected_banner("SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13");
+/// let result = checker.check();
+/// ```
+pub struct SshChecker {
+  host: String,
+  port: u16,
+  timeout: time::Duration,
+  key_type: Option<String>,
+  fingerprint: Option<String>,
+  banner: Option<String>,
+}
+
+impl SshChecker {
+  pub fn new(host: &str, port: u16) -> Self {
+    SshChecker{host: host.to_owned(), port, timeout: time::Duration::from_secs(10), key_type: None, fingerprint: None, banner: None}
+  }
+
+  /// Pin the host key of type `key_type` (like `ssh-ed25519`,
+  /// `ecdsa-sha2-nistp256` or `ssh-rsa`) to `fingerprint`, as printed by
+  /// `ssh-keygen -l -f /etc/ssh/ssh_host_ed25519_key.pub`. Only that type of
+  /// host key is asked for.
+  ///
+  /// Default is to accept any type, and pin the first key seen.
+  pub fn set_host_key(&mut self, key_type: &str, fingerprint: &str) -> &mut Self {
+    self.key_type = Some(key_type.to_owned());
+    self.fingerprint = Some(fingerprint.to_owned());
+    self
+  }
+
+  /// The full version line sent by the server, like
+  /// `SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13`.
+  ///
+  /// Default is the first banner seen.
+  pub fn set_expected_banner(&mut self, value: &str) -> &mut Self {
+    self.banner = Some(value.to_owned());
+    self
+  }
+
+  /// Time limit for the whole check, from resolving the host to reading the
+  /// server's key exchange reply.
+  ///
+  /// Default is 10s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  fn host_key_algorithms(&self) -> Result<Vec<&'static str>, String> {
+    let algorithms = match self.key_type.as_ref().map(|t| &t[..]) {
+      Some("ssh-rsa") => vec!["rsa-sha2-512", "rsa-sha2-256"],
+      Some(key_type) => HOST_KEY_ALGORITHMS.iter().cloned().filter(|a| *a == key_type).collect(),
+      None => HOST_KEY_ALGORITHMS.to_vec(),
+    };
+    if algorithms.is_empty() {
+      return Err(format!("Unsupported host key type {}.", self.key_type.as_ref().unwrap()));
+    }
+    Ok(algorithms)
+  }
+
+  async fn query<T: Transport>(&self, conn: &mut Buffered<T>, host_key_algorithms: &[&str]) -> Result<ServerInfo, String> {
+    conn.write_all(format!("{}\r\n", CLIENT_VERSION).as_bytes()).await?;
+    let version = read_version(conn).await?;
+    let client_kexinit = kexinit(host_key_algorithms)?;
+    write_packet(conn, &client_kexinit).await?;
+    let server_kexinit = read_packet(conn).await?;
+    if server_kexinit[0] != MSG_KEXINIT {
+      return Err(format!("Expected KEXINIT, got message {}.", server_kexinit[0]));
+    }
+    let mut r = reader(&server_kexinit[1..]);
+    r.take(16)?;
+    let server_kex = r.name_list()?;
+    let server_host_key_algorithms = r.name_list()?;
+    let kex = negotiate(KEX_ALGORITHMS, &server_kex).ok_or_else(|| format!("No supported key exchange, server offers {}.", server_kex.join(",")))?;
+    negotiate(host_key_algorithms, &server_host_key_algorithms)
+      .ok_or_else(|| format!("No supported host key, server offers {}.", server_host_key_algorithms.join(",")))?;
+
+    let key = KexKey::generate(kex)?;
+    let client_public = key.public()?;
+    let mut w = Writer::default();
+    w.u8(MSG_KEX_ECDH_INIT).string(&client_public);
+    write_packet(conn, &w.0).await?;
+    let reply = read_packet(conn).await?;
+    if reply[0] != MSG_KEX_ECDH_REPLY {
+      return Err(format!("Expected KEX_ECDH_REPLY, got message {}.", reply[0]));
+    }
+    let mut r = reader(&reply[1..]);
+    let host_key = r.string()?;
+    let server_public = r.string()?;
+    let signature = r.string()?;
+    let secret = key.derive(server_public)?;
+    let h = exchange_hash(CLIENT_VERSION, &version, &client_kexinit, &server_kexinit, host_key, &client_public, server_public, &secret);
+    if !verify_signature(host_key, signature, &h)? {
+      return Err("Invalid host key signature in key exchange. Is there a man in the middle?".to_owned());
+    }
+
+    let mut w = Writer::default();
+    w.u8(MSG_DISCONNECT).u32(DISCONNECT_BY_APPLICATION).string(b"check done").string(b"");
+    let _ = write_packet(conn, &w.0).await;
+    let key_type = String::from_utf8_lossy(reader(host_key).string()?).into_owned();
+    Ok(ServerInfo{version, key_type, fingerprint: fingerprint(host_key)})
+  }
+
+  /// Compare what the server showed us with the pinned host key and banner,
+  /// pinning them on the first successful check.
+  fn result(&mut self, server: Result<ServerInfo, String>) -> CheckResult {
+    let server = match server {
+      Ok(s) => s,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let expected_fingerprint = self.fingerprint.get_or_insert_with(|| server.fingerprint.clone());
+    if *expected_fingerprint != server.fingerprint {
+      return CheckResult::error(Some(format!("Host key of {}:{} changed: expected {}, got {} {}.", &self.host, self.port, expected_fingerprint, &server.key_type, &server.fingerprint)));
+    }
+    let mut result_type = CheckResultType::UP;
+    let mut info = format!("{}:{} is {} with {} key {}.", &self.host, self.port, &server.version, &server.key_type, &server.fingerprint);
+    let expected_banner = self.banner.get_or_insert_with(|| server.version.clone());
+    if *expected_banner != server.version {
+      result_type = CheckResultType::WARN;
+      info = format!("Banner of {}:{} changed from {} to {}.", &self.host, self.port, expected_banner, &server.version);
+    }
+    CheckResult::new(result_type, Some(info))
+  }
+}
+
+impl Checker for SshChecker {
+  fn check(&mut self) -> CheckResult {
+    let server = self.host_key_algorithms().and_then(|algorithms| {
+      let mut conn = connect_blocking(&self.host, self.port, self.timeout)?;
+      block_on(self.query(&mut conn, &algorithms))
+    });
+    self.result(server)
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for SshChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    use crate::checkers::async_checker::within;
+    use crate::utils::transport::connect_async;
+    Box::pin(async move {
+      let server = match self.host_key_algorithms() {
+        Ok(algorithms) => within(self.timeout, async {
+          self.query(&mut connect_async(&self.host, self.port).await?, &algorithms).await
+        }).await,
+        Err(e) => Err(e),
+      };
+      self.result(server)
+    })
+  }
+}
+
+/// Play the server side of the key exchange on one connection, with `banner`
+/// and an ed25519 host key. With `bad_signature`, the wrong data is signed.
+#[cfg(test)]
+fn serve_ssh(host_key: PKey<Private>, banner: &'static str, bad_signature: bool) -> std::net::SocketAddr {
+  use crate::testing::serve_once;
+  use crate::utils::transport::Blocking;
+  serve_once(move |s| {
+    let mut s = Buffered::new(Blocking(s));
+    block_on(s.write_all(format!("Welcome\r\n{}\r\n", banner).as_bytes())).unwrap();
+    let client_version = block_on(read_version(&mut s)).unwrap();
+    let client_kexinit = block_on(read_packet(&mut s)).unwrap();
+    let server_kexinit = kexinit(&["ssh-ed25519"]).unwrap();
+    block_on(write_packet(&mut s, &server_kexinit)).unwrap();
+    let init = block_on(read_packet(&mut s)).unwrap();
+    let client_public = reader(&init[1..]).string().unwrap().to_vec();
+    let key = KexKey::generate("curve25519-sha256").unwrap();
+    let server_public = key.public().unwrap();
+    let secret = key.derive(&client_public).unwrap();
+    let mut blob = Writer::default();
+    blob.string(b"ssh-ed25519").string(&host_key.raw_public_key().unwrap());
+    let mut h = exchange_hash(&client_version, banner, &client_kexinit, &server_kexinit, &blob.0, &client_public, &server_public, &secret);
+    if bad_signature {
+      h[0] ^= 1;
+    }
+    let mut signer = openssl::sign::Signer::new_without_digest(&host_key).unwrap();
+    let mut sig = Writer::default();
+    sig.string(b"ssh-ed25519").string(&signer.sign_oneshot_to_vec(&h).unwrap());
+    let mut w = Writer::default();
+    w.u8(MSG_KEX_ECDH_REPLY).string(&blob.0).string(&server_public).string(&sig.0);
+    block_on(write_packet(&mut s, &w.0)).unwrap();
+    let _ = block_on(read_packet(&mut s));
+  })
+}
+
+#[test]
+fn ssh_checker_test() {
+  let host_key = PKey::generate_ed25519().unwrap();
+  let mut blob = Writer::default();
+  blob.string(b"ssh-ed25519").string(&host_key.raw_public_key().unwrap());
+  let expected = fingerprint(&blob.0);
+  let banner = "SSH-2.0-OpenSSH_9.6";
+
+  let addr = serve_ssh(host_key.clone(), banner, false);
+  let mut checker = SshChecker::new("127.0.0.1", addr.port());
+  let res = checker.check();
+  assert_eq!(res, CheckResult::up(Some(format!("127.0.0.1:{} is SSH-2.0-OpenSSH_9.6 with ssh-ed25519 key {}.", addr.port(), &expected))));
+
+  let addr = serve_ssh(host_key.clone(), "SSH-2.0-OpenSSH_9.7", false);
+  let mut checker = SshChecker::new("127.0.0.1", addr.port());
+  let res = checker.set_host_key("ssh-ed25519", &expected).set_expected_banner(banner).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.unwrap(), format!("Banner of 127.0.0.1:{} changed from SSH-2.0-OpenSSH_9.6 to SSH-2.0-OpenSSH_9.7.", addr.port()));
+
+  let addr = serve_ssh(PKey::generate_ed25519().unwrap(), banner, false);
+  SshChecker::new("127.0.0.1", addr.port()).set_host_key("ssh-ed25519", &expected).check().expect_err_contains(&format!("changed: expected {}", &expected));
+
+  let addr = serve_ssh(host_key, banner, true);
+  SshChecker::new("127.0.0.1", addr.port()).check().expect_err_contains("Invalid host key signature");
+
+  for kex in KEX_ALGORITHMS {
+    let (a, b) = (KexKey::generate(kex).unwrap(), KexKey::generate(kex).unwrap());
+    assert_eq!(a.derive(&b.public().unwrap()).unwrap(), b.derive(&a.public().unwrap()).unwrap());
+  }
+
+  let mut w = Writer::default();
+  w.mpint(&[0, 0, 0x80, 1]).mpint(&[0x7f]).mpint(&[0]);
+  assert_eq!(w.0, vec![0, 0, 0, 3, 0, 0x80, 1, 0, 0, 0, 1, 0x7f, 0, 0, 0, 0]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_ssh_checker_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  let host_key = PKey::generate_ed25519().unwrap();
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let addr = serve_ssh(host_key.clone(), "SSH-2.0-OpenSSH_9.6", false);
+  let mut checker = SshChecker::new("127.0.0.1", addr.port());
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect();
+  let addr = serve_ssh(host_key, "SSH-2.0-OpenSSH_9.7", false);
+  checker.port = addr.port();
+  let res = runtime.block_on(AsyncChecker::check(&mut checker));
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.unwrap(), format!("Banner of 127.0.0.1:{} changed from SSH-2.0-OpenSSH_9.6 to SSH-2.0-OpenSSH_9.7.", addr.port()));
+}
+
+#[test]
+fn verify_signature_test() {
+  let data = b"exchange hash";
+  let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+  let ec = EcKey::generate(&group).unwrap();
+  let mut ctx = openssl::bn::BigNumContext::new().unwrap();
+  let mut blob = Writer::default();
+  blob.string(b"ecdsa-sha2-nistp256").string(b"nistp256").string(&ec.public_key().to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx).unwrap());
+  let sig = EcdsaSig::sign(&openssl::sha::sha256(data), &ec).unwrap();
+  let mut inner = Writer::default();
+  inner.mpint(&sig.r().to_vec()).mpint(&sig.s().to_vec());
+  let mut sig = Writer::default();
+  sig.string(b"ecdsa-sha2-nistp256").string(&inner.0);
+  assert!(verify_signature(&blob.0, &sig.0, data).unwrap());
+  assert!(!verify_signature(&blob.0, &sig.0, b"something else").unwrap());
+
+  let rsa = Rsa::generate(2048).unwrap();
+  let mut blob = Writer::default();
+  blob.string(b"ssh-rsa").mpint(&rsa.e().to_vec()).mpint(&rsa.n().to_vec());
+  let key = PKey::from_rsa(rsa).unwrap();
+  let mut signer = openssl::sign::Signer::new(MessageDigest::sha512(), &key).unwrap();
+  signer.update(data).unwrap();
+  let mut sig = Writer::default();
+  sig.string(b"rsa-sha2-512").string(&signer.sign_to_vec().unwrap());
+  assert!(verify_signature(&blob.0, &sig.0, data).unwrap());
+  assert!(verify_signature(&blob.0, &sig.0[..20], data).is_err());
+}
@@ -1,12 +1,16 @@
 //! Check that a TLS server's certificate is valid and is not too close to expiry.
 
-use crate::checkers::{Checker, CheckResult, CheckResultType};
+use crate::checkers::{AddressFamily, Checker, CheckResult, CheckResultType, NotifyHint, merge_address_results, resolve_addresses};
 use std::time;
 use std::net;
 use std::io;
 use std::io::{BufRead, Read, Write};
 use openssl;
-use crate::utils::with_timeout;
+use crate::utils::{DeadlineStream, time_left};
+use crate::utils::dns::DnsClient;
+
+mod dane;
+#[cfg(feature = "async")] mod async_check;
 
 /// Builder for [`CertificateChecker`](crate::checkers::tls::CertificateChecker).
 /// Returned by
@@ -21,6 +25,89 @@ pub struct CertificateCheckerBuilder {
   fake_now: Option<time::SystemTime>,
   starttls: CertificateCheckerStartTLSOptions,
   timeout: time::Duration,
+  server_name: Option<String>,
+  connect_address: Option<String>,
+  address_family: AddressFamily,
+  client_cert: Option<(Vec<openssl::x509::X509>, openssl::pkey::PKey<openssl::pkey::Private>)>,
+  expiry_tiers: Option<Vec<(time::Duration, ExpiryLevel)>>,
+  check_dane: bool,
+  caa_issuers: Option<Vec<String>>,
+  dns_server: Option<net::SocketAddr>,
+}
+
+/// How serious it is for a certificate to be within an expiry threshold. See
+/// [`set_expiry_tiers`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_tiers).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpiryLevel {
+  /// Result is still `UP`, but a notification is requested.
+  INFO,
+  WARN,
+  ERROR,
+}
+
+impl ExpiryLevel {
+  /// The level of a single expiry threshold with the failure mode `value`.
+  pub(crate) fn from_failure_mode(value: CheckResultType) -> Self {
+    match value {
+      CheckResultType::UP => ExpiryLevel::INFO,
+      CheckResultType::WARN => ExpiryLevel::WARN,
+      CheckResultType::ERROR => ExpiryLevel::ERROR,
+    }
+  }
+
+  pub(crate) fn result_type(self) -> CheckResultType {
+    match self {
+      ExpiryLevel::INFO => CheckResultType::UP,
+      ExpiryLevel::WARN => CheckResultType::WARN,
+      ExpiryLevel::ERROR => CheckResultType::ERROR,
+    }
+  }
+}
+
+/// Expiry thresholds, and the one the last check was within, so that only
+/// crossing a threshold asks for a notification. Shared by the checkers of
+/// things which expire.
+#[derive(Clone, Debug)]
+pub(crate) struct ExpiryTiers {
+  /// Least urgent first.
+  tiers: Vec<(time::Duration, ExpiryLevel)>,
+  /// Whether the tiers were configured, and so notify only on crossing one.
+  /// The single threshold of the certificate checker leaves the decision to
+  /// the result type on every check, as it did before tiers.
+  tiered: bool,
+  last: Option<usize>,
+}
+
+impl ExpiryTiers {
+  pub(crate) fn new(mut tiers: Vec<(time::Duration, ExpiryLevel)>, tiered: bool) -> Self {
+    tiers.sort_by_key(|t| std::cmp::Reverse(t.0));
+    ExpiryTiers{tiers, tiered, last: None}
+  }
+
+  /// The index of the most urgent tier something valid for another
+  /// `rem_days` days is within.
+  pub(crate) fn tier(&self, rem_days: f32) -> Option<usize> {
+    self.tiers.iter().rposition(|(threshold, _)| rem_days < threshold.as_secs() as f32 / (24*60*60) as f32)
+  }
+
+  pub(crate) fn result_type(&self, tier: Option<usize>) -> CheckResultType {
+    tier.map(|t| self.tiers[t].1.result_type()).unwrap_or(CheckResultType::UP)
+  }
+
+  /// Decide whether `result`, about something valid for another `rem_days`
+  /// days, asks for a notification, and remember its tier for next time.
+  pub(crate) fn set_notify(&mut self, result: &mut CheckResult, rem_days: f32) {
+    if !self.tiered {
+      return;
+    }
+    let tier = self.tier(rem_days);
+    // Only take over the notification decision if the result is entirely due
+    // to expiry, and not e.g. one address failing to connect.
+    if tier.is_some() && result.result_type == self.result_type(tier) {
+      result.notify = if tier == self.last { NotifyHint::Never } else { NotifyHint::Always };
+    }
+    self.last = tier;
+  }
 }
 
 #[derive(Clone)]
@@ -47,15 +134,37 @@ impl CertificateCheckerBuilder {
       }
       connector.set_verify_cert_store(st.build()).map_err(|e| format!("Connector::Set verify cert store: {}", &e))?;
     }
+    let mut client_cert = None;
+    if let Some((chain, key)) = self.client_cert {
+      let mut chain = chain.into_iter();
+      let leaf = chain.next().ok_or_else(|| "Client certificate chain is empty".to_owned())?;
+      connector.set_certificate(&leaf).map_err(|e| format!("Setting client certificate: {}", &e))?;
+      for cert in chain {
+        connector.add_extra_chain_cert(cert).map_err(|e| format!("Adding client certificate chain: {}", &e))?;
+      }
+      connector.set_private_key(&key).map_err(|e| format!("Setting client private key: {}", &e))?;
+      connector.check_private_key().map_err(|e| format!("Client private key does not match certificate: {}", &e))?;
+      client_cert = Some(leaf);
+    }
+    let failure_level = ExpiryLevel::from_failure_mode(self.failure_mode);
+    let exipry_threshold = self.exipry_threshold;
+    let tiered = self.expiry_tiers.is_some();
+    let expiry_tiers = ExpiryTiers::new(self.expiry_tiers.unwrap_or_else(|| vec![(exipry_threshold, failure_level)]), tiered);
     Ok(CertificateChecker{
       host: self.host,
       port: self.port,
-      failure_mode: self.failure_mode,
-      exipry_threshold: self.exipry_threshold,
+      expiry_tiers,
       openssl_connector: connector.build(),
       fake_now: self.fake_now,
       starttls: self.starttls,
       timeout: self.timeout,
+      server_name: self.server_name,
+      connect_address: self.connect_address,
+      address_family: self.address_family,
+      client_cert,
+      check_dane: self.check_dane,
+      caa_issuers: self.caa_issuers,
+      dns_server: self.dns_server,
     })
   }
 
@@ -65,7 +174,8 @@ impl CertificateCheckerBuilder {
   /// Defaults to `WARN`.
   ///
   /// Other errors, such as unable to connect to the server, returns `ERROR`
-  /// regardless of this setting.
+  /// regardless of this setting. With `UP`, an expiring certificate only shows
+  /// in the info, and no notification is asked for.
   pub fn set_failure_mode(&mut self, value: CheckResultType) {
     self.failure_mode = value;
   }
@@ -76,12 +186,62 @@ impl CertificateCheckerBuilder {
   /// of the check (or
   /// [`fake_time`](crate::checkers::tls::CertificateCheckerBuilder::fake_time)),
   /// the check will fail with self.failure_mode, which defaults to `WARN`.
+  /// Unlike with
+  /// [`set_expiry_tiers`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_tiers),
+  /// every such result is notified as usual for its type.
   ///
   /// Defaults to 2 days. Increase this to make the check stricter.
   pub fn set_expiry_threshold(&mut self, value: time::Duration) {
     self.exipry_threshold = value;
   }
 
+  /// Use several expiry thresholds instead of the single one set by
+  /// [`set_expiry_threshold`](crate::checkers::tls::CertificateCheckerBuilder::set_expiry_threshold)
+  /// and [`set_failure_mode`](crate::checkers::tls::CertificateCheckerBuilder::set_failure_mode).
+  /// For example, `INFO` at 30 days, `WARN` at 14 days and `ERROR` at 3 days.
+  ///
+  /// The check result is determined by the most urgent threshold the certificate
+  /// is within. Only the first check result after a threshold is crossed asks
+  /// for a notification; later checks within the same threshold set
+  /// [`notify`](crate::checkers::CheckResult::notify) to `Never`.
+  ///
+  /// Either way, the remaining validity is reported in the `expiry_days`
+  /// [metric](crate::checkers::CheckResult::metrics) of every result.
+  pub fn set_expiry_tiers(&mut self, tiers: Vec<(time::Duration, ExpiryLevel)>) {
+    self.expiry_tiers = Some(tiers);
+  }
+
+  /// Check that the DANE TLSA records published at `_<port>._tcp.<server name>`
+  /// match the certificate (or chain) the server presents. A mismatch, or a
+  /// failure to look the records up, is `ERROR`; no TLSA records at all is
+  /// `WARN`.
+  ///
+  /// Default is `false`.
+  pub fn set_check_dane(&mut self, value: bool) {
+    self.check_dane = value;
+  }
+
+  /// Check that the CAA records of the server name (or its closest ancestor
+  /// which has any) permit the CA which issued the certificate. Otherwise, the
+  /// check returns `WARN`, since the next renewal will fail.
+  ///
+  /// `issuer_domains` are the CAA domain names of the CA in use, e.g.
+  /// `letsencrypt.org`. If empty, it is guessed from the issuer of the
+  /// certificate, which only works for some well-known CAs.
+  pub fn set_check_caa(&mut self, issuer_domains: Vec<String>) {
+    self.caa_issuers = Some(issuer_domains);
+  }
+
+  /// Where to send the DNS queries of
+  /// [`set_check_dane`](crate::checkers::tls::CertificateCheckerBuilder::set_check_dane)
+  /// and [`set_check_caa`](crate::checkers::tls::CertificateCheckerBuilder::set_check_caa).
+  /// For DANE, this should be a DNSSEC-validating resolver.
+  ///
+  /// Default is the first nameserver in `/etc/resolv.conf`.
+  pub fn set_dns_server(&mut self, value: net::SocketAddr) {
+    self.dns_server = Some(value);
+  }
+
   /// For testing. This make `check` act as if the system time is `value`.
   pub fn fake_time(&mut self, value: time::SystemTime) {
     self.fake_now = Some(value);
@@ -107,6 +267,71 @@ impl CertificateCheckerBuilder {
   pub fn set_timeout(&mut self, value: time::Duration) {
     self.timeout = value;
   }
+
+  /// Send `value` as the SNI name and verify the certificate against it, instead
+  /// of `host`. The TCP connection still goes to `host` (or the
+  /// [`connect address`](crate::checkers::tls::CertificateCheckerBuilder::set_connect_address),
+  /// if set).
+  pub fn set_server_name(&mut self, value: String) {
+    self.server_name = Some(value);
+  }
+
+  /// Connect to `value` (a hostname or an IP address) instead of `host`, while
+  /// still using `host` as the SNI name and for certificate verification.
+  ///
+  /// Useful for checking an individual backend behind a load balancer, or an
+  /// origin server behind a CDN.
+  pub fn set_connect_address(&mut self, value: String) {
+    self.connect_address = Some(value);
+  }
+
+  /// Which addresses of the connect address to check: only IPv4, only IPv6,
+  /// or every resolved A/AAAA record individually. See
+  /// [`AddressFamily`](crate::checkers::AddressFamily).
+  ///
+  /// Default is `AddressFamily::Any`, which lets the OS pick an address.
+  pub fn set_address_family(&mut self, value: AddressFamily) {
+    self.address_family = value;
+  }
+
+  /// Same as `set_address_family(AddressFamily::Each)` if `value` is `true`,
+  /// and `set_address_family(AddressFamily::Any)` otherwise.
+  pub fn set_check_each_address(&mut self, value: bool) {
+    self.set_address_family(if value { AddressFamily::Each } else { AddressFamily::Any });
+  }
+
+  /// Present a client certificate during the handshake, for servers which
+  /// require mutual TLS. `chain` starts with the client certificate itself,
+  /// optionally followed by intermediates.
+  ///
+  /// The client certificate's own expiry is checked against the same threshold
+  /// as the server's.
+  pub fn set_client_certificate(&mut self, chain: Vec<openssl::x509::X509>, key: openssl::pkey::PKey<openssl::pkey::Private>) {
+    self.client_cert = Some((chain, key));
+  }
+
+  /// Like
+  /// [`set_client_certificate`](crate::checkers::tls::CertificateCheckerBuilder::set_client_certificate),
+  /// but takes a PEM certificate chain and a PEM private key.
+  pub fn set_client_certificate_pem(&mut self, chain_pem: &[u8], key_pem: &[u8]) -> Result<(), String> {
+    let chain = openssl::x509::X509::stack_from_pem(chain_pem).map_err(|e| format!("Parsing client certificate: {}", &e))?;
+    let key = openssl::pkey::PKey::private_key_from_pem(key_pem).map_err(|e| format!("Parsing client private key: {}", &e))?;
+    self.set_client_certificate(chain, key);
+    Ok(())
+  }
+
+  /// Like
+  /// [`set_client_certificate`](crate::checkers::tls::CertificateCheckerBuilder::set_client_certificate),
+  /// but takes a DER-encoded PKCS#12 archive and its password.
+  pub fn set_client_certificate_pkcs12(&mut self, der: &[u8], password: &str) -> Result<(), String> {
+    let parsed = openssl::pkcs12::Pkcs12::from_der(der).and_then(|p| p.parse2(password)).map_err(|e| format!("Parsing PKCS#12: {}", &e))?;
+    let mut chain = vec![parsed.cert.ok_or_else(|| "PKCS#12 has no certificate".to_owned())?];
+    if let Some(ca) = parsed.ca {
+      chain.extend(ca);
+    }
+    self.set_client_certificate(chain, parsed.pkey.ok_or_else(|| "PKCS#12 has no private key".to_owned())?);
+    Ok(())
+  }
 }
 
 /// Check that a TLS server's certificate is valid and is not too close to expiry.
@@ -117,15 +342,22 @@ impl CertificateCheckerBuilder {
 /// let mut checker = CertificateChecker::builder("google.com".to_owned(), 443).build().unwrap();
 /// checker.check().expect();
 /// ```
+#[derive(Clone)]
 pub struct CertificateChecker {
   host: String,
   port: u16,
-  failure_mode: CheckResultType,
-  exipry_threshold: time::Duration,
+  expiry_tiers: ExpiryTiers,
   openssl_connector: openssl::ssl::SslConnector,
   fake_now: Option<time::SystemTime>,
   starttls: CertificateCheckerStartTLSOptions,
   timeout: time::Duration,
+  server_name: Option<String>,
+  connect_address: Option<String>,
+  address_family: AddressFamily,
+  client_cert: Option<openssl::x509::X509>,
+  check_dane: bool,
+  caa_issuers: Option<Vec<String>>,
+  dns_server: Option<net::SocketAddr>,
 }
 
 impl CertificateChecker {
@@ -142,12 +374,177 @@ impl CertificateChecker {
       fake_now: None,
       starttls: CertificateCheckerStartTLSOptions::NONE,
       timeout: time::Duration::from_secs(10),
+      server_name: None,
+      connect_address: None,
+      address_family: AddressFamily::Any,
+      client_cert: None,
+      expiry_tiers: None,
+      check_dane: false,
+      caa_issuers: None,
+      dns_server: None,
     }
   }
+
+  /// For testing. Same as
+  /// [`CertificateCheckerBuilder::fake_time`](crate::checkers::tls::CertificateCheckerBuilder::fake_time),
+  /// but keeps the state of the checker.
+  pub fn fake_time(&mut self, value: time::SystemTime) {
+    self.fake_now = Some(value);
+  }
+
+  fn now_time_t(&self) -> time_t {
+    system_time_to_time_t(self.fake_now.unwrap_or(time::SystemTime::now()))
+  }
+
+  fn server_name(&self) -> &str {
+    self.server_name.as_ref().unwrap_or(&self.host)
+  }
+
+  fn connect_host(&self) -> &str {
+    self.connect_address.as_ref().unwrap_or(&self.host)
+  }
+}
+
+enum ConnectTarget {
+  Name(String, u16),
+  /// Tried in order until one connects.
+  Addrs(Vec<net::SocketAddr>),
+}
+
+/// Connect to `target` and do the handshake before `deadline`, returning the
+/// server's certificate and chain.
+fn connect_and_handshake(target: ConnectTarget, ssl: openssl::ssl::ConnectConfiguration, server_name: &str, starttls: CertificateCheckerStartTLSOptions, deadline: time::Instant) -> Result<(openssl::x509::X509, Vec<openssl::x509::X509>), CheckResult> {
+  let addrs = match target {
+    ConnectTarget::Name(ref host, port) => match time_left(deadline).map_err(|e| format!("{}", &e)).and_then(|t| resolve_addresses(host, port, AddressFamily::Any, t)) {
+      Ok(addrs) => addrs,
+      Err(e) => return Err(CheckResult::error(Some(e))),
+    },
+    ConnectTarget::Addrs(addrs) => addrs,
+  };
+  let mut conn = match DeadlineStream::connect(&addrs, deadline) {
+    Ok(k) => k,
+    Err(e) => return Err(CheckResult::error(Some(format!("Unable to connect: {}", &e))))
+  };
+
+  match starttls {
+    CertificateCheckerStartTLSOptions::NONE => {},
+    CertificateCheckerStartTLSOptions::SMTP => smtp_starttls(&mut conn)?,
+  }
+
+  let mut tls_stream = match ssl.connect(server_name, conn) {
+    Ok(k) => k,
+    Err(e) => return Err(CheckResult::error(Some(format!("OpenSSL handshake: {}", &e))))
+  };
+  let peer_cert = match tls_stream.ssl().peer_certificate() {
+    Some(c) => c,
+    None => return Err(CheckResult::error(Some(format!("No peer certificate?"))))
+  };
+  let chain: Vec<openssl::x509::X509> = tls_stream.ssl().peer_cert_chain().map(|c| c.iter().map(|c| c.to_owned()).collect()).unwrap_or_default();
+  std::thread::spawn(move || {
+    if {let s = tls_stream.shutdown(); s.is_ok() && s.unwrap() == openssl::ssl::ShutdownResult::Sent} {
+      let _ = tls_stream.shutdown();
+    }
+  });
+  Ok((peer_cert, chain))
 }
 
+/// Ask an SMTP server to start TLS on `conn`.
+fn smtp_starttls<S: Read + Write>(conn: &mut S) -> Result<(), CheckResult> {
+  macro_rules! try_io {
+    ($e:expr) => {
+      if let Err(e) = $e {
+        return Err(CheckResult::error(Some(format!("IO error: {}", &e))));
+      }
+    };
+  }
+  let mut buf: Vec<u8> = Vec::new();
+  macro_rules! read_till_crlf {
+    ($reader:expr) => {{
+      buf.clear();
+      try_io!($reader.read_until(b'\r', &mut buf));
+      if buf.last() != Some(&b'\r') {
+        return Err(CheckResult::error(Some(format!("Unexpected EOF in protocol"))));
+      }
+      buf.pop();
+      let mut r = [0u8];
+      try_io!($reader.read_exact(&mut r[..]));
+      if r[0] != b'\n' {
+        return Err(CheckResult::error(Some(format!("Protocol error when doing starttls"))));
+      }
+      match std::str::from_utf8(&buf) {
+        Ok(s) => s,
+        Err(_) => { return Err(CheckResult::error(Some(format!("Protocol error when doing starttls")))); }
+      }
+    }};
+  }
+  macro_rules! try_unwrap {
+    ($e:expr) => {
+      match $e {
+        Some(s) => s,
+        None => { return Err(CheckResult::error(Some(format!("Protocol error when doing starttls")))); }
+      }
+    };
+  }
+
+  let mut buf_reader = io::BufReader::new(conn);
+  // 220 maowtm.org ESMTP Postfix (Debian/GNU)
+  let _welcome_line = read_till_crlf!(buf_reader);
+  let mut welcome_line = _welcome_line.split_ascii_whitespace();
+  if try_unwrap!(welcome_line.next()) != "220" {
+    return Err(CheckResult::error(Some(format!("Unexpected welcome: {}", _welcome_line))));
+  }
+  try_io!(buf_reader.get_mut().write_all(b"EHLO example.com\r\n"));
+  let mut has_starttls = false;
+  loop {
+    let line = read_till_crlf!(buf_reader).to_ascii_uppercase();
+    if &line == "250-STARTTLS" {
+      has_starttls = true;
+    } else if &line == "250 SMTPUTF8" {
+      break;
+    }
+  }
+  if !has_starttls {
+    return Err(CheckResult::error(Some(format!("STARTTLS SMTP extension not present."))));
+  }
+  try_io!(buf_reader.get_mut().write_all(b"STARTTLS\r\n"));
+  if try_unwrap!(read_till_crlf!(buf_reader).split_ascii_whitespace().next()) != "220" {
+    return Err(CheckResult::error(Some(format!("Protocol error"))));
+  }
+  if buf_reader.buffer().len() > 0 {
+    return Err(CheckResult::error(Some(format!("Protocol error"))));
+  }
+  Ok(())
+}
+
+pub(crate) fn system_time_to_time_t(t: time::SystemTime) -> time_t {
+  if t > time::UNIX_EPOCH {
+    t.duration_since(time::UNIX_EPOCH).unwrap().as_secs() as time_t
+  } else {
+    -(time::UNIX_EPOCH.duration_since(t).unwrap().as_secs() as time_t)
+  }
+}
+
+fn time_t_to_asn1(t: time_t) -> openssl::asn1::Asn1Time {
+  use foreign_types::ForeignType;
+  unsafe { openssl::asn1::Asn1Time::from_ptr(ASN1_TIME_set(std::ptr::null_mut(), t)) }
+}
+
+/// Number of (fractional) days from `now` until `not_after`. Negative if
+/// `not_after` is in the past.
+pub(crate) fn days_until(now: time_t, not_after: &openssl::asn1::Asn1TimeRef) -> f32 {
+  use foreign_types::{ForeignType, ForeignTypeRef};
+  let now_asn1 = time_t_to_asn1(now);
+  let mut diff_day: std::os::raw::c_int = 0;
+  let mut diff_sec: std::os::raw::c_int = 0;
+  unsafe { ASN1_TIME_diff(&mut diff_day as *mut _, &mut diff_sec as *mut _, now_asn1.as_ptr(), not_after.as_ptr()) };
+  let mut valid_rem_days: f32 = diff_day as f32;
+  valid_rem_days += diff_sec as f32 / (24*60*60) as f32;
+  valid_rem_days
+}
+
+use libc::time_t;
+
 extern "C" {
-  fn ASN1_TIME_cmp_time_t(s: *const openssl_sys::ASN1_TIME, t: libc::time_t) -> std::os::raw::c_int;
   fn X509_VERIFY_PARAM_set_time(param: *mut openssl_sys::X509_VERIFY_PARAM, t: libc::time_t);
   fn ASN1_TIME_set(s: *mut openssl_sys::ASN1_TIME, t: libc::time_t) -> *mut openssl_sys::ASN1_TIME;
   fn ASN1_TIME_diff(pday: *mut std::os::raw::c_int, psec: *mut std::os::raw::c_int, from: *const openssl_sys::ASN1_TIME, to: *const openssl_sys::ASN1_TIME) -> std::os::raw::c_int;
@@ -155,146 +552,159 @@ extern "C" {
 
 impl Checker for CertificateChecker {
   fn check(&mut self) -> CheckResult {
-    use std::ops::Add;
-    use foreign_types::{ForeignType, ForeignTypeRef};
-    let now = self.fake_now.unwrap_or(time::SystemTime::now());
-    let now_time_t = if now > time::UNIX_EPOCH {
-      now.duration_since(time::UNIX_EPOCH).unwrap().as_secs() as time_t
-    } else {
-      -(time::UNIX_EPOCH.duration_since(now).unwrap().as_secs() as time_t)
+    let server_result = self.check_server();
+    self.finish_check(server_result)
+  }
+}
+
+impl CertificateChecker {
+  /// Add the client certificate's status to the result of checking the
+  /// server, and decide whether to notify about expiry.
+  fn finish_check(&mut self, server_result: CheckResult) -> CheckResult {
+    let mut result = self.add_client_cert_result(server_result);
+    let rem_days = result.metrics.iter().filter(|m| m.name == "expiry_days" || m.name == "client_expiry_days")
+      .map(|m| m.value as f32).fold(None, |a: Option<f32>, b| Some(a.map_or(b, |a| a.min(b))));
+    if let Some(rem_days) = rem_days {
+      self.expiry_tiers.set_notify(&mut result, rem_days);
+    }
+    result
+  }
+
+  fn check_server(&mut self) -> CheckResult {
+    // Resolving and every address share the one timeout.
+    let deadline = time::Instant::now() + self.timeout;
+    if self.address_family == AddressFamily::Any {
+      let target = ConnectTarget::Name(self.connect_host().to_owned(), self.port);
+      return self.check_target(target, deadline);
+    }
+    let addrs = match resolve_addresses(self.connect_host(), self.port, self.address_family, self.timeout) {
+      Ok(addrs) => addrs,
+      Err(e) => return CheckResult::error(Some(e)),
     };
-    let compare_with = now.add(self.exipry_threshold);
-    use libc::time_t;
-    let compare_with: time_t = if compare_with > time::UNIX_EPOCH {
-      compare_with.duration_since(time::UNIX_EPOCH).unwrap().as_secs() as time_t
+    if self.address_family != AddressFamily::Each {
+      return self.check_target(ConnectTarget::Addrs(addrs), deadline);
+    }
+    let results: Vec<(net::SocketAddr, CheckResult)> = addrs.into_iter().map(|addr| (addr, self.check_target(ConnectTarget::Addrs(vec![addr]), deadline))).collect();
+    merge_address_results(results, &["expiry_days"], false)
+  }
+
+  /// Append the client certificate's expiry status, if we have one, to the
+  /// result of checking the server.
+  fn add_client_cert_result(&self, server_result: CheckResult) -> CheckResult {
+    let client_cert = match self.client_cert {
+      Some(ref c) => c,
+      None => return server_result,
+    };
+    let not_after = client_cert.not_after();
+    let rem_days = days_until(self.now_time_t(), not_after);
+    let tier = self.expiry_tiers.tier(rem_days);
+    let (result_type, info) = if rem_days <= 0f32 {
+      (CheckResultType::ERROR, format!("Client certificate expired: valid until {}.", not_after))
+    } else if tier.is_some() {
+      (self.expiry_tiers.result_type(tier), format!("Client certificate expiring in {:.1} days: valid until {}.", rem_days, not_after))
     } else {
-      -(time::UNIX_EPOCH.duration_since(compare_with).unwrap().as_secs() as time_t)
+      (CheckResultType::UP, format!("Client certificate valid until {}", not_after))
     };
-    let host = self.host.to_owned();
-    let port = self.port;
+    let mut result = server_result;
+    result.result_type = std::cmp::max(result.result_type, result_type);
+    result.info = Some(match result.info {
+      Some(server_info) => format!("{}\n{}", server_info, info),
+      None => info,
+    });
+    result.with_metric("client_expiry_days", rem_days as f64, "days")
+  }
+}
+
+impl CertificateChecker {
+  /// The SSL configuration for one connection, verifying certificates as of
+  /// `now_time_t`.
+  fn configure_ssl(&self, now_time_t: time_t) -> Result<openssl::ssl::ConnectConfiguration, CheckResult> {
+    use foreign_types::ForeignTypeRef;
     let mut ssl = match self.openssl_connector.configure() {
       Ok(k) => k,
-      Err(e) => return CheckResult::error(Some(format!("Allocating SSL: {}", &e)))
+      Err(e) => return Err(CheckResult::error(Some(format!("Allocating SSL: {}", &e))))
     };
     unsafe { X509_VERIFY_PARAM_set_time(ssl.param_mut().as_ptr(), now_time_t) };
-    let failure_mode = self.failure_mode;
-    let starttls = self.starttls;
-    let check_result = with_timeout(move || {
-      let mut conn = match net::TcpStream::connect((&host[..], port)) {
-        Ok(k) => k,
-        Err(e) => return CheckResult::error(Some(format!("Unable to connect: {}", &e)))
-      };
-      let _ = conn.set_nodelay(true);
+    Ok(ssl)
+  }
 
-      macro_rules! try_io {
-        ($e:expr) => {
-          if let Err(e) = $e {
-            return CheckResult::error(Some(format!("IO error: {}", &e)));
-          }
-        };
-      }
-      let mut buf: Vec<u8> = Vec::new();
-      macro_rules! read_till_crlf {
-        ($reader:expr) => {{
-          buf.clear();
-          try_io!($reader.read_until(b'\r', &mut buf));
-          if buf.last() != Some(&b'\r') {
-            return CheckResult::error(Some(format!("Unexpected EOF in protocol")));
-          }
-          buf.pop();
-          let mut r = [0u8];
-          try_io!($reader.read_exact(&mut r[..]));
-          if r[0] != b'\n' {
-            return CheckResult::error(Some(format!("Protocol error when doing starttls")));
-          }
-          match std::str::from_utf8(&buf) {
-            Ok(s) => s,
-            Err(_) => { return CheckResult::error(Some(format!("Protocol error when doing starttls"))); }
-          }
-        }};
-      }
-      macro_rules! try_unwrap {
-        ($e:expr) => {
-          match $e {
-            Some(s) => s,
-            None => { return CheckResult::error(Some(format!("Protocol error when doing starttls"))); }
-          }
-        };
-      }
+  fn check_target(&mut self, target: ConnectTarget, deadline: time::Instant) -> CheckResult {
+    let now_time_t = self.now_time_t();
+    let ssl = match self.configure_ssl(now_time_t) {
+      Ok(k) => k,
+      Err(r) => return r,
+    };
+    let (peer_cert, chain) = match connect_and_handshake(target, ssl, self.server_name(), self.starttls, deadline) {
+      Ok(c) => c,
+      // However it failed, it ran out of time first.
+      Err(_) if time::Instant::now() >= deadline => return CheckResult::error(Some("Timed out".to_owned())),
+      Err(r) => return r,
+    };
+    let result = self.evaluate_certificate(now_time_t, &peer_cert);
+    match self.dns_record_checks(&peer_cert, &chain) {
+      Some(checks) => add_dns_record_results(result, checks()),
+      None => result,
+    }
+  }
 
-      match starttls {
-        CertificateCheckerStartTLSOptions::NONE => {},
-        CertificateCheckerStartTLSOptions::SMTP => {
-          let mut buf_reader = io::BufReader::new(match conn.try_clone() {
-            Ok(cl) => cl,
-            Err(e) => { return CheckResult::error(Some(format!("Unexpected IO error when attempting to clone socket handle: {}", &e))); }
-          });
-          // 220 maowtm.org ESMTP Postfix (Debian/GNU)
-          let _welcome_line = read_till_crlf!(buf_reader);
-          let mut welcome_line = _welcome_line.split_ascii_whitespace();
-          if try_unwrap!(welcome_line.next()) != "220" {
-            return CheckResult::error(Some(format!("Unexpected welcome: {}", _welcome_line)));
-          }
-          try_io!(conn.write_all(b"EHLO example.com\r\n"));
-          let mut has_starttls = false;
-          loop {
-            let line = read_till_crlf!(buf_reader).to_ascii_uppercase();
-            if &line == "250-STARTTLS" {
-              has_starttls = true;
-            } else if &line == "250 SMTPUTF8" {
-              break;
-            }
-          }
-          if !has_starttls {
-            return CheckResult::error(Some(format!("STARTTLS SMTP extension not present.")));
-          }
-          try_io!(conn.write_all(b"STARTTLS\r\n"));
-          if try_unwrap!(read_till_crlf!(buf_reader).split_ascii_whitespace().next()) != "220" {
-            return CheckResult::error(Some(format!("Protocol error")));
-          }
-          if buf_reader.buffer().len() > 0 {
-            return CheckResult::error(Some(format!("Protocol error")));
-          }
-          std::mem::drop(buf_reader);
-        }
-      }
+  /// Check the expiry of the server's certificate.
+  fn evaluate_certificate(&self, now_time_t: time_t, peer_cert: &openssl::x509::X509) -> CheckResult {
+    let not_after = peer_cert.not_after();
+    let valid_rem_days = days_until(now_time_t, not_after);
+    let tier = self.expiry_tiers.tier(valid_rem_days);
+    let result = if tier.is_none() {
+      CheckResult::up(Some(format!("Certificate valid until {}", &not_after.to_string())))
+    } else {
+      let now_asn1 = time_t_to_asn1(now_time_t);
+      CheckResult::new(self.expiry_tiers.result_type(tier), Some(format!("Certificate expiring in {:.1} days: Certificate valid until {}; current time is {}.", valid_rem_days, &not_after.to_string(), &now_asn1.to_string())))
+    };
+    result.with_metric("expiry_days", valid_rem_days as f64, "days")
+  }
 
-      let mut tls_stream = match ssl.connect(&host, conn) {
-        Ok(k) => k,
-        Err(e) => return CheckResult::error(Some(format!("OpenSSL handshake: {}", &e)))
-      };
-      let peer_cert = match tls_stream.ssl().peer_certificate() {
-        Some(c) => c,
-        None => return CheckResult::error(Some(format!("No peer certificate?")))
+  /// The DANE and CAA checks of the server's certificates, if enabled. They
+  /// block on DNS queries, so they can be run on another thread.
+  fn dns_record_checks(&self, peer_cert: &openssl::x509::X509, chain: &[openssl::x509::X509]) -> Option<impl FnOnce() -> Result<Vec<CheckResult>, String> + Send + 'static> {
+    if !self.check_dane && self.caa_issuers.is_none() {
+      return None;
+    }
+    let (dns_server, timeout, port, check_dane) = (self.dns_server, self.timeout, self.port, self.check_dane);
+    let (server_name, caa_issuers) = (self.server_name().to_owned(), self.caa_issuers.clone());
+    let (peer_cert, chain) = (peer_cert.clone(), chain.to_vec());
+    Some(move || {
+      let mut dns = match dns_server {
+        Some(addr) => DnsClient::new(addr),
+        None => DnsClient::system()?,
       };
-      let not_after = peer_cert.not_after();
-      let ret_ok = unsafe { ASN1_TIME_cmp_time_t(not_after.as_ptr(), compare_with) } >= 0;
-      std::thread::spawn(move || {
-        if {let s = tls_stream.shutdown(); s.is_ok() && s.unwrap() == openssl::ssl::ShutdownResult::Sent} {
-          let _ = tls_stream.shutdown();
-        }
-      });
-      if ret_ok {
-        return CheckResult::up(Some(format!("Certificate valid until {}", &not_after.to_string())));
-      } else {
-        let now_asn1 = unsafe { openssl::asn1::Asn1Time::from_ptr(ASN1_TIME_set(std::ptr::null_mut(), now_time_t)) };
-        let mut diff_day: std::os::raw::c_int = 0;
-        let mut diff_sec: std::os::raw::c_int = 0;
-        unsafe { ASN1_TIME_diff(&mut diff_day as *mut _, &mut diff_sec as *mut _, now_asn1.as_ptr(), not_after.as_ptr()) };
-        let mut valid_rem_days: f32 = diff_day as f32;
-        valid_rem_days += diff_sec as f32 / (24*60*60) as f32;
-        return CheckResult{
-          result_type: failure_mode,
-          info: Some(format!("Certificate expiring in {:.1} days: Certificate valid until {}; current time is {}.", valid_rem_days, &not_after.to_string(), &now_asn1.to_string())),
-        };
+      dns.set_timeout(timeout);
+      let mut extra = Vec::new();
+      if check_dane {
+        extra.push(dane::check_tlsa(&dns, port, &server_name, &peer_cert, &chain));
       }
-    }, self.timeout);
-    if let Some(r) = check_result {
-      return r;
-    } else {
-      return CheckResult::error(Some(format!("Timed out")));
+      if let Some(ref issuers) = caa_issuers {
+        extra.push(dane::check_caa(&dns, &server_name, issuers, &peer_cert));
+      }
+      Ok(extra)
+    })
+  }
+}
+
+/// Add the outcome of the DANE and CAA checks to `result`. If they could not
+/// be run at all, that is an `ERROR` on top of the certificate's own result.
+fn add_dns_record_results(mut result: CheckResult, extra: Result<Vec<CheckResult>, String>) -> CheckResult {
+  let extra = match extra {
+    Ok(extra) => extra,
+    Err(e) => vec![CheckResult::error(Some(e))],
+  };
+  for r in extra {
+    result.result_type = std::cmp::max(result.result_type, r.result_type);
+    if let Some(info) = r.info {
+      result.info = Some(match result.info {
+        Some(i) => format!("{}\n{}", i, info),
+        None => info,
+      });
     }
   }
+  result
 }
 
 #[test]
@@ -351,3 +761,419 @@ fn smtp_starttls_test() {
 }
 
 */
+
+#[test]
+fn local_smtp_starttls_test() {
+  let ca = testing::make_ca("Test CA");
+  let addr = testing::serve_smtp_starttls(&testing::make_cert(&ca, &["backend.test"], 30));
+  let mut chk = CertificateChecker::builder("backend.test".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_starttls(CertificateCheckerStartTLSOptions::SMTP);
+  chk.build().unwrap().check().expect();
+}
+
+/// Helpers for testing against locally generated certificates and TLS servers.
+#[cfg(test)]
+pub(crate) mod testing {
+  use openssl::asn1::Asn1Time;
+  use openssl::bn::{BigNum, MsbOption};
+  use openssl::ec::{EcGroup, EcKey};
+  use openssl::hash::MessageDigest;
+  use openssl::nid::Nid;
+  use openssl::pkey::{PKey, Private};
+  use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+  use openssl::x509::{X509, X509Name, X509NameBuilder, extension};
+  use std::io::Read;
+  use std::net;
+
+  pub type CertAndKey = (X509, PKey<Private>);
+
+  fn new_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+  }
+
+  fn name(cn: &str) -> X509Name {
+    let mut nb = X509NameBuilder::new().unwrap();
+    nb.append_entry_by_text("CN", cn).unwrap();
+    nb.build()
+  }
+
+  fn builder(subject: &str, not_after_days: u32) -> openssl::x509::X509Builder {
+    let mut b = X509::builder().unwrap();
+    b.set_version(2).unwrap();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+    b.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    b.set_subject_name(&name(subject)).unwrap();
+    b.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    b.set_not_after(&Asn1Time::days_from_now(not_after_days).unwrap()).unwrap();
+    b
+  }
+
+  /// A self-signed CA valid for a year.
+  pub fn make_ca(cn: &str) -> CertAndKey {
+    let key = new_key();
+    let mut b = builder(cn, 365);
+    b.set_issuer_name(&name(cn)).unwrap();
+    b.set_pubkey(&key).unwrap();
+    b.append_extension(extension::BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    b.sign(&key, MessageDigest::sha256()).unwrap();
+    (b.build(), key)
+  }
+
+  /// A certificate for `dns_names`, signed by `ca` and expiring in `not_after_days` days.
+  pub fn make_cert(ca: &CertAndKey, dns_names: &[&str], not_after_days: u32) -> CertAndKey {
+    let key = new_key();
+    let mut b = builder(dns_names[0], not_after_days);
+    b.set_issuer_name(ca.0.subject_name()).unwrap();
+    b.set_pubkey(&key).unwrap();
+    let mut san = extension::SubjectAlternativeName::new();
+    for n in dns_names {
+      san.dns(n);
+    }
+    let san = san.build(&b.x509v3_context(Some(&ca.0), None)).unwrap();
+    b.append_extension(san).unwrap();
+    b.sign(&ca.1, MessageDigest::sha256()).unwrap();
+    (b.build(), key)
+  }
+
+  /// Like `serve_tls`, but also send the certificates in `chain` after `cert`.
+  pub fn serve_tls_with_chain(cert: &CertAndKey, chain: &[X509]) -> net::SocketAddr {
+    serve(cert, chain, None, false)
+  }
+
+  /// Like `serve_tls`, but as an SMTP server which starts TLS once asked to.
+  pub fn serve_smtp_starttls(cert: &CertAndKey) -> net::SocketAddr {
+    serve(cert, &[], None, true)
+  }
+
+  /// Serve TLS with `cert` on an ephemeral port of 127.0.0.1, forever. If
+  /// `client_ca` is given, clients must present a certificate signed by it.
+  /// (TLS 1.2 is used in that case, so that the rejection of a client
+  /// certificate happens within the handshake.)
+  pub fn serve_tls(cert: &CertAndKey, client_ca: Option<&X509>) -> net::SocketAddr {
+    serve(cert, &[], client_ca, false)
+  }
+
+  /// The server's side of an SMTP exchange up to STARTTLS.
+  fn smtp_greet(conn: &mut net::TcpStream) -> std::io::Result<()> {
+    use std::io::{BufRead, Write};
+    conn.write_all(b"220 backend.test ESMTP\r\n")?;
+    let mut reader = std::io::BufReader::new(conn.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    conn.write_all(b"250-backend.test\r\n250-STARTTLS\r\n250 SMTPUTF8\r\n")?;
+    line.clear();
+    reader.read_line(&mut line)?;
+    if line != "STARTTLS\r\n" {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected STARTTLS"));
+    }
+    conn.write_all(b"220 Ready to start TLS\r\n")
+  }
+
+  fn serve(cert: &CertAndKey, chain: &[X509], client_ca: Option<&X509>, starttls: bool) -> net::SocketAddr {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert.0).unwrap();
+    acceptor.set_private_key(&cert.1).unwrap();
+    for c in chain {
+      acceptor.add_extra_chain_cert(c.clone()).unwrap();
+    }
+    if let Some(ca) = client_ca {
+      let mut st = openssl::x509::store::X509StoreBuilder::new().unwrap();
+      st.add_cert(ca.clone()).unwrap();
+      acceptor.set_verify_cert_store(st.build()).unwrap();
+      acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+      acceptor.set_max_proto_version(Some(openssl::ssl::SslVersion::TLS1_2)).unwrap();
+    }
+    let acceptor = acceptor.build();
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+      for conn in listener.incoming() {
+        let mut conn = match conn { Ok(c) => c, Err(_) => continue };
+        let acceptor = acceptor.clone();
+        std::thread::spawn(move || {
+          if starttls && smtp_greet(&mut conn).is_err() {
+            return;
+          }
+          if let Ok(mut stream) = acceptor.accept(conn) {
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf) {
+              if n == 0 {
+                break;
+              }
+            }
+          }
+        });
+      }
+    });
+    addr
+  }
+}
+
+#[test]
+fn server_name_and_connect_address_test() {
+  let ca = testing::make_ca("Test CA");
+  let addr = testing::serve_tls(&testing::make_cert(&ca, &["backend.test"], 30), None);
+
+  let mut chk = CertificateChecker::builder("backend.test".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_timeout(time::Duration::from_secs(2));
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.clone().build().unwrap().check().expect();
+
+  let mut chk = CertificateChecker::builder("127.0.0.1".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.clone().build().unwrap().check().expect_err_contains("certificate verify failed");
+  chk.set_server_name("backend.test".to_owned());
+  chk.clone().build().unwrap().check().expect();
+  chk.set_server_name("other.test".to_owned());
+  chk.clone().build().unwrap().check().expect_err_contains("certificate verify failed");
+
+  // Each address
+  let mut chk = CertificateChecker::builder("backend.test".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_check_each_address(true);
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().starts_with(&format!("{}: UP: Certificate valid until", addr)));
+  chk.set_connect_address("nonexistent.invalid".to_owned());
+  chk.clone().build().unwrap().check().expect_err_contains("Unable to resolve nonexistent.invalid");
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_address_family(AddressFamily::V4);
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().starts_with("Certificate valid until"));
+  chk.set_address_family(AddressFamily::V6);
+  chk.clone().build().unwrap().check().expect_err_contains("127.0.0.1 has no IPv6 addresses.");
+
+  // Accepts, but never does the handshake.
+  let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut chk = CertificateChecker::builder("backend.test".to_owned(), listener.local_addr().unwrap().port());
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_timeout(time::Duration::from_millis(200));
+  let start = time::Instant::now();
+  chk.build().unwrap().check().expect_err_contains("Timed out");
+  assert!(start.elapsed() < time::Duration::from_millis(400));
+}
+
+#[test]
+fn client_certificate_test() {
+  let ca = testing::make_ca("Test CA");
+  let client_ca = testing::make_ca("Test client CA");
+  let addr = testing::serve_tls(&testing::make_cert(&ca, &["localhost"], 30), Some(&client_ca.0));
+
+  let mut chk = CertificateChecker::builder("localhost".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_timeout(time::Duration::from_secs(2));
+  chk.clone().build().unwrap().check().expect_err_contains("OpenSSL handshake");
+
+  let client_cert = testing::make_cert(&client_ca, &["client.test"], 30);
+  let mut good = chk.clone();
+  good.set_client_certificate(vec![client_cert.0.clone()], client_cert.1.clone());
+  let res = good.build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().contains("Client certificate valid until"));
+
+  // Same thing, loaded from PEM and PKCS#12.
+  let mut good = chk.clone();
+  good.set_client_certificate_pem(&client_cert.0.to_pem().unwrap(), &client_cert.1.private_key_to_pem_pkcs8().unwrap()).unwrap();
+  good.build().unwrap().check().expect();
+  let p12 = openssl::pkcs12::Pkcs12::builder().name("client").pkey(&client_cert.1).cert(&client_cert.0).build2("pass").unwrap();
+  let mut good = chk.clone();
+  assert!(good.set_client_certificate_pkcs12(&p12.to_der().unwrap(), "wrong").is_err());
+  good.set_client_certificate_pkcs12(&p12.to_der().unwrap(), "pass").unwrap();
+  good.build().unwrap().check().expect();
+
+  // Client certificate close to expiry
+  let client_cert = testing::make_cert(&client_ca, &["client.test"], 1);
+  let mut expiring = chk.clone();
+  expiring.set_client_certificate(vec![client_cert.0.clone()], client_cert.1.clone());
+  let res = expiring.clone().build().unwrap().check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("Client certificate expiring in"));
+  expiring.fake_time(time::SystemTime::now() + time::Duration::from_secs(2*24*60*60));
+  expiring.build().unwrap().check().expect_err_contains("Client certificate expired");
+
+  let other_key = testing::make_cert(&client_ca, &["client.test"], 30).1;
+  let mut mismatch = chk.clone();
+  mismatch.set_client_certificate(vec![client_cert.0.clone()], other_key);
+  assert!(mismatch.build().is_err());
+}
+
+#[test]
+fn expiry_tiers_test() {
+  use crate::checkers::NotifyHint;
+  let ca = testing::make_ca("Test CA");
+  let addr = testing::serve_tls(&testing::make_cert(&ca, &["localhost"], 20), None);
+  let day = time::Duration::from_secs(24*60*60);
+  let mut chk = CertificateChecker::builder("localhost".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+
+  // Single threshold: no tier crossed.
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert_eq!(res.notify, NotifyHint::Default);
+  let days = res.get_metric("expiry_days").unwrap();
+  assert!(days > 19.9 && days <= 20.0, "expiry_days = {}", days);
+
+  // Single threshold crossed, with the UP failure mode: still quiet.
+  let mut quiet = chk.clone();
+  quiet.set_expiry_threshold(30*day);
+  quiet.set_failure_mode(CheckResultType::UP);
+  let res = quiet.build().unwrap().check();
+  res.expect();
+  assert!(res.info.as_ref().unwrap().starts_with("Certificate expiring in"));
+  assert_eq!(res.notify, NotifyHint::Default);
+  assert!(!res.should_notify());
+
+  // Single threshold crossed with WARN: notified as usual on every check.
+  let mut warn = chk.clone();
+  warn.set_expiry_threshold(30*day);
+  let mut warn = warn.build().unwrap();
+  for _ in 0..2 {
+    let res = warn.check();
+    assert_eq!(res.result_type, CheckResultType::WARN);
+    assert_eq!(res.notify, NotifyHint::Default);
+    assert!(res.should_notify());
+  }
+
+  chk.set_expiry_tiers(vec![(3*day, ExpiryLevel::ERROR), (30*day, ExpiryLevel::INFO), (14*day, ExpiryLevel::WARN)]);
+  let mut chk = chk.build().unwrap();
+  let now = time::SystemTime::now();
+  let res = chk.check();
+  res.expect();
+  assert!(res.info.as_ref().unwrap().starts_with("Certificate expiring in"));
+  assert_eq!(res.notify, NotifyHint::Always);
+  assert!(res.should_notify());
+  let res = chk.check();
+  res.expect();
+  assert_eq!(res.notify, NotifyHint::Never);
+
+  chk.fake_time(now + 8*day);
+  let res = chk.check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.notify, NotifyHint::Always);
+  let res = chk.check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(!res.should_notify());
+
+  chk.fake_time(now + 18*day);
+  let res = chk.check();
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.notify, NotifyHint::Always);
+  let res = chk.check();
+  assert_eq!(res.notify, NotifyHint::Never);
+
+  // Expired: handshake failure, which always notifies.
+  chk.fake_time(now + 21*day);
+  let res = chk.check();
+  res.expect_err_contains("certificate has expired");
+  assert_eq!(res.notify, NotifyHint::Default);
+  assert!(res.should_notify());
+}
+
+#[test]
+fn dane_and_caa_test() {
+  use crate::utils::dns::{RecordData, testing::{record, serve_dns, Zone}};
+  let ca = testing::make_ca("Test CA");
+  let cert = testing::make_cert(&ca, &["mx.example.test"], 30);
+  let addr = testing::serve_tls(&cert, None);
+  let spki_sha256 = openssl::sha::sha256(&cert.0.public_key().unwrap().public_key_to_der().unwrap()).to_vec();
+  let tlsa_name = format!("_{}._tcp.mx.example.test", addr.port());
+  let (dns_addr, zone) = serve_dns(Zone{records: vec![
+    record(&tlsa_name, RecordData::TLSA{usage: 3, selector: 1, matching_type: 1, data: vec![0u8; 32]}),
+    record(&tlsa_name, RecordData::TLSA{usage: 3, selector: 1, matching_type: 1, data: spki_sha256.clone()}),
+  ], ..Zone::default()});
+
+  let mut chk = CertificateChecker::builder("mx.example.test".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_dns_server(dns_addr);
+  chk.set_check_dane(true);
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().contains("TLSA 3 1 1"));
+
+  // Certificate rolled over, but TLSA records not updated
+  zone.lock().unwrap().records.remove(1);
+  chk.clone().build().unwrap().check().expect_err_contains("None of the 1 TLSA records");
+
+  // Trust anchor usage, which needs the server to send the CA certificate.
+  let chained = testing::serve_tls_with_chain(&cert, std::slice::from_ref(&ca.0));
+  let mut with_chain = CertificateChecker::builder("mx.example.test".to_owned(), chained.port());
+  with_chain.set_trusted_CAs(vec![ca.0.clone()]);
+  with_chain.set_connect_address("127.0.0.1".to_owned());
+  with_chain.set_dns_server(dns_addr);
+  with_chain.set_check_dane(true);
+  zone.lock().unwrap().records.push(record(&format!("_{}._tcp.mx.example.test", chained.port()), RecordData::TLSA{usage: 2, selector: 0, matching_type: 2, data: openssl::sha::sha512(&ca.0.to_der().unwrap()).to_vec()}));
+  with_chain.build().unwrap().check().expect();
+
+  zone.lock().unwrap().records.clear();
+  let res = chk.clone().build().unwrap().check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("No TLSA records"));
+
+  // CAA, found on the parent domain.
+  zone.lock().unwrap().records.push(record("example.test", RecordData::CAA{flags: 0, tag: "issue".to_owned(), value: b"ca.example.test; accounturi=x".to_vec()}));
+  let mut chk = CertificateChecker::builder("mx.example.test".to_owned(), addr.port());
+  chk.set_trusted_CAs(vec![ca.0.clone()]);
+  chk.set_connect_address("127.0.0.1".to_owned());
+  chk.set_dns_server(dns_addr);
+  chk.set_check_caa(vec!["ca.example.test".to_owned()]);
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().contains("CAA records at example.test permit ca.example.test"));
+  chk.set_check_caa(vec!["letsencrypt.org".to_owned()]);
+  let res = chk.clone().build().unwrap().check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("do not permit letsencrypt.org to issue (allowed: ca.example.test)"));
+  // Can't guess the CAA domain of our test CA.
+  chk.set_check_caa(vec![]);
+  let res = chk.clone().build().unwrap().check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.info.unwrap().contains("Set it explicitly"));
+
+  zone.lock().unwrap().records.clear();
+  chk.set_check_caa(vec!["letsencrypt.org".to_owned()]);
+  let res = chk.clone().build().unwrap().check();
+  res.expect();
+  assert!(res.info.unwrap().contains("No CAA records for mx.example.test"));
+}
+
+#[test]
+fn add_dns_record_results_test() {
+  let cert_result = || CheckResult::warn(Some("Certificate expiring in 5.0 days".to_owned())).with_metric("expiry_days", 5f64, "days");
+  let res = add_dns_record_results(cert_result(), Err("No nameserver in /etc/resolv.conf".to_owned()));
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.info.as_ref().unwrap(), "Certificate expiring in 5.0 days\nNo nameserver in /etc/resolv.conf");
+  assert_eq!(res.get_metric("expiry_days"), Some(5f64));
+  let res = add_dns_record_results(cert_result(), Ok(vec![CheckResult::up(Some("CAA permits letsencrypt.org".to_owned()))]));
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.unwrap(), "Certificate expiring in 5.0 days\nCAA permits letsencrypt.org");
+}
+
+#[test]
+fn merge_address_results_test() {
+  let a: net::SocketAddr = "192.0.2.1:443".parse().unwrap();
+  let b: net::SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+  let res = merge_address_results(vec![(a, CheckResult::up(Some("fine".to_owned()))), (b, CheckResult::warn(Some("expiring".to_owned())))], &["expiry_days"], false);
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.info.unwrap(), "1 of 2 addresses failed.\n192.0.2.1:443: UP: fine\n[2001:db8::1]:443: WARN: expiring");
+  let with_days = |r: CheckResult, days: f64| r.with_metric("expiry_days", days, "days");
+  let res = merge_address_results(vec![(a, with_days(CheckResult::up(None), 30f64)), (b, CheckResult::error(Some("Unable to connect".to_owned())))], &["expiry_days"], true);
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert_eq!(res.get_metric("expiry_days"), Some(30f64));
+  // An expired certificate on one backend is not made up for by the others.
+  let res = merge_address_results(vec![(a, with_days(CheckResult::up(None), 30f64)), (b, with_days(CheckResult::error(Some("Certificate expired".to_owned())), -2f64))], &["expiry_days"], false);
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.get_metric("expiry_days"), Some(-2f64));
+  let res = merge_address_results(vec![(a, with_days(CheckResult::error(None), 3f64)), (b, with_days(CheckResult::warn(None), 1f64))], &["expiry_days"], false);
+  assert_eq!(res.result_type, CheckResultType::ERROR);
+  assert_eq!(res.get_metric("expiry_days"), Some(1f64));
+}
@@ -0,0 +1,163 @@
+//! [`AsyncChecker`](crate::checkers::async_checker::AsyncChecker) for
+//! [`CertificateChecker`](crate::checkers::tls::CertificateChecker).
+
+use super::{CertificateChecker, CertificateCheckerStartTLSOptions, ConnectTarget, add_dns_record_results, smtp_starttls};
+use crate::checkers::{AddressFamily, CheckResult, merge_address_results};
+use crate::checkers::async_checker::{self, AsyncChecker, CheckFuture};
+use crate::utils::DeadlineStream;
+use openssl::x509::X509;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Ask an SMTP server to start TLS on `conn`, before `deadline`. The exchange
+/// is short, so it runs on the blocking pool rather than being written twice.
+async fn async_smtp_starttls(conn: TcpStream, deadline: time::Instant) -> Result<TcpStream, CheckResult> {
+  let io_error = |e: std::io::Error| CheckResult::error(Some(format!("IO error: {}", &e)));
+  let conn = conn.into_std().map_err(io_error)?;
+  conn.set_nonblocking(false).map_err(io_error)?;
+  let conn = match tokio::task::spawn_blocking(move || {
+    let mut conn = DeadlineStream::new(conn, deadline);
+    smtp_starttls(&mut conn).map(|()| conn.into_inner())
+  }).await {
+    Ok(r) => r?,
+    Err(e) => return Err(CheckResult::error(Some(format!("STARTTLS panicked: {}", &e)))),
+  };
+  conn.set_nonblocking(true).map_err(io_error)?;
+  TcpStream::from_std(conn).map_err(io_error)
+}
+
+impl CertificateChecker {
+  /// Connect and do the handshake, returning the server's certificate and
+  /// chain.
+  async fn handshake(&self, target: ConnectTarget, ssl: openssl::ssl::Ssl, deadline: time::Instant) -> Result<(X509, Vec<X509>), CheckResult> {
+    let mut conn = match target {
+      ConnectTarget::Name(ref host, port) => async_checker::connect(host, port, None).await,
+      ConnectTarget::Addrs(ref addrs) => async_checker::connect("", 0, Some(addrs)).await,
+    }.map_err(|e| CheckResult::error(Some(e)))?;
+    match self.starttls {
+      CertificateCheckerStartTLSOptions::NONE => {},
+      CertificateCheckerStartTLSOptions::SMTP => conn = async_smtp_starttls(conn, deadline).await?,
+    }
+    let mut tls_stream = tokio_openssl::SslStream::new(ssl, conn).map_err(|e| CheckResult::error(Some(format!("Allocating SSL: {}", &e))))?;
+    if let Err(e) = Pin::new(&mut tls_stream).connect().await {
+      return Err(CheckResult::error(Some(format!("OpenSSL handshake: {}", &e))));
+    }
+    let peer_cert = tls_stream.ssl().peer_certificate().ok_or_else(|| CheckResult::error(Some("No peer certificate?".to_owned())))?;
+    let chain: Vec<X509> = tls_stream.ssl().peer_cert_chain().map(|c| c.iter().map(|c| c.to_owned()).collect()).unwrap_or_default();
+    tokio::spawn(async move {
+      let _ = tls_stream.shutdown().await;
+    });
+    Ok((peer_cert, chain))
+  }
+
+  async fn check_target_async(&self, target: ConnectTarget, deadline: time::Instant) -> CheckResult {
+    let now_time_t = self.now_time_t();
+    let ssl = match self.configure_ssl(now_time_t).and_then(|c| c.into_ssl(self.server_name()).map_err(|e| CheckResult::error(Some(format!("Allocating SSL: {}", &e))))) {
+      Ok(s) => s,
+      Err(r) => return r,
+    };
+    let (peer_cert, chain) = match tokio::time::timeout_at(deadline.into(), self.handshake(target, ssl, deadline)).await {
+      Ok(Ok(c)) => c,
+      Ok(Err(r)) => return r,
+      Err(_) => return CheckResult::error(Some("Timed out".to_owned())),
+    };
+    let result = self.evaluate_certificate(now_time_t, &peer_cert);
+    match self.dns_record_checks(&peer_cert, &chain) {
+      Some(checks) => match tokio::task::spawn_blocking(checks).await {
+        Ok(extra) => add_dns_record_results(result, extra),
+        Err(e) => CheckResult::error(Some(format!("DNS record checks panicked: {}", &e))),
+      },
+      None => result,
+    }
+  }
+
+  async fn check_server_async(&self) -> CheckResult {
+    // Resolving and every address share the one timeout.
+    let deadline = time::Instant::now() + self.timeout;
+    if self.address_family == AddressFamily::Any {
+      let target = ConnectTarget::Name(self.connect_host().to_owned(), self.port);
+      return self.check_target_async(target, deadline).await;
+    }
+    let addrs = match tokio::time::timeout_at(deadline.into(), async_checker::resolve_addresses(self.connect_host(), self.port, self.address_family)).await {
+      Ok(Ok(addrs)) => addrs,
+      Ok(Err(e)) => return CheckResult::error(Some(e)),
+      Err(_) => return CheckResult::error(Some(format!("Timed out resolving {}", self.connect_host()))),
+    };
+    if self.address_family != AddressFamily::Each {
+      return self.check_target_async(ConnectTarget::Addrs(addrs), deadline).await;
+    }
+    let checker = Arc::new(self.clone());
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, addr) in addrs.into_iter().enumerate() {
+      let checker = checker.clone();
+      tasks.spawn(async move {
+        (i, addr, checker.check_target_async(ConnectTarget::Addrs(vec![addr]), deadline).await)
+      });
+    }
+    let mut results = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+      match joined {
+        Ok(r) => results.push(r),
+        Err(e) => return CheckResult::error(Some(format!("Checking an address panicked: {}", &e))),
+      }
+    }
+    results.sort_by_key(|(i, _, _)| *i);
+    merge_address_results(results.into_iter().map(|(_, addr, r)| (addr, r)).collect(), &["expiry_days"], false)
+  }
+}
+
+impl AsyncChecker for CertificateChecker {
+  fn check(&mut self) -> CheckFuture<'_> {
+    Box::pin(async move {
+      let server_result = self.check_server_async().await;
+      self.finish_check(server_result)
+    })
+  }
+}
+
+#[test]
+fn async_cert_checker_test() {
+  use super::testing::{make_ca, make_cert, serve_smtp_starttls, serve_tls};
+  use crate::checkers::CheckResultType;
+  use std::time;
+  let ca = make_ca("Test CA");
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let check = |cert_days: u32, family: AddressFamily| {
+    let addr = serve_tls(&make_cert(&ca, &["backend.test"], cert_days), None);
+    let mut builder = CertificateChecker::builder("backend.test".to_owned(), addr.port());
+    builder.set_trusted_CAs(vec![ca.0.clone()]);
+    builder.set_connect_address("127.0.0.1".to_owned());
+    builder.set_address_family(family);
+    let mut checker = builder.build().unwrap();
+    (addr, runtime.block_on(AsyncChecker::check(&mut checker)))
+  };
+  let (_, res) = check(30, AddressFamily::Any);
+  res.expect();
+  assert!(res.info.unwrap().starts_with("Certificate valid until "));
+  let (_, res) = check(1, AddressFamily::V4);
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!(res.get_metric("expiry_days").unwrap() < 1.1f64);
+  let (addr, res) = check(30, AddressFamily::Each);
+  assert!(res.info.unwrap().starts_with(&format!("{}: UP: Certificate valid until", addr)));
+
+  let addr = serve_smtp_starttls(&make_cert(&ca, &["backend.test"], 30));
+  let mut builder = CertificateChecker::builder("backend.test".to_owned(), addr.port());
+  builder.set_trusted_CAs(vec![ca.0.clone()]);
+  builder.set_connect_address("127.0.0.1".to_owned());
+  builder.set_starttls(CertificateCheckerStartTLSOptions::SMTP);
+  let mut checker = builder.build().unwrap();
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect();
+
+  // Accepts, but never does the handshake.
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut builder = CertificateChecker::builder("backend.test".to_owned(), listener.local_addr().unwrap().port());
+  builder.set_connect_address("127.0.0.1".to_owned());
+  builder.set_timeout(time::Duration::from_millis(200));
+  let mut checker = builder.build().unwrap();
+  let start = time::Instant::now();
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect_err_contains("Timed out");
+  assert!(start.elapsed() < time::Duration::from_millis(400));
+}
@@ -0,0 +1,133 @@
+//! DANE TLSA and CAA record validation for
+//! [`CertificateChecker`](crate::checkers::tls::CertificateChecker).
+
+use crate::checkers::CheckResult;
+use crate::utils::dns::{self, DnsClient, RecordData};
+use openssl::x509::X509;
+
+/// Check that at least one of the TLSA records for the service matches the
+/// presented certificate chain.
+pub(super) fn check_tlsa(dns: &DnsClient, port: u16, server_name: &str, peer_cert: &X509, chain: &[X509]) -> CheckResult {
+  let tlsa_name = format!("_{}._tcp.{}", port, dns::normalize_name(server_name));
+  let res = match dns.query(&tlsa_name, dns::TYPE_TLSA) {
+    Ok(r) => r,
+    Err(e) => return CheckResult::error(Some(format!("Looking up TLSA records: {}", e))),
+  };
+  if res.rcode != dns::RCODE_NOERROR && res.rcode != dns::RCODE_NXDOMAIN {
+    return CheckResult::error(Some(format!("Looking up TLSA records for {}: rcode {}", tlsa_name, res.rcode)));
+  }
+  let records: Vec<&RecordData> = res.answers_of_type(dns::TYPE_TLSA).collect();
+  if records.is_empty() {
+    return CheckResult::warn(Some(format!("No TLSA records at {}.", tlsa_name)));
+  }
+  let validated = if res.authentic_data { "" } else { " (not DNSSEC-validated by the resolver)" };
+  for record in records.iter() {
+    if let RecordData::TLSA{usage, selector, matching_type, data} = record {
+      match tlsa_matches(*usage, *selector, *matching_type, data, peer_cert, chain) {
+        Ok(true) => return CheckResult::up(Some(format!("{} matched{}.", record, validated))),
+        Ok(false) => {},
+        Err(e) => return CheckResult::error(Some(format!("Matching TLSA records: {}", e))),
+      }
+    }
+  }
+  CheckResult::error(Some(format!("None of the {} TLSA records at {} match the certificate{}.", records.len(), tlsa_name, validated)))
+}
+
+/// Whether a TLSA record matches the end-entity certificate (usages 1 and 3) or
+/// one of the other certificates in the chain (usages 0 and 2).
+fn tlsa_matches(usage: u8, selector: u8, matching_type: u8, data: &[u8], peer_cert: &X509, chain: &[X509]) -> Result<bool, String> {
+  let candidates: Vec<&X509> = match usage {
+    1 | 3 => vec![peer_cert],
+    0 | 2 => chain.iter().filter(|c| c.to_der().ok() != peer_cert.to_der().ok()).collect(),
+    _ => return Ok(false),
+  };
+  for cert in candidates {
+    let selected = match selector {
+      0 => cert.to_der(),
+      1 => cert.public_key().and_then(|k| k.public_key_to_der()),
+      _ => return Ok(false),
+    }.map_err(|e| format!("{}", &e))?;
+    let matches = match matching_type {
+      0 => &selected[..] == data,
+      1 => &openssl::sha::sha256(&selected)[..] == data,
+      2 => &openssl::sha::sha512(&selected)[..] == data,
+      _ => false,
+    };
+    if matches {
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+/// CAA domain names of some well-known CAs, by the organization name in their
+/// issuer certificates.
+const KNOWN_CAS: &[(&str, &str)] = &[
+  ("Let's Encrypt", "letsencrypt.org"),
+  ("DigiCert", "digicert.com"),
+  ("Sectigo", "sectigo.com"),
+  ("ZeroSSL", "sectigo.com"),
+  ("Google Trust Services", "pki.goog"),
+  ("GlobalSign", "globalsign.com"),
+  ("Amazon", "amazon.com"),
+  ("Buypass", "buypass.com"),
+  ("Entrust", "entrust.net"),
+  ("GoDaddy", "godaddy.com"),
+];
+
+fn guess_ca_domain(cert: &X509) -> Option<&'static str> {
+  let org = cert.issuer_name().entries_by_nid(openssl::nid::Nid::ORGANIZATIONNAME).next()?;
+  let org = String::from_utf8_lossy(org.data().as_slice()).into_owned();
+  KNOWN_CAS.iter().find(|(name, _)| org.contains(name)).map(|(_, domain)| *domain)
+}
+
+/// Check that the CAA records of `server_name`, or its closest ancestor which
+/// has any, permit one of `issuer_domains` (or the guessed issuer of `peer_cert`
+/// if empty).
+pub(super) fn check_caa(dns: &DnsClient, server_name: &str, issuer_domains: &[String], peer_cert: &X509) -> CheckResult {
+  let issuers: Vec<String> = if issuer_domains.is_empty() {
+    match guess_ca_domain(peer_cert) {
+      Some(d) => vec![d.to_owned()],
+      None => return CheckResult::warn(Some("Unable to tell the CAA domain of the certificate's issuer. Set it explicitly.".to_owned())),
+    }
+  } else {
+    issuer_domains.iter().map(|d| d.to_ascii_lowercase()).collect()
+  };
+  let wildcard = peer_cert.subject_alt_names().map(|names| names.iter().any(|n| n.dnsname().map(|d| d.starts_with("*.")).unwrap_or(false))).unwrap_or(false);
+  let mut name = dns::normalize_name(server_name);
+  loop {
+    let res = match dns.query(&name, dns::TYPE_CAA) {
+      Ok(r) => r,
+      Err(e) => return CheckResult::error(Some(format!("Looking up CAA records: {}", e))),
+    };
+    if res.rcode != dns::RCODE_NOERROR && res.rcode != dns::RCODE_NXDOMAIN {
+      return CheckResult::error(Some(format!("Looking up CAA records for {}: rcode {}", name, res.rcode)));
+    }
+    let records: Vec<(&str, String)> = res.answers_of_type(dns::TYPE_CAA).filter_map(|r| {
+      if let RecordData::CAA{tag, value, ..} = r {
+        let value = String::from_utf8_lossy(value);
+        let domain = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        Some((&tag[..], domain))
+      } else {
+        None
+      }
+    }).collect();
+    if !records.is_empty() {
+      let has_issuewild = records.iter().any(|(tag, _)| *tag == "issuewild");
+      let tag = if wildcard && has_issuewild { "issuewild" } else { "issue" };
+      let allowed: Vec<&str> = records.iter().filter(|(t, _)| *t == tag).map(|(_, d)| &d[..]).filter(|d| !d.is_empty()).collect();
+      if allowed.iter().any(|d| issuers.iter().any(|i| i == d)) {
+        return CheckResult::up(Some(format!("CAA records at {} permit {}.", name, issuers.join(", "))));
+      }
+      if allowed.is_empty() && !records.iter().any(|(t, _)| *t == tag) {
+        // Only e.g. iodef records: no restriction on issuance.
+        return CheckResult::up(Some(format!("CAA records at {} do not restrict issuance.", name)));
+      }
+      return CheckResult::warn(Some(format!("CAA records at {} do not permit {} to issue (allowed: {}).", name, issuers.join(", "), if allowed.is_empty() { "none".to_owned() } else { allowed.join(", ") })));
+    }
+    match name.find('.') {
+      Some(i) => name = name[i + 1..].to_owned(),
+      None => return CheckResult::up(Some(format!("No CAA records for {}.", dns::normalize_name(server_name)))),
+    }
+  }
+}
@@ -0,0 +1,505 @@
+//! Checks for services which speak UDP: a generic request / response probe,
+//! and an NTP checker built on the same exchange.
+
+use crate::checkers::{Checker, CheckResult, Threshold, AddressFamily, resolve_addresses};
+use crate::utils::transport::block_on;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time;
+
+/// A connected UDP socket, blocking or not, to run [`exchange_addrs`] with.
+trait Datagrams: Sized + Send + Sync {
+  fn connect(addr: SocketAddr) -> impl Future<Output = Result<Self, String>> + Send;
+  fn send<'a>(&'a self, data: &'a [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a;
+  /// Receive a datagram, or `None` once `deadline` has passed.
+  fn recv_before<'a>(&'a self, buf: &'a mut [u8], deadline: time::Instant) -> impl Future<Output = io::Result<Option<usize>>> + Send + 'a;
+}
+
+fn local_addr(addr: SocketAddr) -> SocketAddr {
+  if addr.is_ipv4() { ([0u8; 4], 0).into() } else { ([0u16; 8], 0).into() }
+}
+
+impl Datagrams for UdpSocket {
+  fn connect(addr: SocketAddr) -> impl Future<Output = Result<Self, String>> + Send {
+    std::future::ready((|| {
+      let socket = UdpSocket::bind(local_addr(addr)).map_err(|e| format!("Binding UDP socket: {}", &e))?;
+      socket.connect(addr).map_err(|e| format!("Connecting to {}: {}", addr, &e))?;
+      Ok(socket)
+    })())
+  }
+
+  fn send<'a>(&'a self, data: &'a [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a {
+    std::future::ready(UdpSocket::send(self, data))
+  }
+
+  fn recv_before<'a>(&'a self, buf: &'a mut [u8], deadline: time::Instant) -> impl Future<Output = io::Result<Option<usize>>> + Send + 'a {
+    std::future::ready((|| {
+      let now = time::Instant::now();
+      if now >= deadline {
+        return Ok(None);
+      }
+      self.set_read_timeout(Some(deadline - now))?;
+      match self.recv(buf) {
+        Ok(n) => Ok(Some(n)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+      }
+    })())
+  }
+}
+
+#[cfg(feature = "async")]
+impl Datagrams for tokio::net::UdpSocket {
+  async fn connect(addr: SocketAddr) -> Result<Self, String> {
+    let socket = tokio::net::UdpSocket::bind(local_addr(addr)).await.map_err(|e| format!("Binding UDP socket: {}", &e))?;
+    socket.connect(addr).await.map_err(|e| format!("Connecting to {}: {}", addr, &e))?;
+    Ok(socket)
+  }
+
+  fn send<'a>(&'a self, data: &'a [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a {
+    tokio::net::UdpSocket::send(self, data)
+  }
+
+  async fn recv_before(&self, buf: &mut [u8], deadline: time::Instant) -> io::Result<Option<usize>> {
+    match tokio::time::timeout_at(deadline.into(), self.recv(buf)).await {
+      Ok(res) => res.map(Some),
+      Err(_) => Ok(None),
+    }
+  }
+}
+
+/// Send a datagram made by `make_request` to `host:port` and wait up to
+/// `timeout` for a reply for which `accept(request, reply)` returns true,
+/// sending a new request up to `retries` more times. Replies which are not
+/// accepted, like late replies to an earlier attempt, are ignored. Each address
+/// of `host` is tried in turn until one replies.
+///
+/// Returns the reply and the round-trip time of the attempt it answered.
+pub(crate) fn exchange<M, A>(host: &str, port: u16, timeout: time::Duration, retries: u32, make_request: M, accept: A) -> Result<(Vec<u8>, time::Duration), String>
+where M: FnMut() -> Vec<u8> + Send, A: Fn(&[u8], &[u8]) -> bool + Sync {
+  let addrs = resolve_addresses(host, port, AddressFamily::Any, timeout)?;
+  block_on(exchange_addrs::<UdpSocket, _, _>(addrs, timeout, retries, make_request, accept))
+}
+
+/// Like [`exchange`], without blocking.
+#[cfg(feature = "async")]
+pub(crate) async fn exchange_async<M, A>(host: &str, port: u16, timeout: time::Duration, retries: u32, make_request: M, accept: A) -> Result<(Vec<u8>, time::Duration), String>
+where M: FnMut() -> Vec<u8> + Send, A: Fn(&[u8], &[u8]) -> bool + Sync {
+  use crate::checkers::async_checker::{resolve_addresses, within};
+  let addrs = within(timeout, resolve_addresses(host, port, AddressFamily::Any)).await?;
+  exchange_addrs::<tokio::net::UdpSocket, _, _>(addrs, timeout, retries, make_request, accept).await
+}
+
+async fn exchange_addrs<S, M, A>(addrs: Vec<SocketAddr>, timeout: time::Duration, retries: u32, mut make_request: M, accept: A) -> Result<(Vec<u8>, time::Duration), String>
+where S: Datagrams, M: FnMut() -> Vec<u8> + Send, A: Fn(&[u8], &[u8]) -> bool + Sync {
+  let mut errors = Vec::new();
+  for addr in addrs {
+    match exchange_addr::<S, _, _>(addr, timeout, retries, &mut make_request, &accept).await {
+      Ok(r) => return Ok(r),
+      Err(e) => errors.push(e),
+    }
+  }
+  Err(errors.join(" "))
+}
+
+async fn exchange_addr<S, M, A>(addr: SocketAddr, timeout: time::Duration, retries: u32, make_request: &mut M, accept: &A) -> Result<(Vec<u8>, time::Duration), String>
+where S: Datagrams, M: FnMut() -> Vec<u8> + Send, A: Fn(&[u8], &[u8]) -> bool + Sync {
+  let socket = S::connect(addr).await?;
+  let mut last_err = None;
+  let mut buf = vec![0u8; 65536];
+  for _ in 0..=retries {
+    let request = make_request();
+    let start = time::Instant::now();
+    socket.send(&request).await.map_err(|e| format!("Sending to {}: {}", addr, &e))?;
+    let deadline = start + timeout;
+    loop {
+      match socket.recv_before(&mut buf, deadline).await {
+        Ok(Some(n)) if accept(&request, &buf[..n]) => return Ok((buf[..n].to_vec(), start.elapsed())),
+        Ok(Some(_)) => continue,
+        Ok(None) => break,
+        Err(e) => {
+          // e.g. connection refused, from an ICMP port unreachable.
+          last_err = Some(format!("{}", &e));
+          break;
+        },
+      }
+    }
+  }
+  let attempts = retries + 1;
+  Err(match last_err {
+    Some(e) => format!("No reply from {} after {} attempts: {}.", addr, attempts, e),
+    None => format!("No reply from {} after {} attempts.", addr, attempts),
+  })
+}
+
+/// What the reply to a [`UdpChecker`](crate::checkers::udp::UdpChecker) probe
+/// has to look like.
+#[derive(Clone, Debug)]
+pub enum UdpReplyCheck {
+  Any,
+  /// The reply contains these bytes.
+  Contains(Vec<u8>),
+  /// The reply starts with these bytes.
+  StartsWith(Vec<u8>),
+}
+
+/// Send a datagram and expect a reply, for services like DNS, SIP or game
+/// servers with a simple status query.
+///
+/// The result has the metric `rtt` (milliseconds).
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, udp::{UdpChecker, UdpReplyCheck}};
+/// # use std::time::Duration;
+/// let mut checker = UdpChecker::new("game.example.com", 27015, b"\xff\xff\xff\xffTSource Engine Query\0");
+/// checker.set_reply_check(UdpReplyCheck::StartsWith(b"\xff\xff\xff\xff".to_vec()))
+///        .set_timeout(Duration::from_secs(2))
+///        .set_retries(3);
+/// let result = checker.check();
+/// ```
+pub struct UdpChecker {
+  host: String,
+  port: u16,
+  request: Vec<u8>,
+  reply_check: UdpReplyCheck,
+  timeout: time::Duration,
+  retries: u32,
+}
+
+impl UdpChecker {
+  pub fn new(host: &str, port: u16, request: &[u8]) -> Self {
+    UdpChecker{host: host.to_owned(), port, request: request.to_vec(), reply_check: UdpReplyCheck::Any, timeout: time::Duration::from_secs(3), retries: 2}
+  }
+
+  /// Default is [`UdpReplyCheck::Any`](crate::checkers::udp::UdpReplyCheck::Any).
+  pub fn set_reply_check(&mut self, value: UdpReplyCheck) -> &mut Self {
+    self.reply_check = value;
+    self
+  }
+
+  /// How long to wait for a reply to each attempt.
+  ///
+  /// Default is 3s.
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// How many times to send the request again when there is no reply.
+  ///
+  /// Default is 2.
+  pub fn set_retries(&mut self, value: u32) -> &mut Self {
+    self.retries = value;
+    self
+  }
+}
+
+impl UdpChecker {
+  fn result(&self, reply: Result<(Vec<u8>, time::Duration), String>) -> CheckResult {
+    let (reply, rtt) = match reply {
+      Ok(r) => r,
+      Err(e) => return CheckResult::error(Some(e)),
+    };
+    let matches = match self.reply_check {
+      UdpReplyCheck::Any => true,
+      UdpReplyCheck::Contains(ref find) => find.is_empty() || reply.windows(find.len()).any(|w| w == &find[..]),
+      UdpReplyCheck::StartsWith(ref prefix) => reply.starts_with(prefix),
+    };
+    let rtt_ms = rtt.as_secs_f64() * 1000f64;
+    let result = if matches {
+      CheckResult::up(Some(format!("Reply from {}:{} in {:.1}ms.", &self.host, self.port, rtt_ms)))
+    } else {
+      let shown = &reply[..reply.len().min(64)];
+      CheckResult::error(Some(format!("Unexpected reply from {}:{}: {:?}", &self.host, self.port, String::from_utf8_lossy(shown))))
+    };
+    result.with_metric("rtt", rtt_ms, "ms")
+  }
+}
+
+impl Checker for UdpChecker {
+  fn check(&mut self) -> CheckResult {
+    let request = &self.request;
+    self.result(exchange(&self.host, self.port, self.timeout, self.retries, || request.clone(), |_, _| true))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for UdpChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    Box::pin(async move {
+      let request = &self.request;
+      let reply = exchange_async(&self.host, self.port, self.timeout, self.retries, || request.clone(), |_, _| true).await;
+      self.result(reply)
+    })
+  }
+}
+
+/// Seconds from 1900 (the NTP epoch) to 1970.
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800f64;
+
+fn to_ntp_timestamp(time: time::SystemTime) -> u64 {
+  let since_epoch = time.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+  let secs = (since_epoch.as_secs() + NTP_UNIX_OFFSET as u64) as u32;
+  let frac = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+  ((secs as u64) << 32) | frac
+}
+
+/// Seconds since the unix epoch. Timestamps with the top bit of the seconds
+/// clear are taken to be after the 2036 rollover.
+fn from_ntp_timestamp(value: u64) -> f64 {
+  let mut secs = (value >> 32) as f64;
+  if value >> 63 == 0 {
+    secs += 4_294_967_296f64;
+  }
+  secs - NTP_UNIX_OFFSET + (value & 0xffff_ffff) as f64 / 4_294_967_296f64
+}
+
+fn unix_seconds(time: time::SystemTime) -> f64 {
+  time.duration_since(time::UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0f64)
+}
+
+/// Query an NTP server and check the offset between its clock and the local
+/// one, for example because clock skew breaks TLS and token authentication.
+///
+/// The offset is computed like `ntpdate` does, from a single exchange. A server
+/// which is not synchronized itself (stratum 16, or leap indicator 3), or which
+/// answers with a kiss-of-death packet, is an `ERROR`. The result has the
+/// metrics `offset` (seconds, positive when the local clock is behind),
+/// `stratum` and `rtt` (milliseconds).
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{Checker, Threshold, udp::NtpChecker};
+/// let mut checker = NtpChecker::new("pool.ntp.org", 123);
+/// checker.set_offset_threshold(Threshold::new(Some(0.1f64), Some(1f64)));
+/// let result = checker.check();
+/// ```
+pub struct NtpChecker {
+  host: String,
+  port: u16,
+  timeout: time::Duration,
+  retries: u32,
+  offset_threshold: Threshold,
+}
+
+impl NtpChecker {
+  pub fn new(host: &str, port: u16) -> Self {
+    NtpChecker{host: host.to_owned(), port, timeout: time::Duration::from_secs(3), retries: 2, offset_threshold: Threshold::new(Some(0.5f64), Some(5f64))}
+  }
+
+  /// Same as [`UdpChecker::set_timeout`](crate::checkers::udp::UdpChecker::set_timeout).
+  pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+    self.timeout = value;
+    self
+  }
+
+  /// Same as [`UdpChecker::set_retries`](crate::checkers::udp::UdpChecker::set_retries).
+  pub fn set_retries(&mut self, value: u32) -> &mut Self {
+    self.retries = value;
+    self
+  }
+
+  /// Thresholds on the absolute clock offset, in seconds.
+  ///
+  /// Default is `WARN` at 0.5s and `ERROR` at 5s.
+  pub fn set_offset_threshold(&mut self, value: Threshold) -> &mut Self {
+    self.offset_threshold = value;
+    self
+  }
+
+  fn request() -> Vec<u8> {
+    let mut request = vec![0u8; 48];
+    // Leap indicator 0, version 4, mode 3 (client).
+    request[0] = 4 << 3 | 3;
+    request[40..48].copy_from_slice(&to_ntp_timestamp(time::SystemTime::now()).to_be_bytes());
+    request
+  }
+
+  /// The server copies our transmit timestamp into the originate timestamp,
+  /// which matches replies to requests.
+  fn accept(request: &[u8], reply: &[u8]) -> bool {
+    reply.len() >= 48 && reply[24..32] == request[40..48]
+  }
+
+  /// The clock offset and stratum of the server from `reply`, received at
+  /// `received_at`.
+  fn parse(reply: &[u8], received_at: time::SystemTime) -> Result<(f64, u8), String> {
+    let mode = reply[0] & 0x7;
+    if mode != 4 {
+      return Err(format!("Unexpected NTP mode {} in reply.", mode));
+    }
+    let stratum = reply[1];
+    if stratum == 0 {
+      return Err(format!("NTP server sent kiss code {}.", String::from_utf8_lossy(&reply[12..16]).trim_end_matches('\0')));
+    }
+    if reply[0] >> 6 == 3 || stratum >= 16 {
+      return Err("NTP server is not synchronized.".to_owned());
+    }
+    let read = |at: usize| {
+      let mut bytes = [0u8; 8];
+      bytes.copy_from_slice(&reply[at..at + 8]);
+      from_ntp_timestamp(u64::from_be_bytes(bytes))
+    };
+    let (t1, t2, t3, t4) = (read(24), read(32), read(40), unix_seconds(received_at));
+    Ok(((t2 - t1 + t3 - t4) / 2f64, stratum))
+  }
+
+  fn result(&self, reply: Result<(Vec<u8>, time::Duration), String>) -> CheckResult {
+    let received_at = time::SystemTime::now();
+    match reply.and_then(|(reply, rtt)| Ok((NtpChecker::parse(&reply, received_at)?, rtt))) {
+      Ok(((offset, stratum), rtt)) => {
+        let result_type = self.offset_threshold.evaluate(offset.abs());
+        let info = format!("NTP server {}:{} is at stratum {}, clock offset {:+.3}s.", &self.host, self.port, stratum, offset);
+        CheckResult::new(result_type, Some(info))
+          .with_metric("offset", offset, "s")
+          .with_metric("stratum", stratum as f64, "")
+          .with_metric("rtt", rtt.as_secs_f64() * 1000f64, "ms")
+      },
+      Err(e) => CheckResult::error(Some(e)),
+    }
+  }
+}
+
+impl Checker for NtpChecker {
+  fn check(&mut self) -> CheckResult {
+    self.result(exchange(&self.host, self.port, self.timeout, self.retries, NtpChecker::request, NtpChecker::accept))
+  }
+}
+
+#[cfg(feature = "async")]
+impl crate::checkers::async_checker::AsyncChecker for NtpChecker {
+  fn check(&mut self) -> crate::checkers::async_checker::CheckFuture<'_> {
+    Box::pin(async move {
+      let reply = exchange_async(&self.host, self.port, self.timeout, self.retries, NtpChecker::request, NtpChecker::accept).await;
+      self.result(reply)
+    })
+  }
+}
+
+/// Answer datagrams on a local port with `respond`, which returns `None` to
+/// drop one.
+#[cfg(test)]
+fn serve_udp<F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static>(mut respond: F) -> SocketAddr {
+  let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let addr = socket.local_addr().unwrap();
+  std::thread::spawn(move || {
+    let mut buf = [0u8; 1500];
+    while let Ok((n, from)) = socket.recv_from(&mut buf) {
+      if let Some(reply) = respond(&buf[..n]) {
+        socket.send_to(&reply, from).unwrap();
+      }
+    }
+  });
+  addr
+}
+
+#[test]
+fn udp_checker_test() {
+  let mut dropped = 0;
+  let addr = serve_udp(move |req| {
+    if dropped < 2 {
+      dropped += 1;
+      return None;
+    }
+    let mut reply = b"PONG ".to_vec();
+    reply.extend_from_slice(req);
+    Some(reply)
+  });
+  let timeout = time::Duration::from_millis(200);
+  let res = UdpChecker::new("127.0.0.1", addr.port(), b"hello").set_timeout(timeout).set_retries(2)
+    .set_reply_check(UdpReplyCheck::Contains(b"hello".to_vec())).check();
+  res.expect();
+  assert!(res.get_metric("rtt").is_some());
+  UdpChecker::new("127.0.0.1", addr.port(), b"hello").set_timeout(timeout)
+    .set_reply_check(UdpReplyCheck::StartsWith(b"PING".to_vec())).check().expect_err_contains("Unexpected reply");
+
+  let silent = serve_udp(|_| None);
+  UdpChecker::new("127.0.0.1", silent.port(), b"hello").set_timeout(timeout).set_retries(1).check().expect_err_contains("after 2 attempts");
+
+  // "localhost" may resolve to ::1 first, where nothing is listening.
+  UdpChecker::new("localhost", addr.port(), b"hello").set_timeout(timeout).set_retries(0).check().expect();
+}
+
+#[test]
+fn ntp_checker_test() {
+  use crate::checkers::CheckResultType;
+  let respond = |skew: f64, header: [u8; 2]| move |req: &[u8]| {
+    let mut reply = vec![0u8; 48];
+    reply[0] = header[0];
+    reply[1] = header[1];
+    reply[12..16].copy_from_slice(b"RATE");
+    reply[24..32].copy_from_slice(&req[40..48]);
+    let now = if skew >= 0f64 {
+      time::SystemTime::now() + time::Duration::from_secs_f64(skew)
+    } else {
+      time::SystemTime::now() - time::Duration::from_secs_f64(-skew)
+    };
+    reply[32..40].copy_from_slice(&to_ntp_timestamp(now).to_be_bytes());
+    reply[40..48].copy_from_slice(&to_ntp_timestamp(now).to_be_bytes());
+    Some(reply)
+  };
+  let synced = 4 << 3 | 4;
+
+  let addr = serve_udp(respond(0f64, [synced, 2]));
+  let res = NtpChecker::new("127.0.0.1", addr.port()).check();
+  assert_eq!(res.result_type, CheckResultType::UP);
+  assert!(res.get_metric("offset").unwrap().abs() < 0.1f64);
+  assert_eq!(res.get_metric("stratum"), Some(2f64));
+
+  let addr = serve_udp(respond(2f64, [synced, 2]));
+  let res = NtpChecker::new("127.0.0.1", addr.port()).check();
+  assert_eq!(res.result_type, CheckResultType::WARN);
+  assert!((res.get_metric("offset").unwrap() - 2f64).abs() < 0.1f64);
+  let addr = serve_udp(respond(-10f64, [synced, 2]));
+  NtpChecker::new("127.0.0.1", addr.port()).check().expect_err_contains("clock offset -10.0");
+
+  let addr = serve_udp(respond(0f64, [synced, 0]));
+  NtpChecker::new("127.0.0.1", addr.port()).check().expect_err_contains("kiss code RATE");
+  let addr = serve_udp(respond(0f64, [3 << 6 | synced, 2]));
+  NtpChecker::new("127.0.0.1", addr.port()).check().expect_err_contains("not synchronized");
+
+  let t = time::UNIX_EPOCH + time::Duration::from_millis(2_500_000_000_250);
+  assert!((from_ntp_timestamp(to_ntp_timestamp(t)) - 2_500_000_000.25f64).abs() < 1e-6);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_udp_checker_test() {
+  use crate::checkers::async_checker::AsyncChecker;
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut dropped = 0;
+  let addr = serve_udp(move |req| {
+    if dropped < 1 {
+      dropped += 1;
+      return None;
+    }
+    Some(req.to_vec())
+  });
+  let timeout = time::Duration::from_millis(200);
+  let mut checker = UdpChecker::new("localhost", addr.port(), b"hello");
+  checker.set_timeout(timeout).set_retries(1).set_reply_check(UdpReplyCheck::StartsWith(b"hello".to_vec()));
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect();
+
+  let silent = serve_udp(|_| None);
+  let mut checker = UdpChecker::new("127.0.0.1", silent.port(), b"hello");
+  checker.set_timeout(timeout).set_retries(1);
+  let start = time::Instant::now();
+  runtime.block_on(AsyncChecker::check(&mut checker)).expect_err_contains("after 2 attempts");
+  assert!(start.elapsed() < time::Duration::from_millis(600));
+
+  let addr = serve_udp(|req| {
+    let mut reply = vec![0u8; 48];
+    reply[0] = 4 << 3 | 4;
+    reply[1] = 2;
+    reply[24..32].copy_from_slice(&req[40..48]);
+    let now = to_ntp_timestamp(time::SystemTime::now()).to_be_bytes();
+    reply[32..40].copy_from_slice(&now);
+    reply[40..48].copy_from_slice(&now);
+    Some(reply)
+  });
+  let res = runtime.block_on(AsyncChecker::check(&mut NtpChecker::new("127.0.0.1", addr.port())));
+  res.expect();
+  assert_eq!(res.get_metric("stratum"), Some(2f64));
+}
@@ -6,3 +6,4 @@ extern crate lazy_static;
 pub mod checkers;
 pub mod utils;
 pub mod scheduler;
+#[cfg(test)] mod testing;
@@ -0,0 +1,234 @@
+//! The async checker scheduler.
+//!
+//! Runs every check as a task on a tokio runtime, so thousands of checks can
+//! share a few threads. A check still running at its timeout is cancelled by
+//! dropping its future.
+//!
+//! Requires the `async` feature. Start by calling
+//! [`AsyncSchd::new`](crate::scheduler::async_schd::AsyncSchd::new).
+
+use crate::checkers::{CheckResult, CheckResultType};
+use crate::checkers::async_checker::AsyncChecker;
+use crate::scheduler::simple_schd::LogEntry;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time;
+use tokio::sync::{watch, Notify, Semaphore};
+
+pub struct AsyncCheck {
+  pub checker: Box<dyn AsyncChecker>,
+  pub min_check_interval: time::Duration,
+  /// Checks running for longer than this are cancelled, and the result is
+  /// `ERROR`.
+  pub timeout: time::Duration,
+  pub desc: &'static str,
+}
+
+/// The async checker scheduler.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::checkers::{async_checker::BlockingChecker, heartbeat::HeartbeatChecker};
+/// # use serverwatch::scheduler::async_schd::{AsyncCheck, AsyncSchd};
+/// # use std::{sync::Arc, time::Duration};
+/// let checker = HeartbeatChecker::new(Duration::from_secs(60), Duration::from_secs(10));
+/// let schd = Arc::new(AsyncSchd::new(vec![AsyncCheck{
+///   checker: Box::new(BlockingChecker::new(checker)),
+///   min_check_interval: Duration::from_secs(60),
+///   timeout: Duration::from_secs(10),
+///   desc: "heartbeat",
+/// }]));
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.spawn(schd.clone().run());
+/// schd.stop();
+/// ```
+pub struct AsyncSchd {
+  checks: Mutex<Vec<(usize, AsyncCheck)>>,
+  descs: Vec<&'static str>,
+  max_concurrent_checks: usize,
+  log: Mutex<Vec<LogEntry>>,
+  log_notify: Notify,
+  latest_results: RwLock<Vec<Option<CheckResult>>>,
+  stop: watch::Sender<bool>,
+}
+
+impl AsyncSchd {
+  /// Initialize a new `AsyncSchd` with a list of checks, which can't be
+  /// changed afterwards.
+  ///
+  /// Checks start running when
+  /// [`run`](crate::scheduler::async_schd::AsyncSchd::run) is called, all at
+  /// once without waiting for their interval.
+  pub fn new(checks: Vec<AsyncCheck>) -> Self {
+    let num_checks = checks.len();
+    Self{
+      descs: checks.iter().map(|c| c.desc).collect(),
+      checks: Mutex::new(checks.into_iter().enumerate().collect()),
+      max_concurrent_checks: 256,
+      log: Mutex::new(Vec::new()),
+      log_notify: Notify::new(),
+      latest_results: RwLock::new(vec![None; num_checks]),
+      stop: watch::channel(false).0,
+    }
+  }
+
+  /// How many checks may be running at the same time. The others wait their
+  /// turn, and their timeout only starts when they get to run.
+  ///
+  /// Default is 256.
+  pub fn set_max_concurrent_checks(&mut self, value: usize) -> &mut Self {
+    self.max_concurrent_checks = value.max(1);
+    self
+  }
+
+  /// Run the checks on the current tokio runtime until
+  /// [`stop`](crate::scheduler::async_schd::AsyncSchd::stop) is called. Only
+  /// the first call does anything.
+  pub async fn run(self: Arc<Self>) {
+    let checks: Vec<(usize, AsyncCheck)> = self.checks.lock().unwrap().drain(..).collect();
+    let permits = Arc::new(Semaphore::new(self.max_concurrent_checks));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, check) in checks {
+      tasks.spawn(self.clone().run_check(index, check, permits.clone()));
+    }
+    while tasks.join_next().await.is_some() {}
+  }
+
+  async fn run_check(self: Arc<Self>, index: usize, mut check: AsyncCheck, permits: Arc<Semaphore>) {
+    let mut stop = self.stop.subscribe();
+    while !*stop.borrow_and_update() {
+      let result = tokio::select! {
+        _ = stop.changed() => break,
+        result = async {
+          let _permit = permits.acquire().await;
+          match tokio::time::timeout(check.timeout, check.checker.check()).await {
+            Ok(result) => result,
+            Err(_) => CheckResult::error(Some(format!("Timed out after {}ms.", check.timeout.as_millis()))),
+          }
+        } => result,
+      };
+      self.latest_results.write().unwrap()[index] = Some(result.clone());
+      self.log.lock().unwrap().push(LogEntry{
+        check_index: index,
+        check_desc: check.desc,
+        result,
+        time: time::SystemTime::now(),
+      });
+      self.log_notify.notify_waiters();
+      tokio::select! {
+        _ = stop.changed() => break,
+        _ = tokio::time::sleep(check.min_check_interval) => {},
+      }
+    }
+  }
+
+  /// Stop running checks, cancelling those in progress.
+  pub fn stop(&self) {
+    self.stop.send_replace(true);
+  }
+
+  /// Move the existing log entries into `buf`, clearing the internal log store.
+  pub fn read_logs(&self, buf: &mut Vec<LogEntry>) {
+    buf.append(&mut self.log.lock().unwrap());
+  }
+
+  /// Wait for at least one log entry to become available, without consuming the
+  /// entry.
+  pub async fn wait_logs(&self) {
+    loop {
+      let notified = self.log_notify.notified();
+      if !self.log.lock().unwrap().is_empty() {
+        return;
+      }
+      notified.await;
+    }
+  }
+
+  pub fn get_latest_results(&self) -> RwLockReadGuard<'_, Vec<Option<CheckResult>>> {
+    self.latest_results.read().unwrap()
+  }
+
+  /// The description and latest result of every check whose latest result is
+  /// not `UP`.
+  pub fn get_non_ok_checks(&self) -> Vec<(&'static str, CheckResult)> {
+    self.get_latest_results().iter().enumerate().filter_map(|(i, r)| match r {
+      Some(r) if r.result_type != CheckResultType::UP => Some((self.descs[i], r.clone())),
+      _ => None,
+    }).collect()
+  }
+}
+
+#[test]
+fn async_schd_test() {
+  use crate::checkers::async_checker::CheckFuture;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+  struct Sleepy {
+    sleep: time::Duration,
+    checks: Arc<AtomicUsize>,
+  }
+  impl AsyncChecker for Sleepy {
+    fn check(&mut self) -> CheckFuture<'_> {
+      Box::pin(async move {
+        tokio::time::sleep(self.sleep).await;
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        CheckResult::up(None)
+      })
+    }
+  }
+  /// Sets the flag when dropped.
+  struct DropFlag(Arc<AtomicBool>);
+  impl Drop for DropFlag {
+    fn drop(&mut self) {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
+  struct Hangs(Arc<AtomicBool>);
+  impl AsyncChecker for Hangs {
+    fn check(&mut self) -> CheckFuture<'_> {
+      let flag = DropFlag(self.0.clone());
+      Box::pin(async move {
+        let _flag = flag;
+        tokio::time::sleep(time::Duration::from_secs(3600)).await;
+        CheckResult::up(None)
+      })
+    }
+  }
+
+  let checks_done = Arc::new(AtomicUsize::new(0));
+  let cancelled = Arc::new(AtomicBool::new(false));
+  let mut checks: Vec<AsyncCheck> = (0..2000).map(|_| AsyncCheck{
+    checker: Box::new(Sleepy{sleep: time::Duration::from_millis(100), checks: checks_done.clone()}),
+    min_check_interval: time::Duration::from_secs(3600),
+    timeout: time::Duration::from_secs(10),
+    desc: "sleepy",
+  }).collect();
+  checks.push(AsyncCheck{
+    checker: Box::new(Hangs(cancelled.clone())),
+    min_check_interval: time::Duration::from_secs(3600),
+    timeout: time::Duration::from_millis(200),
+    desc: "hangs",
+  });
+  let mut schd = AsyncSchd::new(checks);
+  schd.set_max_concurrent_checks(5000);
+  let schd = Arc::new(schd);
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let start = time::Instant::now();
+  let mut logs = Vec::new();
+  runtime.block_on(async {
+    let run = tokio::spawn(schd.clone().run());
+    while logs.len() < 2001 {
+      schd.wait_logs().await;
+      schd.read_logs(&mut logs);
+    }
+    schd.stop();
+    run.await.unwrap();
+  });
+  // All at once, on one thread.
+  assert!(start.elapsed() < time::Duration::from_secs(2));
+  assert_eq!(checks_done.load(Ordering::SeqCst), 2000);
+  assert!(cancelled.load(Ordering::SeqCst));
+  let hung = logs.iter().find(|l| l.check_desc == "hangs").unwrap();
+  assert_eq!(hung.check_index, 2000);
+  assert_eq!(hung.result, CheckResult::error(Some("Timed out after 200ms.".to_owned())));
+  assert_eq!(schd.get_non_ok_checks(), vec![("hangs", hung.result.clone())]);
+  assert!(schd.get_latest_results().iter().all(|r| r.is_some()));
+}
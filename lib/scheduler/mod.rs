@@ -1,3 +1,4 @@
 //! Provides some ways of running checkers.
 
 pub mod simple_schd;
+#[cfg(feature = "async")] pub mod async_schd;
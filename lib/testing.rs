@@ -0,0 +1,22 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Accept one connection on a local port, and hand it to `script`, which
+/// plays the part of the server.
+pub fn serve_once<F: FnOnce(TcpStream) + Send + 'static>(script: F) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  std::thread::spawn(move || {
+    let (stream, _) = listener.accept().unwrap();
+    script(stream);
+  });
+  addr
+}
+
+pub fn read_n(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+  let mut buf = vec![0u8; n];
+  stream.read_exact(&mut buf).unwrap();
+  buf
+}
@@ -0,0 +1,654 @@
+//! A minimal DNS client, enough for checkers that need to look at records the
+//! system resolver doesn't expose (TLSA, CAA, TXT, SOA, ...), or need to ask a
+//! particular nameserver directly.
+
+use crate::utils::DeadlineStream;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_TLSA: u16 = 52;
+pub const TYPE_CAA: u16 = 257;
+
+/// The mnemonic of a record type, e.g. `"MX"`, or `"TYPE<n>"` for types
+/// without a constant here.
+pub fn type_name(rtype: u16) -> String {
+	match rtype {
+		TYPE_A => "A".to_owned(),
+		TYPE_NS => "NS".to_owned(),
+		TYPE_CNAME => "CNAME".to_owned(),
+		TYPE_SOA => "SOA".to_owned(),
+		TYPE_MX => "MX".to_owned(),
+		TYPE_TXT => "TXT".to_owned(),
+		TYPE_AAAA => "AAAA".to_owned(),
+		TYPE_TLSA => "TLSA".to_owned(),
+		TYPE_CAA => "CAA".to_owned(),
+		t => format!("TYPE{}", t),
+	}
+}
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+	A(Ipv4Addr),
+	AAAA(Ipv6Addr),
+	NS(String),
+	CNAME(String),
+	MX{preference: u16, exchange: String},
+	/// The character-strings making up the record.
+	TXT(Vec<Vec<u8>>),
+	SOA{mname: String, rname: String, serial: u32, refresh: u32, retry: u32, expire: u32, minimum: u32},
+	TLSA{usage: u8, selector: u8, matching_type: u8, data: Vec<u8>},
+	CAA{flags: u8, tag: String, value: Vec<u8>},
+	/// Any other record type, with its raw rdata.
+	Other(u16, Vec<u8>),
+}
+
+impl RecordData {
+	pub fn rtype(&self) -> u16 {
+		match self {
+			RecordData::A(_) => TYPE_A,
+			RecordData::AAAA(_) => TYPE_AAAA,
+			RecordData::NS(_) => TYPE_NS,
+			RecordData::CNAME(_) => TYPE_CNAME,
+			RecordData::MX{..} => TYPE_MX,
+			RecordData::TXT(_) => TYPE_TXT,
+			RecordData::SOA{..} => TYPE_SOA,
+			RecordData::TLSA{..} => TYPE_TLSA,
+			RecordData::CAA{..} => TYPE_CAA,
+			RecordData::Other(t, _) => *t,
+		}
+	}
+
+	/// For TXT records, the character-strings concatenated together.
+	pub fn txt_string(&self) -> Option<String> {
+		if let RecordData::TXT(parts) = self {
+			Some(parts.iter().map(|p| String::from_utf8_lossy(p)).collect())
+		} else {
+			None
+		}
+	}
+}
+
+impl fmt::Display for RecordData {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RecordData::A(a) => write!(f, "A {}", a),
+			RecordData::AAAA(a) => write!(f, "AAAA {}", a),
+			RecordData::NS(n) => write!(f, "NS {}", n),
+			RecordData::CNAME(n) => write!(f, "CNAME {}", n),
+			RecordData::MX{preference, exchange} => write!(f, "MX {} {}", preference, exchange),
+			RecordData::TXT(_) => write!(f, "TXT {:?}", self.txt_string().unwrap()),
+			RecordData::SOA{mname, rname, serial, ..} => write!(f, "SOA {} {} {}", mname, rname, serial),
+			RecordData::TLSA{usage, selector, matching_type, data} => {
+				write!(f, "TLSA {} {} {} ", usage, selector, matching_type)?;
+				for b in data.iter() {
+					write!(f, "{:02x}", b)?;
+				}
+				Ok(())
+			},
+			RecordData::CAA{flags, tag, value} => write!(f, "CAA {} {} {:?}", flags, tag, String::from_utf8_lossy(value)),
+			RecordData::Other(t, data) => write!(f, "TYPE{} ({} bytes)", t, data.len()),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+	/// Lower-cased, without the trailing dot.
+	pub name: String,
+	pub ttl: u32,
+	pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+	pub rcode: u8,
+	/// The AA bit, set by authoritative nameservers.
+	pub authoritative: bool,
+	/// The AD bit, set by validating resolvers if the answer is DNSSEC-secure.
+	pub authentic_data: bool,
+	pub answers: Vec<Record>,
+	pub authority: Vec<Record>,
+	pub additional: Vec<Record>,
+}
+
+impl Response {
+	/// The data of every answer record of type `rtype`.
+	pub fn answers_of_type(&self, rtype: u16) -> impl Iterator<Item = &RecordData> {
+		self.answers.iter().map(|r| &r.data).filter(move |d| d.rtype() == rtype)
+	}
+}
+
+/// Sends queries to one DNS server.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::utils::dns::{DnsClient, TYPE_MX};
+/// let client = DnsClient::system().unwrap();
+/// let res = client.query("example.com", TYPE_MX);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DnsClient {
+	server: SocketAddr,
+	timeout: time::Duration,
+	recursion_desired: bool,
+}
+
+impl DnsClient {
+	pub fn new(server: SocketAddr) -> Self {
+		DnsClient{server, timeout: time::Duration::from_secs(5), recursion_desired: true}
+	}
+
+	/// Use the first nameserver in `/etc/resolv.conf`.
+	pub fn system() -> Result<Self, String> {
+		let conf = std::fs::read_to_string("/etc/resolv.conf").map_err(|e| format!("Reading /etc/resolv.conf: {}", &e))?;
+		for line in conf.lines() {
+			let mut words = line.split_ascii_whitespace();
+			if words.next() == Some("nameserver") {
+				if let Some(Ok(ip)) = words.next().map(|w| w.parse::<std::net::IpAddr>()) {
+					return Ok(Self::new(SocketAddr::new(ip, 53)));
+				}
+			}
+		}
+		Err("No nameserver in /etc/resolv.conf".to_owned())
+	}
+
+	pub fn server(&self) -> SocketAddr {
+		self.server
+	}
+
+	/// Time limit for each query, including the TCP retry of a truncated answer.
+	///
+	/// Default is 5s.
+	pub fn set_timeout(&mut self, value: time::Duration) -> &mut Self {
+		self.timeout = value;
+		self
+	}
+
+	/// Set to `false` when asking authoritative nameservers directly.
+	///
+	/// Default is `true`.
+	pub fn set_recursion_desired(&mut self, value: bool) -> &mut Self {
+		self.recursion_desired = value;
+		self
+	}
+
+	/// Ask the server for records of type `rtype` for `name`. A response with a
+	/// non-zero rcode is still returned as `Ok`. Responses are only accepted if
+	/// their ID and question match the query.
+	pub fn query(&self, name: &str, rtype: u16) -> Result<Response, String> {
+		let id = query_id()?;
+		let query = encode_query(id, name, rtype, self.recursion_desired)?;
+		let expected = question(&query);
+		let deadline = time::Instant::now() + self.timeout;
+		let sock = UdpSocket::bind(if self.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).map_err(|e| format!("Binding UDP socket: {}", &e))?;
+		sock.connect(self.server).map_err(|e| format!("Connecting to {}: {}", self.server, &e))?;
+		let mut buf = [0u8; 4096];
+		// Retry once, halfway through the timeout, in case a packet is lost.
+		let mut sent = 0;
+		let resp = loop {
+			let now = time::Instant::now();
+			if now >= deadline {
+				return Err(format!("Timed out querying {} for {}", self.server, name));
+			}
+			if sent < 2 && (sent == 0 || now >= deadline - self.timeout / 2) {
+				sock.send(&query).map_err(|e| format!("Sending query to {}: {}", self.server, &e))?;
+				sent += 1;
+			}
+			let wait = if sent < 2 { (deadline - self.timeout / 2).saturating_duration_since(now) } else { deadline - now };
+			sock.set_read_timeout(Some(std::cmp::max(wait, time::Duration::from_millis(1)))).map_err(|e| format!("{}", &e))?;
+			match sock.recv(&mut buf) {
+				Ok(len) => {
+					let msg = &buf[..len];
+					if question(msg) != expected {
+						continue;
+					}
+					break msg.to_vec();
+				},
+				Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => continue,
+				Err(e) => return Err(format!("Receiving from {}: {}", self.server, &e)),
+			}
+		};
+		if resp[2] & 0x02 != 0 {
+			// Truncated. Try again over TCP.
+			return self.query_tcp(&query, deadline);
+		}
+		decode_response(&resp)
+	}
+
+	fn query_tcp(&self, query: &[u8], deadline: time::Instant) -> Result<Response, String> {
+		let mut conn = DeadlineStream::connect(&[self.server], deadline).map_err(|e| format!("Connecting to {} over TCP: {}", self.server, &e))?;
+		let mut msg = Vec::with_capacity(query.len() + 2);
+		msg.extend_from_slice(&(query.len() as u16).to_be_bytes());
+		msg.extend_from_slice(query);
+		conn.write_all(&msg).map_err(|e| format!("Sending query to {} over TCP: {}", self.server, &e))?;
+		let mut len = [0u8; 2];
+		conn.read_exact(&mut len).map_err(|e| format!("Reading from {} over TCP: {}", self.server, &e))?;
+		let mut resp = vec![0u8; u16::from_be_bytes(len) as usize];
+		conn.read_exact(&mut resp).map_err(|e| format!("Reading from {} over TCP: {}", self.server, &e))?;
+		if question(&resp) != question(query) {
+			return Err(format!("Invalid response from {} over TCP", self.server));
+		}
+		decode_response(&resp)
+	}
+}
+
+/// A random query ID, so that off-path attackers can't guess it.
+fn query_id() -> Result<u16, String> {
+	let mut id = [0u8; 2];
+	openssl::rand::rand_bytes(&mut id).map_err(|e| format!("Generating query ID: {}", &e))?;
+	Ok(u16::from_be_bytes(id))
+}
+
+/// The ID, and the name, type and class of the only question, of `msg`, to
+/// match responses to queries.
+fn question(msg: &[u8]) -> Option<(u16, String, u16, u16)> {
+	let mut p = Parser{msg, pos: 0};
+	let id = p.u16().ok()?;
+	p.u16().ok()?;
+	if p.u16().ok()? != 1 {
+		return None;
+	}
+	p.bytes(6).ok()?;
+	Some((id, p.name().ok()?, p.u16().ok()?, p.u16().ok()?))
+}
+
+/// Lower-case `name` and strip the trailing dot.
+pub fn normalize_name(name: &str) -> String {
+	name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<(), String> {
+	let name = name.trim_end_matches('.');
+	if !name.is_empty() {
+		for label in name.split('.') {
+			if label.is_empty() || label.len() > 63 {
+				return Err(format!("Invalid domain name: {:?}", name));
+			}
+			out.push(label.len() as u8);
+			out.extend_from_slice(label.as_bytes());
+		}
+	}
+	out.push(0);
+	Ok(())
+}
+
+fn encode_query(id: u16, name: &str, rtype: u16, recursion_desired: bool) -> Result<Vec<u8>, String> {
+	let mut out = Vec::with_capacity(512);
+	out.extend_from_slice(&id.to_be_bytes());
+	out.push(if recursion_desired { 0x01 } else { 0x00 });
+	// AD bit, to ask for the authentic data flag in the response.
+	out.push(0x20);
+	out.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+	encode_name(&mut out, name)?;
+	out.extend_from_slice(&rtype.to_be_bytes());
+	out.extend_from_slice(&1u16.to_be_bytes());
+	Ok(out)
+}
+
+struct Parser<'a> {
+	msg: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn err<T>(&self) -> Result<T, String> {
+		Err(format!("Malformed DNS message at offset {}", self.pos))
+	}
+
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+		if self.pos + len > self.msg.len() {
+			return self.err();
+		}
+		let b = &self.msg[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(b)
+	}
+
+	fn u8(&mut self) -> Result<u8, String> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, String> {
+		let b = self.bytes(2)?;
+		Ok(u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	fn u32(&mut self) -> Result<u32, String> {
+		let b = self.bytes(4)?;
+		Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+
+	fn name(&mut self) -> Result<String, String> {
+		let mut labels: Vec<String> = Vec::new();
+		let mut pos = self.pos;
+		let mut jumped = false;
+		let mut jumps = 0;
+		loop {
+			if pos >= self.msg.len() {
+				return self.err();
+			}
+			let len = self.msg[pos] as usize;
+			if len & 0xc0 == 0xc0 {
+				if pos + 1 >= self.msg.len() || jumps > 32 {
+					return self.err();
+				}
+				if !jumped {
+					self.pos = pos + 2;
+				}
+				jumped = true;
+				jumps += 1;
+				pos = ((len & 0x3f) << 8) | self.msg[pos + 1] as usize;
+				continue;
+			}
+			if len == 0 {
+				if !jumped {
+					self.pos = pos + 1;
+				}
+				break;
+			}
+			if pos + 1 + len > self.msg.len() {
+				return self.err();
+			}
+			labels.push(String::from_utf8_lossy(&self.msg[pos + 1..pos + 1 + len]).to_ascii_lowercase());
+			pos += 1 + len;
+		}
+		Ok(labels.join("."))
+	}
+
+	fn record(&mut self) -> Result<Record, String> {
+		let name = self.name()?;
+		let rtype = self.u16()?;
+		let _class = self.u16()?;
+		let ttl = self.u32()?;
+		let rdlen = self.u16()? as usize;
+		let rdata_end = self.pos + rdlen;
+		if rdata_end > self.msg.len() {
+			return self.err();
+		}
+		let data = match rtype {
+			TYPE_A if rdlen == 4 => {
+				let b = self.bytes(4)?;
+				RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+			},
+			TYPE_AAAA if rdlen == 16 => {
+				let mut b = [0u8; 16];
+				b.copy_from_slice(self.bytes(16)?);
+				RecordData::AAAA(Ipv6Addr::from(b))
+			},
+			TYPE_NS => RecordData::NS(self.name()?),
+			TYPE_CNAME => RecordData::CNAME(self.name()?),
+			TYPE_MX => RecordData::MX{preference: self.u16()?, exchange: self.name()?},
+			TYPE_TXT => {
+				let mut parts = Vec::new();
+				while self.pos < rdata_end {
+					let len = self.u8()? as usize;
+					parts.push(self.bytes(len)?.to_vec());
+				}
+				RecordData::TXT(parts)
+			},
+			TYPE_SOA => RecordData::SOA{
+				mname: self.name()?, rname: self.name()?,
+				serial: self.u32()?, refresh: self.u32()?, retry: self.u32()?, expire: self.u32()?, minimum: self.u32()?,
+			},
+			TYPE_TLSA if rdlen >= 3 => RecordData::TLSA{
+				usage: self.u8()?, selector: self.u8()?, matching_type: self.u8()?,
+				data: self.bytes(rdlen - 3)?.to_vec(),
+			},
+			TYPE_CAA if rdlen >= 2 => {
+				let flags = self.u8()?;
+				let tag_len = self.u8()? as usize;
+				if 2 + tag_len > rdlen {
+					return self.err();
+				}
+				let tag = String::from_utf8_lossy(self.bytes(tag_len)?).to_ascii_lowercase();
+				RecordData::CAA{flags, tag, value: self.bytes(rdlen - 2 - tag_len)?.to_vec()}
+			},
+			_ => RecordData::Other(rtype, self.bytes(rdlen)?.to_vec()),
+		};
+		if self.pos != rdata_end {
+			return self.err();
+		}
+		Ok(Record{name, ttl, data})
+	}
+}
+
+fn decode_response(msg: &[u8]) -> Result<Response, String> {
+	let mut p = Parser{msg, pos: 0};
+	let _id = p.u16()?;
+	let flags = p.u16()?;
+	let qdcount = p.u16()?;
+	let ancount = p.u16()?;
+	let nscount = p.u16()?;
+	let arcount = p.u16()?;
+	for _ in 0..qdcount {
+		p.name()?;
+		p.bytes(4)?;
+	}
+	let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+	for (section, count) in sections.iter_mut().zip([ancount, nscount, arcount].iter()) {
+		for _ in 0..*count {
+			section.push(p.record()?);
+		}
+	}
+	let [answers, authority, additional] = sections;
+	Ok(Response{
+		rcode: (flags & 0x000f) as u8,
+		authoritative: flags & 0x0400 != 0,
+		authentic_data: flags & 0x0020 != 0,
+		answers, authority, additional,
+	})
+}
+
+#[cfg(test)]
+fn encode_record(out: &mut Vec<u8>, record: &Record) -> Result<(), String> {
+	encode_name(out, &record.name)?;
+	out.extend_from_slice(&record.data.rtype().to_be_bytes());
+	out.extend_from_slice(&1u16.to_be_bytes());
+	out.extend_from_slice(&record.ttl.to_be_bytes());
+	let mut rdata = Vec::new();
+	match record.data {
+		RecordData::A(a) => rdata.extend_from_slice(&a.octets()),
+		RecordData::AAAA(a) => rdata.extend_from_slice(&a.octets()),
+		RecordData::NS(ref n) | RecordData::CNAME(ref n) => encode_name(&mut rdata, n)?,
+		RecordData::MX{preference, ref exchange} => {
+			rdata.extend_from_slice(&preference.to_be_bytes());
+			encode_name(&mut rdata, exchange)?;
+		},
+		RecordData::TXT(ref parts) => {
+			for part in parts.iter() {
+				rdata.push(part.len() as u8);
+				rdata.extend_from_slice(part);
+			}
+		},
+		RecordData::SOA{ref mname, ref rname, serial, refresh, retry, expire, minimum} => {
+			encode_name(&mut rdata, mname)?;
+			encode_name(&mut rdata, rname)?;
+			for v in [serial, refresh, retry, expire, minimum].iter() {
+				rdata.extend_from_slice(&v.to_be_bytes());
+			}
+		},
+		RecordData::TLSA{usage, selector, matching_type, ref data} => {
+			rdata.extend_from_slice(&[usage, selector, matching_type]);
+			rdata.extend_from_slice(data);
+		},
+		RecordData::CAA{flags, ref tag, ref value} => {
+			rdata.push(flags);
+			rdata.push(tag.len() as u8);
+			rdata.extend_from_slice(tag.as_bytes());
+			rdata.extend_from_slice(value);
+		},
+		RecordData::Other(_, ref data) => rdata.extend_from_slice(data),
+	}
+	out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+	out.extend_from_slice(&rdata);
+	Ok(())
+}
+
+/// A DNS stand-in for tests, answering queries from a fixed list of records.
+#[cfg(test)]
+pub(crate) mod testing {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	#[derive(Default)]
+	pub struct Zone {
+		pub records: Vec<Record>,
+		/// Answer every query with this rcode, instead of looking at `records`.
+		pub rcode: Option<u8>,
+		/// Set the AA bit in responses.
+		pub authoritative: bool,
+		/// Don't respond at all.
+		pub silent: bool,
+	}
+
+	pub fn record(name: &str, data: RecordData) -> Record {
+		Record{name: normalize_name(name), ttl: 300, data}
+	}
+
+	/// Serve `zone` over UDP and TCP on an ephemeral port of 127.0.0.1. The zone
+	/// can be changed afterwards through the returned handle.
+	pub fn serve_dns(zone: Zone) -> (SocketAddr, Arc<Mutex<Zone>>) {
+		let zone = Arc::new(Mutex::new(zone));
+		let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let addr = udp.local_addr().unwrap();
+		let tcp = std::net::TcpListener::bind(addr).unwrap();
+		let z = zone.clone();
+		std::thread::spawn(move || {
+			let mut buf = [0u8; 512];
+			while let Ok((len, from)) = udp.recv_from(&mut buf) {
+				if let Some(mut resp) = respond(&z.lock().unwrap(), &buf[..len]) {
+					if resp.len() > 512 {
+						// Keep the question, as servers do.
+						let mut p = Parser{msg: &resp, pos: 12};
+						let _ = p.name();
+						let question_end = p.pos + 4;
+						resp.truncate(question_end);
+						resp[2] |= 0x02;
+						resp[6..12].copy_from_slice(&[0; 6]);
+					}
+					let _ = udp.send_to(&resp, from);
+				}
+			}
+		});
+		let z = zone.clone();
+		std::thread::spawn(move || {
+			for conn in tcp.incoming() {
+				let mut conn = match conn { Ok(c) => c, Err(_) => continue };
+				let mut len = [0u8; 2];
+				if conn.read_exact(&mut len).is_err() {
+					continue;
+				}
+				let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+				if conn.read_exact(&mut query).is_err() {
+					continue;
+				}
+				if let Some(resp) = respond(&z.lock().unwrap(), &query) {
+					let _ = conn.write_all(&(resp.len() as u16).to_be_bytes());
+					let _ = conn.write_all(&resp);
+				}
+			}
+		});
+		(addr, zone)
+	}
+
+	fn respond(zone: &Zone, query: &[u8]) -> Option<Vec<u8>> {
+		if zone.silent {
+			return None;
+		}
+		let mut p = Parser{msg: query, pos: 12};
+		let qname = p.name().ok()?;
+		let qtype = p.u16().ok()?;
+		let question = &query[12..p.pos + 2];
+		let answers: Vec<&Record> = zone.records.iter().filter(|r| r.name == qname && (r.data.rtype() == qtype || r.data.rtype() == TYPE_CNAME)).collect();
+		let rcode = zone.rcode.unwrap_or_else(|| {
+			if answers.is_empty() && !zone.records.iter().any(|r| r.name == qname) { RCODE_NXDOMAIN } else { RCODE_NOERROR }
+		});
+		let answers = if zone.rcode.is_some() { Vec::new() } else { answers };
+		let mut out = Vec::new();
+		out.extend_from_slice(&query[0..2]);
+		out.push(0x80 | (query[2] & 0x01) | if zone.authoritative { 0x04 } else { 0 });
+		out.push(0x80 | rcode);
+		out.extend_from_slice(&[0, 1]);
+		out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+		out.extend_from_slice(&[0, 0, 0, 0]);
+		out.extend_from_slice(question);
+		for r in answers {
+			encode_record(&mut out, r).ok()?;
+		}
+		Some(out)
+	}
+}
+
+#[test]
+fn dns_client_test() {
+	use testing::{record, serve_dns, Zone};
+	let long_txt: Vec<Vec<u8>> = (0..4).map(|i| vec![b'a' + i; 200]).collect();
+	let (addr, zone) = serve_dns(Zone{records: vec![
+		record("example.test", RecordData::A(Ipv4Addr::new(192, 0, 2, 1))),
+		record("example.test", RecordData::MX{preference: 10, exchange: "mx.example.test".to_owned()}),
+		record("example.test", RecordData::TXT(vec![b"v=spf1 ".to_vec(), b"-all".to_vec()])),
+		record("example.test", RecordData::CAA{flags: 0, tag: "issue".to_owned(), value: b"letsencrypt.org".to_vec()}),
+		record("example.test", RecordData::SOA{mname: "ns1.example.test".to_owned(), rname: "hostmaster.example.test".to_owned(), serial: 2019070101, refresh: 1, retry: 2, expire: 3, minimum: 4}),
+		record("_25._tcp.mx.example.test", RecordData::TLSA{usage: 3, selector: 1, matching_type: 1, data: vec![0xab; 32]}),
+		record("long.example.test", RecordData::TXT(long_txt.clone())),
+	], ..Zone::default()});
+	let client = DnsClient::new(addr);
+
+	let res = client.query("Example.Test.", TYPE_A).unwrap();
+	assert_eq!(res.rcode, RCODE_NOERROR);
+	assert_eq!(res.answers, vec![record("example.test", RecordData::A(Ipv4Addr::new(192, 0, 2, 1)))]);
+	let res = client.query("example.test", TYPE_TXT).unwrap();
+	assert_eq!(res.answers[0].data.txt_string().unwrap(), "v=spf1 -all");
+	let res = client.query("example.test", TYPE_MX).unwrap();
+	assert_eq!(res.answers_of_type(TYPE_MX).next().unwrap(), &RecordData::MX{preference: 10, exchange: "mx.example.test".to_owned()});
+	let res = client.query("example.test", TYPE_SOA).unwrap();
+	assert!(matches!(res.answers[0].data, RecordData::SOA{serial: 2019070101, ..}));
+	let res = client.query("example.test", TYPE_CAA).unwrap();
+	assert_eq!(format!("{}", res.answers[0].data), "CAA 0 issue \"letsencrypt.org\"");
+	let res = client.query("_25._tcp.mx.example.test", TYPE_TLSA).unwrap();
+	assert!(matches!(res.answers[0].data, RecordData::TLSA{usage: 3, selector: 1, matching_type: 1, ..}));
+
+	// Truncated over UDP, retried over TCP.
+	let res = client.query("long.example.test", TYPE_TXT).unwrap();
+	assert_eq!(res.answers[0].data, RecordData::TXT(long_txt));
+
+	let res = client.query("nonexistent.example.test", TYPE_A).unwrap();
+	assert_eq!(res.rcode, RCODE_NXDOMAIN);
+
+	// An answer to a different question is ignored, even with the right ID.
+	let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let spoofer_addr = spoofer.local_addr().unwrap();
+	std::thread::spawn(move || {
+		let mut buf = [0u8; 512];
+		let (len, from) = spoofer.recv_from(&mut buf).unwrap();
+		let mut genuine = buf[..len].to_vec();
+		genuine[2] |= 0x80;
+		genuine[3] = RCODE_NOERROR;
+		let mut spoofed = genuine.clone();
+		spoofed[3] = RCODE_NXDOMAIN;
+		// The low byte of the question type.
+		spoofed[len - 3] = TYPE_TXT as u8;
+		spoofer.send_to(&spoofed, from).unwrap();
+		spoofer.send_to(&genuine, from).unwrap();
+	});
+	assert_eq!(DnsClient::new(spoofer_addr).query("example.test", TYPE_A).unwrap().rcode, RCODE_NOERROR);
+
+	zone.lock().unwrap().silent = true;
+	let mut client = client;
+	client.set_timeout(time::Duration::from_millis(200));
+	assert!(client.query("example.test", TYPE_A).unwrap_err().starts_with("Timed out"));
+}
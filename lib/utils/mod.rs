@@ -1,4 +1,12 @@
 //! Things which might be useful for checker writers.
 
 mod timeout;
-pub use timeout::with_timeout;
+pub use timeout::{with_timeout, abandoned_operations, DeadlineStream};
+#[cfg(feature = "checkers")] pub(crate) use timeout::time_left;
+#[cfg(feature = "checkers")] pub mod dns;
+#[cfg(feature = "checkers")] mod net;
+#[cfg(feature = "checkers")] pub(crate) use net::connect;
+#[cfg(feature = "checkers")] pub(crate) mod transport;
+#[cfg(feature = "checkers")] mod reader;
+#[cfg(feature = "checkers")] pub(crate) use reader::Reader;
+#[cfg(test)] pub(crate) mod testing;
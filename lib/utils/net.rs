@@ -0,0 +1,30 @@
+//! Plain TCP helpers for checkers which speak a protocol themselves.
+
+use crate::checkers::{AddressFamily, resolve_addresses};
+use crate::utils::DeadlineStream;
+use std::time;
+
+/// Connect to `host:port`, trying each resolved address in turn. Resolving,
+/// connecting and everything read or written on the stream must be done
+/// within `timeout`.
+pub(crate) fn connect(host: &str, port: u16, timeout: time::Duration) -> Result<DeadlineStream, String> {
+	let deadline = time::Instant::now() + timeout;
+	let addrs = resolve_addresses(host, port, AddressFamily::Any, timeout)?;
+	DeadlineStream::connect(&addrs, deadline).map_err(|e| format!("Connecting to {}: {}", host, &e))
+}
+
+#[test]
+fn connect_deadline_test() {
+	use std::io::{Read, Write};
+	// Sends a byte every 50ms, which would keep a per-read timeout from ever
+	// firing.
+	let addr = crate::testing::serve_once(|mut s| {
+		while s.write_all(b"x").is_ok() {
+			std::thread::sleep(time::Duration::from_millis(50));
+		}
+	});
+	let start = time::Instant::now();
+	let mut stream = connect("127.0.0.1", addr.port(), time::Duration::from_millis(300)).unwrap();
+	assert!(stream.read_exact(&mut [0u8; 1000]).is_err());
+	assert!(start.elapsed() < time::Duration::from_millis(600));
+}
@@ -0,0 +1,47 @@
+//! A cursor for parsing binary protocols.
+
+/// Reads the fields of a message in order. Reading past the end is an error
+/// with the message given to [`new`](Reader::new), so that each protocol can
+/// say what was cut short.
+pub(crate) struct Reader<'a> {
+  data: &'a [u8],
+  truncated: &'static str,
+}
+
+impl<'a> Reader<'a> {
+  pub(crate) fn new(data: &'a [u8], truncated: &'static str) -> Self {
+    Reader{data, truncated}
+  }
+
+  pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+    if self.data.len() < n {
+      return Err(self.truncated.to_owned());
+    }
+    let (taken, rest) = self.data.split_at(n);
+    self.data = rest;
+    Ok(taken)
+  }
+
+  pub(crate) fn u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  /// A big-endian (network order) `u32`.
+  pub(crate) fn u32(&mut self) -> Result<u32, String> {
+    let b = self.take(4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  /// The bytes up to `end`, which is skipped.
+  pub(crate) fn take_until(&mut self, end: u8) -> Result<&'a [u8], String> {
+    let len = self.data.iter().position(|b| *b == end).ok_or(self.truncated)?;
+    let taken = self.take(len)?;
+    self.take(1)?;
+    Ok(taken)
+  }
+
+  /// The bytes not read yet.
+  pub(crate) fn rest(&self) -> &'a [u8] {
+    self.data
+  }
+}
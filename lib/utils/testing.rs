@@ -0,0 +1,13 @@
+//! Helpers for tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// A fresh, empty directory for the test `name`, which the test should remove
+/// when done.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("serverwatch-test-{}-{}", name, std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  dir
+}
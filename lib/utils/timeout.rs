@@ -1,11 +1,43 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
 use std::time;
 use std::thread;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ABANDONED: AtomicUsize = AtomicUsize::new(0);
+
+/// How many closures passed to [`with_timeout`](crate::utils::with_timeout)
+/// timed out but are still running on their threads.
+///
+/// A number which keeps growing means something is hanging without a timeout
+/// of its own.
+pub fn abandoned_operations() -> usize {
+	ABANDONED.load(Ordering::SeqCst)
+}
+
+/// Removes the operation from the abandoned count when it finishes, if it had
+/// been abandoned, even if it panicked.
+struct Finished(Arc<Mutex<bool>>);
+
+impl Drop for Finished {
+	fn drop(&mut self) {
+		if *self.0.lock().unwrap_or_else(|e| e.into_inner()) {
+			ABANDONED.fetch_sub(1, Ordering::SeqCst);
+		}
+	}
+}
 
 /// Run a closure with a timeout, returning either its return value, or None, in case
 /// it timed out.
 ///
-/// This function should only take at most `timeout` to return.
+/// This function should only take at most `timeout` to return. The closure
+/// can't be stopped though, so it keeps running on its thread after a timeout,
+/// and is counted by
+/// [`abandoned_operations`](crate::utils::abandoned_operations) until it
+/// returns. Prefer giving the operation a deadline of its own, e.g. with
+/// [`DeadlineStream`](crate::utils::DeadlineStream), and only use this for
+/// things which can't have one, like resolving a host name.
 ///
 /// ## Example
 /// ```rust
@@ -16,13 +48,25 @@ use std::sync::mpsc;
 /// assert_eq!(with_timeout(|| {sleep(Duration::from_millis(1000)); 1}, Duration::from_millis(1500)), Some(1));
 /// ```
 pub fn with_timeout<F: FnOnce() -> R + Send + 'static, R: Send + 'static>(f: F, timeout: time::Duration) -> Option<R> {
-	let (sender, recv) = mpsc::sync_channel(0);
+	let (sender, recv) = mpsc::sync_channel(1);
+	let abandoned = Arc::new(Mutex::new(false));
+	let finished = Finished(abandoned.clone());
 	thread::spawn(move || {
-		let _ = sender.send(f());
+		let r = f();
+		if !*finished.0.lock().unwrap_or_else(|e| e.into_inner()) {
+			let _ = sender.send(r);
+		}
 	});
 	match recv.recv_timeout(timeout) {
 		Err(e) => {
 			if e == mpsc::RecvTimeoutError::Timeout {
+				let mut abandoned = abandoned.lock().unwrap_or_else(|e| e.into_inner());
+				// It may have returned just now.
+				if let Ok(r) = recv.try_recv() {
+					return Some(r);
+				}
+				*abandoned = true;
+				ABANDONED.fetch_add(1, Ordering::SeqCst);
 				None
 			} else {
 				panic!("sender disconnected?")
@@ -32,6 +76,109 @@ pub fn with_timeout<F: FnOnce() -> R + Send + 'static, R: Send + 'static>(f: F,
 	}
 }
 
+/// A `TcpStream` whose reads and writes fail with `TimedOut` once its deadline
+/// has passed, however slowly the other end sends. Wrap it in e.g. an
+/// `SslStream` to put a deadline on a whole TLS handshake.
+///
+/// ## Example
+/// ```rust
+/// # use serverwatch::utils::DeadlineStream;
+/// use std::io::Read;
+/// use std::time::{Duration, Instant};
+/// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// let deadline = Instant::now() + Duration::from_millis(200);
+/// let mut stream = DeadlineStream::connect(&[listener.local_addr().unwrap()], deadline).unwrap();
+/// assert_eq!(stream.read(&mut [0u8; 1]).unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+/// ```
+#[derive(Debug)]
+pub struct DeadlineStream {
+	stream: TcpStream,
+	deadline: Arc<Mutex<time::Instant>>,
+}
+
+/// Time left until `deadline`, or a `TimedOut` error once it has passed.
+pub(crate) fn time_left(deadline: time::Instant) -> io::Result<time::Duration> {
+	let now = time::Instant::now();
+	if now >= deadline {
+		return Err(io::Error::new(io::ErrorKind::TimedOut, "Deadline reached."));
+	}
+	Ok(deadline - now)
+}
+
+/// Socket timeouts show up as `WouldBlock` on some platforms.
+fn timed_out(e: io::Error) -> io::Error {
+	if e.kind() == io::ErrorKind::WouldBlock {
+		io::Error::new(io::ErrorKind::TimedOut, "Deadline reached.")
+	} else {
+		e
+	}
+}
+
+impl DeadlineStream {
+	/// Put a deadline on an already connected `stream`.
+	pub fn new(stream: TcpStream, deadline: time::Instant) -> Self {
+		DeadlineStream{stream, deadline: Arc::new(Mutex::new(deadline))}
+	}
+
+	/// Connect to the first of `addrs` which accepts the connection before
+	/// `deadline`.
+	pub fn connect(addrs: &[SocketAddr], deadline: time::Instant) -> io::Result<Self> {
+		let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to.");
+		for addr in addrs {
+			match TcpStream::connect_timeout(addr, time_left(deadline)?) {
+				Ok(stream) => {
+					let _ = stream.set_nodelay(true);
+					return Ok(DeadlineStream::new(stream, deadline));
+				},
+				Err(e) => last_err = e,
+			}
+		}
+		Err(last_err)
+	}
+
+	/// Another handle to the same socket, sharing the deadline.
+	pub fn try_clone(&self) -> io::Result<Self> {
+		Ok(DeadlineStream{stream: self.stream.try_clone()?, deadline: self.deadline.clone()})
+	}
+
+	/// Move the deadline, of this handle and all its clones.
+	pub fn set_deadline(&self, deadline: time::Instant) {
+		*self.deadline.lock().unwrap_or_else(|e| e.into_inner()) = deadline;
+	}
+
+	pub fn deadline(&self) -> time::Instant {
+		*self.deadline.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	pub fn get_ref(&self) -> &TcpStream {
+		&self.stream
+	}
+
+	/// The socket, without the deadline. Its read and write timeouts are left
+	/// as they were last set.
+	pub fn into_inner(self) -> TcpStream {
+		self.stream
+	}
+}
+
+impl Read for DeadlineStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.stream.set_read_timeout(Some(time_left(self.deadline())?))?;
+		self.stream.read(buf).map_err(timed_out)
+	}
+}
+
+impl Write for DeadlineStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.stream.set_write_timeout(Some(time_left(self.deadline())?))?;
+		self.stream.write(buf).map_err(timed_out)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.stream.flush()
+	}
+}
+
 #[test]
 fn with_timeout_test() {
 	assert_eq!(with_timeout(|| {1}, time::Duration::from_secs(1)), Some(1));
@@ -40,3 +187,48 @@ fn with_timeout_test() {
 	assert_eq!(with_timeout(|| {thread::sleep(time::Duration::from_millis(1500)); 1}, time::Duration::from_secs(1)), None);
 	assert!(measure.elapsed() < time::Duration::from_millis(1200));
 }
+
+#[test]
+fn abandoned_operations_test() {
+	// Other tests may be abandoning operations at the same time, so the count
+	// is only compared to itself.
+	let (started, running) = mpsc::channel();
+	let (release, wait) = mpsc::channel::<()>();
+	assert_eq!(with_timeout(move || {
+		started.send(()).unwrap();
+		let _ = wait.recv();
+	}, time::Duration::from_millis(50)), None);
+	running.recv().unwrap();
+	assert!(abandoned_operations() >= 1);
+	let before = abandoned_operations();
+	release.send(()).unwrap();
+	let measure = time::Instant::now();
+	while abandoned_operations() >= before && measure.elapsed() < time::Duration::from_secs(5) {
+		thread::sleep(time::Duration::from_millis(10));
+	}
+	assert!(abandoned_operations() < before);
+}
+
+#[test]
+fn deadline_stream_test() {
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	// Sends a byte every 50ms, forever.
+	thread::spawn(move || {
+		let (mut conn, _) = listener.accept().unwrap();
+		while conn.write_all(b"x").is_ok() {
+			thread::sleep(time::Duration::from_millis(50));
+		}
+	});
+	let start = time::Instant::now();
+	let mut stream = DeadlineStream::connect(&[addr], start + time::Duration::from_millis(300)).unwrap();
+	let err = stream.try_clone().unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+	let elapsed = start.elapsed();
+	assert!(elapsed >= time::Duration::from_millis(250) && elapsed < time::Duration::from_millis(450));
+	stream.set_deadline(time::Instant::now() + time::Duration::from_millis(200));
+	let mut buf = [0u8; 1];
+	stream.read_exact(&mut buf).unwrap();
+	assert_eq!(&buf, b"x");
+}
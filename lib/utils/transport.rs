@@ -0,0 +1,159 @@
+//! Protocol code for the checkers which speak a protocol over TCP themselves is
+//! written once, as `async fn`s over a [`Transport`]. The blocking checkers run
+//! it with [`block_on`] over a [`DeadlineStream`](crate::utils::DeadlineStream),
+//! whose reads and writes are done by the time they return, and the
+//! [async checkers](crate::checkers::async_checker) over a tokio stream.
+
+use crate::utils::DeadlineStream;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::task::{Context, Poll, Waker};
+use std::time;
+
+/// Size of each read into the buffer of [`Buffered`].
+const READ_SIZE: usize = 8192;
+
+pub(crate) trait Transport: Send {
+	/// Like `Read::read`.
+	fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a;
+	/// Like `Write::write_all`.
+	fn write_all<'a>(&'a mut self, data: &'a [u8]) -> impl Future<Output = io::Result<()>> + Send + 'a;
+}
+
+/// A blocking stream as a [`Transport`].
+pub(crate) struct Blocking<S>(pub S);
+
+impl<S: Read + Write + Send> Transport for Blocking<S> {
+	fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a {
+		std::future::ready(self.0.read(buf))
+	}
+
+	fn write_all<'a>(&'a mut self, data: &'a [u8]) -> impl Future<Output = io::Result<()>> + Send + 'a {
+		std::future::ready(self.0.write_all(data))
+	}
+}
+
+/// A tokio stream as a [`Transport`].
+#[cfg(feature = "async")]
+pub(crate) struct Async<S>(pub S);
+
+#[cfg(feature = "async")]
+impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> Transport for Async<S> {
+	fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> impl Future<Output = io::Result<usize>> + Send + 'a {
+		tokio::io::AsyncReadExt::read(&mut self.0, buf)
+	}
+
+	fn write_all<'a>(&'a mut self, data: &'a [u8]) -> impl Future<Output = io::Result<()>> + Send + 'a {
+		tokio::io::AsyncWriteExt::write_all(&mut self.0, data)
+	}
+}
+
+/// Like [`connect`](crate::utils::connect), for protocol code run by
+/// [`block_on`].
+pub(crate) fn connect_blocking(host: &str, port: u16, timeout: time::Duration) -> Result<Buffered<Blocking<DeadlineStream>>, String> {
+	crate::utils::connect(host, port, timeout).map(|s| Buffered::new(Blocking(s)))
+}
+
+/// Connect to any address of `host`, for protocol code run by an async
+/// checker, which puts its own time limit on it.
+#[cfg(feature = "async")]
+pub(crate) async fn connect_async(host: &str, port: u16) -> Result<Buffered<Async<tokio::net::TcpStream>>, String> {
+	crate::checkers::async_checker::connect(host, port, None).await.map(|s| Buffered::new(Async(s)))
+}
+
+/// Run protocol code over a [`Blocking`] transport on this thread. Nothing in
+/// it ever waits to be woken up, so it finishes on the first poll.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+	let mut future = std::pin::pin!(future);
+	match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+		Poll::Ready(output) => output,
+		Poll::Pending => panic!("block_on: the future waited, so it does not run over a blocking transport."),
+	}
+}
+
+/// Reads from a [`Transport`] through a buffer, with the errors the checkers
+/// report.
+pub(crate) struct Buffered<T> {
+	transport: T,
+	buf: Vec<u8>,
+	pos: usize,
+}
+
+impl<T: Transport> Buffered<T> {
+	pub(crate) fn new(transport: T) -> Self {
+		Buffered{transport, buf: Vec::new(), pos: 0}
+	}
+
+	/// Read more into the buffer. Returns false at the end of the stream.
+	async fn fill(&mut self) -> Result<bool, String> {
+		if self.pos == self.buf.len() {
+			self.buf.clear();
+			self.pos = 0;
+		}
+		let start = self.buf.len();
+		self.buf.resize(start + READ_SIZE, 0);
+		let res = self.transport.read(&mut self.buf[start..]).await;
+		let n = *res.as_ref().unwrap_or(&0);
+		self.buf.truncate(start + n);
+		res.map(|n| n > 0).map_err(|e| format!("Reading from server: {}", &e))
+	}
+
+	fn consume(&mut self, len: usize) -> Vec<u8> {
+		let data = self.buf[self.pos..self.pos + len].to_vec();
+		self.pos += len;
+		data
+	}
+
+	pub(crate) async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, String> {
+		while self.buf.len() - self.pos < len {
+			if !self.fill().await? {
+				return Err("Reading from server: connection closed.".to_owned());
+			}
+		}
+		Ok(self.consume(len))
+	}
+
+	/// Read up to and including the next `\n`, but at most `limit` bytes. Less
+	/// than a line is returned at the end of the stream.
+	pub(crate) async fn read_line(&mut self, limit: usize) -> Result<Vec<u8>, String> {
+		loop {
+			let available = &self.buf[self.pos..];
+			if let Some(i) = available.iter().take(limit).position(|b| *b == b'\n') {
+				return Ok(self.consume(i + 1));
+			}
+			if available.len() >= limit {
+				return Ok(self.consume(limit));
+			}
+			if !self.fill().await? {
+				return Ok(self.consume(self.buf.len() - self.pos));
+			}
+		}
+	}
+
+	/// Read until the end of the stream, but at most `limit` bytes.
+	pub(crate) async fn read_to_end(&mut self, limit: usize) -> Result<Vec<u8>, String> {
+		while self.buf.len() - self.pos < limit && self.fill().await? {}
+		Ok(self.consume(limit.min(self.buf.len() - self.pos)))
+	}
+
+	pub(crate) async fn write_all(&mut self, data: &[u8]) -> Result<(), String> {
+		self.transport.write_all(data).await.map_err(|e| format!("Writing to server: {}", &e))
+	}
+}
+
+#[test]
+fn buffered_test() {
+	let mut conn = Buffered::new(Blocking(io::Cursor::new(b"line one\nline two\r\nrest".to_vec())));
+	block_on(async {
+		assert_eq!(conn.read_line(100).await.unwrap(), b"line one\n");
+		assert_eq!(conn.read_line(4).await.unwrap(), b"line");
+		assert_eq!(conn.read_exact(6).await.unwrap(), b" two\r\n");
+		assert_eq!(conn.read_line(100).await.unwrap(), b"rest");
+		assert_eq!(conn.read_line(100).await.unwrap(), b"");
+		assert_eq!(conn.read_exact(1).await.unwrap_err(), "Reading from server: connection closed.");
+	});
+	let mut conn = Buffered::new(Blocking(io::Cursor::new(vec![7u8; 3 * READ_SIZE])));
+	assert_eq!(block_on(conn.read_exact(READ_SIZE + 1)).unwrap().len(), READ_SIZE + 1);
+	assert_eq!(block_on(conn.read_to_end(READ_SIZE)).unwrap().len(), READ_SIZE);
+	assert_eq!(block_on(conn.read_to_end(usize::MAX)).unwrap().len(), READ_SIZE - 1);
+}
@@ -9,6 +9,9 @@ use std::time;
 #[derive(Serialize)]
 pub struct StatusLogResponse {
 	pub checks: Vec<CheckLogResponse>,
+	/// Timed out checker operations which are still running in the background.
+	/// A growing count means some checker is stuck and leaking threads.
+	pub abandoned_operations: usize,
 }
 
 #[derive(Serialize)]
@@ -17,9 +20,17 @@ pub struct CheckLogResponse {
 	pub desc: &'static str,
 	pub log: Vec<CheckLogEntry>,
 	pub last_state: &'static str,
+	pub last_metrics: Vec<MetricResponse>,
 	pub statistics: QuickStatistics,
 }
 
+#[derive(Serialize)]
+pub struct MetricResponse {
+	pub name: String,
+	pub value: f64,
+	pub unit: String,
+}
+
 #[derive(Serialize)]
 pub struct CheckLogEntry {
 	pub id: datastores::CheckLogId,
@@ -35,7 +46,8 @@ pub struct QuickStatistics {
 	pub last_month: datastores::LogCounts,
 }
 
-use serverwatch::checkers::CheckResultType;
+use serverwatch::checkers::{CheckResultType, heartbeat::HeartbeatSignal};
+use std::io::Read;
 
 use std::collections::HashMap;
 
@@ -64,13 +76,21 @@ pub fn result_type_to_string(r: CheckResultType) -> &'static str {
 }
 
 pub fn get_status_log_response_struct(sw_state: State<SwState>) -> Result<StatusLogResponse, datastores::DatabaseError> {
-	let last_states: Vec<&'static str> = sw_state.schd.get_latest_results().iter().map(|s| {
+	let latest_results = sw_state.schd.get_latest_results();
+	let last_states: Vec<&'static str> = latest_results.iter().map(|s| {
 		if let Some(s) = s {
 			result_type_to_string(s.result_type)
 		} else {
 			"null"
 		}
 	}).collect();
+	let mut last_metrics: Vec<Vec<MetricResponse>> = latest_results.iter().map(|s| {
+		match s {
+			Some(s) => s.metrics.iter().map(|m| MetricResponse{name: m.name.clone(), value: m.value, unit: m.unit.clone()}).collect(),
+			None => Vec::new(),
+		}
+	}).collect();
+	std::mem::drop(latest_results);
 	let now = time::SystemTime::now();
 	use datastores::LogFilter;
 	use time::Duration;
@@ -99,6 +119,7 @@ pub fn get_status_log_response_struct(sw_state: State<SwState>) -> Result<Status
 						log
 					},
 					last_state: last_states[index],
+					last_metrics: std::mem::replace(&mut last_metrics[index], Vec::new()),
 					statistics: QuickStatistics{
 						last_day: sw_state.data_store.count_logs(*check_id, LogFilter::after(now - day))?,
 						last_7_day: sw_state.data_store.count_logs(*check_id, LogFilter::after(now - 7*day))?,
@@ -114,7 +135,8 @@ pub fn get_status_log_response_struct(sw_state: State<SwState>) -> Result<Status
 				}
 			};
 			checks
-		}
+		},
+		abandoned_operations: serverwatch::utils::abandoned_operations(),
 	})
 }
 
@@ -123,6 +145,20 @@ fn status_log(sw_state: State<SwState>) -> Result<Json<StatusLogResponse>, Strin
 	Ok(Json(get_status_log_response_struct(sw_state).map_err(|e| format!("Database error: {}", &e))?))
 }
 
+#[derive(Serialize)]
+pub struct MetricPoint {
+	pub time: u64,
+	pub value: f64,
+}
+
+/// The values of a metric of a check over the last month, e.g. `expiry_days`.
+#[get("/metric_history/<check_id>/<name>")]
+fn metric_history(sw_state: State<SwState>, check_id: datastores::CheckId, name: String) -> Result<Json<Vec<MetricPoint>>, String> {
+	let since = time::SystemTime::now() - time::Duration::from_secs(30*24*60*60);
+	let history = sw_state.data_store.metric_history(check_id, &name, datastores::LogFilter::after(since)).map_err(|e| format!("Database error: {}", &e))?;
+	Ok(Json(history.into_iter().map(|(t, value)| MetricPoint{time: t.duration_since(time::UNIX_EPOCH).unwrap().as_millis() as u64, value}).collect()))
+}
+
 #[post("/notification", data = "<task>")]
 fn set_notification(sw_state: State<SwState>, task: Json<NotificationPost>) -> Result<rocket::Response, rocket::Response> {
 	macro_rules! report_error {
@@ -164,6 +200,46 @@ fn push_test(sw_state: State<SwState>, push_test_data: Json<PushTaskData>) -> ro
 	return rocket::Response::build().status(rocket::http::Status::raw(200)).finalize();
 }
 
+/// Longest heartbeat message kept, in bytes.
+const HEARTBEAT_MESSAGE_LIMIT: u64 = 4096;
+
+fn record_heartbeat(sw_state: State<SwState>, token: &str, signal: HeartbeatSignal, message: rocket::Data) -> rocket::Response<'static> {
+	let (check_id, heartbeat) = match sw_state.heartbeats.get(token) {
+		Some(h) => h,
+		None => return rocket::Response::build().status(rocket::http::Status::NotFound).finalize(),
+	};
+	let mut buf = Vec::new();
+	if let Err(e) = message.open().take(HEARTBEAT_MESSAGE_LIMIT).read_to_end(&mut buf) {
+		return rocket::Response::build().status(rocket::http::Status::raw(400)).sized_body(std::io::Cursor::new(format!("{}", e))).finalize();
+	}
+	let message = String::from_utf8_lossy(&buf).trim().to_owned();
+	let message = if message.is_empty() { None } else { Some(message) };
+	let time = std::time::SystemTime::now();
+	if let Err(e) = sw_state.data_store.set_last_heartbeat(*check_id, datastores::HeartbeatLog{signal, time, message: message.clone()}) {
+		return rocket::Response::build().status(rocket::http::Status::InternalServerError).sized_body(std::io::Cursor::new(format!("{}", e))).finalize();
+	}
+	heartbeat.signal_at(signal, message, time);
+	rocket::Response::build().raw_status(200, "Done").finalize()
+}
+
+/// Report success of a job, with an optional message as the body.
+#[post("/heartbeat/<token>", data = "<message>")]
+fn heartbeat_success(sw_state: State<SwState>, token: String, message: rocket::Data) -> rocket::Response<'static> {
+	record_heartbeat(sw_state, &token, HeartbeatSignal::SUCCESS, message)
+}
+
+/// Report that a job has started (`start`) or failed (`fail`).
+#[post("/heartbeat/<token>/<signal>", data = "<message>")]
+fn heartbeat_signal(sw_state: State<SwState>, token: String, signal: String, message: rocket::Data) -> rocket::Response<'static> {
+	let signal = match &signal[..] {
+		"start" => HeartbeatSignal::START,
+		"success" => HeartbeatSignal::SUCCESS,
+		"fail" => HeartbeatSignal::FAIL,
+		_ => return rocket::Response::build().status(rocket::http::Status::NotFound).finalize(),
+	};
+	record_heartbeat(sw_state, &token, signal, message)
+}
+
 pub fn api_routes() -> impl Into<Vec<rocket::Route>> {
-	routes![status_log, set_notification, push_test]
+	routes![status_log, metric_history, set_notification, push_test, heartbeat_success, heartbeat_signal]
 }
@@ -1,4 +1,4 @@
-use serverwatch::checkers::{http::HttpChecker, tls::{CertificateChecker, CertificateCheckerStartTLSOptions}};
+use serverwatch::checkers::{heartbeat::{Heartbeat, HeartbeatChecker}, http::HttpChecker, tls::{CertificateChecker, CertificateCheckerStartTLSOptions, ExpiryLevel}};
 use std::time::Duration;
 use serverwatch::scheduler::simple_schd;
 use crate::datastores::CheckId;
@@ -7,12 +7,23 @@ pub struct Check {
   pub index: CheckId,
   pub desc: &'static str,
   pub schd_check: simple_schd::Check,
+  /// For heartbeat checks, the secret token in the ping URL, and where to send
+  /// the signals.
+  pub heartbeat: Option<(String, Heartbeat)>,
+}
+
+fn expiry_tiers() -> Vec<(Duration, ExpiryLevel)> {
+  let day = Duration::from_secs(24*60*60);
+  vec![(30*day, ExpiryLevel::INFO), (14*day, ExpiryLevel::WARN), (3*day, ExpiryLevel::ERROR)]
 }
 
 pub fn get_checks() -> Vec<Check> {
   let mut list = Vec::new();
   macro_rules! add_check {
     ($index:expr, $desc:expr, $min_check_interval:expr, $checker:expr) => {
+      add_check!($index, $desc, $min_check_interval, $checker, None);
+    };
+    ($index:expr, $desc:expr, $min_check_interval:expr, $checker:expr, $heartbeat:expr) => {
       list.push(Check{
         index: $index,
         desc: $desc,
@@ -20,7 +31,8 @@ pub fn get_checks() -> Vec<Check> {
           desc: $desc,
           checker: $checker,
           min_check_interval: $min_check_interval
-        }
+        },
+        heartbeat: $heartbeat,
       });
     };
   }
@@ -44,7 +56,7 @@ pub fn get_checks() -> Vec<Check> {
     ($index:expr, $domain:expr) => {
       add_check!($index, concat!("TLS ", $domain), Duration::from_secs(60), {
         let mut c = CertificateChecker::builder($domain.to_owned(), 443);
-        c.set_expiry_threshold(Duration::from_secs(10*24*60*60)); // 10 days
+        c.set_expiry_tiers(expiry_tiers());
         Box::new(c.build().unwrap())
       })
     };
@@ -54,11 +66,24 @@ pub fn get_checks() -> Vec<Check> {
       add_check!($index, concat!("SMTP ", $domain), Duration::from_secs(60), {
         let mut c = CertificateChecker::builder($domain.to_owned(), 25);
         c.set_starttls(CertificateCheckerStartTLSOptions::SMTP);
-        c.set_expiry_threshold(Duration::from_secs(10*24*60*60)); // 10 days
+        c.set_expiry_tiers(expiry_tiers());
         Box::new(c.build().unwrap())
       })
     };
   }
+  // Jobs report in with POST /api/heartbeat/<token>[/start|/fail]. The token
+  // should be long and random, and must be unique.
+  macro_rules! heartbeat {
+    ($index:expr, $desc:expr, $token:expr, $period:expr, $grace:expr) => {
+      let token: String = $token;
+      if list.iter().any(|c| c.heartbeat.as_ref().map(|(t, _)| *t == token).unwrap_or(false)) {
+        panic!("Heartbeat token of {:?} is already used by another check.", $desc);
+      }
+      let c = HeartbeatChecker::new($period, $grace);
+      let hb = c.heartbeat();
+      add_check!($index, concat!("Heartbeat ", $desc), Duration::from_secs(60), Box::new(c), Some((token, hb)));
+    };
+  }
   macro_rules! http_and_tls {
     ($start_index:expr, $domain:expr) => {
       http!($start_index, $domain);
@@ -70,5 +95,8 @@ pub fn get_checks() -> Vec<Check> {
   http_and_tls!(2<<4, "static.maowtm.org");
   http_and_tls!(3<<4, "localhost");
   smtp!(        4<<4, "gmail-smtp-in.l.google.com");
+  if let Ok(token) = std::env::var("BACKUP_HEARTBEAT_TOKEN") {
+    heartbeat!( 6<<4, "Nightly backup", token, Duration::from_secs(24*60*60), Duration::from_secs(60*60));
+  }
   list
 }
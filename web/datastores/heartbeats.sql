@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS "Heartbeats" (
+	"check_id"	INTEGER NOT NULL,
+	"signal"	TEXT NOT NULL,
+	"time"	INTEGER NOT NULL,
+	"message"	TEXT DEFAULT NULL,
+	PRIMARY KEY("check_id", "signal")
+);
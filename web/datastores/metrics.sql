@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "LogMetrics" (
+	"log_id"	INTEGER NOT NULL,
+	"name"	TEXT NOT NULL,
+	"value"	REAL NOT NULL,
+	"unit"	TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX IF NOT EXISTS "log_metrics_log_id_index" ON "LogMetrics" (
+	"log_id"	ASC
+);
@@ -1,5 +1,5 @@
 use std::time;
-use serverwatch::checkers::{CheckResult, CheckResultType};
+use serverwatch::checkers::{CheckResult, CheckResultType, heartbeat::HeartbeatSignal};
 
 pub type CheckId = u32;
 
@@ -11,6 +11,14 @@ pub struct CheckLog {
   pub result: CheckResult,
 }
 
+/// A signal received by a heartbeat check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeartbeatLog {
+  pub signal: HeartbeatSignal,
+  pub time: time::SystemTime,
+  pub message: Option<String>,
+}
+
 use std::error::Error;
 
 #[derive(Clone, Debug)]
@@ -135,7 +143,15 @@ pub trait DataStore: Send + Sync {
   fn query_log(&self, id: CheckLogId) -> DataResult<CheckLog>;
   fn search_log<'a>(&'a self, check: CheckId, search: LogFilter, order: LogOrder, each_fn: Box<dyn FnMut(CheckLogId, CheckLog) -> bool + 'a>) -> DataResult<()>;
   fn count_logs(&self, check: CheckId, filter: LogFilter) -> DataResult<LogCounts>;
+  /// The values of the metric `name` logged for `check`, oldest first.
+  /// `include_*` of the filter is ignored.
+  fn metric_history(&self, check: CheckId, name: &str, filter: LogFilter) -> DataResult<Vec<(time::SystemTime, f64)>>;
   fn update_push_subscriptions(&self, endpoint_url: &str, auth: &[u8], client_p256dh: &[u8], list: &[PushSubscription]) -> DataResult<()>;
+  /// Keep `log` as the last signal of its kind for the heartbeat check
+  /// `check`, replacing the one kept before.
+  fn set_last_heartbeat(&self, check: CheckId, log: HeartbeatLog) -> DataResult<()>;
+  /// The last signal of each kind kept for `check`.
+  fn last_heartbeats(&self, check: CheckId) -> DataResult<Vec<HeartbeatLog>>;
 }
 
 pub fn result_type_to_str(t: CheckResultType) -> &'static str {
@@ -155,5 +171,22 @@ pub fn str_to_result_type(s: &str) -> Option<CheckResultType> {
   }
 }
 
+pub fn heartbeat_signal_to_str(s: HeartbeatSignal) -> &'static str {
+  match s {
+    HeartbeatSignal::START => "start",
+    HeartbeatSignal::SUCCESS => "success",
+    HeartbeatSignal::FAIL => "fail",
+  }
+}
+
+pub fn str_to_heartbeat_signal(s: &str) -> Option<HeartbeatSignal> {
+  match s {
+    "start" => Some(HeartbeatSignal::START),
+    "success" => Some(HeartbeatSignal::SUCCESS),
+    "fail" => Some(HeartbeatSignal::FAIL),
+    _ => None
+  }
+}
+
 pub mod sqlite;
 #[cfg(test)] mod test;
@@ -4,7 +4,7 @@ use rusqlite::OptionalExtension;
 use std::sync::Mutex;
 use std::cell::RefCell;
 use std::time::{SystemTime, Duration};
-use serverwatch::checkers::{CheckResult, CheckResultType};
+use serverwatch::checkers::{CheckResult, CheckResultType, Metric};
 use rusqlite::types::{Value, ValueRef};
 
 pub struct SQLiteDataStore {
@@ -23,12 +23,18 @@ impl SQLiteDataStore {
       Self::initialize(&conn)?;
     }
     Self::check(&conn)?;
+    // Databases created before metrics were logged or heartbeats were kept
+    // don't have the tables yet.
+    conn.execute_batch(include_str!("metrics.sql")).map_err(DatabaseError::from_inner)?;
+    conn.execute_batch(include_str!("heartbeats.sql")).map_err(DatabaseError::from_inner)?;
     conn.busy_timeout(Duration::from_millis(100)).map_err(|e| DatabaseError::from_inner_and_str(e, "Unable to set busy timeout"))?;
     Ok(Self{conn: Mutex::new(RefCell::new(conn))})
   }
 
   fn initialize(conn: &rusqlite::Connection) -> DataResult<()> {
-    conn.execute_batch(include_str!("scheme.sql")).map_err(DatabaseError::from_inner)
+    conn.execute_batch(include_str!("scheme.sql")).map_err(DatabaseError::from_inner)?;
+    conn.execute_batch(include_str!("metrics.sql")).map_err(DatabaseError::from_inner)?;
+    conn.execute_batch(include_str!("heartbeats.sql")).map_err(DatabaseError::from_inner)
   }
 
   fn check(conn: &rusqlite::Connection) -> DataResult<()> {
@@ -73,9 +79,9 @@ fn time2int_test() {
 pub fn row_to_check_log(row: &rusqlite::Row) -> rusqlite::Result<DataResult<CheckLog>> {
   Ok(Ok(CheckLog{
     time: int2time(row.get(0)?),
-    result: CheckResult{
-      result_type: match str_to_result_type(&(row.get(1)?: String)) { Some(s) => s, None => return Ok(Err(DatabaseError::from_static_str("Invalid enum value for result_type"))) },
-      info: match row.get_raw_checked(2)? {
+    result: CheckResult::new(
+      match str_to_result_type(&(row.get(1)?: String)) { Some(s) => s, None => return Ok(Err(DatabaseError::from_static_str("Invalid enum value for result_type"))) },
+      match row.get_raw_checked(2)? {
         ValueRef::Null => None,
         ValueRef::Text(s) => Some(match String::from_utf8(Vec::from(s)) {
           Ok(s) => s,
@@ -83,10 +89,22 @@ pub fn row_to_check_log(row: &rusqlite::Row) -> rusqlite::Result<DataResult<Chec
         }),
         _ => return Ok(Err(DatabaseError::from_static_str("invalid column type for info"))),
       }
-    }
+    )
   }))
 }
 
+/// Attach the metrics logged with the log `id` to its result.
+fn load_metrics(conn: &rusqlite::Connection, id: CheckLogId, log: &mut CheckLog) -> DataResult<()> {
+  let mut stat = conn.prepare_cached(r#"SELECT name, value, unit FROM LogMetrics WHERE log_id = ? ORDER BY rowid ASC"#).map_err(DatabaseError::from_inner)?;
+  let metrics = stat.query_map(&[id as i64], |row| {
+    Ok(Metric{name: row.get(0)?, value: row.get(1)?, unit: row.get(2)?})
+  }).map_err(DatabaseError::from_inner)?;
+  for metric in metrics {
+    log.result.metrics.push(metric.map_err(DatabaseError::from_inner)?);
+  }
+  Ok(())
+}
+
 impl DataStore for SQLiteDataStore {
   fn add_log_and_push<'a>(&self, check_id: CheckId, log: CheckLog, mut send_push: Box<dyn FnMut(String, Vec<u8>, Vec<u8>) + 'a>) -> DataResult<CheckLogId> {
     let now = log.time;
@@ -97,6 +115,10 @@ impl DataStore for SQLiteDataStore {
     tr.prepare_cached(r#"INSERT INTO Logs ("check_id", "time", "result_type", "result_info") VALUES (?, ?, ?, ?);"#).map_err(DatabaseError::from_inner)?
       .execute(&[Value::from(check_id), Value::from(time2int(log.time)), Value::from(result_type_to_str(log.result.result_type).to_owned()), match log.result.info { Some(ref s) => Value::from(s.to_owned()), None => Value::Null }]).map_err(DatabaseError::from_inner)?;
     let log_id = tr.last_insert_rowid() as u64;
+    for metric in log.result.metrics.iter() {
+      tr.prepare_cached(r#"INSERT INTO LogMetrics ("log_id", "name", "value", "unit") VALUES (?, ?, ?, ?);"#).map_err(DatabaseError::from_inner)?
+        .execute(&[Value::from(log_id as i64), Value::from(metric.name.clone()), Value::from(metric.value), Value::from(metric.unit.clone())]).map_err(|e| DatabaseError::from_inner_and_str(e, "unable to insert metrics"))?;
+    }
     let last_counts: Option<(i64, i64, i64, SystemTime)> = tr.prepare_cached("SELECT count_up, count_warn, count_error, up_to FROM LogCount WHERE check_id = ? ORDER BY up_to DESC LIMIT 1").map_err(DatabaseError::from_inner)?.query_row(&[check_id],
           |row| {
             Ok((row.get(0)?, row.get(1)?, row.get(2)?, int2time(row.get(3)?)))
@@ -129,14 +151,16 @@ impl DataStore for SQLiteDataStore {
         .execute(&[Value::from(check_id), Value::from(time2int(now)), Value::from(count_up), Value::from(count_warn), Value::from(count_error)]).map_err(|e| DatabaseError::from_inner_and_str(e, "unable to insert new counts"))?;
     }
 
-    if log.result.result_type != CheckResultType::UP {
+    if log.result.should_notify() {
       tr.prepare_cached(r#"SELECT endpoint_url, auth, client_p256dh, notify_warn FROM pushSubscriptions WHERE check_id = ?"#).map_err(DatabaseError::from_inner)?
         .query_and_then::<_, rusqlite::Error, _, _>(&[check_id], |row| {
           let endpoint_url: String = row.get(0)?;
           let auth: Vec<u8> = row.get(1)?;
           let p256dh: Vec<u8> = row.get(2)?;
           let notify_warn: bool = row.get(3)?;
-          if !notify_warn && log.result.result_type == CheckResultType::WARN {
+          // Anything less than an error, including informational notifications
+          // for UP results, is only sent to those who asked for warnings.
+          if !notify_warn && log.result.result_type != CheckResultType::ERROR {
             return Ok(());
           }
           send_push(endpoint_url, auth, p256dh);
@@ -151,7 +175,9 @@ impl DataStore for SQLiteDataStore {
   fn query_log(&self, id: CheckLogId) -> DataResult<CheckLog> {
     let conn = self.conn.lock().unwrap();
     let conn = conn.borrow();
-    conn.query_row("SELECT time, result_type, result_info FROM Logs WHERE id = ?", &[id as i64], row_to_check_log).map_err(DatabaseError::from_inner)?
+    let mut log = conn.query_row("SELECT time, result_type, result_info FROM Logs WHERE id = ?", &[id as i64], row_to_check_log).map_err(DatabaseError::from_inner)??;
+    load_metrics(&*conn, id, &mut log)?;
+    Ok(log)
   }
   fn search_log<'a>(&'a self, check: CheckId, search: LogFilter, order: LogOrder, mut each_fn: Box<dyn FnMut(CheckLogId, CheckLog) -> bool + 'a>) -> DataResult<()> {
     let mut sql = String::from("SELECT time, result_type, result_info, id FROM Logs WHERE check_id = ?");
@@ -198,7 +224,8 @@ impl DataStore for SQLiteDataStore {
         Err(e) => Err(DatabaseError::from_inner(e))
       }
     }) {
-      if let Ok((id, log)) = r {
+      if let Ok((id, mut log)) = r {
+        load_metrics(&*conn, id, &mut log)?;
         if !each_fn(id, log) {
           break;
         }
@@ -251,6 +278,31 @@ impl DataStore for SQLiteDataStore {
     Ok(res)
   }
 
+  fn metric_history(&self, check: CheckId, name: &str, filter: LogFilter) -> DataResult<Vec<(SystemTime, f64)>> {
+    let mut sql = String::from(r#"SELECT Logs.time, LogMetrics.value FROM Logs INNER JOIN LogMetrics ON LogMetrics.log_id = Logs.id WHERE Logs.check_id = ? AND LogMetrics.name = ?"#);
+    let mut values: Vec<Value> = vec![Value::from(check), Value::from(name.to_owned())];
+    if let Some(min_time) = filter.min_time {
+      sql.push_str(" AND Logs.time >= ?");
+      values.push(Value::from(time2int(min_time)));
+    }
+    if let Some(max_time) = filter.max_time {
+      sql.push_str(" AND Logs.time < ?");
+      values.push(Value::from(time2int(max_time)));
+    }
+    sql.push_str(" ORDER BY Logs.time ASC");
+    let conn = self.conn.lock().unwrap();
+    let conn = conn.borrow();
+    let mut stat = conn.prepare_cached(&sql).map_err(|e| DatabaseError::from_inner_and_str(e, "unable to prepare SQL"))?;
+    let points = stat.query_map(&values, |row| {
+      Ok((int2time(row.get(0)?), row.get(1)?))
+    }).map_err(DatabaseError::from_inner)?;
+    let mut history = Vec::new();
+    for point in points {
+      history.push(point.map_err(DatabaseError::from_inner)?);
+    }
+    Ok(history)
+  }
+
   fn update_push_subscriptions(&self, endpoint_url: &str, auth: &[u8], client_p256dh: &[u8], list: &[PushSubscription]) -> DataResult<()> {
     let conn = self.conn.lock().unwrap();
     let mut conn = conn.borrow_mut();
@@ -270,6 +322,31 @@ impl DataStore for SQLiteDataStore {
 
     Ok(())
   }
+
+  fn set_last_heartbeat(&self, check: CheckId, log: HeartbeatLog) -> DataResult<()> {
+    let conn = self.conn.lock().unwrap();
+    let conn = conn.borrow();
+    conn.prepare_cached(r#"INSERT OR REPLACE INTO Heartbeats ("check_id", "signal", "time", "message") VALUES (?, ?, ?, ?);"#).map_err(DatabaseError::from_inner)?
+      .execute(&[Value::from(check as i64), Value::from(heartbeat_signal_to_str(log.signal).to_owned()), Value::from(time2int(log.time)), match log.message { Some(s) => Value::from(s), None => Value::Null }])
+      .map_err(|e| DatabaseError::from_inner_and_str(e, "unable to keep heartbeat"))?;
+    Ok(())
+  }
+
+  fn last_heartbeats(&self, check: CheckId) -> DataResult<Vec<HeartbeatLog>> {
+    let conn = self.conn.lock().unwrap();
+    let conn = conn.borrow();
+    let mut stat = conn.prepare_cached(r#"SELECT signal, time, message FROM Heartbeats WHERE check_id = ?"#).map_err(DatabaseError::from_inner)?;
+    let rows = stat.query_map(&[check as i64], |row| {
+      Ok((row.get(0)?: String, row.get(1)?: i64, row.get(2)?: Option<String>))
+    }).map_err(DatabaseError::from_inner)?;
+    let mut logs = Vec::new();
+    for row in rows {
+      let (signal, time, message) = row.map_err(DatabaseError::from_inner)?;
+      let signal = str_to_heartbeat_signal(&signal).ok_or_else(|| DatabaseError::from_static_str("Invalid enum value for signal"))?;
+      logs.push(HeartbeatLog{signal, time: int2time(time), message});
+    }
+    Ok(logs)
+  }
 }
 
 impl SQLiteDataStore {
@@ -202,3 +202,48 @@ fn count_query() {
 	assert_eq!(store.count_logs(0, LogFilter::after(get_time(300))).unwrap(), get_logcounts(4, 1, 0));
 	assert_eq!(store.count_logs(0, LogFilter::after(get_time(299))).unwrap(), get_logcounts(4, 1, 0));
 }
+
+#[test]
+fn metrics() {
+	let store = SQLiteDataStore::new_in_memory().unwrap();
+	let cl_1 = CheckLog{
+		time: get_time(100),
+		result: CheckResult::up(None).with_metric("expiry_days", 20.5, "days").with_metric("response_ms", 12.0, "ms"),
+	};
+	let id_1 = store.add_log(0, cl_1.clone()).unwrap();
+	let cl_2 = CheckLog{
+		time: get_time(200),
+		result: CheckResult::warn(None).with_metric("expiry_days", 13.5, "days"),
+	};
+	store.add_log(0, cl_2.clone()).unwrap();
+	store.add_log(1, CheckLog{time: get_time(150), result: CheckResult::up(None).with_metric("expiry_days", 1.0, "days")}).unwrap();
+	assert_eq!(&store.query_log(id_1).unwrap(), &cl_1);
+
+	let mut result = vec![];
+	store.search_log(0, LogFilter::default(), LogOrder::TimeAsc, Box::new(|_, check_log| {
+		result.push(check_log);
+		true
+	})).unwrap();
+	assert_eq!(&result[..], &[cl_1, cl_2]);
+
+	assert_eq!(store.metric_history(0, "expiry_days", LogFilter::default()).unwrap(), vec![(get_time(100), 20.5), (get_time(200), 13.5)]);
+	assert_eq!(store.metric_history(0, "expiry_days", LogFilter::after(get_time(150))).unwrap(), vec![(get_time(200), 13.5)]);
+	assert_eq!(store.metric_history(0, "response_ms", LogFilter::default()).unwrap(), vec![(get_time(100), 12.0)]);
+	assert_eq!(store.metric_history(0, "nothing", LogFilter::default()).unwrap(), vec![]);
+}
+
+#[test]
+fn heartbeats() {
+	use serverwatch::checkers::heartbeat::HeartbeatSignal;
+	let store = SQLiteDataStore::new_in_memory().unwrap();
+	assert_eq!(store.last_heartbeats(0).unwrap(), vec![]);
+	let start = HeartbeatLog{signal: HeartbeatSignal::START, time: get_time(100), message: None};
+	store.set_last_heartbeat(0, start.clone()).unwrap();
+	store.set_last_heartbeat(0, HeartbeatLog{signal: HeartbeatSignal::SUCCESS, time: get_time(150), message: None}).unwrap();
+	let success = HeartbeatLog{signal: HeartbeatSignal::SUCCESS, time: get_time(200), message: Some("3 files".to_owned())};
+	store.set_last_heartbeat(0, success.clone()).unwrap();
+	store.set_last_heartbeat(1, HeartbeatLog{signal: HeartbeatSignal::FAIL, time: get_time(300), message: None}).unwrap();
+	let mut logs = store.last_heartbeats(0).unwrap();
+	logs.sort_by_key(|l| l.time);
+	assert_eq!(logs, vec![start, success]);
+}
@@ -10,12 +10,13 @@ use serde::Serialize;
 struct Ctx {
   pub check_state_json: String,
   pub checks: Vec<CheckLogResponse>,
+  pub abandoned_operations: usize,
 }
 
 #[get("/")]
 fn index(sw_state: State<SwState>) -> Result<Template, String> {
   let check_state = get_status_log_response_struct(sw_state).map_err(|e| format!("Unable to fetch status from database: {}", &e))?;
-  Ok(Template::render("index", Ctx{check_state_json: serde_json::to_string(&check_state).map_err(|e| format!("{}", &e))?, checks: check_state.checks}))
+  Ok(Template::render("index", Ctx{check_state_json: serde_json::to_string(&check_state).map_err(|e| format!("{}", &e))?, checks: check_state.checks, abandoned_operations: check_state.abandoned_operations}))
 }
 
 #[get("/sw.js")]
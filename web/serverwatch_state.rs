@@ -1,7 +1,9 @@
 use serverwatch::scheduler::simple_schd::SimpleSchd;
+use serverwatch::checkers::{CheckResultType, heartbeat::Heartbeat};
 use super::{checks, datastores, push::push};
 use datastores::DataStore;
 use std::sync::{Arc, Mutex, mpsc};
+use std::collections::HashMap;
 use openssl::ec;
 use std::time;
 
@@ -13,14 +15,30 @@ pub struct SwState {
   pub app_server_key: ec::EcKey<openssl::pkey::Private>,
   pub app_server_pub_key_b64: String,
   pub push_queue: Mutex<mpsc::Sender<(String, Vec<u8>, Vec<u8>, String, time::Duration, String)>>,
+  /// Heartbeat checks and their check ids, by the token in their ping URL.
+  pub heartbeats: HashMap<String, (datastores::CheckId, Heartbeat)>,
 }
 
 pub fn init() -> SwState {
-  let checks_list = checks::get_checks();
+  let mut checks_list = checks::get_checks();
+  let heartbeats: HashMap<String, (datastores::CheckId, Heartbeat)> = checks_list.iter_mut().filter_map(|x| {
+    let index = x.index;
+    x.heartbeat.take().map(|(token, hb)| (token, (index, hb)))
+  }).collect();
   let descs_list: Vec<&'static str> = checks_list.iter().map(|x| x.desc).collect();
   let checkids_list: Vec<datastores::CheckId> = checks_list.iter().map(|x| x.index).collect();
   let schd = Arc::new(SimpleSchd::new(checks_list.into_iter().map(|x| x.schd_check).collect()));
   let data_store = Arc::new(datastores::sqlite::SQLiteDataStore::open("/tmp/test.db").unwrap());
+  // Load the last signals, so that a heartbeat missed while we were down
+  // still shows up.
+  for (check_id, hb) in heartbeats.values() {
+    match data_store.last_heartbeats(*check_id) {
+      Ok(logs) => for log in logs {
+        hb.signal_at(log.signal, log.message, log.time);
+      },
+      Err(e) => eprintln!("Unable to load heartbeats for {}: {}", check_id, e),
+    }
+  }
   for _ in 0..4 {
     let schd_ref = schd.clone();
     std::thread::spawn(move || {
@@ -52,7 +70,11 @@ pub fn init() -> SwState {
                   let mut push_body = String::new();
                   push_body.push_str(&format!("{}\n", check_id));
                   push_body.push_str(&format!("{}\n", log.time.duration_since(time::UNIX_EPOCH).unwrap().as_millis()));
-                  push_body.push_str(&format!("{:?} {:?}ed\n", desc, log.result.result_type));
+                  if log.result.result_type == CheckResultType::UP {
+                    push_body.push_str(&format!("{:?}: notice\n", desc));
+                  } else {
+                    push_body.push_str(&format!("{:?} {:?}ed\n", desc, log.result.result_type));
+                  }
                   push_body.push_str(match log.result.info {
                     Some(ref info) => info,
                     None => "(no info)"
@@ -107,5 +129,6 @@ pub fn init() -> SwState {
     app_server_key,
     app_server_pub_key_b64: pub_key_b64,
     push_queue: Mutex::new(push_queue_send),
+    heartbeats,
   }
 }